@@ -0,0 +1,467 @@
+use std::{error::Error, fmt::{Display, Formatter, Result as FmtResult}, io::{self, stdin, stdout, ErrorKind, IsTerminal, Write}, sync::{Arc, Mutex}, thread, time::Duration};
+
+use crate::{editor::{Input, LineEditor}, json, kvpair::KVPair, operators::{execute_streaming, parse, quote, validate, CancellationToken, Command, KeyRange, Operator, Output, QueryError}, http::HttpServer, replication::{Primary, Secondary, DEFAULT_WAL_ARCHIVE_SIZE}, resp::{RespServer, DEFAULT_RESP_ADDR}, storage::{comparator::builtin_comparator, error::LsmError, lsm::LsmTree, manifest::Manifest, options::LsmOptions}};
+
+// How often a read-only DB being served picks up what its writer has done
+const CATCH_UP_INTERVAL: Duration = Duration::from_millis(100);
+
+pub const USAGE: &str = "\
+usage: probable-fiesta [--json] [--read-only] <db path> [command]
+
+Runs a single command and exits, or with no command reads commands from stdin, with line
+editing and history on a terminal. With --read-only, the DB is opened only to read it,
+alongside whichever process has it open for writing, and picks up what that process
+writes before each command. Commands:
+
+    get <key>                 value of the key, exits with 1 if it has none
+    put <key> <value>
+    del <key>
+    scan [--prefix <prefix> | --range <start> <end>] [--limit <n>]
+    stats
+    compact
+    dump                      every key, as PUT commands that load them into another DB
+    serve [--resp <addr>] [--http <addr>] [--replication <addr>] [--replica-of <addr>]
+                              serves the DB to Redis clients, and over HTTP, on the
+                              addresses given, or only RESP on 127.0.0.1:6379. Ships
+                              its commits to secondaries connecting on the replication
+                              address, or applies those of the primary it is a replica of,
+                              starting from a copy of the primary's segments when the
+                              primary no longer logs the commits the replica is missing
+
+Lines read from stdin are in the query language, e.g. SCAN PREFIX user: | COUNT, along with
+.json and .text to switch the output format, .dump, .help and .quit
+
+Text output quotes keys and values in the query language when they aren't plain words.
+JSON output is a line per command, non-UTF-8 bytes being replaced with U+FFFD";
+
+// How command output is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Repl,
+    Execute(Command),
+    Dump,
+    Serve{resp: Option<String>, http: Option<String>, replication: Option<String>, replica_of: Option<String>},
+    Help,
+}
+
+// What the binary was asked to do, from its arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub path: String,
+    pub format: Format,
+    pub read_only: bool,
+    pub action: Action,
+}
+
+/*
+Parse Args: Parses the arguments the binary was run with, not including its own name.
+Options come before the DB path, and the command with its arguments after it
+*/
+pub fn parse_args(args: &[String]) -> Result<Invocation, String> {
+    let mut args = args.iter().map(String::as_str);
+    let mut format = Format::Text;
+    let mut read_only = false;
+    let path = loop {
+        match args.next() {
+            Some("--json") => format = Format::Json,
+            Some("--text") => format = Format::Text,
+            Some("--read-only") => read_only = true,
+            Some("-h") | Some("--help") => return Ok(Invocation{path: String::new(), format, read_only, action: Action::Help}),
+            Some(option) if option.starts_with("--") => return Err(format!("unknown option {}", option)),
+            Some(path) => break path.to_string(),
+            None => return Err("missing db path".to_string()),
+        }
+    };
+
+    let mut arg = |expected: &str| args.next().map(|arg| arg.as_bytes().to_vec()).ok_or(format!("missing {}", expected));
+    let action = match arg("").ok().as_deref() {
+        None => Action::Repl,
+        Some(b"get") => Action::Execute(Command::Pipeline(vec![Operator::Get{key: arg("key")?}])),
+        Some(b"put") => Action::Execute(Command::Pipeline(vec![Operator::Put{key: arg("key")?, value: arg("value")?}])),
+        Some(b"del") => Action::Execute(Command::Pipeline(vec![Operator::Delete{key: arg("key")?}])),
+        Some(b"scan") => {
+            let mut range = KeyRange::All;
+            let mut limit = None;
+            while let Ok(option) = arg("") {
+                match option.as_slice() {
+                    b"--prefix" => range = KeyRange::Prefix(arg("prefix")?),
+                    b"--range" => range = KeyRange::Range{start: arg("start key")?, end: arg("end key")?},
+                    b"--limit" => {
+                        let number = arg("limit")?;
+                        match std::str::from_utf8(&number).ok().and_then(|number| number.parse().ok()) {
+                            Some(number) => limit = Some(number),
+                            None => return Err(format!("invalid limit {}", String::from_utf8_lossy(&number))),
+                        }
+                    },
+                    _ => return Err(format!("unknown scan option {}", String::from_utf8_lossy(&option))),
+                }
+            }
+            let mut operators = vec![Operator::Scan{range}];
+            operators.extend(limit.map(Operator::Limit));
+            Action::Execute(Command::Pipeline(operators))
+        },
+        Some(b"stats") => Action::Execute(Command::Stats),
+        Some(b"compact") => Action::Execute(Command::Compact),
+        Some(b"dump") => Action::Dump,
+        Some(b"serve") => {
+            let (mut resp, mut http, mut replication, mut replica_of) = (None, None, None, None);
+            while let Ok(option) = arg("") {
+                let addr = match option.as_slice() {
+                    b"--resp" => &mut resp,
+                    b"--http" => &mut http,
+                    b"--replication" => &mut replication,
+                    b"--replica-of" => &mut replica_of,
+                    _ => return Err(format!("unknown serve option {}", String::from_utf8_lossy(&option))),
+                };
+                *addr = Some(String::from_utf8_lossy(&arg("address")?).into_owned());
+            }
+            if read_only && replica_of.is_some() {
+                return Err("a replica writes what it replicates, it can't be read-only".to_string());
+            }
+            if resp.is_none() && http.is_none() {
+                resp = Some(DEFAULT_RESP_ADDR.to_string());
+            }
+            Action::Serve{resp, http, replication, replica_of}
+        },
+        Some(command) => return Err(format!("unknown command {}", String::from_utf8_lossy(command))),
+    };
+    if let Ok(extra) = arg("") {
+        return Err(format!("unexpected argument {}", String::from_utf8_lossy(&extra)));
+    }
+    Ok(Invocation{path, format, read_only, action})
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Query(QueryError),
+    Open(LsmError),
+    // Writing the output failed, e.g. because whatever it was piped into exited
+    Io(io::Error),
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            CliError::Query(e) => write!(f, "{}", e),
+            CliError::Open(e) => write!(f, "unable to open db: {}", e),
+            CliError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CliError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CliError::Query(e) => Some(e),
+            CliError::Open(e) => Some(e),
+            CliError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<QueryError> for CliError {
+    fn from(e: QueryError) -> Self {
+        CliError::Query(e)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Io(e)
+    }
+}
+
+/*
+Open: Opens the DB at the path, creating it if there is none. A DB that already exists is
+opened with the comparator named in its manifest and with all its column families, so any
+DB using the built in comparators can be opened without knowing how it was created
+*/
+pub fn open(path: &str) -> Result<LsmTree, LsmError> {
+    open_with_options(path, LsmOptions::default())
+}
+
+// Same as open, with the options to open the DB and each of its column families with, other than their comparators
+pub fn open_with_options(path: &str, options: LsmOptions) -> Result<LsmTree, LsmError> {
+    let options_for = |comparator: &str| {
+        let mut options = options.clone();
+        if let Some(comparator) = builtin_comparator(comparator) {
+            options = options.comparator(comparator);
+        }
+        options
+    };
+    match Manifest::read(path) {
+        Ok(Some(manifest)) => {
+            let column_families = manifest.column_families.iter().map(|(family, comparator)| (family.as_str(), options_for(comparator))).collect();
+            LsmTree::open_with_column_families(path, options_for(&manifest.comparator), column_families)
+        },
+        _ => LsmTree::open(path, options.clone()),
+    }
+}
+
+/*
+Main: Runs the binary with its arguments, returning the code to exit with: 0 on success, 1
+when a GET found nothing, and 2 on any error
+*/
+pub fn main(args: &[String]) -> i32 {
+    let invocation = match parse_args(args) {
+        Ok(invocation) => invocation,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            return 2;
+        },
+    };
+    if invocation.action == Action::Help {
+        println!("{}", USAGE);
+        return 0;
+    }
+
+    // A primary keeps the commits it flushes, for secondaries to catch up from
+    let options = match &invocation.action {
+        Action::Serve{replication: Some(_), ..} => LsmOptions::default().wal_archive_size(Some(DEFAULT_WAL_ARCHIVE_SIZE)),
+        _ => LsmOptions::default(),
+    };
+    let options = options.read_only(invocation.read_only);
+    let mut lsm = match open_with_options(&invocation.path, options) {
+        Ok(lsm) => lsm,
+        Err(e) => {
+            eprintln!("error: {}", CliError::Open(e));
+            return 2;
+        },
+    };
+    let (mut out, mut err) = (stdout().lock(), io::stderr());
+    let result = match invocation.action {
+        Action::Execute(command) => execute(&mut lsm, &[command], invocation.format, &mut out, &mut err),
+        Action::Dump => dump(&mut lsm, invocation.format, &mut out).map(|_| true).map_err(CliError::Io),
+        Action::Serve{resp, http, replication, replica_of} => {
+            serve(lsm, resp.as_deref(), http.as_deref(), replication.as_deref(), replica_of.as_deref()).map(|_| true).map_err(CliError::Io)
+        },
+        _ => {
+            let result = match stdin().is_terminal() {
+                true => repl(&mut lsm, invocation.format, &mut LineEditor::terminal(), &mut out, &mut err),
+                false => repl(&mut lsm, invocation.format, &mut stdin().lock(), &mut out, &mut err),
+            };
+            result.map(|_| true).map_err(CliError::Io)
+        },
+    };
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        // Whatever the output was piped into has all it wanted
+        Err(CliError::Io(e)) if e.kind() == ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            2
+        },
+    }
+}
+
+// Serves the DB on each address given, following the primary if it has one, until the process is killed
+fn serve(lsm: LsmTree, resp: Option<&str>, http: Option<&str>, replication: Option<&str>, replica_of: Option<&str>) -> io::Result<()> {
+    let read_only = lsm.is_read_only();
+    let lsm = Arc::new(Mutex::new(lsm));
+    let mut servers = Vec::new();
+    if read_only {
+        // Picks up what the DB's writer does, for as long as the process serves it
+        let lsm = lsm.clone();
+        thread::spawn(move || loop {
+            thread::sleep(CATCH_UP_INTERVAL);
+            if let Err(e) = lsm.lock().unwrap().catch_up() {
+                eprintln!("error: failed to catch up: {}", e);
+            }
+        });
+    }
+    // Kept alive while serving, following the primary in the background
+    let _secondary = match replica_of {
+        Some(addr) => {
+            let secondary = Secondary::new(addr, lsm.clone())?;
+            eprintln!("replicating from {}", addr);
+            Some(secondary.spawn())
+        },
+        None => None,
+    };
+    if let Some(addr) = resp {
+        let server = RespServer::bind(addr, lsm.clone())?;
+        eprintln!("serving RESP on {}", server.local_addr()?);
+        servers.push(server.spawn()?);
+    }
+    if let Some(addr) = http {
+        let server = HttpServer::bind(addr, lsm.clone())?;
+        eprintln!("serving HTTP on {}", server.local_addr()?);
+        servers.push(server.spawn()?);
+    }
+    if let Some(addr) = replication {
+        let primary = Primary::bind(addr, lsm.clone())?;
+        eprintln!("shipping commits to secondaries on {}", primary.local_addr()?);
+        servers.push(primary.spawn()?);
+    }
+    for server in servers {
+        server.join()?;
+    }
+    Ok(())
+}
+
+/*
+Execute: Validates the commands, then executes them in order, writing the output of each
+as it goes, scanned rows included. Returns false if a GET found nothing, which text output
+reports on err rather than out, so out only ever holds values
+*/
+pub fn execute(lsm: &mut LsmTree, commands: &[Command], format: Format, out: &mut dyn Write, err: &mut dyn Write) -> Result<bool, CliError> {
+    validate(commands, lsm.comparator().as_ref())?;
+    let mut found = true;
+    for command in commands {
+        // Whether the command writes a GET's value, or the rows of a SCAN
+        let (get, scan) = match command {
+            Command::Pipeline(operators) if !matches!(operators.last(), Some(Operator::Count)) => match operators.first() {
+                Some(Operator::Get{..}) => (true, false),
+                Some(Operator::Scan{..}) => (false, true),
+                _ => (false, false),
+            },
+            _ => (false, false),
+        };
+        let token = CancellationToken::new();
+        // The first failed write, which cancels the rest of the scan
+        let mut failed = None;
+        let mut rows = 0;
+        if format == Format::Json && scan {
+            write!(out, "[")?;
+        }
+        let mut sink = |pair: KVPair<'static>| {
+            let written = match (format, get) {
+                (Format::Text, true) => writeln!(out, "{}", quote(&pair.value)),
+                (Format::Text, false) => writeln!(out, "{} {}", quote(&pair.key), quote(&pair.value)),
+                (Format::Json, _) => write!(out, "{}{}", if rows > 0 { "," } else { "" }, json_pair(&pair)),
+            };
+            rows += 1;
+            if let Err(e) = written {
+                failed.get_or_insert(e);
+                token.cancel();
+            }
+        };
+        let output = execute_streaming(lsm, command, &token, &mut sink);
+        if let Some(e) = failed {
+            return Err(CliError::Io(e));
+        }
+
+        match (output?, format) {
+            (Output::Streamed(0), Format::Text) if get => {
+                writeln!(err, "(not found)")?;
+                found = false;
+            },
+            (Output::Streamed(0), Format::Json) if get => {
+                writeln!(out, "null")?;
+                found = false;
+            },
+            (Output::Streamed(_), Format::Json) if scan => writeln!(out, "]")?,
+            (Output::Streamed(_), _) => {},
+            (Output::Count(count), Format::Text) => writeln!(out, "{}", count)?,
+            (Output::Count(count), Format::Json) => writeln!(out, "{{\"count\":{}}}", count)?,
+            (Output::Written(_) | Output::Compacted, Format::Text) => writeln!(out, "OK")?,
+            (Output::Written(written), Format::Json) => writeln!(out, "{{\"written\":{}}}", written)?,
+            (Output::Compacted, Format::Json) => writeln!(out, "{{\"compacted\":true}}")?,
+            (Output::Stats(stats), Format::Text) => write!(out, "{}", stats)?,
+            (Output::Stats(stats), Format::Json) => {
+                let tickers: Vec<String> = stats.lines()
+                    .filter_map(|line| line.split_once(": "))
+                    .map(|(name, count)| format!("{}:{}", json::string(name.as_bytes()), count))
+                    .collect();
+                writeln!(out, "{{{}}}", tickers.join(","))?;
+            },
+            (Output::Rows(_), _) => unreachable!("streamed commands don't collect rows"),
+        }
+        // A JSON GET is a lone object, not an array of one
+        if format == Format::Json && get && rows > 0 {
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(found)
+}
+
+/*
+Dump: Writes every live key in the default column family with its value. As text, each is
+a PUT command, so the dump can be piped into the binary to load it into another DB, and as
+JSON, each is an object on its own line
+*/
+pub fn dump(lsm: &mut LsmTree, format: Format, out: &mut dyn Write) -> io::Result<()> {
+    for pair in lsm.iter() {
+        match format {
+            Format::Text => writeln!(out, "PUT {} {}", quote(&pair.key), quote(&pair.value))?,
+            Format::Json => writeln!(out, "{}", json_pair(&pair))?,
+        }
+    }
+    out.flush()
+}
+
+/*
+REPL: Reads commands from the input until it ends, executing each line as it is read.
+A line with a BATCH that isn't ended yet, or a string that isn't closed, is continued on
+the next line. Errors are written to err and the REPL goes on to the next line, so a
+script runs to the end even if some of its commands fail
+*/
+pub fn repl(lsm: &mut LsmTree, format: Format, input: &mut dyn Input, out: &mut dyn Write, err: &mut dyn Write) -> io::Result<()> {
+    let mut format = format;
+    let mut pending = String::new();
+    loop {
+        let prompt = if pending.is_empty() { "> " } else { "... " };
+        let line = match input.next_line(prompt) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {
+                pending.clear();
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+
+        if pending.is_empty() {
+            match line.trim() {
+                ".quit" | ".exit" => return Ok(()),
+                ".json" => format = Format::Json,
+                ".text" => format = Format::Text,
+                ".dump" => dump(lsm, format, out)?,
+                ".help" => writeln!(out, "{}", USAGE)?,
+                meta if meta.starts_with('.') => writeln!(err, "error: unknown command {}", meta)?,
+                _ => pending.push_str(&line),
+            }
+        }
+        else {
+            pending.push('\n');
+            pending.push_str(&line);
+        }
+        if pending.is_empty() {
+            continue;
+        }
+
+        let commands = match parse(&pending) {
+            Err(e) if e.incomplete => continue,
+            Err(e) => Err(CliError::Query(QueryError::Parse(e))),
+            // A read-only DB first picks up what its writer has done since the last command
+            Ok(commands) => match lsm.catch_up() {
+                Ok(_) => execute(lsm, &commands, format, out, err),
+                Err(e) => Err(CliError::Query(QueryError::Storage(e))),
+            },
+        };
+        pending.clear();
+        match commands {
+            Ok(_) => {},
+            Err(CliError::Io(e)) => return Err(e),
+            Err(e) => writeln!(err, "error: {}", e)?,
+        }
+    }
+
+    // Input ending part way through a command is reported like any other syntax error
+    if let Err(e) = parse(&pending) {
+        writeln!(err, "error: {}", e)?;
+    }
+    Ok(())
+}
+
+fn json_pair(pair: &KVPair) -> String {
+    format!("{{\"key\":{},\"value\":{}}}", json::string(&pair.key), json::string(&pair.value))
+}
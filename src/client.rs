@@ -0,0 +1,572 @@
+use std::{io::{self, BufRead, BufReader, ErrorKind, Read, Write}, net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs}, sync::{Arc, Mutex}, thread, time::Duration};
+
+use crate::{http::percent_encode, json::{self, Json}, storage::{column_family::DEFAULT_COLUMN_FAMILY, wal::WalRecord, write_batch::WriteBatch}, store::{KeyValueStore, Row, ScanRange, StoreError}};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 8;
+
+// Most bytes in the status line and headers of a response
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/*
+Client Options: How a client connects, how long it waits, and how many connections it
+keeps open for reuse
+*/
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub connect_timeout: Duration,
+    // How long sending a request, or waiting on its response, may stall before giving up
+    pub request_timeout: Duration,
+    // Connections kept open between requests, any more are closed once they are done with
+    pub max_idle_connections: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions{
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_idle_connections: DEFAULT_MAX_IDLE_CONNECTIONS}
+    }
+}
+
+impl ClientOptions {
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn max_idle_connections(mut self, connections: usize) -> Self {
+        self.max_idle_connections = connections;
+        self
+    }
+}
+
+/*
+Client: Talks to an http::HttpServer, with the same operations as an embedded LSM through
+store::KeyValueStore. Clones share a pool of connections, so a client can be handed to
+many threads, each request taking an idle connection or opening one, and putting it back
+once answered. A connection the server closed while it sat idle is replaced and the
+request sent again, which is safe as every request the client sends is idempotent
+*/
+#[derive(Clone)]
+pub struct Client {
+    pool: Arc<Pool>,
+}
+
+struct Pool {
+    addr: SocketAddr,
+    options: ClientOptions,
+    idle: Mutex<Vec<Connection>>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+// What a response means depends on the operation it answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Get,
+    Put,
+    Delete,
+    Scan,
+    Batch,
+}
+
+struct Request {
+    operation: Operation,
+    method: &'static str,
+    // Path and query, already percent-encoded
+    target: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+    // Whether the server closes the connection after this response
+    close: bool,
+}
+
+/*
+Reply: What a pipelined operation returned, see Pipeline
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Value(Option<Vec<u8>>),
+    Written,
+    // Whether the key had a value
+    Deleted(bool),
+}
+
+// Why an exchange failed, distinguishing a connection that never answered, which can be retried on a new one
+enum Failure {
+    Unanswered(StoreError),
+    Failed(StoreError),
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client, StoreError> {
+        Client::connect_with_options(addr, ClientOptions::default())
+    }
+
+    /*
+    Connect With Options: Opens a first connection, to the first of the addresses that
+    accepts one, so a client for a server that isn't there fails here rather than on its
+    first request
+    */
+    pub fn connect_with_options<A: ToSocketAddrs>(addr: A, options: ClientOptions) -> Result<Client, StoreError> {
+        let mut last_error = io::Error::new(ErrorKind::InvalidInput, "no addresses to connect to");
+        for addr in addr.to_socket_addrs()? {
+            match open(addr, &options) {
+                Ok(connection) => return Ok(Client{pool: Arc::new(Pool{addr, options, idle: Mutex::new(vec![connection])})}),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(StoreError::from(last_error))
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.pool.addr
+    }
+
+    // Connections open and waiting to be reused
+    pub fn idle_connections(&self) -> usize {
+        self.pool.idle.lock().unwrap().len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        match self.send(get_request(key))? {
+            Reply::Value(value) => Ok(value),
+            _ => unreachable!("a get is answered with a value"),
+        }
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        self.send(put_request(key, value, None)?).map(|_| ())
+    }
+
+    // Same as put, with the ttl rounded up to whole seconds, the server's resolution
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), StoreError> {
+        self.send(put_request(key, value, Some(ttl))?).map(|_| ())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<bool, StoreError> {
+        match self.send(delete_request(key))? {
+            Reply::Deleted(deleted) => Ok(deleted),
+            _ => unreachable!("a delete is answered with whether there was a value"),
+        }
+    }
+
+    /*
+    Scan: The rows in the range, which the server streams while the client collects them.
+    A scan of a large range is best split up with limits, each starting after the last
+    key the one before returned
+    */
+    pub fn scan(&self, range: &ScanRange) -> Result<Vec<Row>, StoreError> {
+        let mut params = Vec::new();
+        for (name, value) in [("prefix", &range.prefix), ("start", &range.start), ("end", &range.end)] {
+            if let Some(value) = value {
+                params.push(format!("{}={}", name, percent_encode(value)));
+            }
+        }
+        if let Some(limit) = range.limit {
+            params.push(format!("limit={}", limit));
+        }
+        let target = match params.is_empty() {
+            true => "/scan".to_string(),
+            false => format!("/scan?{}", params.join("&")),
+        };
+        let response = self.round_trip(&[Request{operation: Operation::Scan, method: "GET", target, body: Vec::new()}])?.remove(0);
+        if response.status != 200 {
+            return Err(error(&response));
+        }
+        let body = std::str::from_utf8(&response.body).map_err(|_| StoreError::Protocol("scan is not valid UTF-8".to_string()))?;
+        body.lines()
+            .map(|line| {
+                let row = json::parse(line).map_err(|e| StoreError::Protocol(format!("invalid scan row: {}", e)))?;
+                match (json::get_bytes(&row, "key"), json::get_bytes(&row, "value")) {
+                    (Ok(Some(key)), Ok(Some(value))) => Ok((key, value)),
+                    _ => Err(StoreError::Protocol(format!("invalid scan row {}", line))),
+                }
+            })
+            .collect()
+    }
+
+    // Sends the batch to be written all together, which can only hold puts and deletes in the default column family
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<(), StoreError> {
+        let mut operations = Vec::with_capacity(batch.len());
+        for (family, record) in batch.into_records() {
+            if family != DEFAULT_COLUMN_FAMILY {
+                return Err(StoreError::Unsupported(format!("batch writes to column family {}", family)));
+            }
+            operations.push(match record {
+                WalRecord::Put{key, value, expires_at: None} => format!("{{\"op\":\"put\",{},{}}}", json::bytes_member("key", &key), json::bytes_member("value", &value)),
+                WalRecord::Delete{key} => format!("{{\"op\":\"delete\",{}}}", json::bytes_member("key", &key)),
+                WalRecord::Put{..} => return Err(StoreError::Unsupported("batch puts with a ttl".to_string())),
+                WalRecord::Merge{..} => return Err(StoreError::Unsupported("batch merges".to_string())),
+            });
+        }
+        let body = format!("[{}]", operations.join(",")).into_bytes();
+        self.send(Request{operation: Operation::Batch, method: "POST", target: "/batch".to_string(), body}).map(|_| ())
+    }
+
+    // Operations to send together, see Pipeline
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline{client: self, requests: Vec::new(), refused: Vec::new()}
+    }
+
+    fn send(&self, request: Request) -> Result<Reply, StoreError> {
+        let operation = request.operation;
+        let response = self.round_trip(&[request])?.remove(0);
+        reply(operation, response)
+    }
+
+    /*
+    Round Trip: Sends the requests on one connection and reads their responses, in order.
+    If the connection was reused and nothing came back, the server likely closed it while
+    it was idle, so they are sent once more on a new connection
+    */
+    fn round_trip(&self, requests: &[Request]) -> Result<Vec<Response>, StoreError> {
+        let bytes: Vec<u8> = requests.iter().flat_map(|request| self.encode(request)).collect();
+        let (mut connection, reused) = match self.pool.idle.lock().unwrap().pop() {
+            Some(connection) => (connection, true),
+            None => (open(self.pool.addr, &self.pool.options)?, false),
+        };
+        let responses = match exchange(&mut connection, &bytes, requests) {
+            Ok(responses) => responses,
+            Err(Failure::Unanswered(_)) if reused => {
+                connection = open(self.pool.addr, &self.pool.options)?;
+                exchange(&mut connection, &bytes, requests).map_err(|(Failure::Unanswered(e) | Failure::Failed(e))| e)?
+            },
+            Err(Failure::Unanswered(e) | Failure::Failed(e)) => return Err(e),
+        };
+        if responses.iter().all(|response| !response.close) {
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < self.pool.options.max_idle_connections {
+                idle.push(connection);
+            }
+        }
+        Ok(responses)
+    }
+
+    fn encode(&self, request: &Request) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, request.target, self.pool.addr);
+        match request.operation {
+            Operation::Get => head.push_str("Accept: application/octet-stream\r\n"),
+            Operation::Batch => head.push_str("Content-Type: application/json\r\n"),
+            _ => {},
+        }
+        if request.method == "PUT" || request.method == "POST" {
+            head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&request.body);
+        bytes
+    }
+}
+
+impl KeyValueStore for Client {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Client::get(self, key)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        Client::put(self, key, value)
+    }
+
+    fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), StoreError> {
+        Client::put_with_ttl(self, key, value, ttl)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, StoreError> {
+        Client::delete(self, key)
+    }
+
+    fn scan(&mut self, range: &ScanRange) -> Result<Vec<Row>, StoreError> {
+        Client::scan(self, range)
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<(), StoreError> {
+        Client::write_batch(self, batch)
+    }
+}
+
+/*
+Pipeline: Gets, puts and deletes sent together on one connection without waiting for
+each to be answered, which saves a round trip per operation. They are carried out in
+the order they were added, though not atomically, unlike a batch. Executing returns
+each operation's reply or error, or fails as a whole if the connection does
+*/
+pub struct Pipeline<'a> {
+    client: &'a Client,
+    requests: Vec<Request>,
+    // Operations that were refused before they were sent, by their index
+    refused: Vec<(usize, StoreError)>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: &[u8]) -> &mut Self {
+        self.requests.push(get_request(key));
+        self
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.requests.push(put_request(key, value, None).unwrap());
+        self
+    }
+
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> &mut Self {
+        match put_request(key, value, Some(ttl)) {
+            Ok(request) => self.requests.push(request),
+            Err(e) => self.refused.push((self.requests.len() + self.refused.len(), e)),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.requests.push(delete_request(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len() + self.refused.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn execute(self) -> Result<Vec<Result<Reply, StoreError>>, StoreError> {
+        let responses = match self.requests.is_empty() {
+            true => Vec::new(),
+            false => self.client.round_trip(&self.requests)?,
+        };
+        let mut replies: Vec<Result<Reply, StoreError>> = self.requests.iter()
+            .zip(responses)
+            .map(|(request, response)| reply(request.operation, response))
+            .collect();
+        for (i, e) in self.refused {
+            replies.insert(i, Err(e));
+        }
+        Ok(replies)
+    }
+}
+
+fn get_request(key: &[u8]) -> Request {
+    Request{operation: Operation::Get, method: "GET", target: format!("/kv/{}", percent_encode(key)), body: Vec::new()}
+}
+
+fn put_request(key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<Request, StoreError> {
+    let target = match ttl {
+        Some(ttl) if ttl.is_zero() => return Err(StoreError::InvalidRequest("ttl must not be zero".to_string())),
+        Some(ttl) => format!("/kv/{}?ttl={}", percent_encode(key), ttl.as_secs() + (ttl.subsec_nanos() > 0) as u64),
+        None => format!("/kv/{}", percent_encode(key)),
+    };
+    Ok(Request{operation: Operation::Put, method: "PUT", target, body: value.to_vec()})
+}
+
+fn delete_request(key: &[u8]) -> Request {
+    Request{operation: Operation::Delete, method: "DELETE", target: format!("/kv/{}", percent_encode(key)), body: Vec::new()}
+}
+
+// What the response to an operation other than a scan means
+fn reply(operation: Operation, response: Response) -> Result<Reply, StoreError> {
+    match (operation, response.status) {
+        (Operation::Get, 200) => Ok(Reply::Value(Some(response.body))),
+        (Operation::Get, 404) => Ok(Reply::Value(None)),
+        (Operation::Put, 204) | (Operation::Batch, 200) => Ok(Reply::Written),
+        (Operation::Delete, 204) => Ok(Reply::Deleted(true)),
+        (Operation::Delete, 404) => Ok(Reply::Deleted(false)),
+        _ => Err(error(&response)),
+    }
+}
+
+// The error a response reports, a request the server refused being told apart from one it failed to carry out
+fn error(response: &Response) -> StoreError {
+    let message = match std::str::from_utf8(&response.body).ok().and_then(|body| json::parse(body).ok()) {
+        Some(body) => body.get("error").and_then(Json::as_str).unwrap_or("").to_string(),
+        None => String::from_utf8_lossy(&response.body).into_owned(),
+    };
+    match response.status {
+        400 | 413 | 415 => StoreError::InvalidRequest(message),
+        status => StoreError::Server{status, message},
+    }
+}
+
+fn open(addr: SocketAddr, options: &ClientOptions) -> io::Result<Connection> {
+    let stream = TcpStream::connect_timeout(&addr, options.connect_timeout)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(options.request_timeout))?;
+    stream.set_write_timeout(Some(options.request_timeout))?;
+    Ok(Connection{reader: BufReader::new(stream.try_clone()?), stream})
+}
+
+/*
+Exchange: Writes the requests and reads a response to each. Many requests are written on
+another thread while the responses are read, so neither side can stall with its buffers
+full waiting on the other to read
+*/
+fn exchange(connection: &mut Connection, bytes: &[u8], requests: &[Request]) -> Result<Vec<Response>, Failure> {
+    let Connection{stream, reader} = connection;
+    let stream: &TcpStream = stream;
+    let unanswered = |e: io::Error| match e.kind() {
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => Failure::Unanswered(StoreError::from(e)),
+        _ => Failure::Failed(StoreError::from(e)),
+    };
+    if requests.len() == 1 {
+        let mut writer = stream;
+        writer.write_all(bytes).map_err(unanswered)?;
+        return read_responses(reader, requests);
+    }
+    thread::scope(|scope| {
+        let writer = scope.spawn(move || {
+            let mut writer = stream;
+            writer.write_all(bytes)
+        });
+        let responses = read_responses(reader, requests);
+        if responses.is_err() {
+            // Stops the writer, which may be stuck on a server no longer reading
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let written = writer.join().unwrap_or_else(|_| Err(io::Error::other("writer thread panicked")));
+        match (responses, written) {
+            (Ok(responses), _) => Ok(responses),
+            (Err(Failure::Unanswered(e)), Ok(())) => Err(Failure::Unanswered(e)),
+            (Err(Failure::Unanswered(_)), Err(e)) => Err(unanswered(e)),
+            (Err(failure), _) => Err(failure),
+        }
+    })
+}
+
+fn read_responses(reader: &mut dyn BufRead, requests: &[Request]) -> Result<Vec<Response>, Failure> {
+    let mut responses = Vec::with_capacity(requests.len());
+    for request in requests {
+        match read_response(reader, request.method == "HEAD") {
+            Ok(Some(response)) => responses.push(response),
+            Ok(None) if responses.is_empty() => return Err(Failure::Unanswered(StoreError::Io(closed()))),
+            Err(StoreError::Io(e)) if responses.is_empty() && matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted) => {
+                return Err(Failure::Unanswered(StoreError::Io(e)));
+            },
+            Ok(None) => return Err(Failure::Failed(StoreError::Io(closed()))),
+            Err(e) => return Err(Failure::Failed(e)),
+        }
+    }
+    Ok(responses)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(ErrorKind::UnexpectedEof, "server closed the connection")
+}
+
+// Reads a line of the response head, without its line ending, counting it against what's left of the head's budget
+fn read_head_line(reader: &mut dyn BufRead, budget: &mut usize) -> Result<Option<String>, StoreError> {
+    let mut line = Vec::new();
+    (&mut *reader).take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return match line.len() > *budget {
+            true => Err(StoreError::Protocol("response head too large".to_string())),
+            false => Err(StoreError::Io(closed())),
+        };
+    }
+    *budget -= line.len();
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| StoreError::Protocol("response head is not valid UTF-8".to_string()))
+}
+
+/*
+Read Response: Reads the next response, None if the server closed the connection before
+sending any of it. Interim 1xx responses are skipped, and the body is read by its
+Content-Length, its chunks, or up to the connection closing
+*/
+fn read_response(reader: &mut dyn BufRead, head: bool) -> Result<Option<Response>, StoreError> {
+    let mut budget = MAX_HEAD_BYTES;
+    let (status, mut close, headers) = loop {
+        let status_line = match read_head_line(reader, &mut budget)? {
+            Some(line) => line,
+            None if budget == MAX_HEAD_BYTES => return Ok(None),
+            None => return Err(StoreError::Io(closed())),
+        };
+        let (close, status) = match status_line.split(' ').collect::<Vec<_>>().as_slice() {
+            ["HTTP/1.1", status, ..] => (false, status.parse::<u16>()),
+            ["HTTP/1.0", status, ..] => (true, status.parse::<u16>()),
+            _ => return Err(StoreError::Protocol(format!("malformed status line {:?}", status_line))),
+        };
+        let status = status.map_err(|_| StoreError::Protocol(format!("malformed status line {:?}", status_line)))?;
+        let mut headers = Vec::new();
+        loop {
+            match read_head_line(reader, &mut budget)? {
+                Some(line) if line.is_empty() => break,
+                Some(line) => match line.split_once(':') {
+                    Some((name, value)) => headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string())),
+                    None => return Err(StoreError::Protocol(format!("malformed header {:?}", line))),
+                },
+                None => return Err(StoreError::Io(closed())),
+            }
+        }
+        if !(100..200).contains(&status) {
+            break (status, close, headers);
+        }
+    };
+
+    let header = |name: &str| headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str());
+    if let Some(connection) = header("connection") {
+        close = connection.split(',').any(|option| option.trim().eq_ignore_ascii_case("close"));
+    }
+    let body = if head || status == 204 || status == 304 {
+        Vec::new()
+    }
+    else if header("transfer-encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        read_chunked_body(reader)?
+    }
+    else if let Some(length) = header("content-length") {
+        let length = length.parse().map_err(|_| StoreError::Protocol(format!("invalid Content-Length {:?}", length)))?;
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        body
+    }
+    else {
+        close = true;
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        body
+    };
+    Ok(Some(Response{status, body, close}))
+}
+
+fn read_chunked_body(reader: &mut dyn BufRead) -> Result<Vec<u8>, StoreError> {
+    let mut body = Vec::new();
+    let mut budget = MAX_HEAD_BYTES;
+    loop {
+        let line = read_head_line(reader, &mut budget)?.ok_or_else(closed)?;
+        // The budget bounds the chunk size lines and trailers, not the chunks themselves
+        budget = MAX_HEAD_BYTES;
+        let size = line.split(';').next().unwrap().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| StoreError::Protocol(format!("invalid chunk size {:?}", size)))?;
+        if size == 0 {
+            while !read_head_line(reader, &mut budget)?.ok_or_else(closed)?.is_empty() {}
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if read_head_line(reader, &mut budget)?.is_none_or(|line| !line.is_empty()) {
+            return Err(StoreError::Protocol("chunk not ended by CRLF".to_string()));
+        }
+    }
+}
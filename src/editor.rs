@@ -0,0 +1,273 @@
+use std::{io::{self, stdin, stdout, BufRead, ErrorKind, Read, Stdin, Stdout, Write}, process::{Command, Stdio}};
+
+/*
+Input: Where the REPL reads its lines from, a line editor on a terminal or any reader
+otherwise. None once the input has ended
+*/
+pub trait Input {
+    fn next_line(&mut self, prompt: &str) -> io::Result<Option<String>>;
+}
+
+// Reads lines as they are, without prompting, for input piped in from a script or file
+impl<B: BufRead> Input for B {
+    fn next_line(&mut self, _prompt: &str) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match self.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line.trim_end_matches(['\n', '\r']).to_string())),
+        }
+    }
+}
+
+/*
+Line Editor: Reads lines a key at a time, moving through the line with the arrow keys,
+Home and End (or Ctrl-A and Ctrl-E), deleting with Backspace and Delete, Ctrl-U and
+Ctrl-K, and recalling earlier lines with Up and Down. Ctrl-C abandons the line, reported
+as an Interrupted error, and Ctrl-D on an empty line ends the input. Wide characters are
+taken as one column, so the cursor may drift on lines holding them
+*/
+pub struct LineEditor<R: Read, W: Write> {
+    input: R,
+    output: W,
+    history: Vec<String>,
+    // Whether the input is a terminal to put in raw mode while a line is read
+    terminal: bool,
+}
+
+impl LineEditor<Stdin, Stdout> {
+    // Line editor on the process's terminal, raw mode being set through stty, as std has no termios
+    pub fn terminal() -> LineEditor<Stdin, Stdout> {
+        LineEditor{input: stdin(), output: stdout(), history: Vec::new(), terminal: true}
+    }
+}
+
+impl<R: Read, W: Write> LineEditor<R, W> {
+    // Line editor reading keys from the input as is, with the line drawn to the output
+    pub fn new(input: R, output: W) -> LineEditor<R, W> {
+        LineEditor{input, output, history: Vec::new(), terminal: false}
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn key(&mut self) -> io::Result<Option<Key>> {
+        let byte = match self.byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        let key = match byte {
+            b'\r' | b'\n' => Key::Enter,
+            0x01 => Key::Home,
+            0x02 => Key::Left,
+            0x03 => Key::Interrupt,
+            0x04 => Key::EndOfInput,
+            0x05 => Key::End,
+            0x06 => Key::Right,
+            0x08 | 0x7f => Key::Backspace,
+            0x0b => Key::KillToEnd,
+            0x0e => Key::Down,
+            0x10 => Key::Up,
+            0x15 => Key::KillToStart,
+            0x1b => self.escape()?,
+            byte if byte < 0x20 => Key::Ignored,
+            byte => {
+                // The rest of a multi-byte character follows its first byte
+                let len = match byte {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    0xf0..=0xf7 => 4,
+                    _ => 1,
+                };
+                let mut bytes = vec![byte];
+                while bytes.len() < len {
+                    match self.byte()? {
+                        Some(byte) => bytes.push(byte),
+                        None => break,
+                    }
+                }
+                match std::str::from_utf8(&bytes) {
+                    Ok(c) => Key::Char(c.chars().next().unwrap()),
+                    Err(_) => Key::Ignored,
+                }
+            },
+        };
+        Ok(Some(key))
+    }
+
+    // The key sent as an escape sequence, e.g. ESC [ A for Up or ESC [ 3 ~ for Delete
+    fn escape(&mut self) -> io::Result<Key> {
+        let introducer = self.byte()?;
+        if introducer != Some(b'[') && introducer != Some(b'O') {
+            return Ok(Key::Ignored);
+        }
+        let mut parameters = Vec::new();
+        loop {
+            match self.byte()? {
+                Some(byte @ 0x40..=0x7e) => return Ok(match (byte, parameters.as_slice()) {
+                    (b'A', _) => Key::Up,
+                    (b'B', _) => Key::Down,
+                    (b'C', _) => Key::Right,
+                    (b'D', _) => Key::Left,
+                    (b'H', _) | (b'~', b"1") | (b'~', b"7") => Key::Home,
+                    (b'F', _) | (b'~', b"4") | (b'~', b"8") => Key::End,
+                    (b'~', b"3") => Key::Delete,
+                    _ => Key::Ignored,
+                }),
+                Some(byte) => parameters.push(byte),
+                None => return Ok(Key::Ignored),
+            }
+        }
+    }
+
+    fn edit(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let mut line = Line{chars: Vec::new(), cursor: 0};
+        // Where in the history Up and Down have got to, and the line being written before
+        let mut recalled = self.history.len();
+        let mut unsent = Vec::new();
+        line.draw(&mut self.output, prompt)?;
+
+        loop {
+            let key = match self.key()? {
+                Some(key) => key,
+                None if line.chars.is_empty() => return Ok(None),
+                None => Key::Enter,
+            };
+            match key {
+                Key::Enter => {
+                    write!(self.output, "\r\n")?;
+                    self.output.flush()?;
+                    let line: String = line.chars.into_iter().collect();
+                    if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                        self.history.push(line.clone());
+                    }
+                    return Ok(Some(line));
+                },
+                Key::Interrupt => {
+                    write!(self.output, "^C\r\n")?;
+                    self.output.flush()?;
+                    return Err(io::Error::new(ErrorKind::Interrupted, "line abandoned"));
+                },
+                Key::EndOfInput if line.chars.is_empty() => {
+                    write!(self.output, "\r\n")?;
+                    self.output.flush()?;
+                    return Ok(None);
+                },
+                Key::EndOfInput | Key::Delete => if line.cursor < line.chars.len() {
+                    line.chars.remove(line.cursor);
+                },
+                Key::Backspace => if line.cursor > 0 {
+                    line.cursor -= 1;
+                    line.chars.remove(line.cursor);
+                },
+                Key::Left => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Key::Home => line.cursor = 0,
+                Key::End => line.cursor = line.chars.len(),
+                Key::KillToStart => {
+                    line.chars.drain(..line.cursor);
+                    line.cursor = 0;
+                },
+                Key::KillToEnd => line.chars.truncate(line.cursor),
+                Key::Up if recalled > 0 => {
+                    if recalled == self.history.len() {
+                        unsent = line.chars.clone();
+                    }
+                    recalled -= 1;
+                    line.replace(self.history[recalled].chars().collect());
+                },
+                Key::Down if recalled < self.history.len() => {
+                    recalled += 1;
+                    match self.history.get(recalled) {
+                        Some(earlier) => line.replace(earlier.chars().collect()),
+                        None => line.replace(unsent.clone()),
+                    }
+                },
+                Key::Char(c) => {
+                    line.chars.insert(line.cursor, c);
+                    line.cursor += 1;
+                },
+                Key::Up | Key::Down | Key::Ignored => continue,
+            }
+            line.draw(&mut self.output, prompt)?;
+        }
+    }
+}
+
+impl<R: Read, W: Write> Input for LineEditor<R, W> {
+    fn next_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        if !self.terminal {
+            return self.edit(prompt);
+        }
+        // The terminal is only raw while a line is read, so it is restored even if
+        // executing the line panics
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "-ixon", "min", "1"])?;
+        let line = self.edit(prompt);
+        stty(&[saved.trim()])?;
+        line
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillToStart,
+    KillToEnd,
+    Interrupt,
+    EndOfInput,
+    Ignored,
+}
+
+struct Line {
+    chars: Vec<char>,
+    // Index of the char the cursor is before
+    cursor: usize,
+}
+
+impl Line {
+    fn replace(&mut self, chars: Vec<char>) {
+        self.cursor = chars.len();
+        self.chars = chars;
+    }
+
+    // Redraws the whole line over the one on screen, then moves the cursor back into place
+    fn draw(&self, output: &mut dyn Write, prompt: &str) -> io::Result<()> {
+        let line: String = self.chars.iter().collect();
+        write!(output, "\r{}{}\x1b[K", prompt, line)?;
+        if self.cursor < self.chars.len() {
+            write!(output, "\x1b[{}D", self.chars.len() - self.cursor)?;
+        }
+        output.flush()
+    }
+}
+
+// Runs stty on the terminal the process reads from, returning what it prints
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).stderr(Stdio::inherit()).output()?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(io::Error::other(format!("stty {} failed with {}", args.join(" "), output.status))),
+    }
+}
@@ -0,0 +1,669 @@
+use std::{io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write}, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{atomic::AtomicBool, Arc, Mutex, MutexGuard}, time::Duration};
+
+use crate::{json::{self, Json}, server::{serve_connections, ServerHandle}, storage::{error::LsmError, lsm::LsmTree, write_batch::WriteBatch}, store::ScanRange};
+
+pub const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:8080";
+
+// Most bytes in the request line and headers of a request, and most headers
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+// Largest request body, a value or a batch
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+// How long a connection may sit idle waiting for a request, or stall taking a response
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+// Bytes of a streamed scan sent at a time, each as one chunk
+const CHUNK_BYTES: usize = 16 * 1024;
+
+const OCTET_STREAM: &str = "application/octet-stream";
+const APPLICATION_JSON: &str = "application/json";
+const NDJSON: &str = "application/x-ndjson";
+
+/*
+HTTP Server: Serves an LSM over HTTP/1.1, with persistent connections, each with its own
+thread. Keys are the percent-decoded rest of the path, so any bytes can be a key, values
+are request and response bodies as they are, and JSON holds bytes that aren't UTF-8 as
+base64, see json::bytes_member. The endpoints are
+
+    GET /kv/{key}             the value, 404 if there is none. HEAD too. As JSON when
+                              the client accepts JSON and not application/octet-stream
+    PUT /kv/{key}[?ttl=s]     writes the body as the value, expiring after ttl seconds
+    DELETE /kv/{key}          404 if there was no value
+    GET /scan                 rows in key order, streamed as a JSON object per line, from
+                              the query's start up to but not including its end, with its
+                              prefix, up to its limit, all of them optional
+    POST /batch               a JSON array of {"op": "put", "key", "value"} and
+                              {"op": "delete", "key"}, written all together or not at all
+    GET /stats                sizes and tickers
+    GET /health
+
+Errors are JSON objects with an "error" message. Writes to a DB open read-only are 403
+*/
+pub struct HttpServer {
+    listener: TcpListener,
+    lsm: Arc<Mutex<LsmTree>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl HttpServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, lsm: Arc<Mutex<LsmTree>>) -> io::Result<HttpServer> {
+        Ok(HttpServer{listener: TcpListener::bind(addr)?, lsm, shutdown: Arc::new(AtomicBool::new(false))})
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Accepts connections until shut down through a handle, see spawn
+    pub fn serve(self) -> io::Result<()> {
+        let lsm = self.lsm;
+        serve_connections(&self.listener, &self.shutdown, move |stream| handle_connection(stream, &lsm))
+    }
+
+    // Serves on a background thread, until the handle is shut down
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let (addr, shutdown) = (self.local_addr()?, self.shutdown.clone());
+        Ok(ServerHandle::spawn(addr, shutdown, move || self.serve()))
+    }
+}
+
+struct Request {
+    method: String,
+    // The path as sent, still percent-encoded, and the decoded query parameters
+    path: String,
+    query: Vec<(String, Vec<u8>)>,
+    // Names are lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Whether the connection stays open after the response
+    keep_alive: bool,
+    // Whether the client takes chunked responses, which HTTP/1.0 clients don't
+    chunked: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    fn param(&self, name: &str) -> Option<&[u8]> {
+        self.query.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_slice())
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response{status, headers: vec![("Content-Type", content_type.to_string())], body}
+    }
+
+    fn json(status: u16, body: String) -> Response {
+        Response::new(status, APPLICATION_JSON, body.into_bytes())
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::json(status, format!("{{\"error\":{}}}", json::string(message.as_bytes())))
+    }
+
+    fn no_content() -> Response {
+        Response{status: 204, headers: Vec::new(), body: Vec::new()}
+    }
+
+    fn method_not_allowed(allow: &str) -> Response {
+        let mut response = Response::error(405, "method not allowed");
+        response.headers.push(("Allow", allow.to_string()));
+        response
+    }
+}
+
+// What to reply to a request with
+enum Reply {
+    Full(Response),
+    Scan(ScanRange),
+}
+
+// Why a request couldn't be read, either the connection failed or the request is malformed
+enum RequestError {
+    Io(io::Error),
+    Bad(u16, String),
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+fn bad<T>(status: u16, message: &str) -> Result<T, RequestError> {
+    Err(RequestError::Bad(status, message.to_string()))
+}
+
+fn handle_connection(stream: TcpStream, lsm: &Mutex<LsmTree>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader, &mut writer) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(RequestError::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(()),
+            Err(RequestError::Io(e)) => return Err(e),
+            // There's no telling where the next request would start, so the connection is closed
+            Err(RequestError::Bad(status, message)) => {
+                write_response(&mut writer, &Response::error(status, &message), false, false)?;
+                return writer.flush();
+            },
+        };
+
+        let keep_alive = match route(lsm, &request) {
+            Reply::Full(response) => {
+                write_response(&mut writer, &response, request.method == "HEAD", request.keep_alive)?;
+                request.keep_alive
+            },
+            Reply::Scan(scan) => stream_scan(lsm, &scan, &request, &mut writer)?,
+        };
+        writer.flush()?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Reads a line of the request head, without its line ending, counting it against what's left of the head's budget
+fn read_head_line(reader: &mut dyn BufRead, budget: &mut usize) -> Result<Option<String>, RequestError> {
+    let mut line = Vec::new();
+    (&mut *reader).take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return match line.len() > *budget {
+            true => bad(431, "request head too large"),
+            false => Err(RequestError::Io(io::Error::new(ErrorKind::UnexpectedEof, "connection closed part way through a request"))),
+        };
+    }
+    *budget -= line.len();
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => bad(400, "request head is not valid UTF-8"),
+    }
+}
+
+/*
+Read Request: Reads the next request on the connection, None once the client has closed
+it. Bodies are read whole, whether sent with a Content-Length or chunked, and a client
+expecting 100 Continue is sent it before its body is read
+*/
+fn read_request(reader: &mut dyn BufRead, writer: &mut dyn Write) -> Result<Option<Request>, RequestError> {
+    let mut budget = MAX_HEAD_BYTES;
+    // Blank lines before a request are ignored
+    let request_line = loop {
+        match read_head_line(reader, &mut budget)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let (method, target, version) = match request_line.split(' ').collect::<Vec<_>>().as_slice() {
+        [method, target, version] if !method.is_empty() => (method.to_string(), target.to_string(), *version),
+        _ => return bad(400, "malformed request line"),
+    };
+    let (mut keep_alive, chunked) = match version {
+        "HTTP/1.1" => (true, true),
+        "HTTP/1.0" => (false, false),
+        _ => return bad(505, "only HTTP/1.1 and HTTP/1.0 are supported"),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    if !path.starts_with('/') {
+        return bad(400, "request target must be a path");
+    }
+    let query = match parse_query(query) {
+        Some(query) => query,
+        None => return bad(400, "malformed query"),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = match read_head_line(reader, &mut budget)? {
+            Some(line) if line.is_empty() => break,
+            Some(line) => line,
+            None => return Err(RequestError::Io(io::Error::new(ErrorKind::UnexpectedEof, "connection closed part way through a request"))),
+        };
+        if headers.len() == MAX_HEADERS {
+            return bad(431, "too many headers");
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains(|c: char| c.is_ascii_whitespace()) => {
+                headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+            },
+            _ => return bad(400, "malformed header"),
+        }
+    }
+
+    let header = |name: &'static str| headers.iter().filter(move |(header, _)| header == name).map(|(_, value)| value.as_str());
+    for connection in header("connection").flat_map(|value| value.split(',')) {
+        match connection.trim().to_ascii_lowercase().as_str() {
+            "close" => keep_alive = false,
+            "keep-alive" => keep_alive = true,
+            _ => {},
+        }
+    }
+    let lengths: Vec<&str> = header("content-length").collect();
+    let transfer_encoding = header("transfer-encoding").next_back();
+    let body_length = match (lengths.as_slice(), transfer_encoding) {
+        (_, Some(encoding)) if !encoding.eq_ignore_ascii_case("chunked") => return bad(501, "unsupported transfer encoding"),
+        ([], Some(_)) => None,
+        (_, Some(_)) => return bad(400, "both Content-Length and Transfer-Encoding given"),
+        ([], None) => Some(0),
+        ([length, rest @ ..], None) => match length.parse::<usize>() {
+            Ok(length) if rest.iter().all(|other| other == &lengths[0]) => Some(length),
+            _ => return bad(400, "invalid Content-Length"),
+        },
+    };
+    if body_length.is_some_and(|length| length > MAX_BODY_BYTES) {
+        return bad(413, "request body too large");
+    }
+
+    if body_length != Some(0) {
+        match header("expect").next_back() {
+            // HTTP/1.0 clients don't wait to be told to go on
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") && chunked => {
+                writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                writer.flush()?;
+            },
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => {},
+            Some(_) => return bad(417, "unsupported expectation"),
+            None => {},
+        }
+    }
+    let body = match body_length {
+        Some(length) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            body
+        },
+        None => read_chunked_body(reader)?,
+    };
+    Ok(Some(Request{method, path: path.to_string(), query, headers, body, keep_alive, chunked}))
+}
+
+fn read_chunked_body(reader: &mut dyn BufRead) -> Result<Vec<u8>, RequestError> {
+    let mut body = Vec::new();
+    let mut budget = MAX_HEAD_BYTES;
+    loop {
+        let line = match read_head_line(reader, &mut budget)? {
+            Some(line) => line,
+            None => return Err(RequestError::Io(io::Error::new(ErrorKind::UnexpectedEof, "connection closed part way through a chunked body"))),
+        };
+        // Chunk extensions are ignored
+        let size = line.split(';').next().unwrap().trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) if body.len().checked_add(size).is_some_and(|n| n <= MAX_BODY_BYTES) => size,
+            Ok(_) => return bad(413, "request body too large"),
+            Err(_) => return bad(400, "invalid chunk size"),
+        };
+        if size == 0 {
+            // Trailers are ignored
+            while read_head_line(reader, &mut budget)?.is_some_and(|line| !line.is_empty()) {}
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if read_head_line(reader, &mut budget)?.is_none_or(|line| !line.is_empty()) {
+            return bad(400, "chunk not ended by CRLF");
+        }
+    }
+}
+
+/*
+Percent Decode: Decodes %XX escapes, and '+' as a space when decoding a query. None if an
+escape isn't two hex digits
+*/
+pub fn percent_decode(encoded: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        match encoded[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+                continue;
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    Some(decoded)
+}
+
+// Percent-encodes every byte but the unreserved ones, so any bytes can go in a path or query
+pub fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(*byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn parse_query(query: &str) -> Option<Vec<(String, Vec<u8>)>> {
+    query.split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Some((String::from_utf8(percent_decode(name, true)?).ok()?, percent_decode(value, true)?))
+        })
+        .collect()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+fn write_head(writer: &mut dyn Write, status: u16, headers: &[(&str, String)], keep_alive: bool) -> io::Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\n", status, reason(status))?;
+    for (name, value) in headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    if !keep_alive {
+        write!(writer, "Connection: close\r\n")?;
+    }
+    write!(writer, "\r\n")
+}
+
+// Writes the response, all but the body for a HEAD request, whose Content-Length is still that of the body
+fn write_response(writer: &mut dyn Write, response: &Response, head: bool, keep_alive: bool) -> io::Result<()> {
+    let mut headers = response.headers.clone();
+    if response.status != 204 {
+        headers.push(("Content-Length", response.body.len().to_string()));
+    }
+    write_head(writer, response.status, &headers, keep_alive)?;
+    match head {
+        true => Ok(()),
+        false => writer.write_all(&response.body),
+    }
+}
+
+fn lock(lsm: &Mutex<LsmTree>) -> Result<MutexGuard<'_, LsmTree>, Response> {
+    // A panic while the LSM was locked may have left it part way through a write
+    lsm.lock().map_err(|_| Response::error(503, "store unavailable after a failed write"))
+}
+
+// Writes to a read-only DB are forbidden, any other failure is the server's
+fn write_error(e: LsmError) -> Response {
+    match e {
+        LsmError::ReadOnly => Response::error(403, &e.to_string()),
+        e => Response::error(500, &e.to_string()),
+    }
+}
+
+fn route(lsm: &Mutex<LsmTree>, request: &Request) -> Reply {
+    let method = request.method.as_str();
+    let response = match request.path.as_str() {
+        path if path.starts_with("/kv/") => match percent_decode(&path["/kv/".len()..], false) {
+            Some(key) if key.is_empty() => Response::error(400, "missing key"),
+            Some(key) => match method {
+                "GET" | "HEAD" => get(lsm, request, &key),
+                "PUT" => put(lsm, request, &key),
+                "DELETE" => delete(lsm, &key),
+                _ => Response::method_not_allowed("GET, HEAD, PUT, DELETE"),
+            },
+            None => Response::error(400, "malformed key"),
+        },
+        "/scan" => match method {
+            "GET" => match scan_params(lsm, request) {
+                Ok(scan) => return Reply::Scan(scan),
+                Err(response) => response,
+            },
+            _ => Response::method_not_allowed("GET"),
+        },
+        "/batch" => match method {
+            "POST" => batch(lsm, request),
+            _ => Response::method_not_allowed("POST"),
+        },
+        "/stats" => match method {
+            "GET" => stats(lsm),
+            _ => Response::method_not_allowed("GET"),
+        },
+        "/health" => match method {
+            "GET" => match lock(lsm) {
+                Ok(_) => Response::json(200, "{\"status\":\"ok\"}".to_string()),
+                Err(response) => response,
+            },
+            _ => Response::method_not_allowed("GET"),
+        },
+        _ => Response::error(404, "no such endpoint"),
+    };
+    Reply::Full(response)
+}
+
+// Whether the client would rather have JSON than the raw value
+fn accepts_json(request: &Request) -> bool {
+    let accept = request.header("accept").unwrap_or("");
+    let accepts = |media_type: &str| accept.split(',').any(|range| range.split(';').next().unwrap().trim().eq_ignore_ascii_case(media_type));
+    accepts(APPLICATION_JSON) && !accepts(OCTET_STREAM)
+}
+
+fn get(lsm: &Mutex<LsmTree>, request: &Request, key: &[u8]) -> Response {
+    let value = match lock(lsm) {
+        Ok(mut lsm) => lsm.get(key),
+        Err(response) => return response,
+    };
+    match value {
+        Some(value) if accepts_json(request) => Response::json(200, format!("{{{},{}}}", json::bytes_member("key", key), json::bytes_member("value", &value))),
+        Some(value) => Response::new(200, OCTET_STREAM, value),
+        None => Response::error(404, "key not found"),
+    }
+}
+
+fn put(lsm: &Mutex<LsmTree>, request: &Request, key: &[u8]) -> Response {
+    let ttl = match request.param("ttl").map(|ttl| std::str::from_utf8(ttl).ok().and_then(|ttl| ttl.parse::<u64>().ok())) {
+        Some(Some(ttl)) if ttl > 0 => Some(Duration::from_secs(ttl)),
+        Some(_) => return Response::error(400, "ttl must be a positive number of seconds"),
+        None => None,
+    };
+    let mut lsm = match lock(lsm) {
+        Ok(lsm) => lsm,
+        Err(response) => return response,
+    };
+    let written = match ttl {
+        Some(ttl) => lsm.write_with_ttl(key, &request.body, ttl),
+        None => lsm.write(key, &request.body),
+    };
+    match written {
+        Ok(()) => Response::no_content(),
+        Err(e) => write_error(e),
+    }
+}
+
+fn delete(lsm: &Mutex<LsmTree>, key: &[u8]) -> Response {
+    let mut lsm = match lock(lsm) {
+        Ok(lsm) => lsm,
+        Err(response) => return response,
+    };
+    if lsm.get(key).is_none() {
+        return Response::error(404, "key not found");
+    }
+    match lsm.delete(key) {
+        Ok(()) => Response::no_content(),
+        Err(e) => write_error(e),
+    }
+}
+
+fn batch(lsm: &Mutex<LsmTree>, request: &Request) -> Response {
+    let content_type = request.header("content-type").unwrap_or("");
+    if !content_type.split(';').next().unwrap().trim().eq_ignore_ascii_case(APPLICATION_JSON) {
+        return Response::error(415, "batch must be application/json");
+    }
+    let operations = match std::str::from_utf8(&request.body).map_err(|_| "batch is not valid UTF-8".to_string()).and_then(json::parse) {
+        Ok(Json::Array(operations)) => operations,
+        Ok(_) => return Response::error(400, "batch must be an array of operations"),
+        Err(e) => return Response::error(400, &format!("invalid JSON: {}", e)),
+    };
+
+    let mut batch = WriteBatch::new();
+    for (i, operation) in operations.iter().enumerate() {
+        let op = operation.get("op").and_then(Json::as_str);
+        let key_value = json::get_bytes(operation, "key").and_then(|key| Ok((key, json::get_bytes(operation, "value")?)));
+        let added = match (op, key_value) {
+            (_, Err(e)) => Err(e),
+            (Some("put"), Ok((Some(key), Some(value)))) => {
+                batch.put(key, value);
+                Ok(())
+            },
+            (Some("delete"), Ok((Some(key), None))) => {
+                batch.delete(key);
+                Ok(())
+            },
+            (Some("put" | "delete"), Ok((None, _))) => Err("missing key".to_string()),
+            (Some("put"), Ok((_, None))) => Err("missing value".to_string()),
+            (Some("delete"), Ok(_)) => Err("delete takes no value".to_string()),
+            _ => Err("op must be \"put\" or \"delete\"".to_string()),
+        };
+        if let Err(e) = added {
+            return Response::error(400, &format!("operation {}: {}", i, e));
+        }
+    }
+
+    let written = batch.len();
+    let result = match lock(lsm) {
+        Ok(mut lsm) => lsm.write_batch(batch),
+        Err(response) => return response,
+    };
+    match result {
+        Ok(()) => Response::json(200, format!("{{\"written\":{}}}", written)),
+        Err(e) => write_error(e),
+    }
+}
+
+fn stats(lsm: &Mutex<LsmTree>) -> Response {
+    let lsm = match lock(lsm) {
+        Ok(lsm) => lsm,
+        Err(response) => return response,
+    };
+    let tickers: Vec<String> = lsm.statistics().snapshot().iter().map(|(name, count)| format!("{}:{}", json::string(name.as_bytes()), count)).collect();
+    Response::json(200, format!("{{\"entries\":{},\"segments\":{},\"total_size\":{},\"tickers\":{{{}}}}}",
+        lsm.num_entries(), lsm.total_segments(), lsm.total_size(), tickers.join(",")))
+}
+
+fn scan_params(lsm: &Mutex<LsmTree>, request: &Request) -> Result<ScanRange, Response> {
+    let limit = match request.param("limit").map(|limit| std::str::from_utf8(limit).ok().and_then(|limit| limit.parse().ok())) {
+        Some(Some(limit)) => Some(limit),
+        Some(None) => return Err(Response::error(400, "limit must be a number")),
+        None => None,
+    };
+    let scan = ScanRange{
+        prefix: request.param("prefix").map(<[u8]>::to_vec),
+        start: request.param("start").map(<[u8]>::to_vec),
+        end: request.param("end").map(<[u8]>::to_vec),
+        limit,
+    };
+    match scan.is_reversed(&*lock(lsm)?.comparator()) {
+        true => Err(Response::error(400, "start is after end")),
+        false => Ok(scan),
+    }
+}
+
+/*
+Stream Scan: Writes the scanned rows as they are read, a JSON object per line, in chunks
+so the response doesn't have to be held in memory, or until the connection closes for
+HTTP/1.0 clients. The LSM is only locked while a chunk is read, the scan resuming after
+the last key sent, so other requests go on while a slow client reads. Returns whether
+the connection can be kept open
+*/
+fn stream_scan(lsm: &Mutex<LsmTree>, scan: &ScanRange, request: &Request, writer: &mut dyn Write) -> io::Result<bool> {
+    let mut locked = match lock(lsm) {
+        Ok(guard) => Some(guard),
+        Err(response) => {
+            write_response(writer, &response, false, request.keep_alive)?;
+            return Ok(request.keep_alive);
+        },
+    };
+    let keep_alive = request.keep_alive && request.chunked;
+    let mut headers = vec![("Content-Type", NDJSON.to_string())];
+    if request.chunked {
+        headers.push(("Transfer-Encoding", "chunked".to_string()));
+    }
+    write_head(writer, 200, &headers, keep_alive)?;
+
+    let mut send = |chunk: &[u8]| -> io::Result<()> {
+        match request.chunked {
+            true => {
+                write!(writer, "{:x}\r\n", chunk.len())?;
+                writer.write_all(chunk)?;
+                writer.write_all(b"\r\n")
+            },
+            false => writer.write_all(chunk),
+        }
+    };
+    // The limit is counted here, as the key a chunk resumes after may be read again
+    let mut rest = ScanRange{limit: None, ..scan.clone()};
+    let mut remaining = scan.limit.unwrap_or(usize::MAX);
+    let mut after: Option<Vec<u8>> = None;
+    while remaining > 0 {
+        // Failing part way through leaves the response unfinished, so the client can tell
+        let mut guard = match locked.take() {
+            Some(guard) => guard,
+            None => lock(lsm).map_err(|_| io::Error::other("store unavailable part way through a scan"))?,
+        };
+        let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+        let mut last = None;
+        for pair in rest.rows(&mut guard).skip_while(|pair| after.as_deref().is_some_and(|after| pair.key.as_ref() == after)).take(remaining) {
+            chunk.extend_from_slice(format!("{{{},{}}}\n", json::bytes_member("key", &pair.key), json::bytes_member("value", &pair.value)).as_bytes());
+            remaining -= 1;
+            if chunk.len() >= CHUNK_BYTES {
+                last = Some(pair.key.into_owned());
+                break;
+            }
+        }
+        drop(guard);
+
+        if !chunk.is_empty() {
+            send(&chunk)?;
+        }
+        match last {
+            Some(key) => {
+                rest.start = Some(key.clone());
+                after = Some(key);
+            },
+            None => break,
+        }
+    }
+    if request.chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    Ok(keep_alive)
+}
@@ -0,0 +1,286 @@
+use std::{iter::Peekable, str::CharIndices};
+
+// How deeply arrays and objects may nest, so a hostile document can't exhaust the stack
+const MAX_DEPTH: usize = 128;
+
+/*
+JSON: A parsed JSON document, for the request bodies the servers take. Objects keep their
+members in the order they were written
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // The member with this name, if this is an object that has one
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(member, _)| member == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/*
+Parse: Parses a whole JSON document, the error saying what is wrong and at which byte
+*/
+pub fn parse(input: &str) -> Result<Json, String> {
+    let mut parser = Parser{chars: input.char_indices().peekable(), len: input.len()};
+    let value = parser.value(0)?;
+    parser.whitespace();
+    match parser.chars.peek() {
+        Some((offset, _)) => Err(format!("unexpected data after the document at byte {}", offset)),
+        None => Ok(value),
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+}
+
+impl Parser<'_> {
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.len, |(offset, _)| *offset)
+    }
+
+    fn error<T>(&mut self, message: &str) -> Result<T, String> {
+        Err(format!("{} at byte {}", message, self.offset()))
+    }
+
+    fn whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r')).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next_if(|(_, c)| *c == expected) {
+            Some(_) => Ok(()),
+            None => self.error(&format!("expected '{}'", expected)),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        for expected in literal.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return self.error("document nested too deeply");
+        }
+        self.whitespace();
+        match self.chars.peek().map(|(_, c)| *c) {
+            Some('n') => self.literal("null", Json::Null),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => {
+                self.chars.next();
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => continue,
+                        Some((_, ']')) => return Ok(Json::Array(values)),
+                        _ => return self.error("expected ',' or ']'"),
+                    }
+                }
+            },
+            Some('{') => {
+                self.chars.next();
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    let name = self.string()?;
+                    self.whitespace();
+                    self.expect(':')?;
+                    members.push((name, self.value(depth + 1)?));
+                    self.whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => continue,
+                        Some((_, '}')) => return Ok(Json::Object(members)),
+                        _ => return self.error("expected ',' or '}'"),
+                    }
+                }
+            },
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.offset();
+                let mut number = String::new();
+                while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                    number.push(c);
+                }
+                match number.parse() {
+                    Ok(number) => Ok(Json::Number(number)),
+                    Err(_) => Err(format!("invalid number at byte {}", start)),
+                }
+            },
+            Some(_) => self.error("expected a value"),
+            None => self.error("unexpected end of document"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((_, '\\')) => {
+                    let c = match self.chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => self.unicode_escape()?,
+                        _ => return self.error("invalid escape"),
+                    };
+                    s.push(c);
+                },
+                Some((_, c)) if (c as u32) < 0x20 => return self.error("control character in string"),
+                Some((_, c)) => s.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    // The character of a \u escape, which takes two escapes for one outside the BMP
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                self.expect('\\')?;
+                self.expect('u')?;
+                match self.hex4()? {
+                    low @ 0xdc00..=0xdfff => 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00),
+                    _ => return self.error("invalid surrogate pair"),
+                }
+            },
+            code => code,
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match self.chars.next().and_then(|(_, c)| c.to_digit(16)) {
+                Some(digit) => code = code * 16 + digit,
+                None => return self.error("expected four hex digits"),
+            }
+        }
+        Ok(code)
+    }
+}
+
+// Bytes as a JSON string, any that aren't UTF-8 being replaced
+pub fn string(bytes: &[u8]) -> String {
+    let mut json = String::from("\"");
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/*
+Bytes Member: An object member holding bytes without losing any, as "name": string when
+they are UTF-8, and otherwise as "name_base64": their base64 encoding
+*/
+pub fn bytes_member(name: &str, bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => format!("{}:{}", string(name.as_bytes()), string(s.as_bytes())),
+        Err(_) => format!("{}:{}", string(format!("{}_base64", name).as_bytes()), string(base64_encode(bytes).as_bytes())),
+    }
+}
+
+// Reads back a member written by bytes_member, None when the object has neither form of it
+pub fn get_bytes(object: &Json, name: &str) -> Result<Option<Vec<u8>>, String> {
+    match (object.get(name), object.get(&format!("{}_base64", name))) {
+        (Some(_), Some(_)) => Err(format!("only one of {} and {}_base64 may be given", name, name)),
+        (Some(Json::String(s)), None) => Ok(Some(s.as_bytes().to_vec())),
+        (None, Some(Json::String(encoded))) => match base64_decode(encoded) {
+            Some(bytes) => Ok(Some(bytes)),
+            None => Err(format!("{}_base64 is not valid base64", name)),
+        },
+        (None, None) => Ok(None),
+        _ => Err(format!("{} must be a string", name)),
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Standard base64, padded with '='
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+// Decodes standard base64, None if it isn't validly padded base64
+pub fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && i != encoded.len() / 4 - 1) {
+            return None;
+        }
+        let mut n = 0u32;
+        for c in &chunk[..4 - padding] {
+            n = n << 6 | BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+        }
+        n <<= 6 * padding;
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
@@ -1,56 +1,85 @@
-use std::{fmt::{Debug, Display, write}, cmp::Ordering};
+use std::{borrow::Cow, cmp::{Ordering, Reverse}, fmt::{Display, Formatter, Result}};
 
-#[derive(Debug, Clone, Copy)]
+/*
+KV Pair: A record as handed out of the LSM. Key and value either borrow or own their bytes,
+so a caller can build pairs over its own buffers without copying, while anything read out
+of the LSM owns its bytes (KVPair<'static>). A deleted key is a pair with the tombstone
+flag set and an empty value. The sequence number, when known, orders writes to one key.
+*/
+#[derive(Debug, Clone)]
 pub struct KVPair<'a> {
-    pub key: &'a str,
-    pub value: &'a str,
+    pub key: Cow<'a, [u8]>,
+    pub value: Cow<'a, [u8]>,
+    pub seq: Option<u64>,
+    pub tombstone: bool,
 }
 
-impl KVPair<'_> {
-    pub fn new<'a>(key: &'a str, value: &'a str) -> KVPair<'a> {
-        KVPair{key, value}
+impl<'a> KVPair<'a> {
+    pub fn new<K: Into<Cow<'a, [u8]>>, V: Into<Cow<'a, [u8]>>>(key: K, value: V) -> KVPair<'a> {
+        KVPair{key: key.into(), value: value.into(), seq: None, tombstone: false}
     }
-}
 
-impl Ord for KVPair<'_> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.key > other.key {
-            Ordering::Greater
-        }
-        else if self.key < other.key {
-            Ordering::Less
-        }
-        else {
-            Ordering::Equal
-        }
+    pub fn tombstone<K: Into<Cow<'a, [u8]>>>(key: K) -> KVPair<'a> {
+        KVPair{key: key.into(), value: Cow::Borrowed(&[]), seq: None, tombstone: true}
     }
-}
 
-impl Eq for KVPair<'_> {
+    pub fn with_seq(mut self, seq: u64) -> KVPair<'a> {
+        self.seq = Some(seq);
+        self
+    }
+
+    pub fn into_owned(self) -> KVPair<'static> {
+        KVPair{key: Cow::Owned(self.key.into_owned()), value: Cow::Owned(self.value.into_owned()), seq: self.seq, tombstone: self.tombstone}
+    }
+
+    // Lossy text views of the key and value, for keys and values known to be text
+    pub fn key_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.key)
+    }
+
+    pub fn value_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.value)
+    }
 }
 
-impl PartialOrd for KVPair<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.key.partial_cmp(&other.key) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
-        }
-        self.value.partial_cmp(&other.value)
+/*
+Pairs order bytewise by key, then newest first by sequence number (pairs without one
+after those with one), then tombstones before values, then by value. Two pairs are only
+equal when every field is
+*/
+impl Ord for KVPair<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+            .then_with(|| (self.seq.is_none(), Reverse(self.seq)).cmp(&(other.seq.is_none(), Reverse(other.seq))))
+            .then_with(|| other.tombstone.cmp(&self.tombstone))
+            .then_with(|| self.value.cmp(&other.value))
     }
 }
 
-impl Display for KVPair<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write(f, format_args!("key: {}, value: {}", self.key, self.value))
+impl PartialOrd for KVPair<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl PartialEq for KVPair<'_> {
-    fn eq(&self, other: &KVPair) -> bool { 
-        self.key == other.key
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
+}
 
-    fn ne(&self, other: &Self) -> bool {
-        self.key == other.key && self.value == other.value
+impl Eq for KVPair<'_> {
+}
+
+impl Display for KVPair<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.tombstone {
+            true => write!(f, "key: {}, deleted", self.key_str())?,
+            false => write!(f, "key: {}, value: {}", self.key_str(), self.value_str())?,
+        }
+        match self.seq {
+            Some(seq) => write!(f, ", seq: {}", seq),
+            None => Ok(()),
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,13 +1,40 @@
-use storage::{lsm::LsmTree};
-
 pub mod kvpair;
 pub mod operators;
+pub mod cli;
+pub mod editor;
+pub mod resp;
+pub mod json;
+pub mod server;
+pub mod http;
+pub mod store;
+pub mod client;
+pub mod replication;
 
 pub mod storage {
     pub mod tree;
+    pub mod skiplist;
     pub mod lsm;
     pub mod diskseg;
     pub mod files;
+    pub mod options;
+    pub mod coding;
+    pub mod block;
+    pub mod segment;
+    pub mod filter;
+    pub mod stats;
+    pub mod cache;
+    pub mod compression;
+    pub mod merge;
+    pub mod mmap;
+    pub mod wal;
+    pub mod comparator;
+    pub mod manifest;
+    pub mod error;
+    pub mod clock;
+    pub mod merge_operator;
+    pub mod column_family;
+    pub mod write_batch;
+    pub mod checkpoint;
 }
 
 pub mod tst {
@@ -15,6 +42,19 @@ pub mod tst {
     // https://stackoverflow.com/questions/58935890/how-to-import-from-a-file-in-a-subfolder-of-src
     pub mod bst_test;
     pub mod lsm_test;
+    pub mod skiplist_test;
+    pub mod segment_test;
+    pub mod cache_test;
+    pub mod compression_test;
+    pub mod comparator_test;
+    pub mod kvpair_test;
+    pub mod operators_test;
+    pub mod cli_test;
+    pub mod resp_test;
+    pub mod http_test;
+    pub mod json_test;
+    pub mod client_test;
+    pub mod replication_test;
     pub mod tst_util;
 }
 
@@ -27,13 +67,6 @@ pub fn log(msg: &str) {
 }
 
 fn main() {
-    let k = "foo";
-    let mut l = LsmTree::new("x");
-
-    let result= l.write(k, "bar");
-    if result {
-        if let Some(v) = l.get(k) {log(&format!("Value for {} is {}", k, v))}
-    }
-
-    //let mux: Mutex<LogSegment<String>> = Mutex::new(LogSegment::new());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(cli::main(&args));
 }
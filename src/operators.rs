@@ -1,4 +1,760 @@
+use std::{cell::Cell, cmp::Ordering, error::Error, fmt::{Display, Formatter, Result as FmtResult}, iter::Peekable, mem::take, str::CharIndices, sync::{atomic::{AtomicBool, Ordering::{Acquire, Release}}, Arc}, vec::IntoIter};
+
+use crate::{kvpair::KVPair, storage::{comparator::Comparator, error::LsmError, lsm::LsmTree, write_batch::WriteBatch}};
+
 // spec
 
 // parse: given input, parses command to determine which operators to execute
-// or whether to cancel the query if invalid
\ No newline at end of file
+// or whether to cancel the query if invalid
+
+/*
+Command: One parsed command, the grammar being
+
+    GET key
+    PUT key value
+    DEL key
+    SCAN [PREFIX prefix | RANGE start end] [LIMIT n]
+    BATCH, then any number of PUT and DEL, then END
+    COMPACT
+    STATS
+
+where the rows read by GET and SCAN can be piped through any of
+
+    | FILTER (KEY | VALUE) (PREFIX | CONTAINS | EQUALS) operand
+    | LIMIT n
+    | COUNT
+
+Commands are separated by newlines or ';', so a batch can be written on one line as
+"BATCH; PUT a 1; DEL b; END". Command names and keywords are case insensitive. Arguments
+are bare words, or quoted for anything else: "..." takes the escapes \\ \" \' \n \r \t
+\0 and \xHH for any byte, so keys and values can hold arbitrary bytes, while '...' is
+taken as is. A '#' starts a comment running to the end of the line
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // GET, PUT, DEL and SCAN, along with whatever they are piped through
+    Pipeline(Vec<Operator>),
+    Batch(Vec<Write>),
+    Compact,
+    Stats,
+}
+
+/*
+Operator: One step of a pipeline. A pipeline starts with a point get or range scan
+reading rows, which then pass through the filters, limits and counts after it in order,
+or is a single put or delete
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operator {
+    Get{key: Vec<u8>},
+    Scan{range: KeyRange},
+    Filter(Predicate),
+    Limit(usize),
+    Count,
+    Put{key: Vec<u8>, value: Vec<u8>},
+    Delete{key: Vec<u8>},
+}
+
+impl Operator {
+    fn name(&self) -> &'static str {
+        match self {
+            Operator::Get{..} => "GET",
+            Operator::Scan{..} => "SCAN",
+            Operator::Filter(_) => "FILTER",
+            Operator::Limit(_) => "LIMIT",
+            Operator::Count => "COUNT",
+            Operator::Put{..} => "PUT",
+            Operator::Delete{..} => "DEL",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyRange {
+    All,
+    Prefix(Vec<u8>),
+    // From start up to but not including end, in the LSM's comparator order
+    Range{start: Vec<u8>, end: Vec<u8>},
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Key,
+    Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Prefix,
+    Contains,
+    Equals,
+}
+
+// Keeps the rows whose key or value compares to the operand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Predicate {
+    pub field: Field,
+    pub comparison: Comparison,
+    pub operand: Vec<u8>,
+}
+
+impl Predicate {
+    pub fn matches(&self, pair: &KVPair) -> bool {
+        let bytes: &[u8] = match self.field {
+            Field::Key => &pair.key,
+            Field::Value => &pair.value,
+        };
+        match self.comparison {
+            Comparison::Prefix => bytes.starts_with(&self.operand),
+            Comparison::Contains => self.operand.is_empty() || bytes.windows(self.operand.len()).any(|window| window == self.operand),
+            Comparison::Equals => bytes == self.operand,
+        }
+    }
+}
+
+// The writes that can be grouped in a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Write {
+    Put{key: Vec<u8>, value: Vec<u8>},
+    Delete{key: Vec<u8>},
+}
+
+// Where in the input a token starts, as a byte offset and 1-based line and column (in chars)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub position: Position,
+    // The input ended part way through a string or BATCH, so more of it could still parse
+    pub incomplete: bool,
+}
+
+impl ParseError {
+    fn new(message: String, position: Position) -> ParseError {
+        ParseError{message, position, incomplete: false}
+    }
+
+    fn incomplete(message: String, position: Position) -> ParseError {
+        ParseError{message, position, incomplete: true}
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "syntax error at line {}, column {}: {}", self.position.line, self.position.column, self.message)
+    }
+}
+
+impl Error for ParseError {
+}
+
+/*
+Parse: Parses the input into the commands to execute, in order. Nothing is returned for
+input with any syntax error, only the first error, so that the whole query is cancelled
+rather than run in part
+*/
+pub fn parse(input: &str) -> Result<Vec<Command>, ParseError> {
+    let mut commands = Vec::new();
+    // Writes of the batch being parsed, and where it started
+    let mut batch: Option<(Vec<Write>, Position)> = None;
+
+    for statement in tokenize(input)? {
+        let mut segments = statement.segments.into_iter();
+        let mut args = Args::new(segments.next().unwrap());
+        let (name, position) = args.keyword("a command")?;
+        let command = match (name.as_str(), &mut batch) {
+            ("BATCH", Some(_)) => return Err(ParseError::new("BATCH cannot be nested".to_string(), position)),
+            ("BATCH", None) => {
+                args.finish()?;
+                batch = Some((Vec::new(), position));
+                None
+            },
+            ("END", Some(_)) => {
+                args.finish()?;
+                Some(Command::Batch(batch.take().unwrap().0))
+            },
+            ("END", None) => return Err(ParseError::new("END without BATCH".to_string(), position)),
+            (_, Some((writes, _))) => {
+                let write = match parse_command(&name, position, &mut args)? {
+                    Command::Pipeline(mut operators) if operators.len() == 1 => match operators.pop().unwrap() {
+                        Operator::Put{key, value} => Some(Write::Put{key, value}),
+                        Operator::Delete{key} => Some(Write::Delete{key}),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match write {
+                    Some(write) => writes.push(write),
+                    None => return Err(ParseError::new(format!("{} cannot be used in a BATCH, only PUT and DEL", name), position)),
+                }
+                None
+            },
+            (_, None) => Some(parse_command(&name, position, &mut args)?),
+        };
+
+        match command {
+            Some(Command::Pipeline(mut operators)) => {
+                for segment in segments {
+                    operators.push(parse_operator(&mut Args::new(segment))?);
+                }
+                commands.push(Command::Pipeline(operators));
+            },
+            Some(command) => match segments.next() {
+                Some(segment) => return Err(ParseError::new(format!("{} cannot be piped", name), segment.pipe.unwrap())),
+                None => commands.push(command),
+            },
+            None => if let Some(segment) = segments.next() {
+                return Err(ParseError::new(format!("{} cannot be piped", name), segment.pipe.unwrap()));
+            },
+        }
+    }
+
+    match batch {
+        Some((_, position)) => Err(ParseError::incomplete("BATCH without END".to_string(), position)),
+        None => Ok(commands),
+    }
+}
+
+fn parse_command(name: &str, position: Position, args: &mut Args) -> Result<Command, ParseError> {
+    let command = match name {
+        "GET" => Command::Pipeline(vec![Operator::Get{key: args.bytes("a key")?}]),
+        "PUT" => Command::Pipeline(vec![Operator::Put{key: args.bytes("a key")?, value: args.bytes("a value")?}]),
+        "DEL" => Command::Pipeline(vec![Operator::Delete{key: args.bytes("a key")?}]),
+        "SCAN" => {
+            let range = match args.peek_keyword().as_deref() {
+                Some("PREFIX") => {
+                    args.keyword("PREFIX")?;
+                    KeyRange::Prefix(args.bytes("a prefix")?)
+                },
+                Some("RANGE") => {
+                    args.keyword("RANGE")?;
+                    KeyRange::Range{start: args.bytes("a start key")?, end: args.bytes("an end key")?}
+                },
+                _ => KeyRange::All,
+            };
+            let mut operators = vec![Operator::Scan{range}];
+            if args.peek_keyword().as_deref() == Some("LIMIT") {
+                args.keyword("LIMIT")?;
+                operators.push(Operator::Limit(args.number("a number for LIMIT")?));
+            }
+            Command::Pipeline(operators)
+        },
+        "COMPACT" => Command::Compact,
+        "STATS" => Command::Stats,
+        _ => return Err(ParseError::new(format!("unknown command {}", name), position)),
+    };
+    args.finish()?;
+    Ok(command)
+}
+
+// Parses an operator piped into after the command
+fn parse_operator(args: &mut Args) -> Result<Operator, ParseError> {
+    let (name, position) = args.keyword("an operator")?;
+    let operator = match name.as_str() {
+        "FILTER" => {
+            let (field, position) = args.keyword("KEY or VALUE")?;
+            let field = match field.as_str() {
+                "KEY" => Field::Key,
+                "VALUE" => Field::Value,
+                _ => return Err(ParseError::new(format!("expected KEY or VALUE, found {}", field), position)),
+            };
+            let (comparison, position) = args.keyword("PREFIX, CONTAINS or EQUALS")?;
+            let comparison = match comparison.as_str() {
+                "PREFIX" => Comparison::Prefix,
+                "CONTAINS" => Comparison::Contains,
+                "EQUALS" => Comparison::Equals,
+                _ => return Err(ParseError::new(format!("expected PREFIX, CONTAINS or EQUALS, found {}", comparison), position)),
+            };
+            Operator::Filter(Predicate{field, comparison, operand: args.bytes("an operand")?})
+        },
+        "LIMIT" => Operator::Limit(args.number("a number for LIMIT")?),
+        "COUNT" => Operator::Count,
+        _ => return Err(ParseError::new(format!("unknown operator {}", name), position)),
+    };
+    args.finish()?;
+    Ok(operator)
+}
+
+/*
+Quote: Writes bytes as an argument that parses back to the same bytes. Printable UTF-8
+that can't be mistaken for anything else is left bare, anything else goes in "..." with
+escapes for quotes, control characters and bytes that aren't UTF-8
+*/
+pub fn quote(bytes: &[u8]) -> String {
+    let bare = match std::str::from_utf8(bytes) {
+        Ok(word) => !word.is_empty() && !word.starts_with('#')
+            && !word.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, ';' | '|' | '"' | '\'')),
+        Err(_) => false,
+    };
+    if bare {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+
+    let mut quoted = String::from("\"");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => quoted.push_str("\\\\"),
+                '"' => quoted.push_str("\\\""),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                '\0' => quoted.push_str("\\0"),
+                c if c.is_control() && c.is_ascii() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+                c => quoted.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/*
+Query Error: Why a query was cancelled, or failed part way through. Syntax and plan
+errors are found before anything is executed, so nothing in the query has run
+*/
+#[derive(Debug)]
+pub enum QueryError {
+    Parse(ParseError),
+    // The plan for the command at this index can't be executed
+    Invalid{command: usize, message: String},
+    // The query was cancelled through its token, commands before the one running had run
+    Cancelled,
+    Storage(LsmError),
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            QueryError::Parse(e) => write!(f, "{}", e),
+            QueryError::Invalid{command, message} => write!(f, "invalid command {}: {}", command + 1, message),
+            QueryError::Cancelled => write!(f, "query cancelled"),
+            QueryError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for QueryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            QueryError::Parse(e) => Some(e),
+            QueryError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for QueryError {
+    fn from(e: ParseError) -> Self {
+        QueryError::Parse(e)
+    }
+}
+
+impl From<LsmError> for QueryError {
+    fn from(e: LsmError) -> Self {
+        QueryError::Storage(e)
+    }
+}
+
+/*
+Cancellation Token: Shared between a running query and whoever may cancel it, e.g. from
+another thread. Scans check it before each row they read, so a long scan stops part way
+*/
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Acquire)
+    }
+}
+
+// What executing a command produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Rows(Vec<KVPair<'static>>),
+    // Number of rows passed to the sink when streaming
+    Streamed(usize),
+    Count(usize),
+    // Number of keys written or deleted
+    Written(usize),
+    Compacted,
+    Stats(String),
+}
+
+/*
+Validate: Checks the plan for every command before any is executed. A pipeline starts
+with GET or SCAN, followed by any number of FILTER and LIMIT and at most one COUNT,
+last, or is a lone PUT or DEL, and a range can't start after it ends
+*/
+pub fn validate(commands: &[Command], comparator: &dyn Comparator) -> Result<(), QueryError> {
+    for (i, command) in commands.iter().enumerate() {
+        let invalid = |message: String| Err(QueryError::Invalid{command: i, message});
+        let operators = match command {
+            Command::Pipeline(operators) => operators,
+            _ => continue,
+        };
+        let (source, rest) = match operators.split_first() {
+            Some(split) => split,
+            None => return invalid("empty pipeline".to_string()),
+        };
+
+        match source {
+            Operator::Scan{range: KeyRange::Range{start, end}} if comparator.compare(start, end) == Ordering::Greater => {
+                return invalid("RANGE starts after it ends".to_string());
+            },
+            Operator::Get{..} | Operator::Scan{..} => {},
+            Operator::Put{..} | Operator::Delete{..} => if let Some(next) = rest.first() {
+                return invalid(format!("{} cannot be followed by {}", source.name(), next.name()));
+            },
+            _ => return invalid(format!("{} needs rows to read, from GET or SCAN", source.name())),
+        }
+        for (j, operator) in rest.iter().enumerate() {
+            match operator {
+                Operator::Filter(_) | Operator::Limit(_) => {},
+                Operator::Count if j == rest.len() - 1 => {},
+                Operator::Count => return invalid("COUNT must be the last operator".to_string()),
+                _ => return invalid(format!("{} can only start a pipeline", operator.name())),
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+Execute: Validates the commands, then executes them against the LSM in order, collecting
+the rows of each pipeline. Nothing is executed if any command is invalid
+*/
+pub fn execute(lsm: &mut LsmTree, commands: &[Command], token: &CancellationToken) -> Result<Vec<Output>, QueryError> {
+    validate(commands, lsm.comparator().as_ref())?;
+    let mut outputs = Vec::with_capacity(commands.len());
+    for command in commands {
+        let mut rows = Vec::new();
+        let output = match execute_command(lsm, command, token, &mut |pair| rows.push(pair))? {
+            Output::Streamed(_) => Output::Rows(rows),
+            output => output,
+        };
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+// Parses and executes the input, see parse and execute
+pub fn run(lsm: &mut LsmTree, input: &str, token: &CancellationToken) -> Result<Vec<Output>, QueryError> {
+    execute(lsm, &parse(input)?, token)
+}
+
+/*
+Execute Streaming: Validates and executes a single command, passing each row it reads to
+the sink as it goes rather than collecting them, for scans too large to hold at once
+*/
+pub fn execute_streaming(lsm: &mut LsmTree, command: &Command, token: &CancellationToken, sink: &mut dyn FnMut(KVPair<'static>)) -> Result<Output, QueryError> {
+    validate(std::slice::from_ref(command), lsm.comparator().as_ref())?;
+    execute_command(lsm, command, token, sink)
+}
+
+fn execute_command(lsm: &mut LsmTree, command: &Command, token: &CancellationToken, sink: &mut dyn FnMut(KVPair<'static>)) -> Result<Output, QueryError> {
+    if token.is_cancelled() {
+        return Err(QueryError::Cancelled);
+    }
+    match command {
+        Command::Pipeline(operators) => execute_pipeline(lsm, operators, token, sink),
+        Command::Batch(writes) => {
+            let mut batch = WriteBatch::new();
+            for write in writes {
+                match write {
+                    Write::Put{key, value} => batch.put(key, value),
+                    Write::Delete{key} => batch.delete(key),
+                }
+            }
+            lsm.write_batch(batch)?;
+            Ok(Output::Written(writes.len()))
+        },
+        Command::Compact => {
+            lsm.compact()?;
+            Ok(Output::Compacted)
+        },
+        Command::Stats => Ok(Output::Stats(lsm.statistics().to_string())),
+    }
+}
+
+fn execute_pipeline(lsm: &mut LsmTree, operators: &[Operator], token: &CancellationToken, sink: &mut dyn FnMut(KVPair<'static>)) -> Result<Output, QueryError> {
+    let comparator = lsm.comparator();
+    // Set when the token stops the rows being read
+    let cancelled = Cell::new(false);
+    let live = |_: &KVPair| {
+        cancelled.set(token.is_cancelled());
+        !cancelled.get()
+    };
+
+    let (source, stages) = operators.split_first().unwrap();
+    let mut rows: Box<dyn Iterator<Item = KVPair<'static>> + '_> = match source {
+        Operator::Get{key} => Box::new(lsm.get(key).map(|value| KVPair::new(key.clone(), value)).into_iter()),
+        Operator::Scan{range: KeyRange::All} => Box::new(lsm.iter().take_while(live)),
+        // Keys with the prefix are only found together from the prefix on in some orders, in others every key is checked
+        Operator::Scan{range: KeyRange::Prefix(prefix)} if comparator.groups_prefixes() => {
+            Box::new(lsm.iter_from(prefix).take_while(live).take_while(move |pair| pair.key.starts_with(prefix)))
+        },
+        Operator::Scan{range: KeyRange::Prefix(prefix)} => Box::new(lsm.iter().take_while(live).filter(move |pair| pair.key.starts_with(prefix))),
+        Operator::Scan{range: KeyRange::Range{start, end}} => {
+            Box::new(lsm.iter_from(start).take_while(live)
+                .take_while(move |pair| comparator.compare(&pair.key, end) == Ordering::Less))
+        },
+        Operator::Put{key, value} => return Ok(lsm.write(key, value).map(|_| Output::Written(1))?),
+        Operator::Delete{key} => return Ok(lsm.delete(key).map(|_| Output::Written(1))?),
+        _ => unreachable!("pipelines are validated to start with a source"),
+    };
+
+    let mut count = false;
+    for stage in stages {
+        rows = match stage {
+            Operator::Filter(predicate) => Box::new(rows.filter(move |pair| predicate.matches(pair))),
+            Operator::Limit(limit) => Box::new(rows.take(*limit)),
+            _ => {
+                count = true;
+                rows
+            },
+        };
+    }
+
+    let mut read = 0;
+    for pair in rows {
+        if !count {
+            sink(pair);
+        }
+        read += 1;
+    }
+    if cancelled.get() {
+        return Err(QueryError::Cancelled);
+    }
+    match count {
+        true => Ok(Output::Count(read)),
+        false => Ok(Output::Streamed(read)),
+    }
+}
+
+struct Token {
+    bytes: Vec<u8>,
+    // Quoted tokens are always arguments, never command names or keywords
+    quoted: bool,
+    position: Position,
+}
+
+// The part of a statement before the first '|', or between two
+#[derive(Default)]
+struct Segment {
+    // Where the '|' before the segment is, if there is one
+    pipe: Option<Position>,
+    tokens: Vec<Token>,
+    // Where the segment ends, which is where a missing argument was expected
+    end: Position,
+}
+
+struct Statement {
+    segments: Vec<Segment>,
+}
+
+// The tokens of a segment, taken in order
+struct Args {
+    tokens: Peekable<IntoIter<Token>>,
+    end: Position,
+}
+
+impl Args {
+    fn new(segment: Segment) -> Args {
+        Args{end: segment.end, tokens: segment.tokens.into_iter().peekable()}
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token, ParseError> {
+        match self.tokens.next() {
+            Some(token) => Ok(token),
+            None => Err(ParseError::new(format!("expected {}", expected), self.end)),
+        }
+    }
+
+    fn bytes(&mut self, expected: &str) -> Result<Vec<u8>, ParseError> {
+        Ok(self.next(expected)?.bytes)
+    }
+
+    fn keyword(&mut self, expected: &str) -> Result<(String, Position), ParseError> {
+        let token = self.next(expected)?;
+        match token.quoted {
+            true => Err(ParseError::new(format!("expected {}, found a quoted argument", expected), token.position)),
+            false => Ok((String::from_utf8_lossy(&token.bytes).to_ascii_uppercase(), token.position)),
+        }
+    }
+
+    fn peek_keyword(&mut self) -> Option<String> {
+        match self.tokens.peek() {
+            Some(token) if !token.quoted => Some(String::from_utf8_lossy(&token.bytes).to_ascii_uppercase()),
+            _ => None,
+        }
+    }
+
+    fn number(&mut self, expected: &str) -> Result<usize, ParseError> {
+        let token = self.next(expected)?;
+        match std::str::from_utf8(&token.bytes).ok().and_then(|number| number.parse().ok()) {
+            Some(number) => Ok(number),
+            None => Err(ParseError::new(format!("expected {}, found {}", expected, String::from_utf8_lossy(&token.bytes)), token.position)),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), ParseError> {
+        match self.tokens.next() {
+            Some(token) => Err(ParseError::new(format!("unexpected argument {}", String::from_utf8_lossy(&token.bytes)), token.position)),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Lexer<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer<'_> {
+    fn position(&mut self) -> Position {
+        let offset = self.chars.peek().map_or(self.len, |(offset, _)| *offset);
+        Position{offset, line: self.line, column: self.column}
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        }
+        else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn word(&mut self, position: Position) -> Result<Token, ParseError> {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ';' || c == '|' {
+                break;
+            }
+            if c == '"' || c == '\'' {
+                return Err(ParseError::new("quotes must start an argument".to_string(), self.position()));
+            }
+            word.push(c);
+            self.bump();
+        }
+        Ok(Token{bytes: word.into_bytes(), quoted: false, position})
+    }
+
+    // '...' is taken as is, up to the closing quote
+    fn raw_quoted(&mut self, position: Position) -> Result<Token, ParseError> {
+        self.bump();
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(Token{bytes: text.into_bytes(), quoted: true, position}),
+                Some(c) => text.push(c),
+                None => return Err(ParseError::incomplete("unterminated string".to_string(), position)),
+            }
+        }
+    }
+
+    fn quoted(&mut self, position: Position) -> Result<Token, ParseError> {
+        self.bump();
+        let mut bytes = Vec::new();
+        loop {
+            let escape = self.position();
+            let c = match self.bump() {
+                Some('"') => return Ok(Token{bytes, quoted: true, position}),
+                Some('\\') => self.bump(),
+                Some(c) => {
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    continue;
+                },
+                None => None,
+            };
+            match c {
+                Some('\\') => bytes.push(b'\\'),
+                Some('"') => bytes.push(b'"'),
+                Some('\'') => bytes.push(b'\''),
+                Some('n') => bytes.push(b'\n'),
+                Some('r') => bytes.push(b'\r'),
+                Some('t') => bytes.push(b'\t'),
+                Some('0') => bytes.push(0),
+                Some('x') => {
+                    let digits: String = [self.bump(), self.bump()].into_iter().flatten().collect();
+                    match u8::from_str_radix(&digits, 16) {
+                        Ok(byte) if digits.len() == 2 => bytes.push(byte),
+                        _ => return Err(ParseError::new("\\x must be followed by two hex digits".to_string(), escape)),
+                    }
+                },
+                Some(c) => return Err(ParseError::new(format!("invalid escape \\{}", c), escape)),
+                None => return Err(ParseError::incomplete("unterminated string".to_string(), position)),
+            }
+        }
+    }
+}
+
+// Splits the input into statements at newlines and ';', and each statement into segments at '|'
+fn tokenize(input: &str) -> Result<Vec<Statement>, ParseError> {
+    let mut lexer = Lexer{chars: input.char_indices().peekable(), len: input.len(), line: 1, column: 1};
+    let mut statements = Vec::new();
+    let mut segments = Vec::new();
+    let mut segment = Segment{pipe: None, tokens: Vec::new(), end: lexer.position()};
+    loop {
+        let position = lexer.position();
+        match lexer.peek() {
+            None | Some('\n') | Some(';') => {
+                if !segments.is_empty() || !segment.tokens.is_empty() {
+                    segment.end = position;
+                    segments.push(take(&mut segment));
+                    statements.push(Statement{segments: take(&mut segments)});
+                }
+                if lexer.bump().is_none() {
+                    return Ok(statements);
+                }
+            },
+            Some('|') => {
+                lexer.bump();
+                segment.end = position;
+                segments.push(take(&mut segment));
+                segment.pipe = Some(position);
+            },
+            Some('#') => {
+                while lexer.peek().is_some_and(|c| c != '\n') {
+                    lexer.bump();
+                }
+            },
+            Some(c) if c.is_whitespace() => {
+                lexer.bump();
+            },
+            Some('"') => segment.tokens.push(lexer.quoted(position)?),
+            Some('\'') => segment.tokens.push(lexer.raw_quoted(position)?),
+            Some(_) => segment.tokens.push(lexer.word(position)?),
+        }
+    }
+}
@@ -0,0 +1,591 @@
+use std::{io::{self, BufWriter, ErrorKind, Read, Write}, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{atomic::{AtomicBool, Ordering::{Acquire, Release}}, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use crate::{log, server::{serve_connections, ServerHandle}, storage::{checkpoint::Checkpoint, coding::*, error::LsmError, lsm::LsmTree, manifest::Manifest, wal::{encode_sequenced, read_batches, SequencedBatch}}};
+
+// How much of the WAL archive a primary keeps for secondaries to catch up from, see LsmOptions::wal_archive_size
+pub const DEFAULT_WAL_ARCHIVE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Sent by a secondary on connecting, followed by the sequence number it has applied up to
+const MAGIC: &[u8; 8] = b"PFREPL01";
+
+// Messages from the primary, each its type, the length of its payload (fixed 32 bit), then the payload
+const MSG_HELLO: u8 = 0;
+const MSG_BATCH: u8 = 1;
+const MSG_HEARTBEAT: u8 = 2;
+const MSG_ERROR: u8 = 3;
+const MSG_CHECKPOINT: u8 = 4;
+const MSG_CHUNK: u8 = 5;
+// Largest message payload either side accepts
+const MAX_MESSAGE_BYTES: usize = 256 * 1024 * 1024;
+// Bytes of commits a primary reads from its log at a time
+const BATCH_BYTES: usize = 1024 * 1024;
+// Bytes of a checkpoint's files a primary sends in each message
+const CHUNK_BYTES: usize = 1024 * 1024;
+// How long a secondary waits on a read before checking whether it has been shut down
+const READ_POLL: Duration = Duration::from_millis(100);
+// Delay before a secondary's first attempt to reconnect, doubling with each failed attempt
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(50);
+
+/*
+Replication Options: How often a primary checks for new commits to ship and tells an idle
+secondary it is still there, how long a secondary waits on a silent primary before
+reconnecting, and the longest it waits between attempts to reconnect
+*/
+#[derive(Debug, Clone)]
+pub struct ReplicationOptions {
+    pub poll_interval: Duration,
+    pub heartbeat_interval: Duration,
+    pub primary_timeout: Duration,
+    pub max_reconnect_delay: Duration,
+}
+
+impl Default for ReplicationOptions {
+    fn default() -> Self {
+        ReplicationOptions{
+            poll_interval: Duration::from_millis(10),
+            heartbeat_interval: Duration::from_secs(1),
+            primary_timeout: Duration::from_secs(5),
+            max_reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ReplicationOptions {
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    pub fn primary_timeout(mut self, timeout: Duration) -> Self {
+        self.primary_timeout = timeout;
+        self
+    }
+
+    pub fn max_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.max_reconnect_delay = delay;
+        self
+    }
+}
+
+/*
+Primary: Ships an LSM's commits to the secondaries that connect to it, each on its own
+thread. A secondary sends the magic bytes and the sequence number of the last commit it
+applied, the primary answers with a hello holding its latest sequence number and the names
+of its column families, by id, then sends every commit after the secondary's, in order, as
+it is logged, each a WAL record as encode_sequenced writes it. Heartbeats holding the latest
+sequence number fill any silence. Commits flushed out of the WAL are read back from the WAL
+archive, so the LSM should be opened with LsmOptions::wal_archive_size for secondaries to
+catch up after being away. One too far behind for that, or new to a primary that no longer
+has its first commits, is sent a checkpoint instead (see LsmTree::checkpoint): a message
+holding its sequence number, the manifest, and the column family, name and length of each
+segment file, followed by the files' contents in order, in chunks. The commits after the
+checkpoint's sequence number are then shipped as usual
+*/
+pub struct Primary {
+    listener: TcpListener,
+    lsm: Arc<Mutex<LsmTree>>,
+    shutdown: Arc<AtomicBool>,
+    options: ReplicationOptions,
+}
+
+impl Primary {
+    pub fn bind<A: ToSocketAddrs>(addr: A, lsm: Arc<Mutex<LsmTree>>) -> io::Result<Primary> {
+        Primary::bind_with_options(addr, lsm, ReplicationOptions::default())
+    }
+
+    pub fn bind_with_options<A: ToSocketAddrs>(addr: A, lsm: Arc<Mutex<LsmTree>>, options: ReplicationOptions) -> io::Result<Primary> {
+        Ok(Primary{listener: TcpListener::bind(addr)?, lsm, shutdown: Arc::new(AtomicBool::new(false)), options})
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Accepts secondaries until shut down through a handle, see spawn. Shutting down disconnects them
+    pub fn serve(self) -> io::Result<()> {
+        let (lsm, shutdown, options) = (self.lsm, self.shutdown.clone(), self.options);
+        serve_connections(&self.listener, &self.shutdown, move |stream| ship(stream, &lsm, &shutdown, &options))
+    }
+
+    // Serves on a background thread, until the handle is shut down
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let (addr, shutdown) = (self.local_addr()?, self.shutdown.clone());
+        Ok(ServerHandle::spawn(addr, shutdown, move || self.serve()))
+    }
+}
+
+fn write_message<W: Write>(writer: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![kind];
+    put_fixed32(&mut header, payload.len() as u32);
+    writer.write_all(&header)?;
+    writer.write_all(payload)
+}
+
+fn sequence_payload(sequence: u64) -> Vec<u8> {
+    let mut payload = Vec::new();
+    put_fixed64(&mut payload, sequence);
+    payload
+}
+
+// Sends the secondary on the stream the commits after the one it has, until either side goes away
+fn ship(stream: TcpStream, lsm: &Mutex<LsmTree>, shutdown: &AtomicBool, options: &ReplicationOptions) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(options.primary_timeout))?;
+    stream.set_write_timeout(Some(options.primary_timeout))?;
+    let mut subscribe = [0; MAGIC.len() + 8];
+    (&stream).read_exact(&mut subscribe)?;
+    if subscribe[..MAGIC.len()] != MAGIC[..] {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a secondary"));
+    }
+    let mut sequence = decode_fixed64(&subscribe[MAGIC.len()..]);
+    let peer = stream.peer_addr()?;
+    let mut writer = BufWriter::new(&stream);
+
+    let (latest, families): (u64, Vec<String>) = {
+        let lsm = lsm.lock().unwrap();
+        (lsm.latest_sequence(), lsm.column_family_names().into_iter().map(String::from).collect())
+    };
+    if sequence > latest {
+        let message = format!("secondary has applied up to sequence number {}, past the primary's {}", sequence, latest);
+        write_message(&mut writer, MSG_ERROR, message.as_bytes())?;
+        return writer.flush();
+    }
+    let mut hello = sequence_payload(latest);
+    put_varint32(&mut hello, families.len() as u32);
+    for family in families {
+        put_length_prefixed(&mut hello, family.as_bytes());
+    }
+    write_message(&mut writer, MSG_HELLO, &hello)?;
+    writer.flush()?;
+    log(&format!("shipping commits after sequence number {} to {}", sequence, peer));
+
+    let mut last_sent = Instant::now();
+    while !shutdown.load(Acquire) {
+        let (updates, latest) = {
+            let mut lsm = lsm.lock().unwrap();
+            (lsm.updates_since(sequence, BATCH_BYTES), lsm.latest_sequence())
+        };
+        match updates {
+            Ok(batches) if !batches.is_empty() => {
+                for batch in batches {
+                    write_message(&mut writer, MSG_BATCH, &encode_sequenced(batch.sequence, &batch.records))?;
+                    sequence = batch.sequence;
+                }
+                writer.flush()?;
+                last_sent = Instant::now();
+            },
+            Ok(_) => {
+                if last_sent.elapsed() >= options.heartbeat_interval {
+                    write_message(&mut writer, MSG_HEARTBEAT, &sequence_payload(latest))?;
+                    writer.flush()?;
+                    last_sent = Instant::now();
+                }
+                thread::sleep(options.poll_interval);
+            },
+            Err(LsmError::SequenceUnavailable{..}) => {
+                let checkpoint = lsm.lock().unwrap().checkpoint();
+                match checkpoint {
+                    Ok(checkpoint) => {
+                        log(&format!("sending checkpoint at sequence number {} of {} files to {}", checkpoint.sequence, checkpoint.files.len(), peer));
+                        sequence = checkpoint.sequence;
+                        if !send_checkpoint(&mut writer, checkpoint, shutdown)? {
+                            return Ok(());
+                        }
+                        last_sent = Instant::now();
+                    },
+                    Err(e) => {
+                        log(&format!("unable to checkpoint for {}: {}", peer, e));
+                        write_message(&mut writer, MSG_ERROR, e.to_string().as_bytes())?;
+                        return writer.flush();
+                    },
+                }
+            },
+            Err(e) => {
+                log(&format!("unable to ship commits to {}: {}", peer, e));
+                write_message(&mut writer, MSG_ERROR, e.to_string().as_bytes())?;
+                return writer.flush();
+            },
+        }
+    }
+    Ok(())
+}
+
+// Sends the checkpoint's files, returning false if shut down part way through
+fn send_checkpoint<W: Write>(writer: &mut W, checkpoint: Checkpoint, shutdown: &AtomicBool) -> io::Result<bool> {
+    let mut header = sequence_payload(checkpoint.sequence);
+    put_length_prefixed(&mut header, checkpoint.manifest.encode().as_bytes());
+    put_varint32(&mut header, checkpoint.files.len() as u32);
+    for file in &checkpoint.files {
+        put_length_prefixed(&mut header, file.family.as_bytes());
+        put_length_prefixed(&mut header, file.name.as_bytes());
+        put_fixed64(&mut header, file.len);
+    }
+    write_message(writer, MSG_CHECKPOINT, &header)?;
+    let mut chunk = vec![0; CHUNK_BYTES];
+    for mut file in checkpoint.files {
+        let mut remaining = file.len;
+        while remaining > 0 {
+            if shutdown.load(Acquire) {
+                return Ok(false);
+            }
+            let n = remaining.min(CHUNK_BYTES as u64) as usize;
+            file.file.read_exact(&mut chunk[..n])?;
+            write_message(writer, MSG_CHUNK, &chunk[..n])?;
+            remaining -= n as u64;
+        }
+    }
+    writer.flush()?;
+    Ok(true)
+}
+
+/*
+Secondary Status: Whether a secondary is connected to its primary, the sequence numbers of
+the last commit it applied and the latest the primary has told it of, that of the last
+checkpoint it installed, if it has installed one, and why it stopped, if it stopped
+following the primary other than by being shut down
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecondaryStatus {
+    pub connected: bool,
+    pub applied: u64,
+    pub primary_sequence: u64,
+    pub checkpoint: Option<u64>,
+    pub error: Option<String>,
+}
+
+/*
+Secondary: Follows a primary, applying each commit it ships to the LSM under the commit's
+sequence number, see LsmTree::apply_replicated. The LSM logs the sequence number with the
+commit, so it knows the last one applied even after a crash, and following starts after it.
+Connections that fail or go silent are retried with a growing delay, resuming where the
+last left off. A checkpoint the primary sends in place of commits it no longer has is
+received into the LSM's checkpoint directory and then replaces everything in the LSM, see
+LsmTree::install_checkpoint, after which the commits that follow it are applied. Following
+stops for good when the primary reports an error, or ships a commit or checkpoint for a
+column family the LSM doesn't have. Nothing else should write to the LSM
+*/
+pub struct Secondary {
+    primary: Vec<SocketAddr>,
+    lsm: Arc<Mutex<LsmTree>>,
+    options: ReplicationOptions,
+}
+
+impl Secondary {
+    pub fn new<A: ToSocketAddrs>(primary: A, lsm: Arc<Mutex<LsmTree>>) -> io::Result<Secondary> {
+        Secondary::with_options(primary, lsm, ReplicationOptions::default())
+    }
+
+    pub fn with_options<A: ToSocketAddrs>(primary: A, lsm: Arc<Mutex<LsmTree>>, options: ReplicationOptions) -> io::Result<Secondary> {
+        let primary: Vec<SocketAddr> = primary.to_socket_addrs()?.collect();
+        if primary.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "primary address resolved to nothing"));
+        }
+        Ok(Secondary{primary, lsm, options})
+    }
+
+    // Follows the primary on a background thread, until the handle is shut down
+    pub fn spawn(self) -> SecondaryHandle {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let applied = self.lsm.lock().unwrap().latest_sequence();
+        let status = Arc::new(Mutex::new(SecondaryStatus{applied, ..SecondaryStatus::default()}));
+        let (stop, shared) = (shutdown.clone(), status.clone());
+        let thread = thread::spawn(move || self.run(&stop, &shared));
+        SecondaryHandle{shutdown, status, thread}
+    }
+
+    fn run(&self, shutdown: &AtomicBool, status: &Mutex<SecondaryStatus>) {
+        let mut delay = MIN_RECONNECT_DELAY;
+        while !shutdown.load(Acquire) {
+            let result = self.follow(shutdown, status, &mut delay);
+            status.lock().unwrap().connected = false;
+            match result {
+                Ok(()) => return,
+                Err(FollowError::Fatal(message)) => {
+                    log(&format!("stopped following primary {}: {}", self.primary[0], message));
+                    status.lock().unwrap().error = Some(message);
+                    return;
+                },
+                Err(FollowError::Io(e)) => log(&format!("lost primary {}, reconnecting in {:?}: {}", self.primary[0], delay, e)),
+            }
+            let retry_at = Instant::now() + delay;
+            while !shutdown.load(Acquire) && Instant::now() < retry_at {
+                thread::sleep(READ_POLL.min(retry_at - Instant::now()));
+            }
+            delay = (delay * 2).min(self.options.max_reconnect_delay);
+        }
+    }
+
+    // Connects to the primary and applies what it ships, returning once shut down
+    fn follow(&self, shutdown: &AtomicBool, status: &Mutex<SecondaryStatus>, delay: &mut Duration) -> Result<(), FollowError> {
+        let stream = TcpStream::connect_timeout(&self.primary[0], self.options.primary_timeout)
+            .or_else(|e| self.primary[1..].iter().find_map(|addr| TcpStream::connect_timeout(addr, self.options.primary_timeout).ok()).ok_or(e))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(READ_POLL))?;
+        stream.set_write_timeout(Some(self.options.primary_timeout))?;
+        let mut subscribe = MAGIC.to_vec();
+        put_fixed64(&mut subscribe, self.lsm.lock().unwrap().latest_sequence());
+        (&stream).write_all(&subscribe)?;
+
+        let mut receiver = Receiver{stream, heard_at: Instant::now(), timeout: self.options.primary_timeout};
+        let families = match receiver.message(shutdown)? {
+            None => return Ok(()),
+            Some((MSG_HELLO, hello)) => {
+                let (primary_sequence, families) = decode_hello(&hello)?;
+                let lsm = self.lsm.lock().unwrap();
+                if let Some(missing) = families.iter().find(|family| !lsm.column_family_names().contains(&family.as_str())) {
+                    return Err(FollowError::Fatal(LsmError::UnknownColumnFamily(missing.clone()).to_string()));
+                }
+                let mut status = status.lock().unwrap();
+                status.connected = true;
+                status.primary_sequence = primary_sequence;
+                status.error = None;
+                families
+            },
+            Some(message) => return Err(unexpected(message)),
+        };
+        *delay = MIN_RECONNECT_DELAY;
+
+        while let Some(message) = receiver.message(shutdown)? {
+            match message {
+                (MSG_BATCH, payload) => {
+                    let SequencedBatch{sequence, records} = decode_batch(&payload)?;
+                    let records = records.into_iter()
+                        .map(|(family, record)| families.get(family as usize).map(|name| (name.clone(), record)))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or(FollowError::Io(protocol_error("commit for a column family the primary didn't name")))?;
+                    self.lsm.lock().unwrap().apply_replicated(sequence, records)?;
+                    let mut status = status.lock().unwrap();
+                    status.applied = sequence;
+                    status.primary_sequence = status.primary_sequence.max(sequence);
+                },
+                (MSG_CHECKPOINT, payload) => {
+                    let sequence = match self.receive_checkpoint(&mut receiver, &payload, shutdown)? {
+                        Some(sequence) => sequence,
+                        None => return Ok(()),
+                    };
+                    let mut status = status.lock().unwrap();
+                    status.applied = sequence;
+                    status.primary_sequence = status.primary_sequence.max(sequence);
+                    status.checkpoint = Some(sequence);
+                },
+                (MSG_HEARTBEAT, payload) if payload.len() == 8 => status.lock().unwrap().primary_sequence = decode_fixed64(&payload),
+                (MSG_ERROR, payload) => return Err(FollowError::Fatal(format!("primary refused: {}", String::from_utf8_lossy(&payload)))),
+                message => return Err(unexpected(message)),
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    Receive Checkpoint: Stages the files of the checkpoint the header announces as their
+    chunks arrive, without holding the LSM's lock, then installs it, returning its sequence
+    number, or None if shut down first
+    */
+    fn receive_checkpoint(&self, receiver: &mut Receiver, header: &[u8], shutdown: &AtomicBool) -> Result<Option<u64>, FollowError> {
+        let (sequence, manifest, files) = decode_checkpoint(header)?;
+        log(&format!("receiving checkpoint at sequence number {} of {} files from {}", sequence, files.len(), self.primary[0]));
+        let staged = self.lsm.lock().unwrap().stage_checkpoint(sequence, &manifest)?;
+        for (family, name, len) in files {
+            let mut file = staged.create_file(&family, &name).map_err(|e| protocol_error(&e.to_string()))?;
+            let mut received = 0;
+            while received < len {
+                match receiver.message(shutdown)? {
+                    None => return Ok(None),
+                    Some((MSG_CHUNK, chunk)) if received + chunk.len() as u64 <= len => {
+                        file.write_all(&chunk)?;
+                        received += chunk.len() as u64;
+                    },
+                    Some((MSG_CHUNK, _)) => return Err(FollowError::Io(protocol_error("checkpoint file longer than announced"))),
+                    Some(message) => return Err(unexpected(message)),
+                }
+            }
+            file.sync_all()?;
+        }
+        Ok(Some(self.lsm.lock().unwrap().install_checkpoint(staged)?))
+    }
+}
+
+// Why following a primary stopped, either for good or until reconnecting
+enum FollowError {
+    Fatal(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for FollowError {
+    fn from(e: io::Error) -> Self {
+        FollowError::Io(e)
+    }
+}
+
+// Failing to write to the LSM is only retried if it was for want of I/O
+impl From<LsmError> for FollowError {
+    fn from(e: LsmError) -> Self {
+        match e {
+            LsmError::Io(e) => FollowError::Io(e),
+            e => FollowError::Fatal(e.to_string()),
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("replication protocol error: {}", message))
+}
+
+fn unexpected((kind, payload): (u8, Vec<u8>)) -> FollowError {
+    match kind {
+        MSG_ERROR => FollowError::Fatal(format!("primary refused: {}", String::from_utf8_lossy(&payload))),
+        _ => FollowError::Io(protocol_error(&format!("unexpected message type {}", kind))),
+    }
+}
+
+fn decode_hello(hello: &[u8]) -> io::Result<(u64, Vec<String>)> {
+    if hello.len() < 8 {
+        return Err(protocol_error("hello too short"));
+    }
+    let (count, mut pos) = get_varint32(&hello[8..])?;
+    let mut families = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (name, n) = get_length_prefixed(&hello[8 + pos..])?;
+        families.push(String::from_utf8(name.to_vec()).map_err(|_| protocol_error("column family name isn't UTF-8"))?);
+        pos += n;
+    }
+    Ok((decode_fixed64(hello), families))
+}
+
+// Column family, name and length of a file of a checkpoint
+type FileEntry = (String, String, u64);
+
+// The sequence number, manifest and files of a checkpoint
+fn decode_checkpoint(header: &[u8]) -> io::Result<(u64, Manifest, Vec<FileEntry>)> {
+    if header.len() < 8 {
+        return Err(protocol_error("checkpoint too short"));
+    }
+    let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| protocol_error("checkpoint text isn't UTF-8"));
+    let (manifest, mut pos) = get_length_prefixed(&header[8..])?;
+    let manifest = Manifest::decode(&text(manifest)?)?;
+    let (count, n) = get_varint32(&header[8 + pos..])?;
+    pos += 8 + n;
+    let mut files = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (family, n) = get_length_prefixed(&header[pos..])?;
+        let (name, m) = get_length_prefixed(&header[pos + n..])?;
+        pos += n + m;
+        let len = match header.get(pos..pos + 8) {
+            Some(len) => decode_fixed64(len),
+            None => return Err(protocol_error("checkpoint too short")),
+        };
+        pos += 8;
+        files.push((text(family)?, text(name)?, len));
+    }
+    Ok((decode_fixed64(header), manifest, files))
+}
+
+// A shipped commit is a single WAL record, which must be whole and pass its checksum
+fn decode_batch(payload: &[u8]) -> io::Result<SequencedBatch> {
+    match read_batches(payload, 0) {
+        (mut batches, len) if batches.len() == 1 && len == payload.len() => Ok(batches.remove(0).1),
+        _ => Err(protocol_error("malformed commit")),
+    }
+}
+
+// Reads the primary's messages, giving up on a primary silent for longer than the timeout
+struct Receiver {
+    stream: TcpStream,
+    heard_at: Instant,
+    timeout: Duration,
+}
+
+impl Receiver {
+    // The next message, or None once shut down
+    fn message(&mut self, shutdown: &AtomicBool) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let mut header = [0; 5];
+        if !self.read_exact(&mut header, shutdown)? {
+            return Ok(None);
+        }
+        let len = decode_fixed32(&header[1..]) as usize;
+        if len > MAX_MESSAGE_BYTES {
+            return Err(protocol_error("message too large"));
+        }
+        let mut payload = vec![0; len];
+        match self.read_exact(&mut payload, shutdown)? {
+            true => Ok(Some((header[0], payload))),
+            false => Ok(None),
+        }
+    }
+
+    // Fills the buffer, returning false if shut down first
+    fn read_exact(&mut self, buf: &mut [u8], shutdown: &AtomicBool) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if shutdown.load(Acquire) {
+                return Ok(false);
+            }
+            match self.stream.read(&mut buf[filled..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "primary closed the connection")),
+                Ok(n) => {
+                    filled += n;
+                    self.heard_at = Instant::now();
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                    if self.heard_at.elapsed() > self.timeout {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "primary went silent"));
+                    }
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+// A secondary following its primary on a background thread, which stops when shut down
+pub struct SecondaryHandle {
+    shutdown: Arc<AtomicBool>,
+    status: Arc<Mutex<SecondaryStatus>>,
+    thread: JoinHandle<()>,
+}
+
+impl SecondaryHandle {
+    pub fn status(&self) -> SecondaryStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /*
+    Wait For: Waits until the secondary has applied the commit with the sequence number,
+    returning false if it hasn't within the timeout or has stopped following the primary
+    */
+    pub fn wait_for(&self, sequence: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = self.status();
+            if status.applied >= sequence {
+                return true;
+            }
+            if status.error.is_some() || self.thread.is_finished() || Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // Waits for the secondary to stop following the primary, which it only does on an error or once shut down
+    pub fn join(self) -> SecondaryStatus {
+        let SecondaryHandle{status, thread, ..} = self;
+        if thread.join().is_err() {
+            status.lock().unwrap().error = Some("secondary thread panicked".to_string());
+        }
+        let status = status.lock().unwrap().clone();
+        status
+    }
+
+    // Stops following the primary, returning the status it stopped with
+    pub fn shutdown(self) -> SecondaryStatus {
+        self.shutdown.store(true, Release);
+        self.join()
+    }
+}
@@ -0,0 +1,525 @@
+use std::{collections::BTreeMap, io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write}, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{atomic::AtomicBool, Arc, Mutex}, time::Duration};
+
+use crate::{server::{serve_connections, ServerHandle}, storage::{error::LsmError, lsm::LsmTree, write_batch::WriteBatch}};
+
+pub const DEFAULT_RESP_ADDR: &str = "127.0.0.1:6379";
+
+// Longest bulk string and most elements of an array a client may send, as in Redis
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+// Longest line holding an inline command or the header of a value
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Deepest arrays may be nested in a value read, past which reading it would run out of stack
+const MAX_NESTING: usize = 64;
+
+// SCAN cursors kept for clients to resume from, past which the oldest are forgotten
+const MAX_CURSORS: usize = 1024;
+// Keys a SCAN looks at when not given a COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/*
+RESP Value: A value of the Redis serialization protocol, version 2, which commands are
+sent and replied to in
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    // None is the null bulk string, e.g. a GET of a missing key
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>),
+}
+
+impl RespValue {
+    pub fn bulk<B: AsRef<[u8]>>(bytes: B) -> RespValue {
+        RespValue::Bulk(Some(bytes.as_ref().to_vec()))
+    }
+
+    pub fn ok() -> RespValue {
+        RespValue::Simple("OK".to_string())
+    }
+
+    pub fn error(message: &str) -> RespValue {
+        RespValue::Error(format!("ERR {}", message))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            RespValue::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            RespValue::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            RespValue::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::Bulk(Some(bytes)) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            },
+            RespValue::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            RespValue::Array(Some(values)) => {
+                out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(out);
+                }
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+// Encodes a command the way clients send them, as an array of bulk strings
+pub fn encode_command<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    RespValue::Array(Some(args.iter().map(RespValue::bulk).collect())).to_bytes()
+}
+
+// Read-only DBs refuse writes the way Redis replicas do
+fn write_error(e: LsmError) -> RespValue {
+    match e {
+        LsmError::ReadOnly => RespValue::Error("READONLY You can't write against a read only replica.".to_string()),
+        e => RespValue::error(&e.to_string()),
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("Protocol error: {}", message))
+}
+
+// Reads a line ending in CRLF, or a bare LF as typed into netcat, without the line ending.
+// None if the stream has ended
+fn read_line(reader: &mut dyn BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE_LEN as u64 + 2).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match line.ends_with(b"\n") {
+        true => {
+            let ending = if line.ends_with(b"\r\n") { 2 } else { 1 };
+            line.truncate(line.len() - ending);
+            Ok(Some(line))
+        },
+        false if line.len() > MAX_LINE_LEN => Err(protocol_error("too big inline request")),
+        false => Err(io::Error::new(ErrorKind::UnexpectedEof, "stream ended part way through a line")),
+    }
+}
+
+// The length in a value's header, -1 for a null bulk string or array
+fn read_length(header: &[u8], max: usize) -> io::Result<Option<usize>> {
+    match std::str::from_utf8(header).ok().and_then(|length| length.parse::<i64>().ok()) {
+        Some(-1) => Ok(None),
+        Some(length) if length >= 0 && length as usize <= max => Ok(Some(length as usize)),
+        _ => Err(protocol_error(&format!("invalid length {}", String::from_utf8_lossy(header)))),
+    }
+}
+
+// Reads the contents of a bulk string given the rest of its header, None for a null one
+fn read_bulk(reader: &mut dyn BufRead, header: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let length = match read_length(header, MAX_BULK_LEN)? {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+    let mut bytes = vec![0; length + 2];
+    reader.read_exact(&mut bytes)?;
+    if !bytes.ends_with(b"\r\n") {
+        return Err(protocol_error("bulk string not ended by CRLF"));
+    }
+    bytes.truncate(length);
+    Ok(Some(bytes))
+}
+
+/*
+Read Value: Reads the next value from the stream, None if the stream ends before one
+starts. A malformed value is an InvalidData error, after which the stream can't be read
+from, since there's no telling where the next value starts. So is one with arrays nested
+deeper than MAX_NESTING
+*/
+pub fn read_value(reader: &mut dyn BufRead) -> io::Result<Option<RespValue>> {
+    read_nested_value(reader, 0)
+}
+
+fn read_nested_value(reader: &mut dyn BufRead, depth: usize) -> io::Result<Option<RespValue>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let (kind, rest) = match line.split_first() {
+        Some(split) => split,
+        None => return Err(protocol_error("empty line")),
+    };
+    let value = match kind {
+        b'+' => RespValue::Simple(String::from_utf8_lossy(rest).into_owned()),
+        b'-' => RespValue::Error(String::from_utf8_lossy(rest).into_owned()),
+        b':' => match std::str::from_utf8(rest).ok().and_then(|i| i.parse().ok()) {
+            Some(i) => RespValue::Integer(i),
+            None => return Err(protocol_error("invalid integer")),
+        },
+        b'$' => RespValue::Bulk(read_bulk(reader, rest)?),
+        b'*' if depth >= MAX_NESTING => return Err(protocol_error("arrays nested too deeply")),
+        b'*' => match read_length(rest, MAX_ARRAY_LEN)? {
+            Some(length) => {
+                let mut values = Vec::with_capacity(length.min(1024));
+                for _ in 0..length {
+                    match read_nested_value(reader, depth + 1)? {
+                        Some(value) => values.push(value),
+                        None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "stream ended part way through an array")),
+                    }
+                }
+                RespValue::Array(Some(values))
+            },
+            None => RespValue::Array(None),
+        },
+        _ => return Err(protocol_error(&format!("unexpected '{}'", *kind as char))),
+    };
+    Ok(Some(value))
+}
+
+/*
+Read Command: Reads the next command a client sent, as its name and arguments. Commands
+are flat arrays of bulk strings, each element failing as soon as it's anything else, or
+inline, a line of words as typed into telnet. A blank inline line is an empty command,
+to be ignored
+*/
+pub fn read_command(reader: &mut dyn BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    match reader.fill_buf()?.first() {
+        None => Ok(None),
+        Some(b'*') => {
+            let header = match read_line(reader)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            let length = match read_length(&header[1..], MAX_ARRAY_LEN)? {
+                Some(length) => length,
+                None => return Ok(Some(Vec::new())),
+            };
+            let mut args = Vec::with_capacity(length.min(1024));
+            for _ in 0..length {
+                let line = match read_line(reader)? {
+                    Some(line) => line,
+                    None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "stream ended part way through an array")),
+                };
+                match line.split_first() {
+                    Some((b'$', rest)) => match read_bulk(reader, rest)? {
+                        Some(bytes) => args.push(bytes),
+                        None => return Err(protocol_error("expected a bulk string")),
+                    },
+                    _ => return Err(protocol_error("expected a bulk string")),
+                }
+            }
+            Ok(Some(args))
+        },
+        Some(_) => Ok(read_line(reader)?.map(|line| {
+            line.split(u8::is_ascii_whitespace).filter(|word| !word.is_empty()).map(<[u8]>::to_vec).collect()
+        })),
+    }
+}
+
+/*
+Glob Match: Whether the text matches a Redis glob pattern, where * matches any bytes, ?
+any one byte, [abc], [a-z] and [^abc] one byte in or out of the set, and \ escapes the
+byte after it
+*/
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where matching resumes after the last '*' when what follows it fails to match, in
+    // the pattern and in the text, the '*' taking one more byte each time
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, t));
+                continue;
+            },
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(c) => (*c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            },
+            (None, Some((star_p, star_t))) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p;
+                t = star_t + 1;
+            },
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// Matches a byte against the class starting at the '[', giving where the pattern goes on after it
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<usize> {
+    let mut i = start + 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+        }
+        let low = pattern[i];
+        match pattern.get(i + 1..i + 3) {
+            Some([b'-', high]) if *high != b']' => {
+                matched |= (low.min(*high)..=low.max(*high)).contains(&byte);
+                i += 3;
+            },
+            _ => {
+                matched |= low == byte;
+                i += 1;
+            },
+        }
+    }
+    // An unclosed class runs to the end of the pattern
+    (matched != negated).then_some((i + 1).min(pattern.len()))
+}
+
+// Where SCAN cursors given out resume from, the last key each one looked at
+#[derive(Default)]
+struct Cursors {
+    next: u64,
+    last_keys: BTreeMap<u64, Vec<u8>>,
+}
+
+struct Shared {
+    lsm: Arc<Mutex<LsmTree>>,
+    cursors: Mutex<Cursors>,
+}
+
+/*
+RESP Server: Serves an LSM to Redis clients over TCP, speaking RESP2. Supports GET, SET
+with EX or PX for a TTL, DEL, EXISTS, MGET, MSET, SCAN, PING, ECHO and QUIT, along with
+an empty reply to COMMAND for clients that ask for it when they connect. Each connection
+has its own thread, and commands run one at a time against the shared LSM. Replies to
+pipelined commands are sent together once all of them have run
+*/
+pub struct RespServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl RespServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, lsm: Arc<Mutex<LsmTree>>) -> io::Result<RespServer> {
+        let shared = Shared{lsm, cursors: Mutex::new(Cursors{next: 1, ..Cursors::default()})};
+        Ok(RespServer{listener: TcpListener::bind(addr)?, shared: Arc::new(shared), shutdown: Arc::new(AtomicBool::new(false))})
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Accepts connections until shut down through a handle, see spawn
+    pub fn serve(self) -> io::Result<()> {
+        let shared = self.shared;
+        serve_connections(&self.listener, &self.shutdown, move |stream| handle_connection(stream, &shared))
+    }
+
+    // Serves on a background thread, until the handle is shut down
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let (addr, shutdown) = (self.local_addr()?, self.shutdown.clone());
+        Ok(ServerHandle::spawn(addr, shutdown, move || self.serve()))
+    }
+}
+
+fn handle_connection(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let (reply, quit) = match read_command(&mut reader) {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => (shared.execute(&args), args[0].eq_ignore_ascii_case(b"QUIT")),
+            Ok(None) => break,
+            // Replies with the error, then closes the connection as Redis does
+            Err(e) if e.kind() == ErrorKind::InvalidData => (RespValue::error(&e.to_string()), true),
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&reply.to_bytes())?;
+        if quit {
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+fn wrong_arity(name: &str) -> RespValue {
+    RespValue::error(&format!("wrong number of arguments for '{}' command", name))
+}
+
+fn parse_integer(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+impl Shared {
+    fn execute(&self, args: &[Vec<u8>]) -> RespValue {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let args = &args[1..];
+        // Fewest and most arguments each command takes, and whether they come in key value pairs
+        let (min_args, max_args, pairs) = match name.as_str() {
+            "command" | "quit" => (0, usize::MAX, false),
+            "ping" => (0, 1, false),
+            "get" | "echo" => (1, 1, false),
+            "del" | "exists" | "mget" | "scan" => (1, usize::MAX, false),
+            "set" => (2, usize::MAX, false),
+            "mset" => (2, usize::MAX, true),
+            _ => {
+                let first = args.first().map(|arg| String::from_utf8_lossy(arg).into_owned()).unwrap_or_default();
+                return RespValue::error(&format!("unknown command '{}', with args beginning with: '{}'", name, first));
+            },
+        };
+        if !(min_args..=max_args).contains(&args.len()) || (pairs && !args.len().is_multiple_of(2)) {
+            return wrong_arity(&name);
+        }
+
+        match name.as_str() {
+            "ping" => match args.first() {
+                None => RespValue::Simple("PONG".to_string()),
+                Some(message) => RespValue::bulk(message),
+            },
+            "echo" => RespValue::bulk(&args[0]),
+            "quit" => RespValue::ok(),
+            "command" => RespValue::Array(Some(Vec::new())),
+            "get" => RespValue::Bulk(self.lsm.lock().unwrap().get(&args[0])),
+            "set" => self.set(args),
+            "del" => {
+                let mut lsm = self.lsm.lock().unwrap();
+                let mut deleted = 0;
+                for key in args {
+                    if lsm.get(key).is_some() {
+                        if let Err(e) = lsm.delete(key) {
+                            return write_error(e);
+                        }
+                        deleted += 1;
+                    }
+                }
+                RespValue::Integer(deleted)
+            },
+            "exists" => {
+                let found = self.lsm.lock().unwrap().multi_get(args);
+                RespValue::Integer(found.iter().filter(|value| value.is_some()).count() as i64)
+            },
+            "mget" => {
+                let values = self.lsm.lock().unwrap().multi_get(args);
+                RespValue::Array(Some(values.into_iter().map(RespValue::Bulk).collect()))
+            },
+            "mset" => {
+                let mut batch = WriteBatch::new();
+                for pair in args.chunks(2) {
+                    batch.put(&pair[0], &pair[1]);
+                }
+                match self.lsm.lock().unwrap().write_batch(batch) {
+                    Ok(()) => RespValue::ok(),
+                    Err(e) => write_error(e),
+                }
+            },
+            _ => self.scan(args),
+        }
+    }
+
+    // SET key value [EX seconds | PX milliseconds]
+    fn set(&self, args: &[Vec<u8>]) -> RespValue {
+        let mut ttl = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let unit = match option.to_ascii_uppercase().as_slice() {
+                b"EX" if ttl.is_none() => Duration::from_secs,
+                b"PX" if ttl.is_none() => Duration::from_millis,
+                _ => return RespValue::error("syntax error"),
+            };
+            match options.next().map(|arg| parse_integer(arg)) {
+                Some(Some(time)) if time > 0 => ttl = Some(unit(time as u64)),
+                Some(Some(_)) => return RespValue::error("invalid expire time in 'set' command"),
+                Some(None) => return RespValue::error("value is not an integer or out of range"),
+                None => return RespValue::error("syntax error"),
+            }
+        }
+
+        let mut lsm = self.lsm.lock().unwrap();
+        let written = match ttl {
+            Some(ttl) => lsm.write_with_ttl(&args[0], &args[1], ttl),
+            None => lsm.write(&args[0], &args[1]),
+        };
+        match written {
+            Ok(()) => RespValue::ok(),
+            Err(e) => write_error(e),
+        }
+    }
+
+    /*
+    Scan: SCAN cursor [MATCH pattern] [COUNT count], looking at the next count keys after
+    the cursor and replying with the next cursor and the keys among them matching the
+    pattern, the cursor being 0 once every key has been looked at. A cursor resumes after
+    the last key it looked at, so every key present for the whole scan is returned
+    */
+    fn scan(&self, args: &[Vec<u8>]) -> RespValue {
+        let cursor = match parse_integer(&args[0]) {
+            Some(cursor) if cursor >= 0 => cursor as u64,
+            _ => return RespValue::error("invalid cursor"),
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_uppercase().as_slice(), options.next()) {
+                (b"MATCH", Some(arg)) => pattern = Some(arg),
+                (b"COUNT", Some(arg)) => match parse_integer(arg) {
+                    Some(n) if n > 0 => count = n as usize,
+                    Some(_) => return RespValue::error("syntax error"),
+                    None => return RespValue::error("value is not an integer or out of range"),
+                },
+                _ => return RespValue::error("syntax error"),
+            }
+        }
+
+        let after = match cursor {
+            0 => None,
+            cursor => match self.cursors.lock().unwrap().last_keys.get(&cursor) {
+                Some(key) => Some(key.clone()),
+                None => return RespValue::error("invalid cursor"),
+            },
+        };
+        let (looked_at, more) = {
+            let mut lsm = self.lsm.lock().unwrap();
+            // The scan picks up by seeking to the last key it looked at, which is skipped if still there
+            let mut keys: Box<dyn Iterator<Item = Vec<u8>>> = match &after {
+                Some(after) => Box::new(lsm.iter_from(after).map(|pair| pair.key.into_owned()).skip_while(move |key| key == after)),
+                None => Box::new(lsm.iter().map(|pair| pair.key.into_owned())),
+            };
+            let looked_at: Vec<Vec<u8>> = keys.by_ref().take(count).collect();
+            (looked_at, keys.next().is_some())
+        };
+
+        let next = match (more, looked_at.last()) {
+            (true, Some(last)) => {
+                let mut cursors = self.cursors.lock().unwrap();
+                let next = cursors.next;
+                cursors.next += 1;
+                cursors.last_keys.insert(next, last.clone());
+                if cursors.last_keys.len() > MAX_CURSORS {
+                    cursors.last_keys.pop_first();
+                }
+                next
+            },
+            _ => 0,
+        };
+        let keys = looked_at.into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(RespValue::bulk);
+        RespValue::Array(Some(vec![RespValue::bulk(next.to_string()), RespValue::Array(Some(keys.collect()))]))
+    }
+}
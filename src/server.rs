@@ -0,0 +1,66 @@
+use std::{io, net::{SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, Ordering::{Acquire, Release}}, Arc}, thread::{self, JoinHandle}, time::Duration};
+
+use crate::log;
+
+/*
+Serve Connections: Accepts connections on the listener until shut down, handling each on
+its own thread
+*/
+pub fn serve_connections<H>(listener: &TcpListener, shutdown: &AtomicBool, handler: H) -> io::Result<()>
+where H: Fn(TcpStream) -> io::Result<()> + Clone + Send + 'static {
+    for stream in listener.incoming() {
+        if shutdown.load(Acquire) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // e.g. the client reset the connection before it was accepted
+            Err(e) => {
+                log(&format!("failed to accept connection {}", e));
+                continue;
+            },
+        };
+        let handler = handler.clone();
+        thread::spawn(move || {
+            if let Err(e) = handler(stream) {
+                log(&format!("connection failed {}", e));
+            }
+        });
+    }
+    Ok(())
+}
+
+// A server serving on a background thread, which stops it when shut down
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    // Runs serve on a background thread, which must stop accepting once shutdown is set
+    pub fn spawn<F>(addr: SocketAddr, shutdown: Arc<AtomicBool>, serve: F) -> ServerHandle
+    where F: FnOnce() -> io::Result<()> + Send + 'static {
+        ServerHandle{addr, shutdown, thread: thread::spawn(serve)}
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Waits for the server to stop, which it only does once shut down
+    pub fn join(self) -> io::Result<()> {
+        self.thread.join().unwrap_or_else(|_| Err(io::Error::other("server thread panicked")))
+    }
+
+    /*
+    Shutdown: Stops accepting connections and waits for the server to stop. Connections
+    already open are served until their clients close them
+    */
+    pub fn shutdown(self) -> io::Result<()> {
+        self.shutdown.store(true, Release);
+        // Wakes the accept, which has no timeout
+        drop(TcpStream::connect_timeout(&self.addr, Duration::from_secs(1)));
+        self.join()
+    }
+}
@@ -0,0 +1,198 @@
+use std::{cmp::Ordering, io::Result, sync::Arc};
+
+use crate::storage::{coding::*, comparator::Comparator};
+
+// Every RESTART_INTERVAL entries the full key is written, rather than the suffix after the
+// prefix it shares with the previous key, so a seek can binary search these restart points
+pub const RESTART_INTERVAL: usize = 16;
+
+/*
+Block Builder: Builds a sorted block of entries, each laid out as
+
+    shared key len | unshared key len | value len | unshared key bytes | value bytes
+
+(lengths as varints), followed by the offset of each restart point and the count of them
+(fixed 32 bit). Keys must be added in sorted order.
+*/
+pub struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        BlockBuilder{buf: Vec::new(), restarts: vec![0], counter: 0, last_key: Vec::new()}
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut shared = 0;
+        if self.counter < RESTART_INTERVAL {
+            shared = self.last_key.iter().zip(key).take_while(|(a, b)| a == b).count();
+        }
+        else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+        }
+
+        put_varint32(&mut self.buf, shared as u32);
+        put_varint32(&mut self.buf, (key.len() - shared) as u32);
+        put_varint32(&mut self.buf, value.len() as u32);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    // Size of the block were it finished now
+    pub fn size_estimate(&self) -> usize {
+        self.buf.len() + self.restarts.len() * 4 + 4
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        for restart in &self.restarts {
+            put_fixed32(&mut self.buf, *restart);
+        }
+        put_fixed32(&mut self.buf, self.restarts.len() as u32);
+
+        let block = std::mem::take(&mut self.buf);
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+impl Default for BlockBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+Block: A block read back from disk, see BlockBuilder for the layout. The contents are
+shared, so a block can be handed out of the block cache without copying it
+*/
+#[derive(Clone)]
+pub struct Block {
+    data: Arc<Vec<u8>>,
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    pub fn new<D: Into<Arc<Vec<u8>>>>(data: D) -> Result<Block> {
+        let data = data.into();
+        if data.len() < 4 {
+            return Err(corruption("block too small for restart count"));
+        }
+        let num_restarts = decode_fixed32(&data[data.len() - 4..]) as usize;
+        let restarts_len = num_restarts.checked_mul(4).and_then(|len| len.checked_add(4));
+        match restarts_len {
+            Some(len) if len <= data.len() => Ok(Block{restarts_offset: data.len() - len, data, num_restarts}),
+            _ => Err(corruption("bad restart count in block")),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn iter(&self) -> BlockIter {
+        BlockIter{block: self.clone(), offset: 0, key: Vec::new()}
+    }
+
+    fn restart_point(&self, index: usize) -> usize {
+        decode_fixed32(&self.data[self.restarts_offset + index * 4..]) as usize
+    }
+}
+
+/*
+Block Iter: Walks the entries of a block in order, rebuilding each full key from the
+prefix it shares with the key before it. The iterator holds its own reference to the
+block contents, so it can outlive the Block it came from
+*/
+pub struct BlockIter {
+    block: Block,
+    offset: usize,
+    key: Vec<u8>,
+}
+
+impl BlockIter {
+    /*
+    Seek: Positions the iterator so the next entry returned is the first with a key >= target
+    in the comparator's order, which must be the order the block was written in, by binary
+    searching the restart points and then scanning forward from the closest one
+    */
+    pub fn seek(&mut self, target: &[u8], comparator: &dyn Comparator) -> Result<()> {
+        let (mut left, mut right) = (0, self.block.num_restarts.saturating_sub(1));
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            self.offset = self.block.restart_point(mid);
+            self.key.clear();
+            match self.next_entry()? {
+                Some((key, _)) if comparator.compare(&key, target) == Ordering::Less => left = mid,
+                _ => right = mid - 1,
+            }
+        }
+
+        self.offset = self.block.restart_point(left);
+        self.key.clear();
+        loop {
+            let offset = self.offset;
+            let key = self.key.clone();
+            match self.next_entry()? {
+                Some((k, _)) if comparator.compare(&k, target) == Ordering::Less => {},
+                _ => {
+                    // Rewind so the entry we stopped at is the next one returned
+                    self.offset = offset;
+                    self.key = key;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let data = &self.block.data[..self.block.restarts_offset];
+        if self.offset >= data.len() {
+            return Ok(None);
+        }
+
+        let mut pos = self.offset;
+        let (shared, n) = get_varint32(&data[pos..])?;
+        pos += n;
+        let (unshared, n) = get_varint32(&data[pos..])?;
+        pos += n;
+        let (value_len, n) = get_varint32(&data[pos..])?;
+        pos += n;
+
+        let (shared, unshared, value_len) = (shared as usize, unshared as usize, value_len as usize);
+        if shared > self.key.len() || pos + unshared + value_len > data.len() {
+            return Err(corruption("bad entry in block"));
+        }
+
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&data[pos..pos + unshared]);
+        pos += unshared;
+        let value = data[pos..pos + value_len].to_vec();
+        self.offset = pos + value_len;
+
+        Ok(Some((self.key.clone(), value)))
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
@@ -0,0 +1,106 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicU64, Ordering::*}, Arc, Mutex}};
+
+// Bookkeeping memory charged per cached block on top of its contents
+const ENTRY_OVERHEAD: usize = 64;
+
+// Blocks are keyed by the cache id handed to the segment they were read from, and their
+// offset within that segment
+pub type CacheKey = (u64, u64);
+
+struct CacheEntry {
+    block: Arc<Vec<u8>>,
+    charge: usize,
+    last_used: u64,
+}
+
+struct LruState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Entries ordered by when they were last used, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    usage: usize,
+}
+
+/*
+Block Cache: Holds recently read segment blocks, shared across every segment (and LSM) it
+is handed to, evicting the least recently used blocks to stay within its capacity. Blocks
+are handed out as Arcs, so an evicted block stays alive while a reader is still using it
+*/
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    next_id: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache{
+            capacity,
+            state: Mutex::new(LruState{entries: HashMap::new(), lru: BTreeMap::new(), clock: 0, usage: 0}),
+            next_id: AtomicU64::new(0)}
+    }
+
+    // Each segment takes a new id when opened, so its blocks never collide with another's
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn usage(&self) -> usize {
+        self.state.lock().unwrap().usage
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn lookup(&self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = state.clock;
+
+        let entry = state.entries.get_mut(key)?;
+        let last_used = entry.last_used;
+        entry.last_used = now;
+        let block = entry.block.clone();
+        state.lru.remove(&last_used);
+        state.lru.insert(now, *key);
+        Some(block)
+    }
+
+    /*
+    Insert: Adds the block as the most recently used, evicting the least recently used
+    blocks until the cache is back within capacity. A block bigger than the whole cache
+    is not cached at all
+    */
+    pub fn insert(&self, key: CacheKey, block: Arc<Vec<u8>>) {
+        let charge = block.len() + ENTRY_OVERHEAD;
+        if charge > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = state.clock;
+
+        if let Some(old) = state.entries.insert(key, CacheEntry{block, charge, last_used: now}) {
+            state.lru.remove(&old.last_used);
+            state.usage -= old.charge;
+        }
+        state.lru.insert(now, key);
+        state.usage += charge;
+
+        while state.usage > self.capacity {
+            let (_, oldest) = state.lru.pop_first().unwrap();
+            let evicted = state.entries.remove(&oldest).unwrap();
+            state.usage -= evicted.charge;
+        }
+    }
+}
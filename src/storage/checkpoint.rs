@@ -0,0 +1,137 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, ErrorKind}, path::{Path, PathBuf}};
+use crate::log;
+
+use crate::storage::{files::*, manifest::Manifest};
+
+// Written to the staging directory, holding the sequence number, once every file has been received
+// and then once the first step of installing the checkpoint is done. Neither is a valid column
+// family name, so they can't clash with a family's directory
+const COMMITTED_FILE: &str = "checkpoint.sequence";
+const INSTALLING_FILE: &str = "checkpoint.installing";
+
+/*
+Checkpoint: The segments of every column family of a DB as of its last flush, along with
+the sequence number of the last commit they hold and the DB's manifest. The commits after
+it are still in the WAL. Each segment file is held open, so its contents can still be read
+once the DB has gone on to compact or evict it
+*/
+pub struct Checkpoint {
+    pub sequence: u64,
+    pub manifest: Manifest,
+    pub files: Vec<CheckpointFile>,
+}
+
+pub struct CheckpointFile {
+    pub family: String,
+    // Name of the file within the family's directory
+    pub name: String,
+    pub len: u64,
+    pub file: File,
+}
+
+/*
+Staged Checkpoint: A checkpoint of another DB being received into the DB's checkpoint
+directory, one file per segment in a directory per column family. It only replaces the
+DB's segments once committed, see install_checkpoint, and a checkpoint that was never
+committed is thrown away the next time one is staged or the DB is opened
+*/
+pub struct StagedCheckpoint {
+    dir: PathBuf,
+    sequence: u64,
+    families: Vec<String>,
+}
+
+impl StagedCheckpoint {
+    // Starts staging the checkpoint at the sequence number, for the column families named
+    pub fn create(name: &str, sequence: u64, families: Vec<String>) -> io::Result<StagedCheckpoint> {
+        let dir = get_checkpoint_dir(name);
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir(&dir)?;
+        Ok(StagedCheckpoint{dir, sequence, families})
+    }
+
+    // Creates the segment file for the family, failing for families not in the checkpoint and names that aren't segments'
+    pub fn create_file(&self, family: &str, name: &str) -> io::Result<File> {
+        if !self.families.iter().any(|staged| staged == family) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("column family {} isn't in the checkpoint", family)));
+        }
+        let path = Path::new(name);
+        if path.file_name().is_none_or(|file_name| file_name != name) || path.extension().is_none_or(|ext| ext != DATA_EXT) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("{} isn't the name of a segment", name)));
+        }
+        let dir = self.dir.join(family);
+        if !dir.is_dir() {
+            fs::create_dir(&dir)?;
+        }
+        OpenOptions::new().create_new(true).write(true).open(dir.join(name))
+    }
+
+    // Marks every file as received and synced, after which installing the checkpoint can't be undone
+    pub fn commit(&self) -> io::Result<()> {
+        write_sequence(&self.dir.join(COMMITTED_FILE), self.sequence)
+    }
+}
+
+/*
+Install Checkpoint: Replaces the DB's segments with those of the checkpoint committed to
+its checkpoint directory, for each column family given by name and directory, returning
+the checkpoint's sequence number. Every segment the DB had is deleted and its WAL and WAL
+archive emptied, as the checkpoint holds all their commits, then the staged segments are
+moved in and the sequence number persisted. Each step can be done again, so one a crash
+interrupted is finished when the DB is next opened. Returns None when there is no
+committed checkpoint, deleting any partly received one
+*/
+pub fn install_checkpoint(name: &str, families: &[(String, String)]) -> io::Result<Option<u64>> {
+    let staged = get_checkpoint_dir(name);
+    if !staged.is_dir() {
+        return Ok(None);
+    }
+    if !staged.join(COMMITTED_FILE).is_file() {
+        log(&format!("discarding checkpoint for db {} that was never committed", name));
+        fs::remove_dir_all(&staged)?;
+        return Ok(None);
+    }
+    let sequence = read_sequence(&staged.join(COMMITTED_FILE))?;
+
+    let installing = staged.join(INSTALLING_FILE);
+    if !installing.is_file() {
+        for (_, dir) in families {
+            for item in fs::read_dir(get_lsmdir(dir))? {
+                let path = item?.path();
+                if path.is_file() && path.extension().is_some_and(|ext| ext == DATA_EXT) {
+                    fs::remove_file(path)?;
+                }
+            }
+            // Synced, as the deletes aren't redone once the installing file is written
+            sync_dir(dir)?;
+        }
+        match OpenOptions::new().write(true).open(get_wal_path(name)) {
+            Ok(wal) => wal.set_len(0)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        if get_wal_archive_dir(name).is_dir() {
+            fs::remove_dir_all(get_wal_archive_dir(name))?;
+        }
+        File::create(&installing)?.sync_all()?;
+    }
+
+    for (family, dir) in families {
+        let from = staged.join(family);
+        if from.is_dir() {
+            for item in fs::read_dir(from)? {
+                let item = item?;
+                fs::rename(item.path(), get_lsmdir(dir).join(item.file_name()))?;
+            }
+        }
+        // The renames are durable before the staged copy they'd be finished from is removed
+        sync_dir(dir)?;
+    }
+    write_sequence(&get_sequence_path(name), sequence)?;
+    fs::remove_dir_all(&staged)?;
+    log(&format!("installed checkpoint at sequence number {} for db {}", sequence, name));
+    Ok(Some(sequence))
+}
+
@@ -0,0 +1,46 @@
+use std::{sync::atomic::{AtomicU64, Ordering::*}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/*
+Clock: Source of the current time for an LSM, in milliseconds since the unix epoch, which
+is what entries written with a TTL store their expiry as. Injectable so that expiry can be
+tested without waiting on the wall clock
+*/
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        // A clock set before the epoch is as good as the epoch
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+    }
+}
+
+/*
+Manual Clock: A clock that only moves when told to
+*/
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now_millis: u64) -> ManualClock {
+        ManualClock{now: AtomicU64::new(now_millis)}
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.fetch_add(by.as_millis() as u64, AcqRel);
+    }
+
+    pub fn set(&self, now_millis: u64) {
+        self.now.store(now_millis, Release);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> u64 {
+        self.now.load(Acquire)
+    }
+}
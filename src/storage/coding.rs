@@ -0,0 +1,132 @@
+use std::io::{Error, ErrorKind, Result};
+
+/*
+Coding: Little-endian fixed width and LEB128 varint encodings shared by the on-disk formats
+*/
+
+pub fn put_fixed32(dst: &mut Vec<u8>, v: u32) {
+    dst.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_fixed64(dst: &mut Vec<u8>, v: u64) {
+    dst.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn decode_fixed32(src: &[u8]) -> u32 {
+    u32::from_le_bytes(src[..4].try_into().unwrap())
+}
+
+pub fn decode_fixed64(src: &[u8]) -> u64 {
+    u64::from_le_bytes(src[..8].try_into().unwrap())
+}
+
+pub fn put_varint64(dst: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        dst.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    dst.push(v as u8);
+}
+
+pub fn put_varint32(dst: &mut Vec<u8>, v: u32) {
+    put_varint64(dst, v as u64)
+}
+
+// Writes the slice length ahead of its bytes, so it can be read back without a delimiter
+pub fn put_length_prefixed(dst: &mut Vec<u8>, src: &[u8]) {
+    put_varint64(dst, src.len() as u64);
+    dst.extend_from_slice(src);
+}
+
+/*
+Get Varint: Decodes a varint from the front of the slice, returning the value and the
+number of bytes it took up
+*/
+pub fn get_varint64(src: &[u8]) -> Result<(u64, usize)> {
+    let mut v: u64 = 0;
+    for (i, b) in src.iter().enumerate().take(10) {
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((v, i + 1));
+        }
+    }
+    Err(corruption("truncated or overlong varint"))
+}
+
+pub fn get_varint32(src: &[u8]) -> Result<(u32, usize)> {
+    let (v, n) = get_varint64(src)?;
+    match u32::try_from(v) {
+        Ok(v) => Ok((v, n)),
+        Err(_) => Err(corruption("varint overflows 32 bits")),
+    }
+}
+
+/*
+Get Length Prefixed: Decodes a slice written by put_length_prefixed, returning it and the
+total number of bytes consumed
+*/
+pub fn get_length_prefixed(src: &[u8]) -> Result<(&[u8], usize)> {
+    let (len, n) = get_varint64(src)?;
+    match n.checked_add(len as usize) {
+        Some(end) if end <= src.len() => Ok((&src[n..end], end)),
+        _ => Err(corruption("length prefixed slice runs past end of input")),
+    }
+}
+
+pub fn corruption(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+// CRC-32 (IEEE) lookup table, built at compile time
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc = CRC_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// A framed record starts with the checksum and the length of its payload
+pub const FRAME_HEADER_SIZE: usize = 8;
+
+/*
+Frame: Prefixes the payload with its crc32 and length (fixed 32 bit), so a reader can tell
+a record that was cut short or damaged from a good one. WAL records and segment blocks are
+both framed this way
+*/
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    put_fixed32(&mut record, crc32(payload));
+    put_fixed32(&mut record, payload.len() as u32);
+    record.extend_from_slice(payload);
+    record
+}
+
+// Reads the framed record at the start of src, returning its payload and framed length, or None if it is cut short or fails its checksum
+pub fn read_frame(src: &[u8]) -> Option<(&[u8], usize)> {
+    if src.len() < FRAME_HEADER_SIZE {
+        return None;
+    }
+    let crc = decode_fixed32(src);
+    let end = FRAME_HEADER_SIZE.checked_add(decode_fixed32(&src[4..]) as usize)?;
+    if end > src.len() || crc32(&src[FRAME_HEADER_SIZE..end]) != crc {
+        return None;
+    }
+    Some((&src[FRAME_HEADER_SIZE..end], end))
+}
@@ -0,0 +1,541 @@
+use std::{fs::{metadata, remove_file}, io, mem::take, sync::Arc, time::Duration};
+use crate::{kvpair::KVPair, storage::tree::{TriOption::*, *}, log};
+
+use crate::storage::{diskseg::{extract_seg_id, DiskSegment::{self, *}}, files::*, merge::MergingIter, merge_operator::{fold_operands, MergeOperator}, options::LsmOptions, segment::{Entry, SegmentBuilder, SegmentProperties, SegmentReader}, stats::{Statistics, Ticker}, wal::WalRecord};
+
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/*
+Column Family: One keyspace of an LSM, with its own memtable, segments and options, so
+its own comparator, compaction and compression settings, merge operator and so on. The
+families of an LSM share its WAL, which is what makes a write batch across them atomic,
+so writes reach a family through the LSM, and the LSM flushes every family's memtable
+together, since the WAL can't be truncated while any of them still needs it. The default
+family keeps its segments in the DB's directory, the others in subdirectories of it
+*/
+pub struct ColumnFamily {
+    name: String,
+    // Directory the segments are in, relative to the cwd the same as a DB name
+    dir: String,
+    tree: LogSegment<Vec<u8>>,
+    options: LsmOptions,
+    log_segments: Vec<DiskSegment>,
+    next_segment_id: usize,
+    stats: Arc<Statistics>,
+}
+
+impl ColumnFamily {
+    pub fn open(name: &str, dir: String, options: LsmOptions, stats: Arc<Statistics>) -> io::Result<ColumnFamily> {
+        let log_segments = reclaim_segments(&dir)?;
+        // Segment numbers are never reused, compaction deletes segments from the middle
+        let next_segment_id = match log_segments.first() {
+            Some(newest) => extract_seg_id(newest.value().to_string()) as usize + 1,
+            None => 0,
+        };
+        Ok(ColumnFamily{
+            name: name.to_string(),
+            dir,
+            tree: LogSegment::with_comparator(options.comparator.clone()),
+            options,
+            log_segments,
+            next_segment_id,
+            stats})
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn options(&self) -> &LsmOptions {
+        &self.options
+    }
+
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    // Whether the segments on disk are other than the family's, because another LsmTree has the DB open for writing
+    pub fn segments_changed(&self) -> io::Result<bool> {
+        let segments = reclaim_segments(&self.dir)?;
+        Ok(segments.len() != self.log_segments.len() || segments.iter().zip(&self.log_segments).any(|(segment, ours)| segment.value() != ours.value()))
+    }
+
+    // Paths of the disk segments, newest first
+    pub fn segment_paths(&self) -> Vec<&str> {
+        self.log_segments.iter().map(|segment| segment.value()).collect()
+    }
+
+    /*
+    Apply: Applies a logged record to the memtable. A merge is folded into a value or
+    tombstone the memtable holds for the key, as merge_record does when writing it, since
+    the writer may have flushed that value before logging the merge while a secondary
+    applying its commits hasn't
+    */
+    pub fn apply(&self, record: WalRecord) {
+        match record {
+            WalRecord::Put{key, value, expires_at: None} => self.track_memory(|tree| tree.insert((key, value))),
+            WalRecord::Put{key, value, expires_at: Some(expires_at)} => self.track_memory(|tree| tree.insert_expiring((key, value), expires_at)),
+            WalRecord::Delete{key} => self.track_memory(|tree| tree.delete(key)),
+            WalRecord::Merge{key, operand} => match self.merge_record(&key, &operand, None) {
+                Some(put @ WalRecord::Put{..}) => self.apply(put),
+                _ => self.track_memory(|tree| tree.merge(key, operand)),
+            },
+        }
+    }
+
+    // Expiry of a value written now with the ttl, going by the family's clock. A ttl too long to count in millis never expires
+    pub fn expires_at(&self, ttl: Duration) -> u64 {
+        self.options.clock.now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+    }
+
+    /*
+    Merge Record: The record to log for a merge of the operand into the key, None if the
+    family has no merge operator. The operand is logged and kept as it is until a read or
+    compaction folds it over the value it applies to. When the key's value is already in
+    the memtable, or written earlier in the same batch, there's no read to save, so the
+    operand is folded in right away and logged as a put instead, which keeps the value's
+    TTL, if it has one. Folding it in later would leave replaying the WAL to decide
+    whether the value had expired by then
+    */
+    pub fn merge_record(&self, key: &[u8], operand: &[u8], earlier: Option<&WalRecord>) -> Option<WalRecord> {
+        let operator = self.options.merge_operator.as_ref()?;
+        let now = self.options.clock.now_millis();
+        let operands = [operand.to_vec()];
+        let (base, expires_at) = match earlier {
+            Some(WalRecord::Put{value, expires_at, ..}) => (TriSome(value), *expires_at),
+            Some(WalRecord::Delete{..}) => (Tombstoned, None),
+            Some(WalRecord::Merge{..}) => (TriNone, None),
+            None => match self.tree.get_entry(&key.to_vec()) {
+                Some(entry) => (entry.value(), entry.expires_at),
+                None => (TriNone, None),
+            },
+        };
+
+        let record = match base {
+            TriSome(base) if expires_at.is_none_or(|expires_at| expires_at > now) => {
+                WalRecord::Put{key: key.to_vec(), value: operator.full_merge(key, Some(base), &operands), expires_at}
+            },
+            TriSome(_) | Tombstoned => {
+                WalRecord::Put{key: key.to_vec(), value: operator.full_merge(key, None, &operands), expires_at: None}
+            },
+            TriMerge(_) | TriNone => WalRecord::Merge{key: key.to_vec(), operand: operand.to_vec()},
+        };
+        Some(record)
+    }
+
+    /*
+    Flush: Writes the memtable out to a new disk segment and starts an empty one. Truncating
+    the WAL is left to the LSM, once every family sharing it has been flushed
+    */
+    pub fn flush(&mut self) {
+        let seg_id = self.next_segment_id;
+        self.next_segment_id += 1;
+        let segment_buf = match get_segment(&self.dir, seg_id, true) {
+            Ok(file) => file,
+            Err(e) => panic!("failed to create segment {} for column family {} with error {}", seg_id, self.name, e),
+        };
+        let mut builder = SegmentBuilder::new(segment_buf, &self.options, 0);
+        for (k, v) in self.tree.iter() {
+            if let Err(e) = builder.add_expiring(k, v.value().map(|v| v.as_slice()), v.expires_at) {
+                panic!("failed to write segment {} for column family {} with error {}", seg_id, self.name, e);
+            }
+        }
+        if let Err(e) = builder.finish() {
+            panic!("failed to finish segment {} for column family {} with error {}", seg_id, self.name, e);
+        }
+
+        if let Some(manager) = &self.options.write_buffer_manager {
+            manager.free(self.tree.approximate_bytes());
+        }
+        let new_seg = match get_segment(&self.dir, seg_id, false) {
+            Ok(file) => ClosedSegment{path_s: get_seg_path_s(&self.dir, seg_id), file},
+            Err(e) => panic!("failed to open segment {} for column family {} with error {}", seg_id, self.name, e),
+        };
+        // Place log segments in order by name
+        if let Err(pos) = self.log_segments.binary_search(&new_seg) {
+            self.log_segments.insert(pos, new_seg);
+        }
+        self.tree = LogSegment::with_comparator(self.options.comparator.clone());
+        self.evict_to_capacity();
+    }
+
+    /*
+    Evict To Capacity: When the family has a maximum size, deletes the oldest segments until
+    the segments left fit within it, so entries are evicted roughly first in, first out.
+    Deleting the oldest segment can't bring back an older value for any key, there are
+    none. The newest segment is always kept, however large, or a single flush bigger than
+    the limit would throw away the writes it just persisted
+    */
+    fn evict_to_capacity(&mut self) {
+        let max_total_size = match self.options.max_total_size {
+            Some(max_total_size) => max_total_size,
+            None => return,
+        };
+
+        let mut total_size = self.total_size();
+        while total_size > max_total_size && self.log_segments.len() > 1 {
+            let oldest = self.log_segments.pop().unwrap();
+            let size = segment_size(&oldest);
+            if let Err(e) = remove_file(oldest.value()) {
+                panic!("unable to delete evicted segment {} with error {}", oldest.value(), e);
+            }
+            log(&format!("Evicted segment {} of {} bytes from column family {}", oldest.value(), size, self.name));
+            self.stats.record(Ticker::SegmentsEvicted, 1);
+            self.stats.record(Ticker::BytesEvicted, size);
+            total_size -= size;
+        }
+    }
+
+    // Total bytes of the disk segments, the WAL and memtable are not counted
+    pub fn total_size(&self) -> u64 {
+        self.log_segments.iter().map(segment_size).sum()
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.tree.size()
+    }
+
+    pub fn total_segments(&self) -> usize {
+        self.log_segments.len()
+    }
+
+    /*
+    Compact: Merges every disk segment, and the memtable, which the LSM flushes first, into
+    one new level 1 segment holding just the newest entry for each key. Nothing older is
+    left for a tombstone to shadow, so tombstones are dropped rather than carried over, and
+    so are values that have expired. The new segment, and the directory entry for it, are
+    synced before the segments it replaces are deleted, oldest first, so a crash part way
+    through only leaves the newest of them, whose tombstones still shadow any older value
+    left, and no deleted key comes back
+    */
+    pub fn compact(&mut self) -> io::Result<()> {
+        if self.log_segments.is_empty() {
+            return Ok(());
+        }
+
+        let seg_id = self.next_segment_id;
+        self.next_segment_id += 1;
+        let properties = match self.write_compacted(seg_id) {
+            Ok(properties) => properties,
+            Err(e) => {
+                // A partly written segment would be read in as one on reopening
+                let _ = remove_file(get_seg_path(&self.dir, seg_id));
+                return Err(e);
+            },
+        };
+        sync_dir(&self.dir)?;
+
+        // Segments are newest first, those not yet deleted stay readable if deleting one fails
+        while let Some(oldest) = self.log_segments.last() {
+            remove_file(oldest.value())?;
+            self.log_segments.pop();
+        }
+        let path_s = get_seg_path_s(&self.dir, seg_id);
+        if properties.num_entries == 0 {
+            // Everything was deleted, no need to keep an empty segment around
+            remove_file(&path_s)?;
+        }
+        else {
+            self.log_segments.push(ClosedSegment{path_s, file: get_segment(&self.dir, seg_id, false)?});
+        }
+        sync_dir(&self.dir)
+    }
+
+    // Writes the newest live entry for each key to a new level 1 segment
+    fn write_compacted(&mut self, seg_id: usize) -> io::Result<SegmentProperties> {
+        let mut builder = SegmentBuilder::new(get_segment(&self.dir, seg_id, true)?, &self.options, 1);
+        let now = self.options.clock.now_millis();
+        for entry in self.merged_results(None) {
+            let entry = entry?;
+            if let TriSome(value) = &entry.value {
+                if !entry.is_expired(now) {
+                    builder.add_expiring(&entry.key, TriSome(value), entry.expires_at)?;
+                }
+            }
+        }
+        builder.finish()
+    }
+
+    /*
+    Iter: Iterates the live keys in comparator order, each with its newest value, merging
+    the memtable with every disk segment. Deleted and expired keys are skipped
+    */
+    pub fn iter(&mut self) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.entries().filter(|pair| !pair.tombstone)
+    }
+
+    // Same as iter, starting from the first key >= the given one, which the memtable and each
+    // segment seek to rather than reading the keys before it
+    pub fn iter_from(&mut self, key: &[u8]) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.live_entries(Some(key.to_vec())).filter(|pair| !pair.tombstone)
+    }
+
+    /*
+    Entries: Same as iter, except deleted keys whose tombstones are still in the memtable or
+    a segment are included as tombstone pairs, e.g. for exporting the family as it is stored.
+    Expired values are tombstones too, they shadow older values until compacted away
+    */
+    pub fn entries(&mut self) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.live_entries(None)
+    }
+
+    // Entries from the given key on, with expired values as tombstones
+    fn live_entries(&mut self, from: Option<Vec<u8>>) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        let now = self.options.clock.now_millis();
+        self.merged_entries(from).map(move |entry| {
+            let key = entry.key.clone();
+            match entry.live_value(now) {
+                TriSome(value) => KVPair::new(key, value),
+                _ => KVPair::tombstone(key),
+            }
+        })
+    }
+
+    // Same as merged_results, for reads, which have no way to fail
+    fn merged_entries(&mut self, from: Option<Vec<u8>>) -> impl Iterator<Item = Entry> + '_ {
+        self.merged_results(from).map(|entry| match entry {
+            Ok(entry) => entry,
+            Err(e) => panic!("unable to read segment with error {}", e),
+        })
+    }
+
+    /*
+    Merged Results: Newest entry for each key across the memtable and every disk segment, as
+    stored, from the given key on, or the error reading a segment. Every source seeks to the
+    same key, so merge operands still have every older entry for their key to fold over
+    */
+    fn merged_results(&mut self, from: Option<Vec<u8>>) -> impl Iterator<Item = io::Result<Entry>> + '_ {
+        for segment in &mut self.log_segments {
+            get_table_from_segment(segment, &self.options, &self.stats);
+        }
+
+        // The memtable is newer than any segment, which are already newest first
+        let memtable: Box<dyn Iterator<Item = _>> = match &from {
+            Some(key) => Box::new(self.tree.iter_from(key)),
+            None => Box::new(self.tree.iter()),
+        };
+        let memtable = memtable.map(|(k, v)| Ok(Entry::new(k.clone(), v.value().map(|v| v.clone()), v.expires_at)));
+        let mut sources = vec![Box::new(memtable) as Box<dyn Iterator<Item = _>>];
+        sources.extend(self.log_segments.iter().map(|segment| {
+            let table = segment.table().unwrap();
+            let iter = match &from {
+                Some(key) => table.iter_from(key),
+                None => table.iter(),
+            };
+            match iter {
+                Ok(iter) => Box::new(iter) as Box<dyn Iterator<Item = _>>,
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        }));
+        let now = self.options.clock.now_millis();
+        MergingIter::new(sources, self.options.comparator.clone()).with_merge_operator(self.options.merge_operator.clone(), now)
+    }
+
+    pub fn memtable_bytes(&self) -> usize {
+        self.tree.approximate_bytes()
+    }
+
+    /*
+    Memtable Full: The in-memory segment is due to be flushed once it reaches its own
+    write buffer size, or when memtables sharing this family's write buffer manager are
+    over the shared limit between them
+    */
+    pub fn memtable_full(&self) -> bool {
+        if self.memtable_bytes() >= self.options.write_buffer_size {
+            return true;
+        }
+        match &self.options.write_buffer_manager {
+            Some(manager) => manager.should_flush() && self.num_entries() > 0,
+            None => false,
+        }
+    }
+
+    // Applies a mutation to the in-memory segment, reserving whatever it grew by
+    // against the shared write buffer manager
+    fn track_memory<F: FnOnce(&LogSegment<Vec<u8>>)>(&self, mutate: F) {
+        let before = self.tree.approximate_bytes();
+        mutate(&self.tree);
+        if let Some(manager) = &self.options.write_buffer_manager {
+            manager.reserve(self.tree.approximate_bytes() - before);
+        }
+    }
+
+    /*
+    Get: Queries the family for the value for the given key, will traverse log segments in
+    newest to oldest fashion to preverse append-only deletion semantics
+    */
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        // If the key is not already in memory, traverse prior log
+        // segments in newest-to-oldest order until we get a result
+        match self.tree.get_at(key.to_vec(), self.options.clock.now_millis()) {
+            TriSome(result) => Some(result.clone()),
+            Tombstoned => None,
+            found @ (TriMerge(_) | TriNone) => {
+                // Merge operands newer than whatever we find the key's value to be, oldest first
+                let mut operands: Vec<Vec<u8>> = match found {
+                    TriMerge(newer) => newer.into_iter().cloned().collect(),
+                    _ => Vec::new(),
+                };
+                let operator = self.options.merge_operator.clone();
+                for segment in &mut self.log_segments {
+                    log(&format!("Checking for {} in segment {}", String::from_utf8_lossy(key), extract_seg_id(segment.value().to_string())));
+                    let table = get_table_from_segment(segment, &self.options, &self.stats);
+
+                    if table.has_filter() {
+                        self.stats.record(Ticker::BloomFilterChecked, 1);
+                        if !table.key_may_match(key) {
+                            self.stats.record(Ticker::BloomFilterUseful, 1);
+                            continue;
+                        }
+                    }
+
+                    match table.get(key) {
+                        Ok(TriSome(result)) => return resolve_operands(operator.as_ref(), key, Some(result), operands),
+                        Ok(Tombstoned) => return resolve_operands(operator.as_ref(), key, None, operands),
+                        Ok(TriMerge(mut older)) => {
+                            older.append(&mut operands);
+                            operands = older;
+                        },
+                        Ok(TriNone) => {},
+                        Err(e) => panic!("unable to read segment {} with error {}", segment.value(), e),
+                    }
+                }
+                resolve_operands(operator.as_ref(), key, None, operands)
+            }
+        }
+    }
+
+    /*
+    Multi Get: Looks up many keys at once, returning the value for each in the order the
+    keys were given. The keys are sorted so that each segment's index is walked once for
+    all of them, rather than searched once per key, and only the keys not yet found that
+    get past a segment's bloom filter are looked for in it
+    */
+    pub fn multi_get<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        let mut results: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+        // Indexes of the keys not yet found in the memtable or a newer segment, in key order
+        let mut pending: Vec<usize> = Vec::new();
+        // Merge operands found so far for each key, oldest first
+        let mut operands: Vec<Vec<Vec<u8>>> = vec![Vec::new(); keys.len()];
+        let now = self.options.clock.now_millis();
+        for (i, key) in keys.iter().enumerate() {
+            match self.tree.get_at(key.as_ref().to_vec(), now) {
+                TriSome(value) => results[i] = Some(value.clone()),
+                Tombstoned => {},
+                TriMerge(newer) => {
+                    operands[i] = newer.into_iter().cloned().collect();
+                    pending.push(i);
+                },
+                TriNone => pending.push(i),
+            }
+        }
+        let operator = self.options.merge_operator.clone();
+        let comparator = self.options.comparator.clone();
+        pending.sort_by(|a, b| comparator.compare(keys[*a].as_ref(), keys[*b].as_ref()));
+        let mut resolved = vec![false; keys.len()];
+
+        for segment in &mut self.log_segments {
+            if pending.is_empty() {
+                break;
+            }
+            let table = get_table_from_segment(segment, &self.options, &self.stats);
+
+            let candidates: Vec<usize> = match table.has_filter() {
+                true => {
+                    let pending_keys: Vec<&[u8]> = pending.iter().map(|i| keys[*i].as_ref()).collect();
+                    let may_match = table.keys_may_match(&pending_keys);
+                    let candidates: Vec<usize> = pending.iter().zip(may_match).filter(|(_, may_match)| *may_match).map(|(i, _)| *i).collect();
+                    self.stats.record(Ticker::BloomFilterChecked, pending.len() as u64);
+                    self.stats.record(Ticker::BloomFilterUseful, (pending.len() - candidates.len()) as u64);
+                    candidates
+                },
+                false => pending.clone(),
+            };
+
+            let candidate_keys: Vec<&[u8]> = candidates.iter().map(|i| keys[*i].as_ref()).collect();
+            let found = match table.multi_get(&candidate_keys) {
+                Ok(found) => found,
+                Err(e) => panic!("unable to read segment {} with error {}", segment.value(), e),
+            };
+            for (i, value) in candidates.into_iter().zip(found) {
+                match value {
+                    TriSome(value) => {
+                        results[i] = resolve_operands(operator.as_ref(), keys[i].as_ref(), Some(value), take(&mut operands[i]));
+                        resolved[i] = true;
+                    },
+                    Tombstoned => {
+                        results[i] = resolve_operands(operator.as_ref(), keys[i].as_ref(), None, take(&mut operands[i]));
+                        resolved[i] = true;
+                    },
+                    TriMerge(mut older) => {
+                        older.append(&mut operands[i]);
+                        operands[i] = older;
+                    },
+                    TriNone => {},
+                }
+            }
+            pending.retain(|i| !resolved[*i]);
+        }
+
+        // Operands for keys with no value anywhere apply to nothing
+        for i in pending {
+            results[i] = resolve_operands(operator.as_ref(), keys[i].as_ref(), None, take(&mut operands[i]));
+        }
+        results
+    }
+}
+
+impl Drop for ColumnFamily {
+    // Memtable memory is released back to the shared manager along with the family
+    fn drop(&mut self) {
+        if let Some(manager) = &self.options.write_buffer_manager {
+            manager.free(self.tree.approximate_bytes());
+        }
+    }
+}
+
+/*
+Valid Column Family Name: Names end up as directory names and in the manifest, so they
+are kept to letters, digits, '_' and '-'. The default family can't be created again
+*/
+pub fn valid_column_family_name(name: &str) -> bool {
+    !name.is_empty() && name != DEFAULT_COLUMN_FAMILY && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn segment_size(segment: &DiskSegment) -> u64 {
+    match metadata(segment.value()) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => panic!("unable to read size of segment {} with error {}", segment.value(), e),
+    }
+}
+
+// Folds any merge operands over the base value, a base with no operands is the value as is
+fn resolve_operands(operator: Option<&Arc<dyn MergeOperator>>, key: &[u8], base: Option<Vec<u8>>, operands: Vec<Vec<u8>>) -> Option<Vec<u8>> {
+    if operands.is_empty() {
+        return base;
+    }
+    match fold_operands(operator, key, base.as_deref(), &operands) {
+        Ok(value) => Some(value),
+        Err(e) => panic!("unable to read {} with error {}", String::from_utf8_lossy(key), e),
+    }
+}
+
+/*
+Get Table From Segment: Segments are opened lazily, the first time a lookup misses every
+newer segment, which reads in the segment's footer and meta block, and pins its index
+and filter blocks unless those are to go through the block cache
+*/
+fn get_table_from_segment<'a>(segment: &'a mut DiskSegment, options: &LsmOptions, stats: &Arc<Statistics>) -> &'a SegmentReader {
+    if let ClosedSegment{path_s, file} = segment {
+        let table = match file.try_clone().and_then(|file| SegmentReader::open(file, options, stats.clone())) {
+            Ok(table) => table,
+            Err(e) => panic!("unable to open segment {} with error {}", path_s, e),
+        };
+        *segment = OpenSegment{path_s: path_s.to_string(), table: Box::new(table)};
+    }
+
+    match segment {
+        OpenSegment{path_s: _, table} => table,
+        ClosedSegment{..} => panic!("Failed to open disk segment"),
+    }
+}
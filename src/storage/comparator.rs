@@ -0,0 +1,95 @@
+use std::{cmp::Ordering, sync::Arc};
+
+/*
+Comparator: The order keys are kept in, by the memtable, within and across segments, and
+by merges. Chosen when a DB is created and persisted in its manifest by name, since
+segments on disk are only searchable in the order they were written, so the name should
+change whenever the order does. Bloom filters, the WAL and the block cache all work on
+the raw key bytes, so compare must only return Equal for identical keys.
+*/
+pub trait Comparator: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    // Whether the keys starting with a prefix sort together, from the prefix on, so a prefix
+    // scan can seek to the prefix and stop at the first key without it
+    fn groups_prefixes(&self) -> bool {
+        false
+    }
+}
+
+// Lexicographic byte order, the default
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn groups_prefixes(&self) -> bool {
+        true
+    }
+}
+
+// Lexicographic byte order, largest key first
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "reverse_bytewise"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/*
+Big Endian Numeric Comparator: Orders keys as unsigned big-endian integers of any width,
+so [1, 0] sorts after [255]. Keys that are the same number with different amounts of
+zero padding are still distinct keys, the shorter one sorting first
+*/
+pub struct BigEndianNumericComparator;
+
+impl Comparator for BigEndianNumericComparator {
+    fn name(&self) -> &str {
+        "big_endian_numeric"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let strip = |key: &[u8]| -> usize { key.iter().take_while(|b| **b == 0).count() };
+        let (a_digits, b_digits) = (&a[strip(a)..], &b[strip(b)..]);
+        a_digits.len().cmp(&b_digits.len())
+            .then_with(|| a_digits.cmp(b_digits))
+            .then_with(|| a.len().cmp(&b.len()))
+    }
+}
+
+/*
+Case Insensitive Comparator: Orders keys ignoring ASCII case. Keys that only differ in
+case are still distinct keys, ordered bytewise among themselves, so "Foo" sorts just
+before "foo" and both sort before "goo"
+*/
+pub struct CaseInsensitiveComparator;
+
+impl Comparator for CaseInsensitiveComparator {
+    fn name(&self) -> &str {
+        "case_insensitive"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.iter().map(u8::to_ascii_lowercase).cmp(b.iter().map(u8::to_ascii_lowercase))
+            .then_with(|| a.cmp(b))
+    }
+}
+
+// The comparator built in with this name, for opening a DB by the name in its manifest
+pub fn builtin_comparator(name: &str) -> Option<Arc<dyn Comparator>> {
+    let comparators: [Arc<dyn Comparator>; 4] = [Arc::new(BytewiseComparator), Arc::new(ReverseBytewiseComparator), Arc::new(BigEndianNumericComparator), Arc::new(CaseInsensitiveComparator)];
+    comparators.into_iter().find(|comparator| comparator.name() == name)
+}
@@ -0,0 +1,185 @@
+use std::{io::Result, sync::Arc};
+
+use crate::storage::{coding::*, options::DEFAULT_BLOCK_SIZE};
+
+// Codec ids recorded in each block header, ids below 16 are reserved for built-in codecs
+pub const NO_COMPRESSION_ID: u8 = 0;
+pub const LZ_COMPRESSION_ID: u8 = 1;
+
+// A block is only stored compressed if that saves at least 1/8th of its size
+const MIN_SAVINGS_RATIO: usize = 8;
+
+/*
+Compressor: A block compression codec. The id is written in the header of every block
+the codec compresses, so it must be unique and must never change once segments have
+been written with it
+*/
+pub trait Compressor: Send + Sync {
+    fn id(&self) -> u8;
+    fn name(&self) -> &str;
+    fn compress(&self, input: &[u8]) -> Vec<u8>;
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct NoCompression;
+
+impl Compressor for NoCompression {
+    fn id(&self) -> u8 {
+        NO_COMPRESSION_ID
+    }
+
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        input.to_vec()
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        Ok(input.to_vec())
+    }
+}
+
+// Matches shorter than this cost more to encode than the literals they replace
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 14;
+const MAX_OFFSET: usize = 64 * 1024;
+
+/*
+LZ Compressor: Built-in LZ77 style codec. The output is the uncompressed length, then a
+sequence of
+
+    literal len | literal bytes | match len | match offset
+
+(all lengths and offsets as varints), where a match copies match len bytes starting match
+offset bytes back in the output. The last sequence has only literals. Matches are found
+greedily through a hash table of the last position each 4 byte prefix was seen at
+*/
+pub struct LzCompressor;
+
+impl Compressor for LzCompressor {
+    fn id(&self) -> u8 {
+        LZ_COMPRESSION_ID
+    }
+
+    fn name(&self) -> &str {
+        "lz"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() / 2 + 16);
+        put_varint64(&mut out, input.len() as u64);
+
+        let mut table = vec![usize::MAX; 1 << HASH_BITS];
+        let mut literal_start = 0;
+        let mut pos = 0;
+        while pos + MIN_MATCH <= input.len() {
+            let slot = lz_hash(&input[pos..pos + MIN_MATCH]);
+            let candidate = table[slot];
+            table[slot] = pos;
+
+            if candidate == usize::MAX || pos - candidate > MAX_OFFSET || input[candidate..candidate + MIN_MATCH] != input[pos..pos + MIN_MATCH] {
+                pos += 1;
+                continue;
+            }
+
+            let mut match_len = MIN_MATCH;
+            while pos + match_len < input.len() && input[candidate + match_len] == input[pos + match_len] {
+                match_len += 1;
+            }
+
+            put_length_prefixed(&mut out, &input[literal_start..pos]);
+            put_varint64(&mut out, match_len as u64);
+            put_varint64(&mut out, (pos - candidate) as u64);
+            pos += match_len;
+            literal_start = pos;
+        }
+        put_length_prefixed(&mut out, &input[literal_start..]);
+        out
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let (expected_len, mut pos) = get_varint64(input)?;
+        let expected_len = expected_len as usize;
+        // The length is read from the block, so only up to a block's worth is allocated up
+        // front, a bad length fails once the output falls short of it
+        let mut out = Vec::with_capacity(expected_len.min(DEFAULT_BLOCK_SIZE));
+
+        loop {
+            let (literals, n) = get_length_prefixed(&input[pos..])?;
+            if out.len() + literals.len() > expected_len {
+                return Err(corruption("lz compressed block is longer than its uncompressed length"));
+            }
+            out.extend_from_slice(literals);
+            pos += n;
+            if pos == input.len() {
+                break;
+            }
+
+            let (match_len, n) = get_varint64(&input[pos..])?;
+            pos += n;
+            let (offset, n) = get_varint64(&input[pos..])?;
+            pos += n;
+            let (match_len, offset) = (match_len as usize, offset as usize);
+            if offset == 0 || offset > out.len() || out.len() + match_len > expected_len {
+                return Err(corruption("bad match in lz compressed block"));
+            }
+            // Copy byte at a time, a match may overlap the bytes it is producing
+            let start = out.len() - offset;
+            for i in 0..match_len {
+                out.push(out[start + i]);
+            }
+        }
+
+        if out.len() != expected_len {
+            return Err(corruption("lz compressed block has wrong uncompressed length"));
+        }
+        Ok(out)
+    }
+}
+
+fn lz_hash(bytes: &[u8]) -> usize {
+    let v = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/*
+Compress Block: Compresses the block contents with the codec, falling back to storing
+them uncompressed if that doesn't save enough, and prefixes the id of the codec that was
+actually used
+*/
+pub fn compress_block(compressor: &dyn Compressor, contents: &[u8]) -> Vec<u8> {
+    if compressor.id() != NO_COMPRESSION_ID {
+        let compressed = compressor.compress(contents);
+        if compressed.len() < contents.len() - contents.len() / MIN_SAVINGS_RATIO {
+            let mut block = Vec::with_capacity(compressed.len() + 1);
+            block.push(compressor.id());
+            block.extend_from_slice(&compressed);
+            return block;
+        }
+    }
+    let mut block = Vec::with_capacity(contents.len() + 1);
+    block.push(NO_COMPRESSION_ID);
+    block.extend_from_slice(contents);
+    block
+}
+
+/*
+Decompress Block: Reads the codec id from the block header and decompresses the rest
+with the matching codec from the ones given, the built-in codecs are always known
+*/
+pub fn decompress_block(compressors: &[Arc<dyn Compressor>], block: &[u8]) -> Result<Vec<u8>> {
+    let (id, contents) = match block.split_first() {
+        Some((id, contents)) => (*id, contents),
+        None => return Err(corruption("empty block has no codec header")),
+    };
+    match id {
+        NO_COMPRESSION_ID => Ok(contents.to_vec()),
+        LZ_COMPRESSION_ID => LzCompressor.decompress(contents),
+        _ => match compressors.iter().find(|c| c.id() == id) {
+            Some(compressor) => compressor.decompress(contents),
+            None => Err(corruption(&format!("block compressed with unknown codec {}", id))),
+        },
+    }
+}
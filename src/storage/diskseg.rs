@@ -2,17 +2,18 @@ use std::{cmp::Ordering, fs::File};
 
 use self::DiskSegment::*;
 
-use super::tree::LogSegment;
+use super::segment::SegmentReader;
 
 pub enum DiskSegment {
-    OpenSegment{path_s: String, file: File, tree: LogSegment<String>},
+    OpenSegment{path_s: String, table: Box<SegmentReader>},
     ClosedSegment{path_s: String, file: File},
 }
 
 impl DiskSegment {
+    #[allow(clippy::needless_borrow)]
     pub fn value(&self) -> &str {
         match self {
-            OpenSegment { path_s, file: _, tree: _ } => {
+            OpenSegment { path_s, table: _ } => {
                 &path_s
             }
             ClosedSegment{path_s, file: _} => {
@@ -22,6 +23,17 @@ impl DiskSegment {
     }
 }
 
+impl DiskSegment {
+    // The segment's reader, if it has been opened
+    pub fn table(&self) -> Option<&SegmentReader> {
+        match self {
+            OpenSegment{path_s: _, table} => Some(table),
+            ClosedSegment{..} => None,
+        }
+    }
+}
+
+#[allow(clippy::double_ended_iterator_last)]
 pub fn extract_seg_id(path: String) -> i32 {
     path.split('/').last().unwrap().split('.').next().unwrap().split('_').nth(1).unwrap().parse::<i32>().unwrap()
 }
@@ -31,7 +43,7 @@ impl Ord for DiskSegment
 {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         let own_path_id = match self {
-            OpenSegment{path_s, file: _, tree: _} => {path_s},
+            OpenSegment{path_s, table: _} => {path_s},
             ClosedSegment{path_s, file: _} => {path_s}
         };
 
@@ -39,7 +51,7 @@ impl Ord for DiskSegment
         let own_path_id = extract_seg_id(own_path);
 
         let other_path = match other {
-            OpenSegment{path_s, file: _, tree: _} => {path_s},
+            OpenSegment{path_s, table: _} => {path_s},
             ClosedSegment{path_s, file: _} => {path_s}
         };
 
@@ -56,7 +68,7 @@ impl Eq for DiskSegment {
 impl PartialEq for DiskSegment {
     fn eq(&self, other: &Self) -> bool {
         let own_path_id = match self {
-            OpenSegment{path_s, file: _, tree: _} => {path_s},
+            OpenSegment{path_s, table: _} => {path_s},
             ClosedSegment{path_s, file: _} => {path_s}
         };
 
@@ -64,7 +76,7 @@ impl PartialEq for DiskSegment {
         let own_path_id = extract_seg_id(own_path);
 
         let other_path = match other {
-            OpenSegment{path_s, file: _, tree: _} => {path_s},
+            OpenSegment{path_s, table: _} => {path_s},
             ClosedSegment{path_s, file: _} => {path_s}
         };
 
@@ -75,10 +87,11 @@ impl PartialEq for DiskSegment {
     }
 }
 
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for DiskSegment {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let own_path_id = match self {
-            OpenSegment{path_s, file: _, tree: _} => {path_s},
+            OpenSegment{path_s, table: _} => {path_s},
             ClosedSegment{path_s, file: _} => {path_s}
         };
 
@@ -86,7 +99,7 @@ impl PartialOrd for DiskSegment {
         let own_path_id = extract_seg_id(own_path);
 
         let other_path = match other {
-            OpenSegment{path_s, file: _, tree: _} => {path_s},
+            OpenSegment{path_s, table: _} => {path_s},
             ClosedSegment{path_s, file: _} => {path_s}
         };
 
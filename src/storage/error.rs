@@ -0,0 +1,62 @@
+use std::{error::Error, fmt::{Display, Formatter, Result}, io};
+
+/*
+LSM Error: Failures opening or operating on an LSM that a caller can act on, rather than
+ones that leave the LSM unusable, which panic
+*/
+#[derive(Debug)]
+pub enum LsmError {
+    Io(io::Error),
+    // The DB was created with a different comparator than the one it is being opened with
+    ComparatorMismatch{persisted: String, requested: String},
+    // Column family names are made of letters, digits, '_' and '-', and can't be "default"
+    InvalidColumnFamilyName(String),
+    // The DB has no column family with this name
+    UnknownColumnFamily(String),
+    // The DB has a column family that wasn't given options when it was opened
+    ColumnFamilyNotOpened(String),
+    // A merge was written to a column family with no merge operator
+    NoMergeOperator(String),
+    // The commits after this sequence number are no longer logged, the earliest still
+    // logged being the one given, so they can't be read back for replication
+    SequenceUnavailable{sequence: u64, earliest: u64},
+    // The DB was opened read-only, so it can't be written or compacted
+    ReadOnly,
+    // Another LsmTree, in this process or another, has the DB open for writing
+    Locked(String),
+}
+
+impl Display for LsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            LsmError::Io(e) => write!(f, "io error: {}", e),
+            LsmError::ComparatorMismatch{persisted, requested} => {
+                write!(f, "DB was created with comparator {}, cannot open it with comparator {}", persisted, requested)
+            },
+            LsmError::InvalidColumnFamilyName(name) => write!(f, "invalid column family name {:?}", name),
+            LsmError::UnknownColumnFamily(name) => write!(f, "no column family named {}", name),
+            LsmError::ColumnFamilyNotOpened(name) => write!(f, "column family {} must be opened along with the DB", name),
+            LsmError::NoMergeOperator(name) => write!(f, "column family {} has no merge operator", name),
+            LsmError::SequenceUnavailable{sequence, earliest} => {
+                write!(f, "commits after sequence number {} are no longer logged, the earliest logged is {}", sequence, earliest)
+            },
+            LsmError::ReadOnly => write!(f, "DB is open read-only"),
+            LsmError::Locked(name) => write!(f, "DB {} is already open for writing", name),
+        }
+    }
+}
+
+impl Error for LsmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LsmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LsmError {
+    fn from(e: io::Error) -> Self {
+        LsmError::Io(e)
+    }
+}
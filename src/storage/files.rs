@@ -1,30 +1,132 @@
-use std::{fs::{create_dir, read_dir, remove_file, metadata, OpenOptions, File, remove_dir}, path::{PathBuf, Path}, env::current_dir};
+use std::{fs::{self, create_dir, read_dir, remove_file, metadata, OpenOptions, File, remove_dir, remove_dir_all, TryLockError}, io::{self, Write}, path::{PathBuf, Path}, env::current_dir};
 use crate::log;
 
 use super::diskseg::DiskSegment::{self, *};
 
 pub const LOG_EXT: &str = "log";
 pub const DATA_EXT: &str = "data";
-
-pub fn get_wal(name: &str, create: bool) -> File {
+pub const MANIFEST_FILE: &str = "MANIFEST";
+pub const SEQUENCE_FILE: &str = "SEQUENCE";
+// Not a valid column family name, so it can't clash with a family's directory
+pub const WAL_ARCHIVE_DIR: &str = "wal.archive";
+pub const CHECKPOINT_DIR: &str = "checkpoint.staged";
+pub const WRITER_LOCK_FILE: &str = "LOCK";
+pub const FILES_LOCK_FILE: &str = "FILES.LOCK";
+
+pub fn get_wal(name: &str, create: bool) -> io::Result<File> {
     // Check for existing log for this DB, then we are not creating new DB and should
     // restore log to memory
-    let path_str = format!("{}.{}", name, LOG_EXT);
-    let log_file = Path::new(&path_str);
-    let full_file_path = get_lsmdir(name).join(log_file);
+    let full_file_path = get_wal_path(name);
     log(&format!("{:?}", full_file_path.as_os_str()));
-    if create {
-        OpenOptions::new().create(true).write(true).append(true).open(full_file_path).unwrap()
+    OpenOptions::new().create(create).read(true).append(true).open(full_file_path)
+}
+
+// The WAL is in the DB's directory, named after the last component of its path, so a DB
+// named by a nested or absolute path keeps it inside the directory too
+pub fn get_wal_path(name: &str) -> PathBuf {
+    let stem = Path::new(name).file_name().map_or("db".into(), |stem| stem.to_string_lossy());
+    get_lsmdir(name).join(format!("{}.{}", stem, LOG_EXT))
+}
+
+pub fn get_manifest_path(name: &str) -> PathBuf {
+    get_lsmdir(name).join(MANIFEST_FILE)
+}
+
+pub fn get_sequence_path(name: &str) -> PathBuf {
+    get_lsmdir(name).join(SEQUENCE_FILE)
+}
+
+// Sequence number of the last commit in the segments as of the flush that wrote the file, 0 if there is none
+pub fn read_sequence(path: &Path) -> io::Result<u64> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad sequence number file")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
     }
-    else {
-        OpenOptions::new().read(true).write(true).append(true).open(full_file_path).unwrap()
+}
+
+// Persists the sequence number the same way as the manifest, synced to a temporary file renamed over the old one
+pub fn write_sequence(path: &Path, sequence: u64) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(format!("{}\n", sequence).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+// Directory a checkpoint from another DB is received into before it replaces the DB's segments
+pub fn get_checkpoint_dir(name: &str) -> PathBuf {
+    get_lsmdir(name).join(CHECKPOINT_DIR)
+}
+
+pub fn get_wal_archive_dir(name: &str) -> PathBuf {
+    get_lsmdir(name).join(WAL_ARCHIVE_DIR)
+}
+
+// Path of the archived WAL whose first commit has this sequence number
+pub fn get_wal_archive_path(name: &str, first_sequence: u64) -> PathBuf {
+    get_wal_archive_dir(name).join(format!("{:020}.{}", first_sequence, LOG_EXT))
+}
+
+/*
+List WAL Archives: The DB's archived WALs, oldest first, each with the sequence number of
+its first commit. Files left over from archiving a WAL that didn't finish are skipped
+*/
+pub fn list_wal_archives(name: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut archives = Vec::new();
+    let dir = match read_dir(get_wal_archive_dir(name)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(archives),
+        Err(e) => return Err(e),
+    };
+    for item in dir {
+        let path = item?.path();
+        if path.extension().is_some_and(|ext| ext == LOG_EXT) {
+            if let Some(first_sequence) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                archives.push((first_sequence, path));
+            }
+        }
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+/*
+Lock Writer: Locks the DB exclusively for the one LsmTree writing it, returning None if
+another already has it. The lock is held until the returned file is closed
+*/
+pub fn lock_writer(name: &str) -> io::Result<Option<File>> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(get_lsmdir(name).join(WRITER_LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/*
+Get Files Lock: The file whose lock guards which segments make up the DB and what is in
+its WAL. The writer locks it exclusively while it changes them, flushing or compacting,
+and read-only instances lock it shared while they read them in, so they never see a flush
+half done, without keeping the writer or each other out the rest of the time. A read-only
+instance of a DB on a read-only filesystem, where it can't be created, has no writer to
+guard against and gets None
+*/
+pub fn get_files_lock(name: &str, read_only: bool) -> io::Result<Option<File>> {
+    match OpenOptions::new().create(true).truncate(false).write(true).open(get_lsmdir(name).join(FILES_LOCK_FILE)) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if read_only => {
+            log(&format!("unable to create files lock for {}, reading without it: {}", name, e));
+            Ok(None)
+        },
+        Err(e) => Err(e),
     }
 }
 
 pub fn get_seg_path(name: &str, seg_num: usize) -> PathBuf {
     // Check for existing log for this DB, then we are not creating new DB and should
     // restore log to memory
-    let path_str = format!("segment_{}.{}", seg_num, LOG_EXT);
+    let path_str = format!("segment_{}.{}", seg_num, DATA_EXT);
     let seg_path = Path::new(&path_str);
     get_lsmdir(name).join(seg_path)
 }
@@ -32,98 +134,89 @@ pub fn get_seg_path(name: &str, seg_num: usize) -> PathBuf {
 pub fn get_seg_path_s(name: &str, seg_num: usize) -> String {
     // Check for existing log for this DB, then we are not creating new DB and should
     // restore log to memory
-    let path_str = format!("segment_{}.{}", seg_num, LOG_EXT);
+    let path_str = format!("segment_{}.{}", seg_num, DATA_EXT);
     let seg_path = Path::new(&path_str);
-    get_lsmdir(name).join(seg_path).to_str().unwrap().to_string()
+    get_lsmdir(name).join(seg_path).to_string_lossy().into_owned()
 }
 
-pub fn get_segment(name: &str, seg_num: usize, create: bool) -> File {
+pub fn get_segment(name: &str, seg_num: usize, create: bool) -> io::Result<File> {
     let full_file_path = get_seg_path(name, seg_num);
-    log(&format!("Creating segment file {:?}", full_file_path.as_os_str()));
     if create {
-        OpenOptions::new().create(true).write(true).append(true).open(full_file_path).unwrap()
+        // Segments are written once, any leftover file from a flush that did not finish is replaced
+        log(&format!("Creating segment file {:?}", full_file_path.as_os_str()));
+        OpenOptions::new().create(true).write(true).truncate(true).open(full_file_path)
     }
     else {
-        OpenOptions::new().read(true).write(true).append(true).open(full_file_path).unwrap()
+        OpenOptions::new().read(true).open(full_file_path)
     }
 }
 
-pub fn reclaim_segments(name: &str) -> Vec<DiskSegment> {
+pub fn reclaim_segments(name: &str) -> io::Result<Vec<DiskSegment>> {
     let mut old_segments: Vec<DiskSegment> = Vec::new();
 
     let lsm_dir = get_lsmdir(name);
 
-    if lsm_dir.is_dir() {
-        for item in read_dir(lsm_dir).unwrap() {
-            let item = item.unwrap();
-            let path = item.path();
-            
-            if let Some(ext) = path.extension() {
-                let md = metadata(&path).unwrap();
-                if md.is_file() && ext.eq(DATA_EXT) {
-                    let segment = ClosedSegment{
-                        path_s: String::from(name), 
-                        file: OpenOptions::new().read(true).write(false).open(path).unwrap()};
-                    match old_segments.binary_search(&segment) {
-                        // Place log segments in order by name
-                        // TODO: We need to probably extract the numeric ordering
-                        // from the data files to properly order this vector e.g.
-                        // currently foo_10.data would be before foo_9.data
-                        Err(pos) => old_segments.insert(pos, segment),
-                        _ => {},
-                    }
+    // For new LSM, we should have created the LSM dir for the WAL already, reading it fails if not
+    for item in read_dir(lsm_dir)? {
+        let path = item?.path();
+
+        if let Some(ext) = path.extension() {
+            let md = metadata(&path)?;
+            if md.is_file() && ext.eq(DATA_EXT) {
+                let segment = ClosedSegment{
+                    path_s: path.to_string_lossy().into_owned(),
+                    file: OpenOptions::new().read(true).write(false).open(path)?};
+                // Place log segments in newest to oldest order by segment number
+                if let Err(pos) = old_segments.binary_search(&segment) {
+                    old_segments.insert(pos, segment);
                 }
             }
         }
     }
-    else {
-        // For new LSM, we should have created the LSM dir for the WAL already, panic if not
-        panic!("No LSM dir found for LSM {}!", name);
-    }
-    old_segments
+    Ok(old_segments)
+}
+
+// Column families other than the default keep their segments in a directory of their own
+// within the DB's, named so the segment helpers above take it in place of a DB name
+pub fn get_column_family_dir(name: &str, family: &str) -> String {
+    Path::new(name).join(family).to_string_lossy().into_owned()
 }
 
 /*
-Purge LSM directory: Delete LSM directory and all log segment/WAL files.
-Will panic if we try to purge the LSM directory for a non-existing LSM
+Purge LSM directory: Delete LSM directory and all log segment/WAL files, along with the
+directories of any column families. Nothing to do for a non-existing LSM
 */
-pub fn purge_lsm_dir(name: &str) {
+pub fn purge_lsm_dir(name: &str) -> io::Result<()> {
     let lsm_dir = get_lsmdir(name);
 
     if lsm_dir.is_dir() {
-        for item in read_dir(lsm_dir.clone()).unwrap() {
-            let item = item.unwrap();
-            let path = item.path();
-            
-            let md = metadata(&path).unwrap();
+        for item in read_dir(lsm_dir.clone())? {
+            let path = item?.path();
+
+            let md = metadata(&path)?;
             if md.is_file() {
-                if let Err(e) = remove_file(path) {
-                    panic!("unable to delete existing LSM files with error {}", e);
-                }
+                remove_file(path)?;
+            }
+            else if md.is_dir() {
+                remove_dir_all(path)?;
             }
         }
-        if let Err(e) = remove_dir(lsm_dir) {
-            panic!("unable to delete existing LSM directory with error {}", e);
-        }
+        remove_dir(lsm_dir)?;
     }
+    Ok(())
 }
 
 /*
 Create LSM Directory: Create a new LSM directory for the specified LSM.
-Will panic if attempt to create LSM directory already occupied by existing LSM
+Fails with AlreadyExists if the directory is already occupied by an existing LSM
 */
-pub fn create_lsm_dir(name: &str) -> Result<(), &'static str> {
-    let lsm_dir = get_lsmdir(name);
+pub fn create_lsm_dir(name: &str) -> io::Result<()> {
+    create_dir(get_lsmdir(name))
+}
 
-    if !lsm_dir.is_dir() {
-        if let Err(e) = create_dir(lsm_dir) {
-            panic!("failed to create log segments directory {}, with error {}!", name, e);
-        }
-    }
-    else {
-        return Err("Attempting to create LSM directory for existing LSM");
-    }
-    Ok(())
+// Syncs the LSM directory, so the files created, renamed or deleted in it stay that way through a crash
+pub fn sync_dir(name: &str) -> io::Result<()> {
+    File::open(get_lsmdir(name))?.sync_all()
 }
 
 pub fn lsm_exists(name: &str) -> bool {
@@ -0,0 +1,92 @@
+/*
+Bloom Filter: One filter per segment over every key in it (tombstones included), so a
+lookup can skip a segment without reading any of its data blocks. The filter is a bit
+array with the number of probes per key stored in its last byte, probes are derived
+from a single hash of the key by double hashing.
+*/
+pub struct BloomFilterPolicy {
+    bits_per_key: usize,
+    num_probes: usize,
+}
+
+impl BloomFilterPolicy {
+    pub fn new(bits_per_key: usize) -> BloomFilterPolicy {
+        // bits_per_key * ln(2) probes minimizes the false positive rate
+        let num_probes = ((bits_per_key as f64) * 0.69) as usize;
+        BloomFilterPolicy{bits_per_key, num_probes: num_probes.clamp(1, 30)}
+    }
+
+    pub fn bits_per_key(&self) -> usize {
+        self.bits_per_key
+    }
+
+    pub fn create_filter(&self, key_hashes: &[u32]) -> Vec<u8> {
+        // Very small filters have a high false positive rate however many bits per key
+        let bits = (key_hashes.len() * self.bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut filter = vec![0; bytes + 1];
+        for hash in key_hashes {
+            let mut h = *hash;
+            let delta = h.rotate_right(17);
+            for _ in 0..self.num_probes {
+                let bit = h as usize % bits;
+                filter[bit / 8] |= 1 << (bit % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        filter[bytes] = self.num_probes as u8;
+        filter
+    }
+}
+
+/*
+Key May Match: False means the key is definitely not in the segment, true means it may be
+*/
+pub fn key_may_match(key: &[u8], filter: &[u8]) -> bool {
+    if filter.len() < 2 {
+        return true;
+    }
+    let bits = (filter.len() - 1) * 8;
+    let num_probes = filter[filter.len() - 1];
+    if num_probes > 30 {
+        // Reserved for filter encodings we don't know about, so treat as a match
+        return true;
+    }
+
+    let mut h = bloom_hash(key);
+    let delta = h.rotate_right(17);
+    for _ in 0..num_probes {
+        let bit = h as usize % bits;
+        if filter[bit / 8] & (1 << (bit % 8)) == 0 {
+            return false;
+        }
+        h = h.wrapping_add(delta);
+    }
+    true
+}
+
+// Murmur-like 32 bit hash of the key, the same one is used for building and probing
+pub fn bloom_hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(u32::from_le_bytes(chunk.try_into().unwrap()));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, b) in rest.iter().enumerate() {
+            h = h.wrapping_add((*b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
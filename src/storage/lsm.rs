@@ -1,16 +1,30 @@
-use std::{io::{Lines, Result, BufReader, BufRead, Write}, fs::File, path::Path};
-use crate::{storage::tree::{TriOption::*, *}, log};
+use std::{collections::HashMap, io::{self, Read, Seek, SeekFrom, Write}, fs::{self, File}, path::Path, sync::Arc, time::Duration};
+use crate::{kvpair::KVPair, log};
 
-use crate::storage::{diskseg::{extract_seg_id, DiskSegment::{self, *}}, files::*};
+use crate::storage::{checkpoint::{self, Checkpoint, CheckpointFile, StagedCheckpoint}, column_family::{valid_column_family_name, ColumnFamily, DEFAULT_COLUMN_FAMILY}, comparator::{BytewiseComparator, Comparator}, error::LsmError, manifest::Manifest, files::*, options::LsmOptions, stats::Statistics, wal::{check_readable, encode_sequenced, read_batches, SequencedBatch, WalRecord}, write_batch::WriteBatch};
 
-const MAX_TREE_SIZE: usize = 100;
+// Index of the default column family, which is also its id in the WAL
+const DEFAULT_FAMILY: usize = 0;
 
 pub struct LsmTree {
     name: String,
     log_file: File,
-    tree: LogSegment<String>,
-    max_tree_size: usize,
-    log_segments: Vec<DiskSegment>,
+    // The default column family first, then the others in the order they were created,
+    // so a family's index is its id in the WAL and manifest
+    families: Vec<ColumnFamily>,
+    stats: Arc<Statistics>,
+    // Sequence number of the last commit, each commit taking the next one
+    sequence: u64,
+    // Sequence number and offset of each commit in the WAL, and the WAL's length
+    wal_index: Vec<(u64, u64)>,
+    wal_len: u64,
+    wal_archive_size: Option<u64>,
+    // Sequence number of the last commit as of the last flush, the one in the SEQUENCE file
+    flushed_sequence: u64,
+    read_only: bool,
+    // Keeps any other writer out while the DB is open for writing, see lock_writer
+    _writer_lock: Option<File>,
+    files_lock: Option<File>,
 }
 
 impl LsmTree {
@@ -20,222 +34,932 @@ impl LsmTree {
     and restores from the pre-existing WAL.
     */
     pub fn new(name: &str) -> LsmTree {
+        LsmTree::new_with_options(name, LsmOptions::default())
+    }
+
+    pub fn new_with_options(name: &str, options: LsmOptions) -> LsmTree {
+        match LsmTree::open(name, options) {
+            Ok(tree) => tree,
+            Err(e) => panic!("unable to open db {} with error {}", name, e),
+        }
+    }
+
+    /*
+    Open: Same as new_with_options, but fails rather than panicking when the DB can't be
+    opened with these options, e.g. because it was created with another comparator
+    */
+    pub fn open(name: &str, options: LsmOptions) -> Result<LsmTree, LsmError> {
+        LsmTree::open_with_column_families(name, options, Vec::new())
+    }
 
-        if lsm_exists(name) {
-            let existing_log = get_wal(name, false);
-            let mut tree = LsmTree{
-                name: name.to_string(),
-                log_file: existing_log,
-                tree: LogSegment::new(),
-                max_tree_size: MAX_TREE_SIZE,
-                log_segments: reclaim_segments(name)};
-            let restore_result = tree.restore();
-            assert!(restore_result, "Failed to restore WAL!");
-            return tree;
+    /*
+    Open With Column Families: Same as open, along with the column families to open, each
+    with its own options, while the options given for the DB are those of the default
+    family. Every family the DB already has must be given, and those it doesn't have yet
+    are created
+    */
+    pub fn open_with_column_families(name: &str, options: LsmOptions, column_families: Vec<(&str, LsmOptions)>) -> Result<LsmTree, LsmError> {
+        for (i, (family, _)) in column_families.iter().enumerate() {
+            if !valid_column_family_name(family) || column_families[..i].iter().any(|(other, _)| other == family) {
+                return Err(LsmError::InvalidColumnFamilyName(family.to_string()));
+            }
         }
 
-        // Note: we only create LSM directory when the LSM does not exist already, replacing 
-        // this elsewhere in this ctor e.g. prior to above case for existing LSM will cause panic
-        if let Err(e) = create_lsm_dir(name) {
-            panic!("{}", e);
+        let read_only = options.read_only;
+        let exists = lsm_exists(name);
+        if read_only && !exists {
+            return Err(LsmError::Io(io::Error::new(io::ErrorKind::NotFound, format!("no DB named {}", name))));
+        }
+        if !exists {
+            // Note: we only create LSM directory when the LSM does not exist already, replacing
+            // this elsewhere in this ctor e.g. prior to the check for an existing LSM will fail
+            create_lsm_dir(name)?;
         }
+        // Taken before anything is written, so one writer can't get in the way of another
+        let writer_lock = match read_only {
+            true => None,
+            false => Some(lock_writer(name)?.ok_or(LsmError::Locked(name.to_string()))?),
+        };
+        if !exists {
+            Manifest{comparator: options.comparator.name().to_string(), column_families: Vec::new(), merge_operators: Vec::new()}.write(name)?;
+        }
+        let mut manifest = check_manifest(name, &options, &column_families)?;
+        let persisted = manifest.clone();
 
-        LsmTree{
+        // The segments and WAL are read in as they are between one flush and the next, the
+        // writer keeping read-only instances out while it may yet change them
+        let files_lock = get_files_lock(name, read_only)?;
+        match (read_only, &files_lock) {
+            (true, Some(lock)) => lock.lock_shared()?,
+            (false, Some(lock)) => lock.lock()?,
+            (_, None) => {},
+        }
+        if !read_only {
+            // Installing a checkpoint a crash interrupted is finished before the segments are read in
+            let mut dirs = vec![(DEFAULT_COLUMN_FAMILY.to_string(), name.to_string())];
+            dirs.extend(manifest.column_families.iter().map(|(family, _)| (family.clone(), get_column_family_dir(name, family))));
+            checkpoint::install_checkpoint(name, &dirs)?;
+        }
+        let stats = Arc::new(Statistics::new());
+        let mut families = vec![ColumnFamily::open(DEFAULT_COLUMN_FAMILY, name.to_string(), options, stats.clone())?];
+        let mut column_families = column_families;
+        for (family, _) in &manifest.column_families {
+            let (_, options) = column_families.remove(column_families.iter().position(|(name, _)| name == family).unwrap());
+            families.push(ColumnFamily::open(family, get_column_family_dir(name, family), options, stats.clone())?);
+        }
+
+        // Families are added to the manifest before any write to them can be logged
+        if let (true, Some((family, _))) = (read_only, column_families.first()) {
+            return Err(LsmError::UnknownColumnFamily(family.to_string()));
+        }
+        if !column_families.is_empty() {
+            for (family, options) in column_families {
+                let dir = get_column_family_dir(name, family);
+                match create_lsm_dir(&dir) {
+                    Ok(()) => {},
+                    // Left over from creating the family when the manifest didn't get written
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => log(&format!("column family directory exists, reusing {}", dir)),
+                    Err(e) => return Err(e.into()),
+                }
+                manifest.column_families.push((family.to_string(), options.comparator.name().to_string()));
+                families.push(ColumnFamily::open(family, dir, options, stats.clone())?);
+            }
+        }
+        if !read_only {
+            manifest.merge_operators = merge_operators(&families);
+        }
+        if manifest != persisted {
+            manifest.write(name)?;
+        }
+
+        let sequence = read_sequence(&get_sequence_path(name))?;
+        let log_file = match read_only {
+            true => File::open(get_wal_path(name))?,
+            false => get_wal(name, !exists)?,
+        };
+        let wal_archive_size = families[DEFAULT_FAMILY].options().wal_archive_size;
+        let mut tree = LsmTree{
             name: name.to_string(),
-            log_file: get_wal(name, true),
-            tree: LogSegment::new(),
-            max_tree_size: MAX_TREE_SIZE,
-            log_segments: reclaim_segments(name)}
+            log_file,
+            families,
+            stats,
+            sequence,
+            wal_index: Vec::new(),
+            wal_len: 0,
+            wal_archive_size,
+            flushed_sequence: sequence,
+            read_only,
+            _writer_lock: writer_lock,
+            files_lock};
+        if exists {
+            tree.restore()?;
+        }
+        tree.unlock_files();
+        Ok(tree)
     }
 
     /*
     New Delete Existing: Creates DB from scratch, deleting any existing DB with this name
     */
     pub fn new_delete_existing(name: &str) -> LsmTree {
+        LsmTree::new_delete_existing_with_options(name, LsmOptions::default())
+    }
+
+    pub fn new_delete_existing_with_options(name: &str, options: LsmOptions) -> LsmTree {
         // Note: we only purge LSM directory because we are creating an LSM and deleting the
         // existing LSM of this name, should not purge elsewhere
-        purge_lsm_dir(name);
+        if let Err(e) = purge_lsm_dir(name) {
+            panic!("unable to delete existing db {} with error {}", name, e);
+        }
 
         // Once we have purged the existing LSM directory, this ctor operates
         // the same as the default ctor
-        LsmTree::new(name)
+        LsmTree::new_with_options(name, options)
     }
 
     /*
     Log: On each DB operation, we write ahead to log to ensure durability of all operations. This is a persisted
     log that will reflect any actions prior to mutating the in memory log segment(s)
     */
-    fn log(&mut self, key: &str, value: &str) -> bool {
-        match self.log_file.write(format!("{} {}\n", key, value).as_bytes()) {
-            Err(e) => {log(&format!("failed to log ({} {}) to wal for db {} with error {}", key, value, self.name, e)); false}
-            Ok(_) => {true}
+    fn log(&mut self, sequence: u64, records: &[(u32, WalRecord)]) -> io::Result<()> {
+        // Each commit goes out in a single write, so a crash can only tear the last one
+        let encoded = encode_sequenced(sequence, records);
+        if let Err(e) = self.log_file.write_all(&encoded) {
+            log(&format!("failed to log {:?} to wal for db {} with error {}", records, self.name, e));
+            // Part of the record may have been written, which restoring cuts off
+            self.wal_len = self.log_file.metadata()?.len();
+            return Err(e);
         }
+        self.wal_index.push((sequence, self.wal_len));
+        self.wal_len += encoded.len() as u64;
+        Ok(())
     }
 
-    fn log_del(&mut self, key: &str) -> bool {
-        match self.log_file.write(format!("{}\n", key).as_bytes()) {
-            Err(e) => {log(&format!("failed to log delete {} to wal for db {} with error {}", key, self.name, e)); false}
-            Ok(_) => {true}
+    /*
+    Commit: Logs the records, each for the column family with that id, to the WAL as the
+    next commit and then applies them to the families' in-memory segments, first flushing
+    the segments if one of them is full. Nothing is applied if logging fails
+    */
+    fn commit(&mut self, records: Vec<(u32, WalRecord)>) -> Result<(), LsmError> {
+        self.commit_sequenced(self.sequence + 1, records)
+    }
+
+    // Same as commit, with the sequence number given, which must be after the last commit's
+    fn commit_sequenced(&mut self, sequence: u64, records: Vec<(u32, WalRecord)>) -> Result<(), LsmError> {
+        if self.read_only {
+            return Err(LsmError::ReadOnly);
+        }
+        if self.families.iter().any(|family| family.memtable_full()) {
+            self.flush_tree();
         }
+        self.log(sequence, &records)?;
+        self.sequence = sequence;
+        for (family, record) in records {
+            self.families[family as usize].apply(record);
+        }
+        log(&format!("Committed write, tree size is {}", self.num_entries()));
+        Ok(())
     }
 
     /*
     Restore: On DB startup, if this is an existing DB, we will need to restore the existing WAL prior
     to the latest start up. Consumes each entry of the WAL beyond the latest non-persisted log entry
-    and builds a new in-memory log segment
+    and builds a new in-memory log segment for each column family. A record torn by a crash
+    mid-append is cut off the end of the WAL, so records appended from here on are not stuck behind it.
+    A WAL whose first record is unreadable, but not cut short, fails to open instead
     */
-    fn restore(&mut self) -> bool {
+    fn restore(&mut self) -> Result<(), LsmError> {
         // Clone WAL handle since we cannot move WAL behind ref to LSM
-        let restore_handle = self.log_file.try_clone().unwrap();
-        let wal_contents = BufReader::new(restore_handle).lines();
-        for line in wal_contents {
-            if let Ok(line) = line {
-                // WAL format is an append-only log of entries like below
-                // key: foo, value: bar, we can re-create segment from log
-                // by iterating these entries and applying as a sequence of writes
-                let mut line = line.split(' ');
-                let tuple = (line.next().unwrap().into(), line.next().unwrap().into());
-                self.tree.insert(tuple);
+        let mut restore_handle = self.log_file.try_clone()?;
+        let mut wal_contents = Vec::new();
+        restore_handle.read_to_end(&mut wal_contents)?;
+
+        // Replaying the records in order as a sequence of writes and deletes re-creates the segments
+        let (batches, valid_len) = read_batches(&wal_contents, self.sequence + 1);
+        if valid_len == 0 && !wal_contents.is_empty() {
+            check_readable(&wal_contents).map_err(|e| io::Error::new(e.kind(), format!("{} for db {}", e, self.name)))?;
+        }
+        self.replay(batches, 0)?;
+        // The writer of a DB open read-only may be part way through appending the record
+        if valid_len < wal_contents.len() && !self.read_only {
+            log(&format!("dropping {} bytes of torn records from WAL for {}", wal_contents.len() - valid_len, self.name));
+            self.log_file.set_len(valid_len as u64)?;
+        }
+        self.wal_len = valid_len as u64;
+        Ok(())
+    }
+
+    /*
+    Replay: Applies the commits read from the WAL, found at offsets from start, to the column
+    families, skipping those at or before the latest sequence number. They are already in the
+    segments, when a crash came after a flush persisted the sequence number but before it
+    truncated the WAL, and applying a merge a second time would count it twice. Every commit
+    is indexed, so those are still there to ship to secondaries
+    */
+    fn replay(&mut self, batches: Vec<(usize, SequencedBatch)>, start: u64) -> Result<(), LsmError> {
+        for (offset, batch) in batches {
+            self.wal_index.push((batch.sequence, start + offset as u64));
+            if batch.sequence <= self.sequence {
+                continue;
             }
-            else {
-                panic!("unable to get entry from WAL for {}, terminating", self.name);
+            for (family, record) in batch.records {
+                match self.families.get(family as usize) {
+                    Some(family) => family.apply(record),
+                    None => return Err(LsmError::UnknownColumnFamily(format!("with id {}", family))),
+                }
             }
+            self.sequence = batch.sequence;
         }
-        true
+        Ok(())
     }
 
     /*
-    Flushes Tree: Flushes current tree to new disk segment. Note that flushing the in-memory
-    tree is lazy, so we can read this tree until another write occurs
+    Flushes Tree: Flushes the in-memory tree of each column family holding any writes to a
+    new disk segment. Note that flushing the in-memory tree is lazy, so we can read this
+    tree until another write occurs. The families share the WAL, so they are flushed
+    together, however full each one is. Once the segments are synced, everything in the
+    WAL is persisted, so the WAL is truncated
     */
     fn flush_tree(&mut self) {
-        let total_segments = self.total_segments();
-        let mut segment_buf = get_segment(&self.name, total_segments, true);
-        self.tree.write_to_disk(&mut segment_buf);
-        let new_seg = ClosedSegment{path_s: get_seg_path_s(&self.name, total_segments), file: segment_buf};
-        match self.log_segments.binary_search(&new_seg) {
-            // Place log segments in order by name
-            Err(pos) => self.log_segments.insert(pos, new_seg),
-            _ => {},
+        if let Err(e) = self.lock_files(false) {
+            panic!("failed to lock files of db {} for flushing with error {}", self.name, e);
+        }
+        for family in &mut self.families {
+            if family.num_entries() > 0 {
+                family.flush();
+            }
+        }
+        // The WAL is about to lose the sequence number of the last commit
+        if let Err(e) = write_sequence(&get_sequence_path(&self.name), self.sequence) {
+            panic!("failed to persist sequence number for db {} with error {}", self.name, e);
+        }
+        if let (Some(max_size), Some((first_sequence, _))) = (self.wal_archive_size, self.wal_index.first()) {
+            // Commits that can't be archived are only lost to replication, the segments have them
+            if let Err(e) = archive_wal(&self.name, *first_sequence, max_size) {
+                log(&format!("failed to archive wal for db {} with error {}", self.name, e));
+            }
+        }
+        if let Err(e) = self.log_file.set_len(0) {
+            panic!("failed to truncate wal for db {} with error {}", self.name, e);
+        }
+        self.wal_index.clear();
+        self.wal_len = 0;
+        self.flushed_sequence = self.sequence;
+        self.unlock_files();
+    }
+
+    // Locks the files lock, see get_files_lock, shared to read the files or exclusively to change them
+    fn lock_files(&self, shared: bool) -> io::Result<()> {
+        match (&self.files_lock, shared) {
+            (Some(lock), true) => lock.lock_shared(),
+            (Some(lock), false) => lock.lock(),
+            (None, _) => Ok(()),
+        }
+    }
+
+    fn unlock_files(&self) {
+        if let Some(Err(e)) = self.files_lock.as_ref().map(File::unlock) {
+            log(&format!("failed to unlock files of db {} with error {}", self.name, e));
         }
-        self.tree = LogSegment::new();
+    }
+
+    // Total bytes of the default family's disk segments, the WAL and memtable are not counted
+    pub fn total_size(&self) -> u64 {
+        self.families[DEFAULT_FAMILY].total_size()
     }
 
     pub fn num_entries(&self) -> usize {
-        self.tree.size()
+        self.families[DEFAULT_FAMILY].num_entries()
     }
 
-    pub fn max_entries(&self) -> usize {
-        self.max_tree_size
+    /*
+    Flush: Persists the in-memory segments to new disk segments now, rather than waiting
+    for one to fill up. A DB open read-only has nothing of its own to persist, its writer
+    flushes what it has read from the WAL
+    */
+    pub fn flush(&mut self) {
+        if !self.read_only && self.families.iter().any(|family| family.num_entries() > 0) {
+            self.flush_tree();
+        }
     }
 
     /*
-    Get: Queries LSM for value for the given key, will traverse log segments in newest
-    to oldest fashion to preverse append-only deletion semantics
+    Compact: Flushes the memtables, then merges every disk segment of the default family
+    into one, see ColumnFamily::compact
     */
-    pub fn get(&mut self, key: &str) -> Option<&String> {
-        // If the key is not already in memory, traverse prior log
-        // segments in newest-to-oldest order until we get a result
-        match self.tree.get(key.to_string()) {
-            TriSome(result) => return Some(result),
-            Tombstoned => return None,
-            TriNone => {
-                for segment in &mut self.log_segments {
-                    log(&format!("Checking for {} in segment {}", key, extract_seg_id(segment.value().to_string())));
-                    let tree = get_tree_from_segment(segment);
-                    
-                    match tree.get(key.to_string()) {
-                        TriSome(result) => return Some(result),
-                        Tombstoned => return None,
-                        _ => {}
+    pub fn compact(&mut self) -> Result<(), LsmError> {
+        self.default_family().compact()
+    }
+
+    // Iterates the default family's live keys, see ColumnFamily::iter
+    pub fn iter(&mut self) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.families[DEFAULT_FAMILY].iter()
+    }
+
+    // Iterates the default family's live keys from the given one on, see ColumnFamily::iter_from
+    pub fn iter_from(&mut self, key: &[u8]) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.families[DEFAULT_FAMILY].iter_from(key)
+    }
+
+    // Iterates the default family's keys including tombstones, see ColumnFamily::entries
+    pub fn entries(&mut self) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.families[DEFAULT_FAMILY].entries()
+    }
+
+    // Comparator of the default family, which its keys are ordered by
+    pub fn comparator(&self) -> Arc<dyn Comparator> {
+        self.families[DEFAULT_FAMILY].options().comparator.clone()
+    }
+
+    pub fn statistics(&self) -> &Statistics {
+        &self.stats
+    }
+
+    pub fn memtable_bytes(&self) -> usize {
+        self.families[DEFAULT_FAMILY].memtable_bytes()
+    }
+
+    pub fn write_buffer_size(&self) -> usize {
+        self.families[DEFAULT_FAMILY].options().write_buffer_size
+    }
+
+    /*
+    Get: Queries LSM for value for the given key in the default column family, will traverse
+    log segments in newest to oldest fashion to preverse append-only deletion semantics
+    */
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Option<Vec<u8>> {
+        self.families[DEFAULT_FAMILY].get(key.as_ref())
+    }
+
+    // Looks up many keys in the default family at once, see ColumnFamily::multi_get
+    pub fn multi_get<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        self.families[DEFAULT_FAMILY].multi_get(keys)
+    }
+
+    /*
+    Get Str: Convenience over get for text values, any bytes that are not valid UTF-8 are
+    replaced rather than failing the lookup
+    */
+    pub fn get_str(&mut self, key: &str) -> Option<String> {
+        self.default_family().get_str(key)
+    }
+
+    /*
+    Write: Appends a new entry to the latest log segment, after first preserving the
+    operation to the WAL. Keys and values are arbitrary bytes, so &str, String, &[u8]
+    and Vec<u8> all work. Fails if logging the write fails, or with ReadOnly if the DB
+    was opened read-only, as do all writes
+    */
+    pub fn write<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), LsmError> {
+        self.default_family().write(key, value)
+    }
+
+    /*
+    Write With TTL: Same as write, but the entry expires once the ttl has passed, going by
+    the LSM's clock, after which reads treat the key as absent and compaction drops it
+    */
+    pub fn write_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V, ttl: Duration) -> Result<(), LsmError> {
+        self.default_family().write_with_ttl(key, value, ttl)
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), LsmError> {
+        self.default_family().delete(key)
+    }
+
+    /*
+    Merge: Applies the operand to the key's value with the merge operator, without reading
+    the value, see ColumnFamily::merge_record. Fails if there is no merge operator
+    */
+    pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, operand: V) -> Result<(), LsmError> {
+        self.default_family().merge(key, operand)
+    }
+
+    /*
+    Compare And Swap: Writes the new value only if the key's current value is the expected
+    one, where None expects the key to be absent (never written, deleted or expired). The
+    check and the write happen within the one &mut borrow of the LSM, so no other write can
+    land in between, and the write is logged to the WAL as a single put. Returns whether
+    the value was swapped, or the error if logging the write failed
+    */
+    pub fn compare_and_swap<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: Option<&[u8]>, new: V) -> Result<bool, LsmError> {
+        self.default_family().compare_and_swap(key, expected, new)
+    }
+
+    // Writes the value only if the key is absent, returning whether it was written
+    pub fn put_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<bool, LsmError> {
+        self.default_family().put_if_absent(key, value)
+    }
+
+    // Deletes the key only if its current value is the expected one, returning whether it was deleted
+    pub fn delete_if_equals<K: AsRef<[u8]>>(&mut self, key: K, expected: &[u8]) -> Result<bool, LsmError> {
+        self.default_family().delete_if_equals(key, expected)
+    }
+
+    /*
+    Write Batch: Applies every write in the batch, across any column families, or none of
+    them if one is for a family the DB doesn't have, or is a merge into a family without a
+    merge operator, or logging the batch fails
+    */
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), LsmError> {
+        let mut records: Vec<(u32, WalRecord)> = Vec::with_capacity(batch.len());
+        // Index of the last record in the batch for each key, which a merge folds over
+        let mut latest: HashMap<(u32, Vec<u8>), usize> = HashMap::new();
+        for (name, record) in batch.into_records() {
+            let family = self.family_id(&name)?;
+            let record = match record {
+                WalRecord::Merge{key, operand} => {
+                    let earlier = latest.get(&(family, key.clone())).map(|i| &records[*i].1);
+                    match self.families[family as usize].merge_record(&key, &operand, earlier) {
+                        Some(record) => record,
+                        None => return Err(LsmError::NoMergeOperator(name)),
                     }
+                },
+                record => record,
+            };
+            latest.insert((family, record.key().to_vec()), records.len());
+            records.push((family, record));
+        }
+
+        if !records.is_empty() {
+            self.commit(records)?;
+        }
+        Ok(())
+    }
+
+    /*
+    Column Family: The column family with the given name, for reading and writing it. The
+    default family is named "default"
+    */
+    pub fn column_family(&mut self, name: &str) -> Result<ColumnFamilyHandle<'_>, LsmError> {
+        let family = self.family_id(name)?;
+        Ok(ColumnFamilyHandle{lsm: self, family})
+    }
+
+    // Names of the column families, the default family first
+    pub fn column_family_names(&self) -> Vec<&str> {
+        self.families.iter().map(|family| family.name()).collect()
+    }
+
+    fn default_family(&mut self) -> ColumnFamilyHandle<'_> {
+        ColumnFamilyHandle{lsm: self, family: DEFAULT_FAMILY as u32}
+    }
+
+    fn family_id(&self, name: &str) -> Result<u32, LsmError> {
+        match self.families.iter().position(|family| family.name() == name) {
+            Some(family) => Ok(family as u32),
+            None => Err(LsmError::UnknownColumnFamily(name.to_string())),
+        }
+    }
+
+    // Sequence number of the last commit, 0 for a DB never written to
+    pub fn latest_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /*
+    Updates Since: The commits after the sequence number, in order, read back from the WAL
+    and, once it has been flushed, from the WAL archive (see LsmOptions::wal_archive_size).
+    Returns at least one commit if there are any, and stops once about max_bytes of them
+    have been read, so a caller far behind takes them a piece at a time. Fails with
+    SequenceUnavailable if the next commit is no longer logged
+    */
+    pub fn updates_since(&mut self, sequence: u64, max_bytes: usize) -> Result<Vec<SequencedBatch>, LsmError> {
+        let mut batches = Vec::new();
+        if sequence >= self.sequence {
+            return Ok(batches);
+        }
+        let (mut bytes, mut more) = (0, true);
+        // The WAL only holds the commits since the last flush, any before are in the archive, each named by its first commit
+        let archives = list_wal_archives(&self.name)?;
+        if self.wal_index.first().is_none_or(|(first_sequence, _)| *first_sequence > sequence + 1) {
+            let first = archives.partition_point(|(first_sequence, _)| *first_sequence <= sequence + 1).saturating_sub(1);
+            for (first_sequence, path) in &archives[first..] {
+                let contents = fs::read(path)?;
+                let (archived, valid_len) = read_batches(&contents, *first_sequence);
+                more = take_batches(&mut batches, &mut bytes, archived, valid_len, sequence, max_bytes);
+                if !more {
+                    break;
                 }
-                None
             }
         }
+
+        // The WAL is read from the next commit in it, up to about max_bytes past it, unless the archive used them up
+        let after = batches.last().map_or(sequence, |batch| batch.sequence);
+        let start = self.wal_index.partition_point(|(sequence, _)| *sequence <= after);
+        if more && bytes < max_bytes && start < self.wal_index.len() {
+            let offset = self.wal_index[start].1;
+            let end = self.wal_index[start + 1..].iter()
+                .find(|(_, end)| end - offset >= (max_bytes - bytes) as u64)
+                .map_or(self.wal_len, |(_, end)| *end);
+            let mut contents = vec![0; (end - offset) as usize];
+            let mut wal = self.log_file.try_clone()?;
+            wal.seek(SeekFrom::Start(offset))?;
+            wal.read_exact(&mut contents)?;
+            let (logged, valid_len) = read_batches(&contents, self.wal_index[start].0);
+            take_batches(&mut batches, &mut bytes, logged, valid_len, sequence, max_bytes);
+        }
+
+        if batches.is_empty() {
+            let earliest = archives.first().map(|(first_sequence, _)| *first_sequence)
+                .or(self.wal_index.first().map(|(first_sequence, _)| *first_sequence))
+                .unwrap_or(self.sequence + 1);
+            return Err(LsmError::SequenceUnavailable{sequence, earliest});
+        }
+        Ok(batches)
     }
 
     /*
-    Write: Appends a new entry to the latest log segment, after first preserving the
-    operation to the WAL
+    Apply Replicated: Commits the records of another DB's commit, each for the column family
+    with the name given, under that commit's sequence number. Their WAL record carries the
+    sequence number, so the DB's latest sequence number is always that of the last commit
+    applied, even after a crash, which is where replication picks up again. A commit at or
+    before the latest sequence number is skipped, returning false. Like a batch, none of the
+    records are applied if one is a merge into a family without a merge operator
     */
-    pub fn write(&mut self, key: &str, value: &str) -> bool {
-        if self.num_entries() >= self.max_tree_size {
-                self.flush_tree();
+    pub fn apply_replicated(&mut self, sequence: u64, records: Vec<(String, WalRecord)>) -> Result<bool, LsmError> {
+        if sequence <= self.sequence {
+            return Ok(false);
         }
+        let records = records.into_iter()
+            .map(|(name, record)| {
+                let family = self.family_id(&name)?;
+                if matches!(record, WalRecord::Merge{..}) && self.families[family as usize].options().merge_operator.is_none() {
+                    return Err(LsmError::NoMergeOperator(name));
+                }
+                Ok((family, record))
+            })
+            .collect::<Result<Vec<_>, LsmError>>()?;
+        self.commit_sequenced(sequence, records)?;
+        Ok(true)
+    }
 
-        match self.log(key, value) {
-            true => {
-                self.tree.insert((key.to_string(), value.to_string()));
-                log(&format!("Added {} {}, tree size is {}", key, value, self.num_entries()));
-                true
-            },
-            false => {false}
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /*
+    Catch Up: Reads in what the DB's writer, in this process or another, has done since a
+    read-only DB was opened or last caught up, returning whether there was anything. Commits
+    the writer has logged since are read from the tail of the WAL. Once the writer has
+    flushed or compacted, the segments are listed afresh and the WAL read in again from the
+    start, which it was truncated to. A DB open for writing is always caught up
+    */
+    pub fn catch_up(&mut self) -> Result<bool, LsmError> {
+        if !self.read_only {
+            return Ok(false);
         }
+        self.lock_files(true)?;
+        let result = self.read_changes();
+        self.unlock_files();
+        result
     }
 
-    pub fn delete(&mut self, key: &str) -> bool {
-        if self.num_entries() >= self.max_tree_size {
-                self.flush_tree();
+    fn read_changes(&mut self) -> Result<bool, LsmError> {
+        let before = self.sequence;
+        let flushed_sequence = read_sequence(&get_sequence_path(&self.name))?;
+        let mut reload = flushed_sequence != self.flushed_sequence;
+        for family in &self.families {
+            reload = reload || family.segments_changed()?;
+        }
+        if reload {
+            self.reopen_families(flushed_sequence)?;
         }
 
-        match self.log_del(key) {
-            true => {
-                self.tree.delete(key.to_string());
-                log(&format!("Deleted {}, tree size is {}", key, self.num_entries()));
-                true
+        let mut tail = Vec::new();
+        let mut wal = self.log_file.try_clone()?;
+        wal.seek(SeekFrom::Start(self.wal_len))?;
+        wal.read_to_end(&mut tail)?;
+        let (batches, valid_len) = read_batches(&tail, self.sequence + 1);
+        self.replay(batches, self.wal_len)?;
+        // Reading picks up from the end of the last whole record, the writer may be part way through appending the next
+        self.wal_len += valid_len as u64;
+        Ok(reload || self.sequence != before)
+    }
+
+    // Reads the segments in afresh, with empty memtables, as of the flush of the commit with the sequence number
+    fn reopen_families(&mut self, flushed_sequence: u64) -> Result<(), LsmError> {
+        for family in &mut self.families {
+            *family = ColumnFamily::open(family.name(), family.dir().to_string(), family.options().clone(), self.stats.clone())?;
+        }
+        self.sequence = flushed_sequence;
+        self.flushed_sequence = flushed_sequence;
+        self.wal_index.clear();
+        self.wal_len = 0;
+        Ok(())
+    }
+
+    /*
+    Checkpoint: The segments of every column family as of the last flush, for starting
+    another DB from, see Checkpoint. The commits since are read with updates_since from
+    the checkpoint's sequence number. A DB open read-only checkpoints the segments it last
+    caught up with, which its writer may have since deleted
+    */
+    pub fn checkpoint(&self) -> Result<Checkpoint, LsmError> {
+        self.lock_files(true)?;
+        let result = self.pin_segments();
+        self.unlock_files();
+        result
+    }
+
+    fn pin_segments(&self) -> Result<Checkpoint, LsmError> {
+        let mut files = Vec::new();
+        for family in &self.families {
+            for path in family.segment_paths() {
+                let file = File::open(path)?;
+                let len = file.metadata()?.len();
+                let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                files.push(CheckpointFile{family: family.name().to_string(), name, len, file});
+            }
+        }
+        let column_families = self.families[DEFAULT_FAMILY + 1..].iter()
+            .map(|family| (family.name().to_string(), family.options().comparator.name().to_string()))
+            .collect();
+        let manifest = Manifest{comparator: self.comparator().name().to_string(), column_families, merge_operators: merge_operators(&self.families)};
+        Ok(Checkpoint{sequence: self.flushed_sequence, manifest, files})
+    }
+
+    /*
+    Stage Checkpoint: Starts receiving another DB's checkpoint at the sequence number, given
+    its manifest, which must have the comparators this DB's column families have and no
+    family this DB doesn't have. Its files are written through the staged checkpoint, which
+    is then installed with install_checkpoint
+    */
+    pub fn stage_checkpoint(&self, sequence: u64, manifest: &Manifest) -> Result<StagedCheckpoint, LsmError> {
+        if self.read_only {
+            return Err(LsmError::ReadOnly);
+        }
+        check_comparator(&manifest.comparator, self.comparator().name())?;
+        let mut families = vec![DEFAULT_COLUMN_FAMILY.to_string()];
+        for (family, comparator) in &manifest.column_families {
+            let id = self.family_id(family)?;
+            check_comparator(comparator, self.families[id as usize].options().comparator.name())?;
+            families.push(family.clone());
+        }
+        for (family, _) in &manifest.merge_operators {
+            if self.families[self.family_id(family)? as usize].options().merge_operator.is_none() {
+                return Err(LsmError::NoMergeOperator(family.clone()));
+            }
+        }
+        Ok(StagedCheckpoint::create(&self.name, sequence, families)?)
+    }
+
+    /*
+    Install Checkpoint: Commits the staged checkpoint, once every one of its files has been
+    written and synced, then replaces the DB's segments with its, dropping everything else
+    the DB had, in memory and in its WAL, see checkpoint::install_checkpoint. Every column
+    family is then as the other DB's was at the checkpoint's sequence number, which is
+    returned, and the commits after it can be applied with apply_replicated. A failure part
+    way through is finished when the DB is next opened
+    */
+    pub fn install_checkpoint(&mut self, staged: StagedCheckpoint) -> Result<u64, LsmError> {
+        if self.read_only {
+            return Err(LsmError::ReadOnly);
+        }
+        staged.commit()?;
+        self.lock_files(false)?;
+        let result = self.replace_segments();
+        self.unlock_files();
+        result
+    }
+
+    fn replace_segments(&mut self) -> Result<u64, LsmError> {
+        let dirs: Vec<(String, String)> = self.families.iter().map(|family| (family.name().to_string(), family.dir().to_string())).collect();
+        match checkpoint::install_checkpoint(&self.name, &dirs)? {
+            Some(sequence) => {
+                self.reopen_families(sequence)?;
+                Ok(sequence)
             },
-            false => {false}
+            None => Err(LsmError::Io(io::Error::new(io::ErrorKind::NotFound, "no checkpoint staged"))),
         }
     }
 
     /*
-    Total Segments: Gets the total number of log segments on disk
+    Total Segments: Gets the total number of log segments on disk for the default family
     */
     pub fn total_segments(&self) -> usize {
-        self.log_segments.len()
+        self.families[DEFAULT_FAMILY].total_segments()
     }
 }
 
-fn get_tree_from_segment(segment: &mut DiskSegment) -> &LogSegment<String> {
-    match segment {
-        OpenSegment{path_s: _, file: _, tree} => {tree},
-        ClosedSegment{path_s, file} => {
-            let tree = read_segment(Path::new(path_s));
-            *segment = OpenSegment{path_s: path_s.to_string(), file: file.try_clone().unwrap(), tree: tree};
-            if let OpenSegment { path_s: _, file: _, tree } = segment {
-                tree
-            }
-            else {
-                panic!("Failed to open disk segment");
-            }
+/*
+Column Family Handle: One column family of an LSM, borrowed from it for reading and writing
+just that family. Writes go through the LSM's WAL, the same as writes to the LSM itself,
+which go to the default family
+*/
+pub struct ColumnFamilyHandle<'a> {
+    lsm: &'a mut LsmTree,
+    family: u32,
+}
+
+impl ColumnFamilyHandle<'_> {
+    pub fn name(&self) -> &str {
+        self.family().name()
+    }
+
+    fn family(&self) -> &ColumnFamily {
+        &self.lsm.families[self.family as usize]
+    }
+
+    fn family_mut(&mut self) -> &mut ColumnFamily {
+        &mut self.lsm.families[self.family as usize]
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Option<Vec<u8>> {
+        self.family_mut().get(key.as_ref())
+    }
+
+    pub fn get_str(&mut self, key: &str) -> Option<String> {
+        self.get(key).map(|value| String::from_utf8_lossy(&value).into_owned())
+    }
+
+    pub fn multi_get<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        self.family_mut().multi_get(keys)
+    }
+
+    pub fn iter(&mut self) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.family_mut().iter()
+    }
+
+    pub fn iter_from(&mut self, key: &[u8]) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.family_mut().iter_from(key)
+    }
+
+    pub fn entries(&mut self) -> impl Iterator<Item = KVPair<'static>> + '_ {
+        self.family_mut().entries()
+    }
+
+    pub fn write<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<(), LsmError> {
+        self.commit(WalRecord::Put{key: key.as_ref().to_vec(), value: value.as_ref().to_vec(), expires_at: None})
+    }
+
+    pub fn write_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V, ttl: Duration) -> Result<(), LsmError> {
+        let expires_at = self.family().expires_at(ttl);
+        self.commit(WalRecord::Put{key: key.as_ref().to_vec(), value: value.as_ref().to_vec(), expires_at: Some(expires_at)})
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<(), LsmError> {
+        self.commit(WalRecord::Delete{key: key.as_ref().to_vec()})
+    }
+
+    pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, operand: V) -> Result<(), LsmError> {
+        match self.family().merge_record(key.as_ref(), operand.as_ref(), None) {
+            Some(record) => self.commit(record),
+            None => Err(LsmError::NoMergeOperator(self.name().to_string())),
+        }
+    }
+
+    pub fn compare_and_swap<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, expected: Option<&[u8]>, new: V) -> Result<bool, LsmError> {
+        let record = WalRecord::Put{key: key.as_ref().to_vec(), value: new.as_ref().to_vec(), expires_at: None};
+        self.commit_if(expected, record)
+    }
+
+    pub fn put_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<bool, LsmError> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    pub fn delete_if_equals<K: AsRef<[u8]>>(&mut self, key: K, expected: &[u8]) -> Result<bool, LsmError> {
+        self.commit_if(Some(expected), WalRecord::Delete{key: key.as_ref().to_vec()})
+    }
+
+    fn commit_if(&mut self, expected: Option<&[u8]>, record: WalRecord) -> Result<bool, LsmError> {
+        if self.get(record.key()).as_deref() != expected {
+            return Ok(false);
+        }
+        self.commit(record)?;
+        Ok(true)
+    }
+
+    fn commit(&mut self, record: WalRecord) -> Result<(), LsmError> {
+        self.lsm.commit(vec![(self.family, record)])
+    }
+
+    /*
+    Compact: Flushes the memtables, which the families only do together, then compacts
+    this family's segments
+    */
+    pub fn compact(&mut self) -> Result<(), LsmError> {
+        if self.lsm.read_only {
+            return Err(LsmError::ReadOnly);
         }
+        self.lsm.flush();
+        self.lsm.lock_files(false)?;
+        let result = self.family_mut().compact();
+        self.lsm.unlock_files();
+        Ok(result?)
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.family().total_size()
+    }
+
+    pub fn total_segments(&self) -> usize {
+        self.family().total_segments()
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.family().num_entries()
     }
 }
 
-fn read_segment<P: AsRef<Path>>(path: P) -> LogSegment<String> {
-    let mut root = LogSegment::new();
-    if let Ok(lines) = read_lines(path) {
-        for line in lines {
-            if let Ok(line) = line {
-                let mut tuple = line.split(" ");
-                let key = tuple.next().unwrap();
-                if let Some(value) = tuple.next() {
-                    root.insert((key.to_string(), value.to_string()));
-                }
-                else {
-                    root.delete(key.to_string());
-                }
+/*
+Take Batches: Adds the logged commits that follow on from the batches, or from the sequence
+number if there are none yet, counting the bytes each took in the log. Returns false once
+there are about max_bytes of them, or a commit is missing, as the commits must be in order
+with none skipped. A crash part way through archiving can leave commits in both the archive
+and the WAL, so those already taken are passed over
+*/
+fn take_batches(batches: &mut Vec<SequencedBatch>, bytes: &mut usize, logged: Vec<(usize, SequencedBatch)>, valid_len: usize, sequence: u64, max_bytes: usize) -> bool {
+    let ends: Vec<usize> = logged.iter().skip(1).map(|(offset, _)| *offset).chain([valid_len]).collect();
+    for ((offset, batch), end) in logged.into_iter().zip(ends) {
+        let last = batches.last().map_or(sequence, |last| last.sequence);
+        if *bytes >= max_bytes || batch.sequence > last + 1 {
+            return false;
+        }
+        if batch.sequence == last + 1 {
+            *bytes += end - offset;
+            batches.push(batch);
+        }
+    }
+    true
+}
+
+/*
+Archive WAL: Copies the WAL, about to be truncated, into the WAL archive, named by the
+sequence number of its first commit, then deletes the oldest archived WALs until the rest
+take up no more than max_size bytes. The newest is always kept
+*/
+fn archive_wal(name: &str, first_sequence: u64, max_size: u64) -> io::Result<()> {
+    let dir = get_wal_archive_dir(name);
+    if !dir.is_dir() {
+        fs::create_dir(&dir)?;
+    }
+    let path = get_wal_archive_path(name, first_sequence);
+    let tmp_path = path.with_extension("tmp");
+    fs::copy(get_wal_path(name), &tmp_path)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, path)?;
+
+    let archives = list_wal_archives(name)?;
+    let mut total: u64 = archives.iter().map(|(_, path)| fs::metadata(path).map_or(0, |metadata| metadata.len())).sum();
+    for (_, path) in &archives[..archives.len() - 1] {
+        if total <= max_size {
+            break;
+        }
+        total -= fs::metadata(path)?.len();
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/*
+Check Manifest: Segments can only be searched in the order they were written in, so a DB
+only opens with the comparator it was created with, and each column family with its own.
+Every column family the DB has must be opened along with it, or writes to it in the WAL
+couldn't be replayed. A column family once opened with a merge operator may hold operands,
+so it must be opened with one again. DBs from before manifests were written are all
+bytewise, and get a manifest the first time they are opened
+*/
+fn check_manifest(name: &str, options: &LsmOptions, column_families: &[(&str, LsmOptions)]) -> Result<Manifest, LsmError> {
+    let requested = options.comparator.name();
+    let manifest = match Manifest::read(name)? {
+        Some(manifest) => manifest,
+        None => {
+            let manifest = Manifest{comparator: BytewiseComparator.name().to_string(), column_families: Vec::new(), merge_operators: Vec::new()};
+            if requested == manifest.comparator && !options.read_only {
+                manifest.write(name)?;
             }
+            manifest
+        }
+    };
+    check_comparator(&manifest.comparator, requested)?;
+    for (family, comparator) in &manifest.column_families {
+        match column_families.iter().find(|(name, _)| name == family) {
+            Some((_, options)) => check_comparator(comparator, options.comparator.name())?,
+            None => return Err(LsmError::ColumnFamilyNotOpened(family.clone())),
+        }
+    }
+    for (family, _) in &manifest.merge_operators {
+        let options = match column_families.iter().find(|(name, _)| name == family) {
+            Some((_, options)) => options,
+            None => options,
+        };
+        if options.merge_operator.is_none() {
+            return Err(LsmError::NoMergeOperator(family.clone()));
         }
     }
-    root
+    Ok(manifest)
 }
 
-// Generic read lines helper, returns a Result which on success is an iterator over
-// the lines of a BufReader, where the BufReader consumes the result of opening file
-pub fn read_lines<P: AsRef<Path>>(path: P) -> Result<Lines<BufReader<File>>>
-{
-    let input = File::open(path).expect("Unable to open up input file");
-    Ok(BufReader::new(input).lines())
-}
\ No newline at end of file
+// Name of each column family with a merge operator and the operator's name, as kept in the manifest
+fn merge_operators(families: &[ColumnFamily]) -> Vec<(String, String)> {
+    families.iter()
+        .filter_map(|family| family.options().merge_operator.as_ref().map(|operator| (family.name().to_string(), operator.name().to_string())))
+        .collect()
+}
+
+fn check_comparator(persisted: &str, requested: &str) -> Result<(), LsmError> {
+    if persisted != requested {
+        return Err(LsmError::ComparatorMismatch{persisted: persisted.to_string(), requested: requested.to_string()});
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::{fs::{rename, File}, io::{ErrorKind, Read, Result, Write}};
+
+use crate::storage::{coding::corruption, files::get_manifest_path};
+
+const PROP_COMPARATOR: &str = "comparator";
+const PROP_COLUMN_FAMILY: &str = "column_family";
+const PROP_MERGE_OPERATOR: &str = "merge_operator";
+
+/*
+Manifest: Settings fixed when a DB is created, that it can't be opened without. Stored as
+one "name: value" line per setting in the DB's MANIFEST file, with a "column_family: name
+comparator" line for each column family and a "merge_operator: family operator" line for
+each column family, default included, opened with a merge operator
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    // Comparator of the default column family
+    pub comparator: String,
+    // Name and comparator of each other column family, in the order they were created,
+    // which is what their ids in the WAL go by
+    pub column_families: Vec<(String, String)>,
+    // Name of each column family opened with a merge operator and the operator's name, as
+    // the family may hold operands that can't be read without one
+    pub merge_operators: Vec<(String, String)>,
+}
+
+impl Manifest {
+    // Reads the manifest for the DB, None for DBs created before manifests were written
+    pub fn read(name: &str) -> Result<Option<Manifest>> {
+        let mut contents = String::new();
+        match File::open(get_manifest_path(name)) {
+            Ok(mut file) => file.read_to_string(&mut contents)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Manifest::decode(&contents).map(Some)
+    }
+
+    // Parses the manifest from the contents of a MANIFEST file, see encode
+    pub fn decode(contents: &str) -> Result<Manifest> {
+        let mut comparator = None;
+        let mut column_families = Vec::new();
+        let mut merge_operators = Vec::new();
+        for line in contents.lines() {
+            match line.split_once(": ") {
+                Some((PROP_COMPARATOR, value)) => comparator = Some(value.to_string()),
+                Some((PROP_COLUMN_FAMILY, value)) => match value.split_once(' ') {
+                    Some((name, comparator)) => column_families.push((name.to_string(), comparator.to_string())),
+                    None => return Err(corruption("bad column family in manifest")),
+                },
+                Some((PROP_MERGE_OPERATOR, value)) => match value.split_once(' ') {
+                    Some((family, operator)) => merge_operators.push((family.to_string(), operator.to_string())),
+                    None => return Err(corruption("bad merge operator in manifest")),
+                },
+                Some(_) => {},
+                None => return Err(corruption("bad line in manifest")),
+            }
+        }
+        match comparator {
+            Some(comparator) => Ok(Manifest{comparator, column_families, merge_operators}),
+            None => Err(corruption("manifest is missing the comparator")),
+        }
+    }
+
+    // The contents of the MANIFEST file for the manifest
+    pub fn encode(&self) -> String {
+        let mut contents = format!("{}: {}\n", PROP_COMPARATOR, self.comparator);
+        for (name, comparator) in &self.column_families {
+            contents.push_str(&format!("{}: {} {}\n", PROP_COLUMN_FAMILY, name, comparator));
+        }
+        for (family, operator) in &self.merge_operators {
+            contents.push_str(&format!("{}: {} {}\n", PROP_MERGE_OPERATOR, family, operator));
+        }
+        contents
+    }
+
+    /*
+    Write: Replaces the DB's manifest. The new manifest is synced to a temporary file which
+    is then renamed over the old one, so a crash leaves either the old or the new manifest
+    */
+    pub fn write(&self, name: &str) -> Result<()> {
+        let path = get_manifest_path(name);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(self.encode().as_bytes())?;
+        file.sync_all()?;
+        rename(tmp_path, path)
+    }
+}
@@ -0,0 +1,88 @@
+use std::{cmp::Ordering, io::Result, iter::Peekable, sync::Arc};
+
+use crate::storage::{comparator::Comparator, merge_operator::{fold_operands, MergeOperator}, segment::Entry, tree::TriOption::*};
+
+/*
+Merging Iter: Merges entry iterators sorted by the comparator into one sorted iterator,
+yielding each key once. Sources are given newest first, so when several hold the same key the entry from
+the earliest source wins and the older entries for it are skipped. Merge operands are
+the exception, they are folded over the older entries for the key, so the sources must
+include every entry older than them
+*/
+pub struct MergingIter<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = Result<Entry>> + 'a>>>,
+    comparator: Arc<dyn Comparator>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    // Time values are checked for expiry at, when folding operands over them
+    now: u64,
+}
+
+impl<'a> MergingIter<'a> {
+    pub fn new(sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>, comparator: Arc<dyn Comparator>) -> MergingIter<'a> {
+        MergingIter{sources: sources.into_iter().map(|source| source.peekable()).collect(), comparator, merge_operator: None, now: 0}
+    }
+
+    pub fn with_merge_operator(mut self, operator: Option<Arc<dyn MergeOperator>>, now: u64) -> MergingIter<'a> {
+        self.merge_operator = operator;
+        self.now = now;
+        self
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        // Find the smallest key at the head of any source, the newest source wins ties
+        let mut newest: Option<(usize, Vec<u8>)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok(entry)) if newest.as_ref().is_none_or(|(_, smallest)| self.comparator.compare(&entry.key, smallest) == Ordering::Less) => {
+                    newest = Some((i, entry.key.clone()));
+                },
+                Some(Err(_)) => return Err(source.next().unwrap().unwrap_err()),
+                _ => {},
+            }
+        }
+
+        let mut entry = match newest {
+            Some((i, _)) => self.sources[i].next().unwrap()?,
+            None => return Ok(None),
+        };
+
+        // Skip over the shadowed entries for the same key in older sources, unless
+        // there are merge operands still to fold over them
+        for source in self.sources.iter_mut() {
+            while matches!(source.peek(), Some(Ok(older)) if self.comparator.compare(&older.key, &entry.key) == Ordering::Equal) {
+                let older = source.next().unwrap()?;
+                if let TriMerge(operands) = &mut entry.value {
+                    entry = match older.value {
+                        TriMerge(mut older_operands) => {
+                            older_operands.append(operands);
+                            Entry::new(entry.key, TriMerge(older_operands), None)
+                        },
+                        TriSome(ref base) if !older.is_expired(self.now) => {
+                            let value = fold_operands(self.merge_operator.as_ref(), &entry.key, Some(base), operands)?;
+                            Entry::new(entry.key, TriSome(value), older.expires_at)
+                        },
+                        _ => {
+                            let value = fold_operands(self.merge_operator.as_ref(), &entry.key, None, operands)?;
+                            Entry::new(entry.key, TriSome(value), None)
+                        },
+                    };
+                }
+            }
+        }
+
+        // Nothing older is left for the operands to apply to
+        if let TriMerge(operands) = &entry.value {
+            let value = fold_operands(self.merge_operator.as_ref(), &entry.key, None, operands)?;
+            entry = Entry::new(entry.key, TriSome(value), None);
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for MergingIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
@@ -0,0 +1,80 @@
+use std::{io::{Error, Result}, sync::Arc};
+
+/*
+Merge Operator: Folds merge operands written with LsmTree::merge over a key's value, for
+read-modify-write updates (counters, lists) that don't need to read the value first.
+Operands are kept as they are written until a read, or a compaction, meets the value
+they apply to, then full_merge is called with that value (None if the key has none)
+and every operand written since, oldest first. Must be deterministic, since the same
+operands may be folded again by later reads until compaction collapses them
+*/
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+}
+
+/*
+U64 Add Operator: Values and operands are unsigned 64 bit integers (8 bytes little-endian)
+that are added together, wrapping on overflow. Anything that isn't 8 bytes counts as 0
+*/
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn decode(bytes: &[u8]) -> u64 {
+        bytes.try_into().map_or(0, u64::from_le_bytes)
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &str {
+        "u64_add"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+        let base = existing.map_or(0, U64AddOperator::decode);
+        let sum = operands.iter().fold(base, |sum, operand| sum.wrapping_add(U64AddOperator::decode(operand)));
+        sum.to_le_bytes().to_vec()
+    }
+}
+
+/*
+Append Operator: Appends each operand to the value, with the delimiter in between
+*/
+pub struct AppendOperator {
+    delimiter: Vec<u8>,
+}
+
+impl AppendOperator {
+    pub fn new(delimiter: &[u8]) -> AppendOperator {
+        AppendOperator{delimiter: delimiter.to_vec()}
+    }
+}
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+        let mut value = existing.map_or_else(Vec::new, |existing| existing.to_vec());
+        for (i, operand) in operands.iter().enumerate() {
+            if existing.is_some() || i > 0 {
+                value.extend_from_slice(&self.delimiter);
+            }
+            value.extend_from_slice(operand);
+        }
+        value
+    }
+}
+
+/*
+Fold Operands: Folds the operands over the base value with the operator, failing if
+there isn't one, e.g. when a DB with merges in it is opened without its merge operator
+*/
+pub fn fold_operands(operator: Option<&Arc<dyn MergeOperator>>, key: &[u8], base: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>> {
+    match operator {
+        Some(operator) => Ok(operator.full_merge(key, base, operands)),
+        None => Err(Error::other("found merge operands but no merge operator is configured")),
+    }
+}
@@ -0,0 +1,72 @@
+use std::{fs::File, io::{Error, ErrorKind, Result}};
+
+/*
+Mmap: Read-only mapping of a whole file. Only used for segments, which are never written
+again once finished, so the mapped bytes can't change underneath readers. Mapping is only
+supported on 64 bit unix, elsewhere (or when mmap fails) callers fall back to file reads
+*/
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// The mapping is read-only and lives until drop, so it can be read from any thread
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_PRIVATE: c_int = 2;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl Mmap {
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    pub fn map(file: &File) -> Result<Mmap> {
+        use std::{os::unix::io::AsRawFd, ptr};
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "cannot map an empty file"));
+        }
+        let ptr = unsafe { sys::mmap(ptr::null_mut(), len, sys::PROT_READ, sys::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == sys::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(Mmap{ptr: ptr as *const u8, len})
+    }
+
+    #[cfg(not(all(unix, target_pointer_width = "64")))]
+    pub fn map(_file: &File) -> Result<Mmap> {
+        Err(Error::new(ErrorKind::Unsupported, "mmap reads are not supported on this platform"))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
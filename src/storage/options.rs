@@ -0,0 +1,189 @@
+use std::sync::{atomic::{AtomicUsize, Ordering::*}, Arc};
+
+use crate::storage::{cache::BlockCache, clock::{Clock, SystemClock}, comparator::{BytewiseComparator, Comparator}, compression::{Compressor, LzCompressor, NoCompression}, merge_operator::MergeOperator};
+
+// Default memtable budget before it is flushed to a new disk segment
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+// Default target size of the data blocks in a segment, the unit a point lookup reads
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+
+// Default bloom filter size, about a 1% false positive rate
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
+// Default capacity of the block cache created for an LSM that isn't given one
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024;
+
+/*
+LSM Options: Tunables for an LSM, passed when it is created or restored
+*/
+#[derive(Clone)]
+pub struct LsmOptions {
+    // Approximate bytes the memtable may hold before it is flushed to disk
+    pub write_buffer_size: usize,
+    // Optional limit on the memtable bytes across every LSM sharing the manager
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    // Approximate size of the data blocks segments are split into
+    pub block_size: usize,
+    // Bits of bloom filter per key in each segment, 0 to write segments without filters
+    pub bloom_bits_per_key: usize,
+    // Cache for segment blocks, may be shared between LSMs, None to always read from the file
+    pub block_cache: Option<Arc<BlockCache>>,
+    // Keep each open segment's index and filter blocks in memory, rather than in the block cache
+    pub pin_index_and_filter_blocks: bool,
+    // Codec for data blocks of segments at each level, the last one applies to any deeper
+    // levels. Custom codecs must be listed here to read segments written with them
+    pub compression_per_level: Vec<Arc<dyn Compressor>>,
+    // Serve segment reads from a memory mapping of the file rather than reading it
+    pub use_mmap_reads: bool,
+    // Order keys are kept in, fixed when the DB is created
+    pub comparator: Arc<dyn Comparator>,
+    // Time source deciding when entries written with a TTL expire
+    pub clock: Arc<dyn Clock>,
+    // Bytes of disk segments to keep, past which the oldest segments are evicted, None to keep everything
+    pub max_total_size: Option<u64>,
+    // Folds merge operands over values, required to read keys written with merge
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    // Bytes of flushed WALs to keep for LsmTree::updates_since, past which the oldest are
+    // deleted, None to truncate the WAL on flush. Only the default family's options count
+    pub wal_archive_size: Option<u64>,
+    // Open the DB only to read it, see LsmTree::catch_up. Only the default family's options count
+    pub read_only: bool,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions{
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            write_buffer_manager: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            block_cache: Some(Arc::new(BlockCache::new(DEFAULT_BLOCK_CACHE_SIZE))),
+            pin_index_and_filter_blocks: true,
+            // Flushes stay fast by leaving L0 uncompressed, compaction output is compressed
+            compression_per_level: vec![Arc::new(NoCompression), Arc::new(LzCompressor)],
+            use_mmap_reads: false,
+            comparator: Arc::new(BytewiseComparator),
+            clock: Arc::new(SystemClock),
+            max_total_size: None,
+            merge_operator: None,
+            wal_archive_size: None,
+            read_only: false}
+    }
+}
+
+impl LsmOptions {
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    pub fn write_buffer_manager(mut self, manager: Arc<WriteBufferManager>) -> Self {
+        self.write_buffer_manager = Some(manager);
+        self
+    }
+
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    pub fn block_cache(mut self, cache: Option<Arc<BlockCache>>) -> Self {
+        self.block_cache = cache;
+        self
+    }
+
+    pub fn pin_index_and_filter_blocks(mut self, pin: bool) -> Self {
+        self.pin_index_and_filter_blocks = pin;
+        self
+    }
+
+    pub fn compression_per_level(mut self, compressors: Vec<Arc<dyn Compressor>>) -> Self {
+        self.compression_per_level = compressors;
+        self
+    }
+
+    pub fn use_mmap_reads(mut self, mmap: bool) -> Self {
+        self.use_mmap_reads = mmap;
+        self
+    }
+
+    pub fn comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn max_total_size(mut self, bytes: Option<u64>) -> Self {
+        self.max_total_size = bytes;
+        self
+    }
+
+    pub fn merge_operator(mut self, operator: Arc<dyn MergeOperator>) -> Self {
+        self.merge_operator = Some(operator);
+        self
+    }
+
+    pub fn wal_archive_size(mut self, bytes: Option<u64>) -> Self {
+        self.wal_archive_size = bytes;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn compression_for_level(&self, level: usize) -> Arc<dyn Compressor> {
+        match self.compression_per_level.get(level).or(self.compression_per_level.last()) {
+            Some(compressor) => compressor.clone(),
+            None => Arc::new(NoCompression),
+        }
+    }
+}
+
+/*
+Write Buffer Manager: Tracks memtable memory across LSMs sharing it, so the store as a
+whole stays under one budget. Each LSM reserves what its memtable grows by and releases
+it again once the memtable is flushed, and flushes itself whenever the shared total is
+over the limit, even if its own memtable is under its write buffer size
+*/
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    memory_used: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    pub fn new(buffer_size: usize) -> WriteBufferManager {
+        WriteBufferManager{buffer_size, memory_used: AtomicUsize::new(0)}
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_used.load(Acquire)
+    }
+
+    pub fn reserve(&self, bytes: usize) {
+        self.memory_used.fetch_add(bytes, AcqRel);
+    }
+
+    pub fn free(&self, bytes: usize) {
+        self.memory_used.fetch_sub(bytes, AcqRel);
+    }
+
+    pub fn should_flush(&self) -> bool {
+        self.memory_usage() >= self.buffer_size
+    }
+}
@@ -0,0 +1,613 @@
+use std::{cmp::Ordering, fs::File, io::{BufWriter, Result, Write}, sync::Arc};
+
+use crate::log;
+use crate::storage::{block::{Block, BlockBuilder, BlockIter}, cache::BlockCache, coding::*, clock::Clock, comparator::Comparator, compression::{compress_block, decompress_block, Compressor, NoCompression}, filter::{bloom_hash, key_may_match, BloomFilterPolicy}, mmap::Mmap, options::LsmOptions, stats::{Statistics, Ticker}, tree::TriOption::{self, *}};
+
+// Entry kinds, stored as the first byte of each entry's value in a data block
+const KIND_DELETION: u8 = 0;
+const KIND_VALUE: u8 = 1;
+// Value written with a TTL, the kind is followed by its expiry as fixed 64 bit millis
+const KIND_EXPIRING_VALUE: u8 = 2;
+// Merge operands, the kind is followed by the count of them and each one length prefixed
+const KIND_MERGE: u8 = 3;
+
+// Footer is the handles of the meta and index blocks as fixed 64 bit offset/size pairs,
+// then the magic number, so it can be found at a fixed distance from the end of the file
+pub const FOOTER_SIZE: usize = 40;
+const SEGMENT_MAGIC: u64 = 0x7365_6966_6c62_6f73;
+// Magic number of segments written before blocks were framed with a checksum
+const UNFRAMED_SEGMENT_MAGIC: u64 = 0x7365_6966_6c62_6f72;
+
+const PROP_NUM_ENTRIES: &str = "num_entries";
+const PROP_NUM_DELETIONS: &str = "num_deletions";
+const PROP_NUM_DATA_BLOCKS: &str = "num_data_blocks";
+const PROP_LEVEL: &str = "level";
+
+// Meta block entry holding the handle of the filter block, when the segment has one
+const META_FILTER: &str = "filter.bloom";
+
+/*
+Entry: A key and its value (or tombstone) as stored in a segment, along with when the
+value expires, in milliseconds since the epoch, if it was written with a TTL
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: TriOption<Vec<u8>>,
+    pub expires_at: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(key: Vec<u8>, value: TriOption<Vec<u8>>, expires_at: Option<u64>) -> Entry {
+        Entry{key, value, expires_at}
+    }
+
+    /*
+    Live Value: The value as of now. An expired value reads as a tombstone rather than as
+    missing, since it still shadows any older value for the key in older segments
+    */
+    pub fn live_value(self, now: u64) -> TriOption<Vec<u8>> {
+        match self.is_expired(now) {
+            true => Tombstoned,
+            false => self.value,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/*
+Block Handle: Location of a block within a segment file
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        put_varint64(dst, self.offset);
+        put_varint64(dst, self.size);
+    }
+
+    pub fn decode_from(src: &[u8]) -> Result<BlockHandle> {
+        let (offset, n) = get_varint64(src)?;
+        let (size, _) = get_varint64(&src[n..])?;
+        Ok(BlockHandle{offset, size})
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentProperties {
+    pub num_entries: u64,
+    pub num_deletions: u64,
+    pub num_data_blocks: u64,
+    // Level the segment was written to, 0 for segments flushed from the memtable
+    pub level: u64,
+}
+
+/*
+Segment Builder: Writes a sorted segment (SSTable) file laid out as
+
+    data block 0 | ... | data block n | filter block | meta block | index block | footer
+
+Entries are packed into data blocks of roughly block_size bytes. The index block maps the
+last key of each data block to that block's handle, so a point lookup only needs to read
+the one data block that could hold the key. The filter block is a bloom filter over all
+keys in the segment, so most lookups for keys not in the segment read no data block at
+all. The meta block holds the segment properties and the filter block's handle.
+Every block is framed with a checksum and its length, as WAL records are, and starts with a
+one byte header holding the id of the codec it is compressed with. Data blocks are compressed with the codec configured for the segment's level, the
+other blocks are small and read once per open, so are never compressed.
+Keys must be added in the order of the LSM's comparator, without duplicates.
+*/
+pub struct SegmentBuilder {
+    writer: BufWriter<File>,
+    offset: u64,
+    block_size: usize,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    last_key: Vec<u8>,
+    filter_policy: Option<BloomFilterPolicy>,
+    key_hashes: Vec<u32>,
+    compressor: Arc<dyn Compressor>,
+    properties: SegmentProperties,
+}
+
+impl SegmentBuilder {
+    pub fn new(file: File, options: &LsmOptions, level: usize) -> SegmentBuilder {
+        let filter_policy = match options.bloom_bits_per_key {
+            0 => None,
+            bits => Some(BloomFilterPolicy::new(bits)),
+        };
+        SegmentBuilder{
+            writer: BufWriter::new(file),
+            offset: 0,
+            block_size: options.block_size,
+            data_block: BlockBuilder::new(),
+            index_block: BlockBuilder::new(),
+            last_key: Vec::new(),
+            filter_policy,
+            key_hashes: Vec::new(),
+            compressor: options.compression_for_level(level),
+            properties: SegmentProperties{level: level as u64, ..Default::default()}}
+    }
+
+    // Tombstoned entries are written as a kind byte with no value, TriNone entries are skipped
+    pub fn add(&mut self, key: &[u8], value: TriOption<&[u8]>) -> Result<()> {
+        self.add_expiring(key, value, None)
+    }
+
+    // Same as add, for a value that expires at the given time, if any
+    pub fn add_expiring(&mut self, key: &[u8], value: TriOption<&[u8]>, expires_at: Option<u64>) -> Result<()> {
+        let mut entry = Vec::new();
+        match (value, expires_at) {
+            (TriSome(v), None) => {
+                entry.push(KIND_VALUE);
+                entry.extend_from_slice(v);
+            },
+            (TriSome(v), Some(expires_at)) => {
+                entry.push(KIND_EXPIRING_VALUE);
+                put_fixed64(&mut entry, expires_at);
+                entry.extend_from_slice(v);
+            },
+            (TriMerge(operands), _) => {
+                entry.push(KIND_MERGE);
+                put_varint32(&mut entry, operands.len() as u32);
+                for operand in operands {
+                    put_length_prefixed(&mut entry, operand);
+                }
+            },
+            (Tombstoned, _) => {
+                entry.push(KIND_DELETION);
+                self.properties.num_deletions += 1;
+            },
+            (TriNone, _) => return Ok(()),
+        }
+
+        self.data_block.add(key, &entry);
+        if self.filter_policy.is_some() {
+            self.key_hashes.push(bloom_hash(key));
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.properties.num_entries += 1;
+
+        if self.data_block.size_estimate() >= self.block_size {
+            self.flush_data_block()?;
+        }
+        Ok(())
+    }
+
+    /*
+    Finish: Writes out the last data block along with the meta and index blocks and the
+    footer, then syncs the file, after which the segment is immutable
+    */
+    pub fn finish(mut self) -> Result<SegmentProperties> {
+        self.flush_data_block()?;
+
+        let mut meta_block = BlockBuilder::new();
+        if let Some(policy) = &self.filter_policy {
+            let filter = policy.create_filter(&self.key_hashes);
+            let filter_handle = self.write_block(&filter, &NoCompression)?;
+            let mut encoded = Vec::new();
+            filter_handle.encode_to(&mut encoded);
+            meta_block.add(META_FILTER.as_bytes(), &encoded);
+        }
+        for (name, value) in [
+            (PROP_LEVEL, self.properties.level),
+            (PROP_NUM_DATA_BLOCKS, self.properties.num_data_blocks),
+            (PROP_NUM_DELETIONS, self.properties.num_deletions),
+            (PROP_NUM_ENTRIES, self.properties.num_entries)] {
+            let mut encoded = Vec::new();
+            put_varint64(&mut encoded, value);
+            meta_block.add(name.as_bytes(), &encoded);
+        }
+        let meta_handle = self.write_block(&meta_block.finish(), &NoCompression)?;
+        let index_block = self.index_block.finish();
+        let index_handle = self.write_block(&index_block, &NoCompression)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        for v in [meta_handle.offset, meta_handle.size, index_handle.offset, index_handle.size, SEGMENT_MAGIC] {
+            put_fixed64(&mut footer, v);
+        }
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(self.properties)
+    }
+
+    fn flush_data_block(&mut self) -> Result<()> {
+        if self.data_block.is_empty() {
+            return Ok(());
+        }
+        let data_block = self.data_block.finish();
+        let compressor = self.compressor.clone();
+        let handle = self.write_block(&data_block, compressor.as_ref())?;
+        let mut encoded = Vec::new();
+        handle.encode_to(&mut encoded);
+        self.index_block.add(&self.last_key, &encoded);
+        self.properties.num_data_blocks += 1;
+        Ok(())
+    }
+
+    fn write_block(&mut self, contents: &[u8], compressor: &dyn Compressor) -> Result<BlockHandle> {
+        let block = frame(&compress_block(compressor, contents));
+        let handle = BlockHandle{offset: self.offset, size: block.len() as u64};
+        self.writer.write_all(&block)?;
+        self.offset += block.len() as u64;
+        Ok(handle)
+    }
+}
+
+/*
+Segment Source: Where a segment's blocks are read from, either positional reads of the
+file, or a memory mapping of it when mmap reads are enabled and the file could be mapped
+*/
+enum SegmentSource {
+    Buffered{file: File, len: u64},
+    Mapped(Mmap),
+}
+
+impl SegmentSource {
+    fn len(&self) -> u64 {
+        match self {
+            SegmentSource::Buffered{file: _, len} => *len,
+            SegmentSource::Mapped(map) => map.len() as u64,
+        }
+    }
+
+    fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if offset.checked_add(len as u64).is_none_or(|end| end > self.len()) {
+            return Err(corruption("block handle points past end of segment"));
+        }
+        match self {
+            SegmentSource::Buffered{file, len: _} => {
+                let mut data = vec![0; len];
+                read_at(file, offset, &mut data)?;
+                Ok(data)
+            },
+            SegmentSource::Mapped(map) => Ok(map.as_slice()[offset as usize..offset as usize + len].to_vec()),
+        }
+    }
+}
+
+/*
+Segment Reader: Read side of a segment file. Only the properties are always held in memory.
+Data blocks go through the block cache, if there is one, or are read from the file as
+lookups need them. The index and filter blocks are pinned in memory by default, otherwise
+they go through the block cache too, so memory stays bounded however many segments are open
+*/
+pub struct SegmentReader {
+    source: SegmentSource,
+    cache: Option<Arc<BlockCache>>,
+    cache_id: u64,
+    stats: Arc<Statistics>,
+    index_handle: BlockHandle,
+    pinned_index: Option<Block>,
+    filter_handle: Option<BlockHandle>,
+    pinned_filter: Option<Arc<Vec<u8>>>,
+    compressors: Vec<Arc<dyn Compressor>>,
+    comparator: Arc<dyn Comparator>,
+    clock: Arc<dyn Clock>,
+    properties: SegmentProperties,
+}
+
+impl SegmentReader {
+    pub fn open(file: File, options: &LsmOptions, stats: Arc<Statistics>) -> Result<SegmentReader> {
+        let len = file.metadata()?.len();
+        let source = match options.use_mmap_reads {
+            true => match Mmap::map(&file) {
+                Ok(map) => SegmentSource::Mapped(map),
+                Err(e) => {
+                    // Mapping is only an optimization, the file can still be read as usual
+                    log(&format!("unable to mmap segment, falling back to file reads with error {}", e));
+                    stats.record(Ticker::MmapFallback, 1);
+                    SegmentSource::Buffered{file, len}
+                }
+            },
+            false => SegmentSource::Buffered{file, len},
+        };
+
+        let file_len = source.len();
+        if file_len < FOOTER_SIZE as u64 {
+            return Err(corruption("segment file too small for footer"));
+        }
+        let footer = source.read(file_len - FOOTER_SIZE as u64, FOOTER_SIZE)?;
+        match decode_fixed64(&footer[32..]) {
+            SEGMENT_MAGIC => {},
+            UNFRAMED_SEGMENT_MAGIC => return Err(corruption("segment was written by an older version without block checksums")),
+            _ => return Err(corruption("bad magic number in segment footer")),
+        }
+
+        let meta_handle = BlockHandle{offset: decode_fixed64(&footer), size: decode_fixed64(&footer[8..])};
+        let index_handle = BlockHandle{offset: decode_fixed64(&footer[16..]), size: decode_fixed64(&footer[24..])};
+        let compressors = options.compression_per_level.clone();
+        let meta = Block::new(read_block_contents(&source, &meta_handle, &compressors)?)?;
+
+        let mut properties = SegmentProperties::default();
+        let mut filter_handle = None;
+        for entry in meta.iter() {
+            let (name, value) = entry?;
+            if name == META_FILTER.as_bytes() {
+                filter_handle = Some(BlockHandle::decode_from(&value)?);
+                continue;
+            }
+            let (value, _) = get_varint64(&value)?;
+            match name.as_slice() {
+                n if n == PROP_LEVEL.as_bytes() => properties.level = value,
+                n if n == PROP_NUM_ENTRIES.as_bytes() => properties.num_entries = value,
+                n if n == PROP_NUM_DELETIONS.as_bytes() => properties.num_deletions = value,
+                n if n == PROP_NUM_DATA_BLOCKS.as_bytes() => properties.num_data_blocks = value,
+                _ => {},
+            }
+        }
+
+        let cache = options.block_cache.clone();
+        let cache_id = cache.as_ref().map_or(0, |cache| cache.new_id());
+        let mut reader = SegmentReader{
+            source,
+            cache,
+            cache_id,
+            stats,
+            index_handle,
+            pinned_index: None,
+            filter_handle,
+            pinned_filter: None,
+            compressors,
+            comparator: options.comparator.clone(),
+            clock: options.clock.clone(),
+            properties};
+
+        // Without a cache to hold them, index and filter blocks have to be pinned, or
+        // every lookup would read them from the file
+        if options.pin_index_and_filter_blocks || reader.cache.is_none() {
+            reader.pinned_index = Some(Block::new(reader.read_uncached(&index_handle)?)?);
+            if let Some(handle) = filter_handle {
+                reader.pinned_filter = Some(Arc::new(reader.read_uncached(&handle)?));
+            }
+        }
+        Ok(reader)
+    }
+
+    pub fn properties(&self) -> &SegmentProperties {
+        &self.properties
+    }
+
+    pub fn is_mmapped(&self) -> bool {
+        matches!(self.source, SegmentSource::Mapped(_))
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter_handle.is_some()
+    }
+
+    /*
+    Key May Match: Checks the segment's bloom filter, false means the key is definitely
+    not in the segment so there is no need to look for it. Segments written without a
+    filter (or whose filter can't be read) may hold any key
+    */
+    pub fn key_may_match(&self, key: &[u8]) -> bool {
+        match self.filter_block() {
+            Some(filter) => key_may_match(key, &filter),
+            None => true,
+        }
+    }
+
+    // Checks several keys against the bloom filter, only fetching the filter block once
+    pub fn keys_may_match(&self, keys: &[&[u8]]) -> Vec<bool> {
+        match self.filter_block() {
+            Some(filter) => keys.iter().map(|key| key_may_match(key, &filter)).collect(),
+            None => vec![true; keys.len()],
+        }
+    }
+
+    fn filter_block(&self) -> Option<Arc<Vec<u8>>> {
+        match (&self.pinned_filter, &self.filter_handle) {
+            (Some(filter), _) => Some(filter.clone()),
+            (None, Some(handle)) => self.read_cached(handle).ok(),
+            (None, None) => None,
+        }
+    }
+
+    /*
+    Get: Looks up the key in the index to find the only data block that could hold it,
+    then searches that one block. Expired values are returned as tombstones
+    */
+    pub fn get(&self, key: &[u8]) -> Result<TriOption<Vec<u8>>> {
+        let mut index_iter = self.index_block()?.iter();
+        index_iter.seek(key, self.comparator.as_ref())?;
+        let handle = match index_iter.next_entry()? {
+            Some((_, handle)) => BlockHandle::decode_from(&handle)?,
+            // Key is past the last key in the segment
+            None => return Ok(TriNone),
+        };
+
+        let block = Block::new(self.read_cached(&handle)?)?;
+        let mut block_iter = block.iter();
+        block_iter.seek(key, self.comparator.as_ref())?;
+        match block_iter.next_entry()? {
+            Some((k, entry)) if k == key => Ok(decode_entry(k, &entry)?.live_value(self.clock.now_millis())),
+            _ => Ok(TriNone),
+        }
+    }
+
+    /*
+    Multi Get: Looks up keys given in comparator order with a single pass over the index,
+    so each data block is read at most once, however many of the keys it holds. Returns a
+    result per key, in the same order
+    */
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<TriOption<Vec<u8>>>> {
+        let mut results = Vec::with_capacity(keys.len());
+        let now = self.clock.now_millis();
+        let mut index_iter = self.index_block()?.iter();
+        // Last key and handle of the data block the keys have reached, read only once a key lands in it
+        let mut current: Option<(Vec<u8>, BlockHandle, Option<Block>)> = None;
+        for key in keys {
+            while current.as_ref().is_none_or(|(last_key, _, _)| self.comparator.compare(last_key, key) == Ordering::Less) {
+                match index_iter.next_entry()? {
+                    Some((last_key, handle)) => current = Some((last_key, BlockHandle::decode_from(&handle)?, None)),
+                    None => {
+                        current = None;
+                        break;
+                    }
+                }
+            }
+            let (_, handle, block) = match &mut current {
+                Some(current) => current,
+                // Key is past the last key in the segment, and so are the rest
+                None => {
+                    results.push(TriNone);
+                    continue;
+                }
+            };
+
+            if block.is_none() {
+                *block = Some(Block::new(self.read_cached(handle)?)?);
+            }
+            let mut block_iter = block.as_ref().unwrap().iter();
+            block_iter.seek(key, self.comparator.as_ref())?;
+            results.push(match block_iter.next_entry()? {
+                Some((k, entry)) if k == *key => decode_entry(k, &entry)?.live_value(now),
+                _ => TriNone,
+            });
+        }
+        Ok(results)
+    }
+
+    /*
+    Iter: Iterates every entry in the segment in key order, tombstones and expired values
+    included. Blocks are
+    read straight from the file, a full pass over a segment (e.g. to compact it) would
+    otherwise push everything else out of the block cache
+    */
+    pub fn iter(&self) -> Result<SegmentIter<'_>> {
+        Ok(SegmentIter{reader: self, index_iter: self.index_block()?.iter(), data_iter: None})
+    }
+
+    // Same as iter, starting from the first entry with a key >= the given one
+    pub fn iter_from(&self, key: &[u8]) -> Result<SegmentIter<'_>> {
+        let mut index_iter = self.index_block()?.iter();
+        index_iter.seek(key, self.comparator.as_ref())?;
+        let data_iter = match index_iter.next_entry()? {
+            Some((_, handle)) => {
+                let mut data_iter = Block::new(self.read_uncached(&BlockHandle::decode_from(&handle)?)?)?.iter();
+                data_iter.seek(key, self.comparator.as_ref())?;
+                Some(data_iter)
+            },
+            None => None,
+        };
+        Ok(SegmentIter{reader: self, index_iter, data_iter})
+    }
+
+    fn index_block(&self) -> Result<Block> {
+        match &self.pinned_index {
+            Some(index) => Ok(index.clone()),
+            None => Block::new(self.read_cached(&self.index_handle)?),
+        }
+    }
+
+    // Reads a block through the block cache, filling the cache on a miss
+    fn read_cached(&self, handle: &BlockHandle) -> Result<Arc<Vec<u8>>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(Arc::new(self.read_uncached(handle)?)),
+        };
+
+        let key = (self.cache_id, handle.offset);
+        if let Some(block) = cache.lookup(&key) {
+            self.stats.record(Ticker::BlockCacheHit, 1);
+            return Ok(block);
+        }
+        self.stats.record(Ticker::BlockCacheMiss, 1);
+        let block = Arc::new(self.read_uncached(handle)?);
+        cache.insert(key, block.clone());
+        Ok(block)
+    }
+
+    fn read_uncached(&self, handle: &BlockHandle) -> Result<Vec<u8>> {
+        read_block_contents(&self.source, handle, &self.compressors)
+    }
+}
+
+pub struct SegmentIter<'a> {
+    reader: &'a SegmentReader,
+    index_iter: BlockIter,
+    data_iter: Option<BlockIter>,
+}
+
+impl SegmentIter<'_> {
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        loop {
+            if let Some(data_iter) = &mut self.data_iter {
+                if let Some((key, entry)) = data_iter.next_entry()? {
+                    return Ok(Some(decode_entry(key, &entry)?));
+                }
+            }
+            match self.index_iter.next_entry()? {
+                Some((_, handle)) => {
+                    let handle = BlockHandle::decode_from(&handle)?;
+                    self.data_iter = Some(Block::new(self.reader.read_uncached(&handle)?)?.iter());
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for SegmentIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+fn decode_entry(key: Vec<u8>, entry: &[u8]) -> Result<Entry> {
+    match entry.first() {
+        Some(&KIND_VALUE) => Ok(Entry::new(key, TriSome(entry[1..].to_vec()), None)),
+        Some(&KIND_EXPIRING_VALUE) if entry.len() >= 9 => {
+            Ok(Entry::new(key, TriSome(entry[9..].to_vec()), Some(decode_fixed64(&entry[1..]))))
+        },
+        Some(&KIND_DELETION) => Ok(Entry::new(key, Tombstoned, None)),
+        Some(&KIND_MERGE) => {
+            let (count, mut pos) = get_varint32(&entry[1..])?;
+            pos += 1;
+            let mut operands = Vec::new();
+            for _ in 0..count {
+                let (operand, n) = get_length_prefixed(&entry[pos..])?;
+                operands.push(operand.to_vec());
+                pos += n;
+            }
+            Ok(Entry::new(key, TriMerge(operands), None))
+        },
+        _ => Err(corruption("unknown entry kind in data block")),
+    }
+}
+
+// Reads the block and checks its checksum, then strips its header, decompressing the contents if need be
+fn read_block_contents(source: &SegmentSource, handle: &BlockHandle, compressors: &[Arc<dyn Compressor>]) -> Result<Vec<u8>> {
+    let data = source.read(handle.offset, handle.size as usize)?;
+    match read_frame(&data) {
+        Some((block, len)) if len == data.len() => decompress_block(compressors, block),
+        _ => Err(corruption("block checksum mismatch in segment")),
+    }
+}
+
+// Positional read, so lookups don't need to share a file cursor
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
@@ -0,0 +1,218 @@
+use std::{cmp::Ordering, marker::PhantomData, ptr, sync::{atomic::{AtomicPtr, AtomicUsize, Ordering::*}, Mutex}};
+
+const MAX_HEIGHT: usize = 12;
+
+// Each level up holds roughly 1 in BRANCHING of the nodes of the level below it
+const BRANCHING: u64 = 4;
+
+struct Node<K, V> {
+    key: K,
+    value: AtomicPtr<V>,
+    next: Box<[AtomicPtr<Node<K, V>>]>,
+}
+
+impl<K, V> Node<K, V> {
+    fn alloc(key: K, value: V, height: usize) -> *mut Node<K, V> {
+        let next = (0..height).map(|_| AtomicPtr::new(ptr::null_mut())).collect();
+        Box::into_raw(Box::new(Node{key, value: AtomicPtr::new(Box::into_raw(Box::new(value))), next}))
+    }
+}
+
+// State only touched while holding the writer lock
+struct WriterState<V> {
+    rng: u64,
+    retired: Vec<*mut V>,
+}
+
+// Orders the keys of a skip list
+pub type KeyComparator<K> = Box<dyn Fn(&K, &K) -> Ordering + Send + Sync>;
+
+/*
+Skip List: Ordered map used as the memtable, ordered by the keys' Ord or a given comparator. Writers are serialized by an internal lock,
+while readers never lock: nodes are published with release stores once fully initialized,
+and neither nodes nor replaced values are freed until the list itself is dropped, so any
+reference handed to a reader stays valid for the lifetime of the list.
+*/
+pub struct SkipList<K, V> {
+    head: [AtomicPtr<Node<K, V>>; MAX_HEIGHT],
+    height: AtomicUsize,
+    len: AtomicUsize,
+    compare: KeyComparator<K>,
+    writer: Mutex<WriterState<V>>,
+}
+
+// Raw pointers opt us out of Send/Sync, but all shared mutation goes through atomics
+// (readers) or the writer lock (writers), so the list is as thread-safe as its contents
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for SkipList<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for SkipList<K, V> {}
+
+impl<K: Ord + 'static, V> SkipList<K, V> {
+    pub fn new() -> SkipList<K, V> {
+        SkipList::with_comparator(Box::new(K::cmp))
+    }
+}
+
+impl<K, V> SkipList<K, V> {
+    pub fn with_comparator(compare: KeyComparator<K>) -> SkipList<K, V> {
+        SkipList{
+            head: Default::default(),
+            height: AtomicUsize::new(1),
+            len: AtomicUsize::new(0),
+            compare,
+            writer: Mutex::new(WriterState{rng: 0x2545_F491_4F6C_DD1D, retired: Vec::new()})}
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /*
+    Insert: Adds the key to the list, or replaces its value if already present. Returns
+    the value being replaced, which stays alive until the list is dropped since a
+    concurrent reader may still be holding it
+    */
+    pub fn insert(&self, key: K, value: V) -> Option<&V> {
+        let mut writer = self.writer.lock().unwrap();
+
+        let mut prev = [ptr::null::<AtomicPtr<Node<K, V>>>(); MAX_HEIGHT];
+        let found = self.find_greater_or_equal(&key, Some(&mut prev));
+
+        if !found.is_null() && (self.compare)(unsafe { &(*found).key }, &key) == Ordering::Equal {
+            let new_value = Box::into_raw(Box::new(value));
+            let old_value = unsafe { (*found).value.swap(new_value, AcqRel) };
+            writer.retired.push(old_value);
+            return Some(unsafe { &*old_value });
+        }
+
+        let height = random_height(&mut writer.rng);
+        let curr_height = self.height.load(Relaxed);
+        if height > curr_height {
+            // Readers seeing the new height before the node is linked in just
+            // find null links from head at those levels, which is harmless
+            for (level, link) in prev.iter_mut().enumerate().take(height).skip(curr_height) {
+                *link = &self.head[level];
+            }
+            self.height.store(height, Relaxed);
+        }
+
+        let node = Node::alloc(key, value, height);
+        for (level, link) in prev.iter().enumerate().take(height) {
+            let link = unsafe { &**link };
+            // Node is not yet visible, so a relaxed store of its own links is enough,
+            // the release store into the predecessor is what publishes it
+            unsafe { (*node).next[level].store(link.load(Relaxed), Relaxed) };
+            link.store(node, Release);
+        }
+        self.len.fetch_add(1, Release);
+        None
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let node = self.find_greater_or_equal(key, None);
+        if node.is_null() {
+            return None;
+        }
+        let node = unsafe { &*node };
+        match (self.compare)(&node.key, key) {
+            Ordering::Equal => Some(unsafe { &*node.value.load(Acquire) }),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /*
+    Iter: In-order iteration over the list. Entries inserted while iterating may or may
+    not be observed, depending on where they land relative to the cursor
+    */
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter{next: self.head[0].load(Acquire), _list: PhantomData}
+    }
+
+    // Same as iter, starting from the first key >= the given one
+    pub fn iter_from(&self, key: &K) -> Iter<'_, K, V> {
+        Iter{next: self.find_greater_or_equal(key, None), _list: PhantomData}
+    }
+
+    /*
+    Find Greater Or Equal: Walks down from the highest level to find the first node with
+    a key >= the search key, optionally recording the link at each level that would need
+    to point at a new node inserted for this key
+    */
+    fn find_greater_or_equal(&self, key: &K, mut prev: Option<&mut [*const AtomicPtr<Node<K, V>>; MAX_HEIGHT]>) -> *mut Node<K, V> {
+        let mut level = self.height.load(Relaxed) - 1;
+        let mut links: &[AtomicPtr<Node<K, V>>] = &self.head;
+        loop {
+            let next = links[level].load(Acquire);
+            if !next.is_null() && (self.compare)(unsafe { &(*next).key }, key) == Ordering::Less {
+                links = unsafe { &(*next).next };
+                continue;
+            }
+            if let Some(prev) = prev.as_mut() {
+                prev[level] = &links[level];
+            }
+            if level == 0 {
+                return next;
+            }
+            level -= 1;
+        }
+    }
+}
+
+impl<K: Ord + 'static, V> Default for SkipList<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for SkipList<K, V> {
+    fn drop(&mut self) {
+        let mut node = *self.head[0].get_mut();
+        while !node.is_null() {
+            let mut owned = unsafe { Box::from_raw(node) };
+            drop(unsafe { Box::from_raw(*owned.value.get_mut()) });
+            node = *owned.next[0].get_mut();
+        }
+        for value in self.writer.get_mut().unwrap().retired.drain(..) {
+            drop(unsafe { Box::from_raw(value) });
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    next: *mut Node<K, V>,
+    _list: PhantomData<&'a SkipList<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next.is_null() {
+            return None;
+        }
+        let node = unsafe { &*self.next };
+        self.next = node.next[0].load(Acquire);
+        Some((&node.key, unsafe { &*node.value.load(Acquire) }))
+    }
+}
+
+// xorshift64, only needs to be cheap and roughly uniform
+fn random_height(rng: &mut u64) -> usize {
+    let mut height = 1;
+    loop {
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        if height >= MAX_HEIGHT || !rng.is_multiple_of(BRANCHING) {
+            return height;
+        }
+        height += 1;
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt::{Display, Formatter, Result}, sync::atomic::{AtomicU64, Ordering::*}};
+
+/*
+Ticker: Counters an LSM keeps about its own operation
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ticker {
+    // Segment lookups that consulted a bloom filter
+    BloomFilterChecked,
+    // Segment lookups the bloom filter ruled out, each one a data block read saved
+    BloomFilterUseful,
+    // Segment block reads served from the block cache
+    BlockCacheHit,
+    // Segment block reads that had to go to the file
+    BlockCacheMiss,
+    // Segments opened with mmap reads enabled that could not be mapped
+    MmapFallback,
+    // Segments deleted to keep the LSM under its maximum size
+    SegmentsEvicted,
+    // Bytes of segment files deleted to keep the LSM under its maximum size
+    BytesEvicted,
+}
+
+const TICKERS: [(Ticker, &str); 7] = [
+    (Ticker::BloomFilterChecked, "bloom.filter.checked"),
+    (Ticker::BloomFilterUseful, "bloom.filter.useful"),
+    (Ticker::BlockCacheHit, "block.cache.hit"),
+    (Ticker::BlockCacheMiss, "block.cache.miss"),
+    (Ticker::MmapFallback, "mmap.fallback"),
+    (Ticker::SegmentsEvicted, "eviction.segments"),
+    (Ticker::BytesEvicted, "eviction.bytes"),
+];
+
+pub struct Statistics {
+    tickers: [AtomicU64; TICKERS.len()],
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics{tickers: Default::default()}
+    }
+
+    pub fn record(&self, ticker: Ticker, count: u64) {
+        self.tickers[ticker as usize].fetch_add(count, Relaxed);
+    }
+
+    pub fn get(&self, ticker: Ticker) -> u64 {
+        self.tickers[ticker as usize].load(Relaxed)
+    }
+
+    // Name and count of every ticker
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        TICKERS.iter().map(|(ticker, name)| (*name, self.get(*ticker))).collect()
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// One "name: count" line per ticker
+impl Display for Statistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (ticker, name) in TICKERS {
+            writeln!(f, "{}: {}", name, self.get(ticker))?;
+        }
+        Ok(())
+    }
+}
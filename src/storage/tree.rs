@@ -1,171 +1,216 @@
-use std::fmt::{Debug, Display};
-use std::{cmp::*};
-use std::fs::File;
-use std::io::{Write};
+use std::fmt::Debug;
 use crate::log;
-use crate::storage::tree::LogSegment::*;
+use std::sync::{atomic::{AtomicUsize, Ordering::*}, Arc};
+use crate::storage::{comparator::{BytewiseComparator, Comparator}, skiplist::SkipList};
 
+// Rough memory cost of a skip list node and of a boxed value, on top of the key and
+// value bytes themselves, used to estimate how much memory a segment is holding
+const NODE_OVERHEAD: usize = 64;
+const VALUE_OVERHEAD: usize = 32;
 
-#[derive(Debug)]
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TriOption<T> {
     TriSome(T),
     TriNone,
-    Tombstoned
+    Tombstoned,
+    // Merge operands, oldest first, still to be folded over whatever older value the key has
+    TriMerge(Vec<T>)
 }
 
 use self::TriOption::*;
 
-pub enum LogSegment<T: Ord + Clone + Debug + Display> {
-    TreeNode{k: T, v: TriOption<T>, left: Option<Box<LogSegment<T>>>, right: Option<Box<LogSegment<T>>>},
-    Nil
-}
-
-impl<T: Ord + Clone + Debug + Display> LogSegment<T> {
-    pub fn new() -> LogSegment<T> {
-        Nil
+impl<T> TriOption<T> {
+    pub fn as_ref(&self) -> TriOption<&T> {
+        match self {
+            TriSome(v) => TriSome(v),
+            TriNone => TriNone,
+            Tombstoned => Tombstoned,
+            TriMerge(operands) => TriMerge(operands.iter().collect())
+        }
     }
 
-    pub fn insert(&mut self, pair: (T, T)) {
+    pub fn map<U, F: FnMut(T) -> U>(self, mut f: F) -> TriOption<U> {
         match self {
-            Nil => {
-                *self = TreeNode { k: pair.0, v: TriSome(pair.1), left: None, right: None };
-            },
-            TreeNode{k, v, left, right} => {
-                match pair.0.cmp(k) {
-                    Ordering::Equal => *v = TriSome(pair.1),
-                    Ordering::Greater => {
-                        if let Some(right) = right {
-                            right.insert(pair);
-                        }
-                        else {
-                            *right = Some(Box::new(TreeNode { k: pair.0, v: TriSome(pair.1), left: None, right: None }));
-                        }
-                    },
-                    Ordering::Less => {
-                        if let Some(left) = left {
-                            left.insert(pair);
-                        }
-                        else {
-                            *left = Some(Box::new(TreeNode { k: pair.0, v: TriSome(pair.1), left: None, right: None }));
-                        }
-                    },
-                }
-            }
+            TriSome(v) => TriSome(f(v)),
+            TriNone => TriNone,
+            Tombstoned => Tombstoned,
+            TriMerge(operands) => TriMerge(operands.into_iter().map(f).collect())
         }
     }
+}
 
-    pub fn delete(&mut self, del_key: T) {
-        match self {
-            Nil => {
-                *self = TreeNode{k: del_key, v: Tombstoned, left: None, right: None};
-            },
-            TreeNode { k, v, left, right } => {
-                match del_key.cmp(k) {
-                    Ordering::Equal => *v = Tombstoned,
-                    Ordering::Greater => {
-                        if let Some(right) = right {
-                            right.delete(del_key);
-                        }
-                        else {
-                            *right = Some(Box::new(TreeNode { k: del_key, v: Tombstoned, left: None, right: None }));
-                        }
-                    },
-                    Ordering::Less => {
-                        if let Some(left) = left {
-                            left.delete(del_key);
-                        }
-                        else {
-                            *left = Some(Box::new(TreeNode { k: del_key, v: Tombstoned, left: None, right: None }));
-                        }
-                    },
-                }
-            }
+// A value, tombstone or merge operands in the memtable, along with the expiry of a value written with a TTL
+pub struct MemValue<T> {
+    value: TriOption<T>,
+    // Set in place of the value for a key with merge operands
+    operands: Option<Arc<Operand<T>>>,
+    pub expires_at: Option<u64>,
+}
+
+impl<T> MemValue<T> {
+    // The value or tombstone, or the merge operands, oldest first
+    pub fn value(&self) -> TriOption<&T> {
+        let mut next = match &self.operands {
+            Some(newest) => Some(newest),
+            None => return self.value.as_ref(),
+        };
+        let mut operands = Vec::new();
+        while let Some(operand) = next {
+            operands.push(&operand.operand);
+            next = operand.earlier.as_ref();
+        }
+        operands.reverse();
+        TriMerge(operands)
+    }
+}
+
+impl<T: Debug> Debug for MemValue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemValue").field("value", &self.value()).field("expires_at", &self.expires_at).finish()
+    }
+}
+
+/*
+Operand: A merge operand, linked to the ones merged into the key before it, so merging
+another shares the list rather than copying it, the entry it replaces still reading the
+operands it had
+*/
+struct Operand<T> {
+    operand: T,
+    earlier: Option<Arc<Operand<T>>>,
+}
+
+impl<T> Drop for Operand<T> {
+    // Unlinked one at a time, as dropping a long list recursively would overflow the stack
+    fn drop(&mut self) {
+        let mut earlier = self.earlier.take();
+        while let Some(operand) = earlier {
+            earlier = match Arc::try_unwrap(operand) {
+                Ok(mut operand) => operand.earlier.take(),
+                Err(_) => None,
+            };
         }
     }
+}
+
+/*
+Log Segment: The in-memory segment (memtable) that writes land in before being flushed to
+disk. Backed by a skip list, so inserts and lookups stay O(log n) even for the sequential
+keys most workloads write, the size is a counter rather than a traversal, and lookups can
+run concurrently with a writer without taking a lock. Keys are kept in the order of the
+LSM's comparator, bytewise unless given one.
+*/
+pub struct LogSegment<T: Clone + Debug + AsRef<[u8]> + 'static> {
+    entries: SkipList<T, MemValue<T>>,
+    approximate_bytes: AtomicUsize,
+}
+
+impl<T: Clone + Debug + AsRef<[u8]> + 'static> LogSegment<T> {
+    pub fn new() -> LogSegment<T> {
+        LogSegment::with_comparator(Arc::new(BytewiseComparator))
+    }
+
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> LogSegment<T> {
+        let compare = move |a: &T, b: &T| comparator.compare(a.as_ref(), b.as_ref());
+        LogSegment{entries: SkipList::with_comparator(Box::new(compare)), approximate_bytes: AtomicUsize::new(0)}
+    }
+
+    pub fn insert(&self, pair: (T, T)) {
+        let value_bytes = pair.1.as_ref().len();
+        self.insert_entry(pair.0, MemValue{value: TriSome(pair.1), operands: None, expires_at: None}, value_bytes);
+    }
+
+    // Same as insert, for a value that expires at the given time, in millis since the epoch
+    pub fn insert_expiring(&self, pair: (T, T), expires_at: u64) {
+        let value_bytes = pair.1.as_ref().len();
+        self.insert_entry(pair.0, MemValue{value: TriSome(pair.1), operands: None, expires_at: Some(expires_at)}, value_bytes);
+    }
+
+    /*
+    Merge: Adds a merge operand for the key, after any operands it already has. The key
+    must not have a value or tombstone in the segment, merges onto those are folded by
+    the LSM before they get here
+    */
+    pub fn merge(&self, key: T, operand: T) {
+        let earlier = self.entries.get(&key).and_then(|entry| entry.operands.clone());
+        let operand_bytes = operand.as_ref().len();
+        let operands = Some(Arc::new(Operand{operand, earlier}));
+        // Only the new operand counts, the ones before it are shared with the entry it replaces
+        self.insert_entry(key, MemValue{value: TriNone, operands, expires_at: None}, operand_bytes);
+    }
+
+    // Deleted keys stay in the segment as tombstones, so they shadow values for the
+    // same key in older segments
+    pub fn delete(&self, del_key: T) {
+        self.insert_entry(del_key, MemValue{value: Tombstoned, operands: None, expires_at: None}, 0);
+    }
+
+    fn insert_entry(&self, key: T, value: MemValue<T>, value_bytes: usize) {
+        let key_bytes = key.as_ref().len();
+        let value_bytes = value_bytes + VALUE_OVERHEAD;
+
+        // Replaced values are kept alive until the segment is dropped, so an overwrite
+        // still grows the segment by the size of the new value
+        let added = match self.entries.insert(key, value) {
+            Some(_) => value_bytes,
+            None => NODE_OVERHEAD + key_bytes + value_bytes,
+        };
+        self.approximate_bytes.fetch_add(added, AcqRel);
+    }
 
     pub fn get(&self, get_key: T) -> TriOption<&T> {
-        match self {
-            Nil => TriNone,
-            TreeNode { k, v, left, right } => {
-                match get_key.cmp(k) {
-                    Ordering::Equal => {
-                        log(&format!("{} is {:?}", get_key, v));
-                        match v {
-                            TriNone => return TriNone,
-                            TriSome(v) => return TriSome(v),
-                            Tombstoned => return Tombstoned
-                        };
-                    }
-                    Ordering::Greater => {
-                        if let Some(right) = right {
-                            return right.get(get_key);
-                        }
-                        else {
-                            return TriNone;
-                        }
-                    },
-                    Ordering::Less => {
-                        if let Some(left) = left {
-                            return left.get(get_key);
-                        }
-                        else {
-                            return TriNone;
-                        }
-                    },
-                }
+        match self.entries.get(&get_key) {
+            None => TriNone,
+            Some(v) => {
+                log(&format!("{:?} is {:?}", get_key, v));
+                v.value()
             }
         }
     }
 
-    // in-order traversal and write to disk of the tree.
-    pub fn write_to_disk(&mut self, file: &mut File) {
-        match self {
-            Nil => {},
-            TreeNode { k, v, left, right } => {
-                if let Some(left) = left {
-                    left.write_to_disk(file);
-                }
-
-                if let TriSome(v) = v {
-                    if let Err(e) = file.write(format!("{} {}\n", k, v).as_bytes()) {
-                        panic!("{}", e);
-                    }
-                }
-                else {
-                    // Tombstoned entries will be written to disk as keys only
-                    if let Err(e) = file.write(format!("{}\n", k).as_bytes()) {
-                        panic!("{}", e);
-                    }
-                }
-                if let Some(right) = right {
-                    right.write_to_disk(file);
-                }
-            }
+    // The key's entry as stored, including its expiry
+    pub fn get_entry(&self, key: &T) -> Option<&MemValue<T>> {
+        self.entries.get(key)
+    }
+
+    /*
+    Get At: Same as get, but a value that has expired by now reads as a tombstone, since
+    it still shadows any older value for the key
+    */
+    pub fn get_at(&self, get_key: T, now: u64) -> TriOption<&T> {
+        match self.entries.get(&get_key) {
+            Some(MemValue{expires_at: Some(expires_at), ..}) if *expires_at <= now => Tombstoned,
+            _ => self.get(get_key),
         }
     }
 
+    // in-order traversal of the tree, including tombstoned and expired entries
+    pub fn iter(&self) -> impl Iterator<Item = (&T, &MemValue<T>)> {
+        self.entries.iter()
+    }
+
+    // Same as iter, starting from the first key >= the given one
+    pub fn iter_from(&self, key: &T) -> impl Iterator<Item = (&T, &MemValue<T>)> {
+        self.entries.iter_from(key)
+    }
+
     pub fn exists(&self, ex_key: T) -> bool {
-        match self.get(ex_key) {
-            TriSome(_) => true,
-            _ => false
-        }
+        matches!(self.get(ex_key), TriSome(_))
     }
 
     pub fn size(&self) -> usize {
-        match self {
-            Nil => {
-                0
-            }
-            TreeNode { k: _, v: _, left, right } => {
-                let mut sum = 1;
-                if let Some (left) = left {
-                    sum += left.size();
-                }
-                if let Some(right) = right {
-                    sum += right.size();
-                }
-                sum
-            }
-        }
+        self.entries.len()
+    }
+
+    // Approximate memory held by the segment, used to decide when to flush it
+    pub fn approximate_bytes(&self) -> usize {
+        self.approximate_bytes.load(Acquire)
     }
-}
\ No newline at end of file
+}
+
+impl<T: Clone + Debug + AsRef<[u8]> + 'static> Default for LogSegment<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::Result;
+
+use crate::storage::coding::*;
+
+const OP_DELETE: u8 = 0;
+const OP_PUT: u8 = 1;
+const OP_PUT_EXPIRING: u8 = 2;
+const OP_MERGE: u8 = 3;
+const OP_BATCH: u8 = 4;
+const OP_SEQUENCED: u8 = 5;
+
+/*
+WAL Record: One logged operation. Records are written as
+
+    crc32 of payload | payload len | payload
+
+(fixed 32 bit) where the payload is the op byte followed by the length prefixed key, and
+for puts the length prefixed value, so keys and values may hold any bytes. Puts with a TTL
+have their own op, with the expiry (fixed 64 bit millis) after the value. Merges are
+laid out the same as puts, with the operand in place of the value
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalRecord {
+    Put{key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>},
+    Delete{key: Vec<u8>},
+    Merge{key: Vec<u8>, operand: Vec<u8>},
+}
+
+impl WalRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        frame(&self.encode_payload())
+    }
+
+    fn encode_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            WalRecord::Put{key, value, expires_at} => {
+                payload.push(if expires_at.is_some() { OP_PUT_EXPIRING } else { OP_PUT });
+                put_length_prefixed(&mut payload, key);
+                put_length_prefixed(&mut payload, value);
+                if let Some(expires_at) = expires_at {
+                    put_fixed64(&mut payload, *expires_at);
+                }
+            },
+            WalRecord::Delete{key} => {
+                payload.push(OP_DELETE);
+                put_length_prefixed(&mut payload, key);
+            },
+            WalRecord::Merge{key, operand} => {
+                payload.push(OP_MERGE);
+                put_length_prefixed(&mut payload, key);
+                put_length_prefixed(&mut payload, operand);
+            },
+        }
+        payload
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WalRecord::Put{key, ..} | WalRecord::Delete{key} | WalRecord::Merge{key, ..} => key,
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Result<WalRecord> {
+        let (op, rest) = match payload.split_first() {
+            Some((op, rest)) => (*op, rest),
+            None => return Err(corruption("empty wal record")),
+        };
+        let (key, n) = get_length_prefixed(rest)?;
+        match op {
+            OP_PUT => {
+                let (value, _) = get_length_prefixed(&rest[n..])?;
+                Ok(WalRecord::Put{key: key.to_vec(), value: value.to_vec(), expires_at: None})
+            },
+            OP_PUT_EXPIRING => {
+                let (value, m) = get_length_prefixed(&rest[n..])?;
+                match rest.get(n + m..n + m + 8) {
+                    Some(expires_at) => Ok(WalRecord::Put{key: key.to_vec(), value: value.to_vec(), expires_at: Some(decode_fixed64(expires_at))}),
+                    None => Err(corruption("wal record too short for expiry")),
+                }
+            },
+            OP_DELETE => Ok(WalRecord::Delete{key: key.to_vec()}),
+            OP_MERGE => {
+                let (operand, _) = get_length_prefixed(&rest[n..])?;
+                Ok(WalRecord::Merge{key: key.to_vec(), operand: operand.to_vec()})
+            },
+            _ => Err(corruption("unknown op in wal record")),
+        }
+    }
+}
+
+/*
+Sequenced Batch: The records of one commit, each with the id of the column family it is
+for, and the commit's sequence number
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequencedBatch {
+    pub sequence: u64,
+    pub records: Vec<(u32, WalRecord)>,
+}
+
+/*
+Encode Sequenced: Encodes the records of a commit as one framed WAL record, so replaying
+the WAL applies either all of them or, if the commit was torn, none. The payload is the
+sequenced op, the sequence number (fixed 64 bit), then what the payload would be without
+it. A lone record for the default family (id 0) is its own payload, as it was before
+there were column families, and any other commit is the batch op, a varint32 count, then
+for each record the varint32 column family id and its length prefixed payload. WALs
+written before commits had sequence numbers hold those payloads unsequenced
+*/
+pub fn encode_sequenced(sequence: u64, records: &[(u32, WalRecord)]) -> Vec<u8> {
+    let mut payload = vec![OP_SEQUENCED];
+    put_fixed64(&mut payload, sequence);
+    match records {
+        [(0, record)] => payload.extend_from_slice(&record.encode_payload()),
+        records => {
+            payload.push(OP_BATCH);
+            put_varint32(&mut payload, records.len() as u32);
+            for (family, record) in records {
+                put_varint32(&mut payload, *family);
+                put_length_prefixed(&mut payload, &record.encode_payload());
+            }
+        },
+    }
+    frame(&payload)
+}
+
+fn decode_batch(payload: &[u8]) -> Result<Vec<(u32, WalRecord)>> {
+    let (count, mut pos) = get_varint32(payload)?;
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (family, n) = get_varint32(&payload[pos..])?;
+        let (record, m) = get_length_prefixed(&payload[pos + n..])?;
+        records.push((family, WalRecord::decode(record)?));
+        pos += n + m;
+    }
+    Ok(records)
+}
+
+fn decode_commit(payload: &[u8]) -> Result<Vec<(u32, WalRecord)>> {
+    match payload.split_first() {
+        Some((&OP_BATCH, batch)) => decode_batch(batch),
+        _ => WalRecord::decode(payload).map(|record| vec![(0, record)]),
+    }
+}
+
+/*
+Read Batches: Decodes the commits in the WAL contents, in the order they were logged, each
+with the offset of its record in the contents. Commits logged without a sequence number
+take the one after the commit before, the first of them taking next. Reading stops at the
+first record that is cut short or fails its checksum, which is what a crash part way
+through appending a record leaves behind. Returns the commits along with the length of the
+WAL up to the end of the last good record
+*/
+pub fn read_batches(contents: &[u8], mut next: u64) -> (Vec<(usize, SequencedBatch)>, usize) {
+    let mut batches = Vec::new();
+    let mut pos = 0;
+    while let Some((payload, len)) = read_frame(&contents[pos..]) {
+        let decoded = match payload.split_first() {
+            Some((&OP_SEQUENCED, rest)) if rest.len() >= 8 => decode_commit(&rest[8..]).map(|records| (decode_fixed64(rest), records)),
+            Some((&OP_SEQUENCED, _)) => break,
+            _ => decode_commit(payload).map(|records| (next, records)),
+        };
+        match decoded {
+            Ok((sequence, records)) => {
+                batches.push((pos, SequencedBatch{sequence, records}));
+                next = sequence + 1;
+            },
+            Err(_) => break,
+        }
+        pos += len;
+    }
+    (batches, pos)
+}
+
+/*
+Check Readable: Called when not even the first record of a non-empty WAL could be read.
+That is only a torn append if the record is cut short, anything else, such as a WAL in the
+text format of older versions (lines of "key value"), is refused rather than truncated away
+*/
+pub fn check_readable(contents: &[u8]) -> Result<()> {
+    let text = contents.ends_with(b"\n") && std::str::from_utf8(contents).is_ok_and(|text| !text.chars().any(|c| c.is_control() && c != '\n'));
+    if text {
+        return Err(corruption("WAL is in the text format of an older version, which can't be read"));
+    }
+    if contents.len() >= FRAME_HEADER_SIZE && FRAME_HEADER_SIZE + decode_fixed32(&contents[4..]) as usize <= contents.len() {
+        return Err(corruption("first WAL record is corrupt"));
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use crate::storage::{column_family::DEFAULT_COLUMN_FAMILY, wal::WalRecord};
+
+/*
+Write Batch: Writes to any of an LSM's column families, applied by LsmTree::write_batch
+all together or not at all. They are logged to the WAL as a single record, so a crash
+can't leave part of a batch behind, and applied in the order they were added
+*/
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    records: Vec<(String, WalRecord)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.put_cf(DEFAULT_COLUMN_FAMILY, key, value);
+    }
+
+    pub fn put_cf<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, family: &str, key: K, value: V) {
+        self.records.push((family.to_string(), WalRecord::Put{key: key.as_ref().to_vec(), value: value.as_ref().to_vec(), expires_at: None}));
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.delete_cf(DEFAULT_COLUMN_FAMILY, key);
+    }
+
+    pub fn delete_cf<K: AsRef<[u8]>>(&mut self, family: &str, key: K) {
+        self.records.push((family.to_string(), WalRecord::Delete{key: key.as_ref().to_vec()}));
+    }
+
+    pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, operand: V) {
+        self.merge_cf(DEFAULT_COLUMN_FAMILY, key, operand);
+    }
+
+    pub fn merge_cf<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, family: &str, key: K, operand: V) {
+        self.records.push((family.to_string(), WalRecord::Merge{key: key.as_ref().to_vec(), operand: operand.as_ref().to_vec()}));
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // The writes in the order they were added, each with the name of its column family
+    pub fn into_records(self) -> Vec<(String, WalRecord)> {
+        self.records
+    }
+}
@@ -0,0 +1,188 @@
+use std::{cmp::Ordering, error::Error, fmt::{self, Display, Formatter}, io::{self, ErrorKind}, time::Duration};
+
+use crate::{kvpair::KVPair, storage::{comparator::Comparator, error::LsmError, lsm::LsmTree, write_batch::WriteBatch}};
+
+/*
+Key Value Store: What an application needs of a store, so the same code can use an LSM
+embedded in the process or one served over HTTP through a client::Client, switching
+between them by which it is given
+*/
+pub trait KeyValueStore {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError>;
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StoreError>;
+
+    // Same as put, but the value expires once the ttl, which must not be zero, has passed
+    fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), StoreError>;
+
+    // Deletes the key, returning whether it had a value
+    fn delete(&mut self, key: &[u8]) -> Result<bool, StoreError>;
+
+    // The rows in the range, in the store's key order
+    fn scan(&mut self, range: &ScanRange) -> Result<Vec<Row>, StoreError>;
+
+    // Applies every write in the batch or none of them
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<(), StoreError>;
+}
+
+// A key and its value, as a scan returns them
+pub type Row = (Vec<u8>, Vec<u8>);
+
+/*
+Store Error: Why an operation on a store failed. Embedded stores only fail with Lsm and
+InvalidRequest, the rest come from talking to a server
+*/
+#[derive(Debug)]
+pub enum StoreError {
+    // Connecting to the server, or sending or receiving, failed
+    Io(io::Error),
+    // The server didn't answer within the client's timeout
+    Timeout,
+    // The server's response couldn't be understood
+    Protocol(String),
+    // The request was refused as malformed, e.g. a scan whose start is after its end
+    InvalidRequest(String),
+    // The server failed to carry out the request
+    Server{status: u16, message: String},
+    // The operation can't be sent to a server, e.g. a batch holding a merge
+    Unsupported(String),
+    Lsm(LsmError),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "io error: {}", e),
+            StoreError::Timeout => write!(f, "timed out waiting for the server"),
+            StoreError::Protocol(message) => write!(f, "protocol error: {}", message),
+            StoreError::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+            StoreError::Server{status, message} => write!(f, "server error {}: {}", status, message),
+            StoreError::Unsupported(message) => write!(f, "unsupported: {}", message),
+            StoreError::Lsm(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StoreError::Io(e) => Some(e),
+            StoreError::Lsm(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Socket timeouts surface as WouldBlock on some platforms and TimedOut on others
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => StoreError::Timeout,
+            _ => StoreError::Io(e),
+        }
+    }
+}
+
+impl From<LsmError> for StoreError {
+    fn from(e: LsmError) -> Self {
+        StoreError::Lsm(e)
+    }
+}
+
+/*
+Scan Range: Which rows a scan returns, those from start up to but not including end, in
+the store's key order, that have the prefix, up to the limit. Everything by default
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanRange {
+    pub prefix: Option<Vec<u8>>,
+    pub start: Option<Vec<u8>>,
+    pub end: Option<Vec<u8>>,
+    pub limit: Option<usize>,
+}
+
+impl ScanRange {
+    pub fn all() -> ScanRange {
+        ScanRange::default()
+    }
+
+    pub fn prefix<P: AsRef<[u8]>>(mut self, prefix: P) -> Self {
+        self.prefix = Some(prefix.as_ref().to_vec());
+        self
+    }
+
+    pub fn start<K: AsRef<[u8]>>(mut self, start: K) -> Self {
+        self.start = Some(start.as_ref().to_vec());
+        self
+    }
+
+    pub fn end<K: AsRef<[u8]>>(mut self, end: K) -> Self {
+        self.end = Some(end.as_ref().to_vec());
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    // Whether the start is after the end, which stores refuse rather than scanning nothing
+    pub fn is_reversed(&self, comparator: &dyn Comparator) -> bool {
+        match (&self.start, &self.end) {
+            (Some(start), Some(end)) => comparator.compare(start, end) == Ordering::Greater,
+            _ => false,
+        }
+    }
+
+    // The LSM's rows in the range, read as they are iterated
+    pub fn rows<'a>(&'a self, lsm: &'a mut LsmTree) -> impl Iterator<Item = KVPair<'static>> + 'a {
+        let comparator = lsm.comparator();
+        // Keys with the prefix are only found together from the prefix on in some orders, in others every key is checked
+        let grouped_prefix = self.prefix.as_ref().filter(|_| comparator.groups_prefixes());
+        let from = [self.start.as_ref(), grouped_prefix].into_iter().flatten().max_by(|a, b| comparator.compare(a, b));
+        let rows: Box<dyn Iterator<Item = KVPair<'static>> + 'a> = match from {
+            Some(key) => Box::new(lsm.iter_from(key)),
+            None => Box::new(lsm.iter()),
+        };
+        rows.take_while(move |pair| self.end.as_ref().is_none_or(|end| comparator.compare(&pair.key, end) == Ordering::Less))
+            .take_while(move |pair| grouped_prefix.is_none_or(|prefix| pair.key.starts_with(prefix)))
+            .filter(|pair| self.prefix.as_ref().is_none_or(|prefix| pair.key.starts_with(prefix)))
+            .take(self.limit.unwrap_or(usize::MAX))
+    }
+}
+
+impl KeyValueStore for LsmTree {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(LsmTree::get(self, key))
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        Ok(self.write(key, value)?)
+    }
+
+    fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), StoreError> {
+        if ttl.is_zero() {
+            return Err(StoreError::InvalidRequest("ttl must not be zero".to_string()));
+        }
+        Ok(self.write_with_ttl(key, value, ttl)?)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<bool, StoreError> {
+        if LsmTree::get(self, key).is_none() {
+            return Ok(false);
+        }
+        LsmTree::delete(self, key)?;
+        Ok(true)
+    }
+
+    fn scan(&mut self, range: &ScanRange) -> Result<Vec<Row>, StoreError> {
+        if range.is_reversed(&*self.comparator()) {
+            return Err(StoreError::InvalidRequest("start is after end".to_string()));
+        }
+        Ok(range.rows(self).map(|pair| (pair.key.into_owned(), pair.value.into_owned())).collect())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<(), StoreError> {
+        Ok(LsmTree::write_batch(self, batch)?)
+    }
+}
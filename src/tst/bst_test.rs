@@ -1,9 +1,10 @@
 #[cfg(test)]
-use crate::storage::tree::LogSegment;
+use crate::storage::tree::{LogSegment, TriOption::*};
 
 #[test]
+#[allow(clippy::needless_range_loop)]
 pub fn test_bst_insert() {
-    let mut tree: LogSegment<String> = LogSegment::new();
+    let tree: LogSegment<String> = LogSegment::new();
 
     let first_ten_letters = ["A", "B", "C", "D", "E", "F", "G", "H", "I", "J"];
     let mut exp_size = 0;
@@ -16,8 +17,9 @@ pub fn test_bst_insert() {
 }
 
 #[test]
+#[allow(clippy::needless_range_loop)]
 pub fn test_bst_insert_delete() {
-    let mut tree: LogSegment<String> = LogSegment::new();
+    let tree: LogSegment<String> = LogSegment::new();
 
     let first_ten_letters = ["A", "B", "C", "D", "E", "F", "G", "H", "I", "J"];
     let mut exp_size = 0;
@@ -35,4 +37,34 @@ pub fn test_bst_insert_delete() {
         assert!(tree.size() == exp_size, "Expected tree size {}, actually is {}", exp_size, tree.size());
         assert!(!tree.exists(first_ten_letters[i].to_string()), "The letter {} exists in the tree after delete", first_ten_letters[i]);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn test_bst_merge() {
+    /*
+    Merge operands read back oldest first, each merge growing the segment by only the
+    operand it adds, and a key with many of them is dropped without trouble
+    */
+    let tree: LogSegment<String> = LogSegment::new();
+    for operand in ["a", "b", "c"] {
+        tree.merge("key".to_string(), operand.to_string());
+    }
+    let operands = tree.get("key".to_string()).map(|operand| operand.clone());
+    assert!(operands == TriMerge(vec!["a".to_string(), "b".to_string(), "c".to_string()]), "expected operands in merge order, got {:?}", operands);
+
+    let before = tree.approximate_bytes();
+    tree.merge("key".to_string(), "d".to_string());
+    let after = tree.approximate_bytes();
+    tree.merge("key".to_string(), "e".to_string());
+    assert!(tree.approximate_bytes() - after == after - before, "expected each merge to add the same bytes, added {} then {}", after - before, tree.approximate_bytes() - after);
+
+    let tree: LogSegment<String> = LogSegment::new();
+    for i in 0..200000 {
+        tree.merge("counter".to_string(), i.to_string());
+    }
+    match tree.get("counter".to_string()) {
+        TriMerge(operands) => assert!(operands.len() == 200000 && operands[199999] == "199999", "expected every operand in order, got {}", operands.len()),
+        other => panic!("expected merge operands, got {:?}", other),
+    }
+    drop(tree);
+}
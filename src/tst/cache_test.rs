@@ -0,0 +1,40 @@
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(test)]
+use crate::storage::cache::BlockCache;
+
+#[test]
+pub fn test_block_cache_evicts_least_recently_used() {
+    // Room for three 1000 byte blocks once per-entry overhead is counted, but not four
+    let cache = BlockCache::new(3500);
+    for offset in 0..3 {
+        cache.insert((0, offset), Arc::new(vec![offset as u8; 1000]));
+    }
+    assert!(cache.len() == 3, "expected 3 cached blocks, actually {}", cache.len());
+
+    // Touch the oldest block, so the next insert evicts block 1 instead
+    assert!(cache.lookup(&(0, 0)).is_some(), "block 0 missing before any eviction");
+    cache.insert((0, 3), Arc::new(vec![3; 1000]));
+
+    assert!(cache.len() == 3, "expected 3 cached blocks after eviction, actually {}", cache.len());
+    assert!(cache.usage() <= cache.capacity(), "cache usage {} over capacity {}", cache.usage(), cache.capacity());
+    assert!(cache.lookup(&(0, 1)).is_none(), "least recently used block 1 was not evicted");
+    for offset in [0, 2, 3] {
+        let block = cache.lookup(&(0, offset)).unwrap_or_else(|| panic!("block {} evicted", offset));
+        assert!(block[0] == offset as u8, "block {} has contents of another block", offset);
+    }
+
+    // Same offset under another segment's id is a different block
+    assert!(cache.lookup(&(1, 0)).is_none(), "found block for a segment that never cached one");
+}
+
+#[test]
+pub fn test_block_cache_skips_oversized_blocks() {
+    let cache = BlockCache::new(1024);
+    cache.insert((0, 0), Arc::new(vec![0; 100]));
+    cache.insert((0, 1), Arc::new(vec![0; 4096]));
+
+    assert!(cache.lookup(&(0, 1)).is_none(), "cached a block larger than the whole cache");
+    assert!(cache.lookup(&(0, 0)).is_some(), "oversized block evicted a block that fit");
+}
@@ -0,0 +1,178 @@
+#[cfg(test)]
+use std::io::ErrorKind;
+#[cfg(test)]
+use crate::{cli::{dump, execute, main, open, parse_args, repl, Action, Format, Invocation}, editor::{Input, LineEditor}, operators::{parse, Command, KeyRange, Operator}, storage::{comparator::CaseInsensitiveComparator, files::{get_lsmdir, get_wal_path, purge_lsm_dir}, lsm::LsmTree, options::LsmOptions}};
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(test)]
+fn args(args: &str) -> Vec<String> {
+    args.split(' ').map(str::to_string).collect()
+}
+
+#[test]
+pub fn test_cli_parse_args() {
+    let invocation = |path: &str, format, action| Invocation{path: path.to_string(), format, read_only: false, action};
+    assert!(parse_args(&args("db")) == Ok(invocation("db", Format::Text, Action::Repl)), "expected a path alone to start the REPL");
+    assert!(parse_args(&args("--json db get foo")) == Ok(invocation("db", Format::Json, Action::Execute(Command::Pipeline(vec![Operator::Get{key: b"foo".to_vec()}])))), "unexpected get");
+    assert!(parse_args(&args("/tmp/db put foo bar")) == Ok(invocation("/tmp/db", Format::Text, Action::Execute(Command::Pipeline(vec![Operator::Put{key: b"foo".to_vec(), value: b"bar".to_vec()}])))), "unexpected put");
+    assert!(parse_args(&args("db scan --range a c --limit 5")) == Ok(invocation("db", Format::Text, Action::Execute(Command::Pipeline(vec![
+        Operator::Scan{range: KeyRange::Range{start: b"a".to_vec(), end: b"c".to_vec()}},
+        Operator::Limit(5),
+    ])))), "unexpected scan");
+    assert!(parse_args(&args("--json --text db dump")) == Ok(invocation("db", Format::Text, Action::Dump)), "expected the last format to win");
+    assert!(parse_args(&args("db serve")) == Ok(invocation("db", Format::Text, Action::Serve{resp: Some("127.0.0.1:6379".to_string()), http: None, replication: None, replica_of: None})), "unexpected serve");
+    assert!(parse_args(&args("db serve --resp 0.0.0.0:7000")) == Ok(invocation("db", Format::Text, Action::Serve{resp: Some("0.0.0.0:7000".to_string()), http: None, replication: None, replica_of: None})), "unexpected serve address");
+    assert!(parse_args(&args("db serve --http :8080")) == Ok(invocation("db", Format::Text, Action::Serve{resp: None, http: Some(":8080".to_string()), replication: None, replica_of: None})), "expected only HTTP to be served");
+    let expected = Action::Serve{resp: None, http: Some(":8080".to_string()), replication: Some(":7100".to_string()), replica_of: Some("primary:7100".to_string())};
+    assert!(parse_args(&args("db serve --http :8080 --replication :7100 --replica-of primary:7100")) == Ok(invocation("db", Format::Text, expected)), "unexpected replication addresses");
+    assert!(parse_args(&args("--help")).map(|invocation| invocation.action) == Ok(Action::Help), "expected help");
+    assert!(parse_args(&args("--read-only db scan")).is_ok_and(|invocation| invocation.read_only), "expected a read-only invocation");
+    assert!(parse_args(&args("--read-only db serve --replica-of primary:7100")).is_err(), "expected a read-only replica to be refused");
+
+    let errors = [
+        ("--yaml db", "unknown option --yaml"),
+        ("db get", "missing key"),
+        ("db put foo", "missing value"),
+        ("db scan --limit ten", "invalid limit ten"),
+        ("db scan --reverse", "unknown scan option --reverse"),
+        ("db stats now", "unexpected argument now"),
+        ("db serve --resp", "missing address"),
+        ("db serve --tls", "unknown serve option --tls"),
+        ("db frob", "unknown command frob"),
+    ];
+    for (input, message) in errors {
+        let result = parse_args(&args(input));
+        assert!(result == Err(message.to_string()), "expected {:?} to fail with {:?}, actually {:?}", input, message, result);
+    }
+    assert!(parse_args(&[]) == Err("missing db path".to_string()), "expected a path to be required");
+}
+
+#[test]
+pub fn test_cli_output_formats() {
+    let mut lsm = LsmTree::new_delete_existing("test_cli_output_formats");
+    let output = |lsm: &mut LsmTree, input: &str, format| {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let found = execute(lsm, &parse(input).unwrap(), format, &mut out, &mut err).unwrap();
+        (String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap(), found)
+    };
+
+    let (out, err, found) = output(&mut lsm, "PUT foo bar; PUT \"a b\" \"x\\ny\"; BATCH; PUT \"\\xff\" \"\\\"\"; END; GET foo; SCAN; SCAN | COUNT", Format::Text);
+    assert!(out == "OK\nOK\nOK\nbar\n\"a b\" \"x\\ny\"\nfoo bar\n\"\\xff\" \"\\\"\"\n3\n", "unexpected text output {:?}", out);
+    assert!(err.is_empty() && found, "expected every key to be found");
+
+    let (out, err, found) = output(&mut lsm, "PUT baz 1; GET foo; GET missing; SCAN PREFIX a; SCAN PREFIX nothing; SCAN | COUNT; COMPACT", Format::Json);
+    let expected = "{\"written\":1}\n{\"key\":\"foo\",\"value\":\"bar\"}\nnull\n[{\"key\":\"a b\",\"value\":\"x\\ny\"}]\n[]\n{\"count\":4}\n{\"compacted\":true}\n";
+    assert!(out == expected, "unexpected json output {:?}", out);
+    assert!(err.is_empty() && !found, "expected a missing key to be reported");
+
+    let (out, err, found) = output(&mut lsm, "GET missing; STATS", Format::Text);
+    assert!(err == "(not found)\n" && !found, "expected a missing key to be reported on err, actually {:?}", err);
+    assert!(out.lines().all(|line| line.contains(": ")), "unexpected stats {:?}", out);
+    let (out, _, _) = output(&mut lsm, "STATS", Format::Json);
+    assert!(out.starts_with("{\"bloom.filter.checked\":") && out.ends_with("}\n"), "unexpected json stats {:?}", out);
+
+    // A dump loads back into another DB as is, binary keys and values included
+    let mut dumped = Vec::new();
+    dump(&mut lsm, Format::Text, &mut dumped).unwrap();
+    let mut copy = LsmTree::new_delete_existing("test_cli_output_formats_copy");
+    output(&mut copy, &String::from_utf8(dumped).unwrap(), Format::Text);
+    assert!(copy.iter().eq(lsm.iter()), "expected the dump to load into an identical DB");
+}
+
+#[test]
+pub fn test_cli_repl_script() {
+    let mut lsm = LsmTree::new_delete_existing("test_cli_repl_script");
+    let script = "\
+BATCH
+PUT a 1
+PUT b \"two
+lines\"
+END
+bogus
+.json
+SCAN | FILTER VALUE CONTAINS \"\\n\"
+.text
+.unknown
+SCAN | COUNT | LIMIT 1
+GET a
+BATCH; PUT c 3
+";
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    repl(&mut lsm, Format::Text, &mut script.as_bytes(), &mut out, &mut err).unwrap();
+    let (out, err) = (String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap());
+    assert!(out == "OK\n[{\"key\":\"b\",\"value\":\"two\\nlines\"}]\n1\n", "unexpected output {:?}", out);
+    let expected = "\
+error: syntax error at line 1, column 1: unknown command BOGUS
+error: unknown command .unknown
+error: invalid command 1: COUNT must be the last operator
+error: syntax error at line 1, column 1: BATCH without END
+";
+    assert!(err == expected, "unexpected errors {:?}", err);
+    assert!(lsm.get("c").is_none(), "expected an unfinished batch not to be written");
+
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    repl(&mut lsm, Format::Text, &mut "GET b\n.quit\nDEL b\n".as_bytes(), &mut out, &mut err).unwrap();
+    assert!(out == b"\"two\\nlines\"\n" && lsm.get("b").is_some(), "expected the REPL to stop at .quit");
+}
+
+#[test]
+pub fn test_cli_open_existing() {
+    // A DB created with another comparator and column families opens without being told about them
+    let dbname = "test_cli_open_existing";
+    purge_lsm_dir(dbname).unwrap();
+    let options = LsmOptions::default().comparator(Arc::new(CaseInsensitiveComparator));
+    let mut lsm = LsmTree::open_with_column_families(dbname, options, vec![("logs", LsmOptions::default())]).unwrap();
+    lsm.write("Foo", "bar").unwrap();
+    drop(lsm);
+
+    let mut lsm = open(dbname).unwrap();
+    assert!(lsm.get_str("Foo").as_deref() == Some("bar"), "expected the DB to be restored");
+    assert!(lsm.column_family_names() == ["default", "logs"], "unexpected column families {:?}", lsm.column_family_names());
+    assert!(lsm.comparator().name() == "case_insensitive", "expected the DB's comparator, actually {}", lsm.comparator().name());
+}
+
+#[test]
+pub fn test_cli_open_paths() {
+    // DBs named by absolute and nested paths keep their WAL in their own directory
+    let absolute = std::env::temp_dir().join("test_cli_open_paths").to_string_lossy().into_owned();
+    std::fs::create_dir_all("test_cli_open_paths_parent").unwrap();
+    for path in [absolute.as_str(), "test_cli_open_paths_parent/db"] {
+        purge_lsm_dir(path).unwrap();
+        assert!(main(&args(&format!("{} put a b", path))) == 0, "expected a put to {} to succeed", path);
+        assert!(get_wal_path(path).parent() == Some(get_lsmdir(path).as_path()) && get_wal_path(path).is_file(), "expected the WAL in {}, actually {:?}", path, get_wal_path(path));
+        assert!(open(path).unwrap().get_str("a").as_deref() == Some("b"), "expected the put to {} to be restored", path);
+        purge_lsm_dir(path).unwrap();
+    }
+    assert!(!std::path::Path::new(&format!("{}.log", absolute)).exists(), "expected no WAL beside the DB directory");
+
+    // Nor does a path whose parent is missing panic
+    assert!(main(&args("test_cli_open_paths_missing/db get a")) == 2, "expected a DB that can't be created to be an error");
+}
+
+#[test]
+pub fn test_line_editor() {
+    let keys = "PUT k v\r\
+        \x1b[A\x1b[D\x1b[DX\x1b[H\x1b[3~G\r\
+        abc\x01\x0b\x10\x10\x10\x0e\x15DEL k\r\
+        oops\x03\
+        \x10\x05\x08\x08X\x04\r\
+        \x04";
+    let mut editor = LineEditor::new(keys.as_bytes(), Vec::new());
+    let mut lines = Vec::new();
+    loop {
+        match editor.next_line("> ") {
+            Ok(Some(line)) => lines.push(line),
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => lines.push("^C".to_string()),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+    assert!(lines == ["PUT k v", "GUT kX v", "DEL k", "^C", "DELX"], "unexpected lines {:?}", lines);
+    assert!(editor.history() == ["PUT k v", "GUT kX v", "DEL k", "DELX"], "unexpected history {:?}", editor.history());
+
+    let mut editor = LineEditor::new("ключ\x1b[D\x1b[D€\rlast".as_bytes(), Vec::new());
+    assert!(editor.next_line("> ").unwrap().as_deref() == Some("кл€юч"), "expected multi-byte characters to be edited as one");
+    assert!(editor.next_line("> ").unwrap().as_deref() == Some("last"), "expected the input ending to end the line");
+    assert!(editor.next_line("> ").unwrap().is_none(), "expected no more lines");
+}
@@ -0,0 +1,193 @@
+#[cfg(test)]
+use std::{io::{BufRead, BufReader, Write}, net::TcpListener, sync::{atomic::{AtomicUsize, Ordering::SeqCst}, Arc, Mutex}, thread, time::Duration};
+#[cfg(test)]
+use crate::{client::{Client, ClientOptions, Reply}, http::HttpServer, server::ServerHandle, storage::{clock::ManualClock, lsm::LsmTree, options::LsmOptions, write_batch::WriteBatch}, store::{KeyValueStore, ScanRange, StoreError}};
+
+#[cfg(test)]
+fn start(dbname: &str, options: LsmOptions) -> ServerHandle {
+    let lsm = LsmTree::new_delete_existing_with_options(dbname, options);
+    HttpServer::bind("127.0.0.1:0", Arc::new(Mutex::new(lsm))).unwrap().spawn().unwrap()
+}
+
+// Runs the same operations against any store, describing what each returned
+#[cfg(test)]
+fn exercise(store: &mut dyn KeyValueStore, clock: &ManualClock) -> Vec<String> {
+    let mut results = Vec::new();
+    store.put(b"apple", b"red").unwrap();
+    store.put(b"banana", b"yellow").unwrap();
+    store.put(b"bin\x00\xff/key", &[0, 0xff, b'\n']).unwrap();
+    store.put_with_ttl(b"session", b"token", Duration::from_millis(1500)).unwrap();
+    results.push(format!("{:?} {:?} {:?}", store.get(b"apple"), store.get(b"bin\x00\xff/key"), store.get(b"session")));
+    clock.advance(Duration::from_secs(2));
+    results.push(format!("{:?} {:?}", store.get(b"session"), store.get(b"cherry")));
+    results.push(format!("{:?} {:?}", store.delete(b"apple"), store.delete(b"apple")));
+
+    let mut batch = WriteBatch::new();
+    for i in 0..20 {
+        batch.put(format!("fruit:{:02}", i), format!("{}", i));
+    }
+    batch.delete(b"banana");
+    store.write_batch(batch).unwrap();
+    let ranges = [
+        ScanRange::all(),
+        ScanRange::all().prefix("fruit:1").limit(3),
+        ScanRange::all().start("fruit:05").end("fruit:08"),
+        ScanRange::all().start(b"fruit:19").end(b"\xff"),
+        ScanRange::all().prefix("fruit:1").start("fruit:17"),
+        ScanRange::all().prefix("fruit:1").start("b").limit(2),
+        ScanRange::all().prefix("fruit:0").start("fruit:1"),
+    ];
+    for range in ranges {
+        let rows = store.scan(&range).unwrap();
+        results.push(format!("{:?}", rows.iter().map(|(key, value)| (String::from_utf8_lossy(key), String::from_utf8_lossy(value))).collect::<Vec<_>>()));
+    }
+    results.push(format!("{:?}", store.scan(&ScanRange::all().start("b").end("a")).map_err(|e| e.to_string())));
+    results.push(format!("{:?}", store.put_with_ttl(b"k", b"v", Duration::ZERO).map_err(|e| e.to_string())));
+    results
+}
+
+#[test]
+pub fn test_client_store_trait() {
+    let clock = Arc::new(ManualClock::new(0));
+    let mut embedded = LsmTree::new_delete_existing_with_options("test_client_store_trait_embedded", LsmOptions::default().clock(clock.clone()));
+    let expected = exercise(&mut embedded, &clock);
+
+    let clock = Arc::new(ManualClock::new(0));
+    let server = start("test_client_store_trait_remote", LsmOptions::default().clock(clock.clone()));
+    let mut client = Client::connect(server.local_addr()).unwrap();
+    let actual = exercise(&mut client, &clock);
+    assert!(actual == expected, "expected the client to behave as the embedded LSM\n{:#?}\n{:#?}", actual, expected);
+    assert!(expected[7..10] == ["[(\"fruit:17\", \"17\"), (\"fruit:18\", \"18\"), (\"fruit:19\", \"19\")]", "[(\"fruit:10\", \"10\"), (\"fruit:11\", \"11\")]", "[]"], "unexpected prefix scans from a start {:#?}", &expected[7..10]);
+    assert!(expected[2] == "Ok(true) Ok(false)" && expected[expected.len() - 2] == "Err(\"invalid request: start is after end\")", "unexpected results {:#?}", expected);
+    assert!(client.idle_connections() == 1, "expected one connection to be reused throughout, actually {}", client.idle_connections());
+    server.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_client_pipeline() {
+    let server = start("test_client_pipeline", LsmOptions::default());
+    let client = Client::connect(server.local_addr()).unwrap();
+
+    // Enough to fill the socket buffers both ways, were the requests not written while the responses are read
+    let value = vec![b'v'; 8 * 1024];
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.put(format!("key:{:04}", i).as_bytes(), &value);
+    }
+    let replies = pipeline.execute().unwrap();
+    assert!(replies.len() == 1000 && replies.iter().all(|reply| matches!(reply, Ok(Reply::Written))), "unexpected put replies");
+
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.get(format!("key:{:04}", i).as_bytes());
+    }
+    assert!(pipeline.execute().unwrap().iter().all(|reply| matches!(reply, Ok(Reply::Value(Some(v))) if *v == value)), "unexpected get replies");
+
+    let mut pipeline = client.pipeline();
+    pipeline.delete(b"key:0000").put_with_ttl(b"a", b"1", Duration::ZERO).get(b"key:0000").delete(b"key:0000").get(b"");
+    assert!(pipeline.len() == 5, "expected every operation to be counted");
+    let replies: Vec<Result<Reply, String>> = pipeline.execute().unwrap().into_iter().map(|reply| reply.map_err(|e| e.to_string())).collect();
+    let expected = vec![
+        Ok(Reply::Deleted(true)),
+        Err("invalid request: ttl must not be zero".to_string()),
+        Ok(Reply::Value(None)),
+        Ok(Reply::Deleted(false)),
+        Err("invalid request: missing key".to_string()),
+    ];
+    assert!(replies == expected, "unexpected replies {:?}", replies);
+    assert!(client.pipeline().execute().unwrap().is_empty(), "expected an empty pipeline to send nothing");
+    assert!(client.idle_connections() == 1, "expected the pipelines to reuse one connection");
+    server.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_client_pool() {
+    let server = start("test_client_pool", LsmOptions::default());
+    let client = Client::connect_with_options(server.local_addr(), ClientOptions::default().max_idle_connections(2)).unwrap();
+    let threads: Vec<_> = (0..8).map(|t| {
+        let client = client.clone();
+        thread::spawn(move || {
+            for i in 0..50 {
+                let key = format!("{}:{}", t, i);
+                client.put(key.as_bytes(), key.as_bytes()).unwrap();
+                assert!(client.get(key.as_bytes()).unwrap() == Some(key.into_bytes()), "expected to read back the write");
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(client.scan(&ScanRange::all()).unwrap().len() == 400, "expected every thread's writes");
+    assert!((1..=2).contains(&client.idle_connections()), "expected idle connections to be capped, actually {}", client.idle_connections());
+    server.shutdown().unwrap();
+}
+
+// Serves each connection it accepts with a 404 for its first request, then closes it without answering any more
+#[cfg(test)]
+fn answer_once(listener: TcpListener, accepted: Arc<AtomicUsize>) {
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        accepted.fetch_add(1, SeqCst);
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            line.clear();
+        }
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").unwrap();
+    }
+}
+
+#[test]
+pub fn test_client_errors() {
+    // A connection the server closed while it was idle is replaced
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (addr, accepted) = (listener.local_addr().unwrap(), Arc::new(AtomicUsize::new(0)));
+    let counter = accepted.clone();
+    thread::spawn(move || answer_once(listener, counter));
+    let client = Client::connect(addr).unwrap();
+    for _ in 0..3 {
+        assert!(client.get(b"key").unwrap().is_none(), "expected the request to be retried on a new connection");
+    }
+    assert!(accepted.load(SeqCst) == 3, "expected a connection per request, actually {}", accepted.load(SeqCst));
+
+    // A server that never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = Client::connect_with_options(listener.local_addr().unwrap(), ClientOptions::default().request_timeout(Duration::from_millis(100))).unwrap();
+    let result = client.get(b"key");
+    assert!(matches!(result, Err(StoreError::Timeout)), "expected a timeout, actually {:?}", result);
+    assert!(client.idle_connections() == 0, "expected the timed out connection to be dropped");
+    drop(listener);
+
+    // Nothing listening
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let result = Client::connect(addr);
+    assert!(matches!(result, Err(StoreError::Io(_))), "expected connecting to fail, actually {:?}", result.err());
+
+    // A server that isn't this store's
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            line.clear();
+        }
+        stream.write_all(b"SSH-2.0-OpenSSH\r\n").unwrap();
+    });
+    let result = Client::connect(addr).unwrap().get(b"key");
+    assert!(matches!(&result, Err(StoreError::Protocol(message)) if message.starts_with("malformed status line")), "expected a protocol error, actually {:?}", result);
+
+    let server = start("test_client_errors", LsmOptions::default());
+    let client = Client::connect(server.local_addr()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"a", b"1");
+    batch.merge(b"a", b"2");
+    let result = client.write_batch(batch);
+    assert!(matches!(&result, Err(StoreError::Unsupported(message)) if message == "batch merges"), "expected merges to be refused, actually {:?}", result);
+    let mut batch = WriteBatch::new();
+    batch.put_cf("users", b"a", b"1");
+    assert!(matches!(client.write_batch(batch), Err(StoreError::Unsupported(_))), "expected column families to be refused");
+    assert!(client.get(b"a").unwrap().is_none(), "expected refused batches not to be sent");
+    server.shutdown().unwrap();
+}
@@ -0,0 +1,101 @@
+#[cfg(test)]
+use std::{fs::{create_dir_all, OpenOptions}, sync::Arc};
+
+#[cfg(test)]
+use crate::storage::{comparator::*, files::get_lsmdir, merge::MergingIter, options::LsmOptions, segment::{Entry, SegmentBuilder, SegmentReader}, stats::Statistics, tree::{LogSegment, TriOption::*}};
+
+#[cfg(test)]
+fn sorted(comparator: &dyn Comparator, keys: &[&[u8]]) -> Vec<Vec<u8>> {
+    let mut keys: Vec<Vec<u8>> = keys.iter().map(|key| key.to_vec()).collect();
+    keys.sort_by(|a, b| comparator.compare(a, b));
+    keys
+}
+
+// Minimal big-endian encoding of the number, without leading zero bytes
+#[cfg(test)]
+fn numeric_key(n: u64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[zeros..].to_vec()
+}
+
+#[test]
+pub fn test_builtin_comparator_orders() {
+    let keys: [&[u8]; 6] = [b"b", b"B", b"a", b"ab", b"A", b""];
+
+    let reverse = sorted(&ReverseBytewiseComparator, &keys);
+    assert!(reverse == sorted(&BytewiseComparator, &keys).into_iter().rev().collect::<Vec<_>>(), "reverse order is {:?}", reverse);
+
+    let insensitive = sorted(&CaseInsensitiveComparator, &keys);
+    let expected: Vec<Vec<u8>> = [&b""[..], b"A", b"a", b"ab", b"B", b"b"].iter().map(|key| key.to_vec()).collect();
+    assert!(insensitive == expected, "case insensitive order is {:?}", insensitive);
+
+    let numeric = sorted(&BigEndianNumericComparator, &[&[1, 0], &[255], &[0, 255], &[2], &[0, 0, 1], &[]]);
+    let expected: Vec<Vec<u8>> = vec![vec![], vec![0, 0, 1], vec![2], vec![255], vec![0, 255], vec![1, 0]];
+    assert!(numeric == expected, "numeric order is {:?}", numeric);
+}
+
+#[test]
+pub fn test_memtable_uses_comparator() {
+    let tree: LogSegment<Vec<u8>> = LogSegment::with_comparator(Arc::new(BigEndianNumericComparator));
+    for i in (0..1000u64).rev() {
+        tree.insert((numeric_key(i * 7), numeric_key(i)));
+    }
+
+    let keys: Vec<u64> = tree.iter().map(|(k, _)| k.iter().fold(0, |n, b| n << 8 | *b as u64)).collect();
+    assert!(keys == (0..1000).map(|i| i * 7).collect::<Vec<_>>(), "memtable not in numeric order");
+    assert!(matches!(tree.get(numeric_key(700)), TriSome(v) if *v == numeric_key(100)), "lookup of 700 failed");
+}
+
+#[test]
+pub fn test_segment_uses_comparator() {
+    /*
+    A segment written in numeric order, which is nothing like bytewise order for keys of
+    different widths, is searchable when read with the same comparator
+    */
+    let dir = get_lsmdir("test_segment_uses_comparator");
+    create_dir_all(&dir).unwrap();
+    let path = dir.join("segment_0.data");
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path).unwrap();
+
+    let options = LsmOptions::default().block_size(128).comparator(Arc::new(BigEndianNumericComparator));
+    let mut builder = SegmentBuilder::new(file, &options, 0);
+    for i in 0..2000u64 {
+        builder.add(&numeric_key(i * 3), TriSome(format!("value{}", i).as_bytes())).unwrap();
+    }
+    builder.finish().unwrap();
+
+    let reader = SegmentReader::open(OpenOptions::new().read(true).open(&path).unwrap(), &options, Arc::new(Statistics::new())).unwrap();
+    for i in 0..6000u64 {
+        match reader.get(&numeric_key(i)).unwrap() {
+            TriSome(v) => assert!(i % 3 == 0 && v == format!("value{}", i / 3).as_bytes(), "unexpected value for {}", i),
+            _ => assert!(i % 3 != 0, "{} missing from segment", i),
+        }
+    }
+}
+
+#[test]
+pub fn test_merging_iter_uses_comparator() {
+    let newer = vec![Entry::new(b"c".to_vec(), TriSome(b"new".to_vec()), None), Entry::new(b"a".to_vec(), Tombstoned, None)];
+    let older = vec![
+        Entry::new(b"d".to_vec(), TriSome(b"d".to_vec()), None),
+        Entry::new(b"c".to_vec(), TriSome(b"old".to_vec()), None),
+        Entry::new(b"b".to_vec(), TriSome(b"b".to_vec()), None)];
+
+    let sources = vec![
+        Box::new(newer.into_iter().map(Ok)) as Box<dyn Iterator<Item = _>>,
+        Box::new(older.into_iter().map(Ok)) as Box<dyn Iterator<Item = _>>];
+    let merged: Vec<(Vec<u8>, Option<Vec<u8>>)> = MergingIter::new(sources, Arc::new(ReverseBytewiseComparator))
+        .map(|entry| match entry.unwrap() {
+            Entry{key, value: TriSome(v), ..} => (key, Some(v)),
+            Entry{key, ..} => (key, None),
+        })
+        .collect();
+
+    let expected = vec![
+        (b"d".to_vec(), Some(b"d".to_vec())),
+        (b"c".to_vec(), Some(b"new".to_vec())),
+        (b"b".to_vec(), Some(b"b".to_vec())),
+        (b"a".to_vec(), None)];
+    assert!(merged == expected, "merged entries are {:?}", merged);
+}
@@ -0,0 +1,108 @@
+#[cfg(test)]
+use std::{io::Result, sync::Arc};
+
+#[cfg(test)]
+use crate::storage::{coding::{put_length_prefixed, put_varint64}, compression::*};
+
+#[cfg(test)]
+fn json_values(count: usize) -> Vec<u8> {
+    let mut values = Vec::new();
+    for i in 0..count {
+        values.extend_from_slice(format!("{{\"id\":{},\"name\":\"user{}\",\"active\":true,\"tags\":[\"a\",\"b\"]}}", i, i).as_bytes());
+    }
+    values
+}
+
+#[test]
+pub fn test_lz_round_trip() {
+    let mut rng: u64 = 0x9E37_79B9_7F4A_7C15;
+    let random: Vec<u8> = (0..4096).map(|_| {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng as u8
+    }).collect();
+
+    let inputs = [Vec::new(), b"abc".to_vec(), vec![7; 10000], b"abcabcabcabcabcabcx".to_vec(), json_values(100), random];
+    for input in inputs {
+        let compressed = LzCompressor.compress(&input);
+        let decompressed = LzCompressor.decompress(&compressed).unwrap();
+        assert!(decompressed == input, "lz round trip changed a {} byte input", input.len());
+    }
+
+    let json = json_values(100);
+    let compressed = LzCompressor.compress(&json);
+    assert!(compressed.len() * 2 < json.len(), "expected json to compress by half, {} to {} bytes", json.len(), compressed.len());
+}
+
+#[test]
+pub fn test_lz_rejects_corrupt_input() {
+    let mut compressed = LzCompressor.compress(&json_values(10));
+    let last = compressed.len() - 1;
+    compressed.truncate(last);
+    assert!(LzCompressor.decompress(&compressed).is_err(), "decompressed a truncated block");
+
+    // A corrupt uncompressed length fails without allocating that much
+    let mut huge = Vec::new();
+    put_varint64(&mut huge, u64::MAX / 2);
+    put_length_prefixed(&mut huge, b"tiny");
+    assert!(LzCompressor.decompress(&huge).is_err(), "decompressed a block shorter than its length");
+    let mut short = Vec::new();
+    put_varint64(&mut short, 2);
+    put_length_prefixed(&mut short, b"tiny");
+    assert!(LzCompressor.decompress(&short).is_err(), "decompressed a block longer than its length");
+}
+
+#[test]
+pub fn test_compress_block_headers() {
+    let json = json_values(50);
+    let block = compress_block(&LzCompressor, &json);
+    assert!(block[0] == LZ_COMPRESSION_ID, "expected lz codec id in header, actually {}", block[0]);
+    assert!(decompress_block(&[], &block).unwrap() == json, "lz block did not round trip");
+
+    // Nothing to gain from compressing a handful of bytes, so they are stored as is
+    let block = compress_block(&LzCompressor, b"tiny");
+    assert!(block[0] == NO_COMPRESSION_ID, "expected uncompressed header for incompressible block");
+    assert!(decompress_block(&[], &block).unwrap() == b"tiny", "uncompressed block did not round trip");
+}
+
+// Run length codec, to check codecs plugged in through the trait are found by id
+#[cfg(test)]
+struct RunLength;
+
+#[cfg(test)]
+impl Compressor for RunLength {
+    fn id(&self) -> u8 {
+        42
+    }
+
+    fn name(&self) -> &str {
+        "rle"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in input.chunk_by(|a, b| a == b) {
+            for run in chunk.chunks(255) {
+                out.push(run.len() as u8);
+                out.push(run[0]);
+            }
+        }
+        out
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        Ok(input.chunks(2).flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize)).collect())
+    }
+}
+
+#[test]
+pub fn test_custom_compressor() {
+    let input = vec![1; 1000];
+    let block = compress_block(&RunLength, &input);
+    assert!(block[0] == 42, "expected custom codec id in header, actually {}", block[0]);
+
+    let compressors: Vec<Arc<dyn Compressor>> = vec![Arc::new(RunLength)];
+    assert!(decompress_block(&compressors, &block).unwrap() == input, "custom codec block did not round trip");
+    assert!(decompress_block(&[], &block).is_err(), "decompressed a block with a codec that was never registered");
+}
@@ -0,0 +1,288 @@
+#[cfg(test)]
+use std::{io::{BufRead, BufReader, Read, Write}, net::TcpStream, sync::{Arc, Mutex}, time::Duration};
+#[cfg(test)]
+use crate::{http::{percent_decode, HttpServer}, json::{self, Json}, server::ServerHandle, storage::{clock::ManualClock, lsm::LsmTree, options::LsmOptions}};
+
+#[cfg(test)]
+#[derive(Debug)]
+struct TestResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[cfg(test)]
+impl TestResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[cfg(test)]
+struct TestClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+#[cfg(test)]
+impl TestClient {
+    fn connect(server: &ServerHandle) -> TestClient {
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        TestClient{reader: BufReader::new(stream.try_clone().unwrap()), stream}
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end_matches("\r\n").to_string()
+    }
+
+    // Reads a response to a request of this method, the body being read by its length, chunks, or to the end
+    fn response(&mut self, method: &str) -> Option<TestResponse> {
+        let status_line = self.line();
+        if status_line.is_empty() {
+            return None;
+        }
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let line = self.line();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.push((name.to_string(), value.to_string()));
+        }
+        let mut response = TestResponse{status, headers, body: Vec::new()};
+        if method == "HEAD" || status == 204 || status == 100 {
+            return Some(response);
+        }
+        if let Some(length) = response.header("Content-Length") {
+            response.body.resize(length.parse().unwrap(), 0);
+            self.reader.read_exact(&mut response.body).unwrap();
+        }
+        else if response.header("Transfer-Encoding") == Some("chunked") {
+            loop {
+                let size = usize::from_str_radix(&self.line(), 16).unwrap();
+                let start = response.body.len();
+                response.body.resize(start + size, 0);
+                self.reader.read_exact(&mut response.body[start..]).unwrap();
+                assert!(self.line().is_empty(), "expected chunk to end with CRLF");
+                if size == 0 {
+                    break;
+                }
+            }
+        }
+        else {
+            self.reader.read_to_end(&mut response.body).unwrap();
+        }
+        Some(response)
+    }
+
+    fn send(&mut self, method: &str, target: &str, headers: &[&str], body: &[u8]) {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target).into_bytes();
+        for header in headers {
+            request.extend_from_slice(format!("{}\r\n", header).as_bytes());
+        }
+        if !body.is_empty() {
+            request.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+        request.extend_from_slice(body);
+        self.stream.write_all(&request).unwrap();
+    }
+
+    fn request(&mut self, method: &str, target: &str, headers: &[&str], body: &[u8]) -> TestResponse {
+        self.send(method, target, headers, body);
+        self.response(method).unwrap()
+    }
+
+    fn raw(&mut self, request: &[u8], method: &str) -> Option<TestResponse> {
+        self.stream.write_all(request).unwrap();
+        self.response(method)
+    }
+}
+
+#[cfg(test)]
+fn start(dbname: &str, options: LsmOptions) -> ServerHandle {
+    let lsm = LsmTree::new_delete_existing_with_options(dbname, options);
+    HttpServer::bind("127.0.0.1:0", Arc::new(Mutex::new(lsm))).unwrap().spawn().unwrap()
+}
+
+#[cfg(test)]
+fn error(response: &TestResponse) -> String {
+    assert!(response.header("Content-Type") == Some("application/json"), "expected a JSON error");
+    json::parse(&response.text()).unwrap().get("error").and_then(Json::as_str).unwrap().to_string()
+}
+
+#[test]
+pub fn test_http_percent_decode() {
+    assert!(percent_decode("a%2Fb%00%ff+c", false) == Some(b"a/b\0\xff+c".to_vec()), "unexpected path decoding");
+    assert!(percent_decode("a+b%2B", true) == Some(b"a b+".to_vec()), "unexpected query decoding");
+    for invalid in ["%", "%4", "%zz", "a%g1"] {
+        assert!(percent_decode(invalid, false).is_none(), "expected {:?} not to decode", invalid);
+    }
+}
+
+#[test]
+pub fn test_http_kv() {
+    let clock = Arc::new(ManualClock::new(0));
+    let server = start("test_http_kv", LsmOptions::default().clock(clock.clone()));
+    // Every request goes over the one connection, which stays open between them
+    let mut client = TestClient::connect(&server);
+
+    let response = client.request("PUT", "/kv/greeting", &["Content-Type: text/plain"], b"hello");
+    assert!(response.status == 204 && response.header("Content-Length").is_none(), "unexpected put {:?}", response);
+    let response = client.request("GET", "/kv/greeting", &[], b"");
+    assert!(response.status == 200 && response.body == b"hello", "unexpected get {:?}", response);
+    assert!(response.header("Content-Type") == Some("application/octet-stream"), "expected a raw value");
+    let response = client.request("HEAD", "/kv/greeting", &[], b"");
+    assert!(response.status == 200 && response.header("Content-Length") == Some("5") && response.body.is_empty(), "unexpected head {:?}", response);
+
+    // Keys and values are any bytes
+    let value: Vec<u8> = (0..=255).collect();
+    assert!(client.request("PUT", "/kv/a%2Fb%00%FF", &[], &value).status == 204, "unexpected binary put");
+    let response = client.request("GET", "/kv/a%2Fb%00%FF", &[], b"");
+    assert!(response.status == 200 && response.body == value, "unexpected binary get {:?}", response);
+    let response = client.request("GET", "/kv/a%2Fb%00%FF", &["Accept: text/html, application/json;q=0.9"], b"");
+    let object = json::parse(&response.text()).unwrap();
+    assert!(json::get_bytes(&object, "key") == Ok(Some(b"a/b\0\xff".to_vec())) && json::get_bytes(&object, "value") == Ok(Some(value.clone())), "expected binary key and value as base64 {:?}", response.text());
+    let response = client.request("GET", "/kv/greeting", &["Accept: application/json, application/octet-stream"], b"");
+    assert!(response.body == b"hello", "expected a raw value when the client takes one");
+
+    let response = client.request("PUT", "/kv/session?ttl=10", &[], b"x");
+    assert!(response.status == 204, "unexpected put with ttl {:?}", response);
+    clock.advance(Duration::from_secs(10));
+    assert!(client.request("GET", "/kv/session", &[], b"").status == 404, "expected value to have expired");
+
+    assert!(client.request("DELETE", "/kv/greeting", &[], b"").status == 204, "unexpected delete");
+    let response = client.request("GET", "/kv/greeting", &[], b"");
+    assert!(response.status == 404 && error(&response) == "key not found", "expected deleted key to be missing {:?}", response);
+    assert!(client.request("DELETE", "/kv/greeting", &[], b"").status == 404, "expected deleting a missing key to be not found");
+
+    let errors: [(&str, &str, u16, &str); 6] = [
+        ("GET", "/kv/", 400, "missing key"),
+        ("GET", "/kv/%zz", 400, "malformed key"),
+        ("PUT", "/kv/a?ttl=0", 400, "ttl must be a positive number of seconds"),
+        ("POST", "/kv/a", 405, "method not allowed"),
+        ("GET", "/batch", 405, "method not allowed"),
+        ("GET", "/nothing", 404, "no such endpoint"),
+    ];
+    for (method, target, status, message) in errors {
+        let response = client.request(method, target, &[], b"");
+        assert!(response.status == status && error(&response) == message, "expected {} {} to fail with {} {:?}, actually {:?}", method, target, status, message, response);
+    }
+    assert!(client.request("POST", "/kv/a", &[], b"").header("Allow") == Some("GET, HEAD, PUT, DELETE"), "expected the allowed methods");
+
+    let response = client.request("GET", "/health", &[], b"");
+    assert!(response.status == 200 && response.text() == "{\"status\":\"ok\"}", "unexpected health {:?}", response);
+    // Entries counts the records in the memtable, the tombstone and expired value among them
+    let stats = json::parse(&client.request("GET", "/stats", &[], b"").text()).unwrap();
+    assert!(stats.get("entries") == Some(&Json::Number(3.0)) && stats.get("tickers").and_then(|tickers| tickers.get("block.cache.hit")).is_some(), "unexpected stats {:?}", stats);
+    server.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_http_scan_and_batch() {
+    let server = start("test_http_scan_and_batch", LsmOptions::default().write_buffer_size(64 * 1024));
+    let mut client = TestClient::connect(&server);
+
+    let mut operations: Vec<String> = (0..5000).map(|i| format!("{{\"op\":\"put\",\"key\":\"key:{:04}\",\"value\":\"{}\"}}", i, "v".repeat(i % 50))).collect();
+    operations.push("{\"op\":\"put\",\"key_base64\":\"/w==\",\"value_base64\":\"AP8=\"}".to_string());
+    operations.push("{\"op\":\"delete\",\"key\":\"key:0000\"}".to_string());
+    let response = client.request("POST", "/batch", &["Content-Type: application/json; charset=utf-8"], format!("[{}]", operations.join(",")).as_bytes());
+    assert!(response.status == 200 && response.text() == "{\"written\":5002}", "unexpected batch {:?}", response);
+
+    // A large scan streams in many chunks
+    let response = client.request("GET", "/scan", &[], b"");
+    assert!(response.status == 200 && response.header("Content-Type") == Some("application/x-ndjson") && response.header("Transfer-Encoding") == Some("chunked"), "unexpected scan {:?}", response.headers);
+    let rows: Vec<Json> = response.text().lines().map(|line| json::parse(line).unwrap()).collect();
+    assert!(rows.len() == 5000, "expected every live key, actually {}", rows.len());
+    assert!(json::get_bytes(&rows[0], "key") == Ok(Some(b"key:0001".to_vec())), "expected deleted key to be skipped");
+    assert!(json::get_bytes(&rows[4999], "key") == Ok(Some(vec![0xff])) && json::get_bytes(&rows[4999], "value") == Ok(Some(vec![0, 0xff])), "expected binary row as base64");
+
+    let keys = |client: &mut TestClient, target: &str| -> Vec<String> {
+        let response = client.request("GET", target, &[], b"");
+        assert!(response.status == 200, "unexpected scan {:?}", response);
+        response.text().lines().map(|line| json::parse(line).unwrap().get("key").and_then(Json::as_str).unwrap().to_string()).collect()
+    };
+    assert!(keys(&mut client, "/scan?start=key:0010&end=key:0013") == ["key:0010", "key:0011", "key:0012"], "unexpected range scan");
+    assert!(keys(&mut client, "/scan?prefix=key:00&start=key:0095&limit=3") == ["key:0095", "key:0096", "key:0097"], "unexpected prefix scan");
+    let limited = keys(&mut client, "/scan?start=key:0500&limit=3000");
+    assert!(limited.len() == 3000 && limited[0] == "key:0500" && limited[2999] == "key:3499", "expected a limit to hold across chunks, actually {}", limited.len());
+    assert!(keys(&mut client, "/scan?prefix=key%3A1234").len() == 1 && keys(&mut client, "/scan?prefix=nothing").is_empty(), "unexpected prefix scans");
+
+    let response = client.request("GET", "/scan?start=b&end=a", &[], b"");
+    assert!(response.status == 400 && error(&response) == "start is after end", "unexpected error {:?}", response);
+    let response = client.request("GET", "/scan?limit=many", &[], b"");
+    assert!(response.status == 400 && error(&response) == "limit must be a number", "unexpected error {:?}", response);
+
+    // A batch with any invalid operation writes nothing
+    let invalid: [(&str, &str, u16, &str); 7] = [
+        ("text/plain", "[]", 415, "batch must be application/json"),
+        ("application/json", "[", 400, "invalid JSON: unexpected end of document at byte 1"),
+        ("application/json", "{}", 400, "batch must be an array of operations"),
+        ("application/json", "[{\"op\":\"put\",\"key\":\"new\",\"value\":\"1\"},{\"op\":\"put\",\"key\":\"x\"}]", 400, "operation 1: missing value"),
+        ("application/json", "[{\"op\":\"put\",\"key\":\"new\",\"value\":\"1\"},{\"op\":\"delete\",\"key\":\"x\",\"value\":\"1\"}]", 400, "operation 1: delete takes no value"),
+        ("application/json", "[{\"op\":\"merge\",\"key\":\"x\"}]", 400, "operation 0: op must be \"put\" or \"delete\""),
+        ("application/json", "[{\"op\":\"delete\",\"key_base64\":\"!\"}]", 400, "operation 0: key_base64 is not valid base64"),
+    ];
+    for (content_type, body, status, message) in invalid {
+        let response = client.request("POST", "/batch", &[&format!("Content-Type: {}", content_type)], body.as_bytes());
+        assert!(response.status == status && error(&response) == message, "expected batch {} to fail with {:?}, actually {:?}", body, message, response);
+    }
+    assert!(client.request("GET", "/kv/new", &[], b"").status == 404, "expected failed batches not to write");
+    server.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_http_protocol() {
+    let server = start("test_http_protocol", LsmOptions::default());
+
+    // A chunked body, sent after the server agrees to take it
+    let mut client = TestClient::connect(&server);
+    client.stream.write_all(b"PUT /kv/chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n").unwrap();
+    assert!(client.response("PUT").map(|response| response.status) == Some(100), "expected 100 Continue");
+    let response = client.raw(b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n", "PUT").unwrap();
+    assert!(response.status == 204, "unexpected chunked put {:?}", response);
+    assert!(client.request("GET", "/kv/chunked", &[], b"").body == b"hello world", "expected chunks to be joined");
+
+    // Pipelined requests are answered in order
+    let response = client.raw(b"GET /kv/chunked HTTP/1.1\r\n\r\nGET /health HTTP/1.1\r\n\r\n", "GET").unwrap();
+    assert!(response.body == b"hello world" && client.response("GET").unwrap().status == 200, "unexpected pipelined responses");
+
+    let response = client.raw(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n", "GET").unwrap();
+    assert!(response.status == 200 && response.header("Connection") == Some("close"), "expected connection to close {:?}", response);
+    assert!(client.response("GET").is_none(), "expected connection to be closed");
+
+    // HTTP/1.0 clients get the scan without chunks, ended by the connection closing
+    let mut client = TestClient::connect(&server);
+    let response = client.raw(b"GET /scan HTTP/1.0\r\n\r\n", "GET").unwrap();
+    assert!(response.header("Transfer-Encoding").is_none() && response.text() == "{\"key\":\"chunked\",\"value\":\"hello world\"}\n", "unexpected HTTP/1.0 scan {:?}", response);
+
+    let malformed: [(&[u8], u16, &str); 8] = [
+        (b"GET\r\n\r\n", 400, "malformed request line"),
+        (b"GET /health HTTP/2.0\r\n\r\n", 505, "only HTTP/1.1 and HTTP/1.0 are supported"),
+        (b"GET health HTTP/1.1\r\n\r\n", 400, "request target must be a path"),
+        (b"GET /health HTTP/1.1\r\nbad header\r\n\r\n", 400, "malformed header"),
+        (b"PUT /kv/a HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab", 400, "invalid Content-Length"),
+        (b"PUT /kv/a HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n", 413, "request body too large"),
+        (b"PUT /kv/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n", 413, "request body too large"),
+        (b"PUT /kv/a HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", 501, "unsupported transfer encoding"),
+    ];
+    for (request, status, message) in malformed {
+        let mut client = TestClient::connect(&server);
+        let response = client.raw(request, "GET").unwrap();
+        assert!(response.status == status && error(&response) == message, "expected {:?} to fail with {:?}, actually {:?}", String::from_utf8_lossy(request), message, response);
+        assert!(client.response("GET").is_none(), "expected connection to be closed after a malformed request");
+    }
+    let mut client = TestClient::connect(&server);
+    let response = client.raw(format!("GET /health HTTP/1.1\r\nX-Big: {}\r\n\r\n", "x".repeat(70 * 1024)).as_bytes(), "GET").unwrap();
+    assert!(response.status == 431, "expected a large head to be rejected {:?}", response);
+    server.shutdown().unwrap();
+}
@@ -0,0 +1,58 @@
+#[cfg(test)]
+use crate::json::{base64_decode, base64_encode, bytes_member, get_bytes, parse, string, Json};
+
+#[test]
+pub fn test_json_parse() {
+    let document = parse(" {\"a\": [1, -2.5e3, true, false, null], \"b\": {\"c\": \"d\\\"\\u00e9\\ud83d\\ude00\\n\"}, \"\": []} ").unwrap();
+    let expected = Json::Object(vec![
+        ("a".to_string(), Json::Array(vec![Json::Number(1.0), Json::Number(-2500.0), Json::Bool(true), Json::Bool(false), Json::Null])),
+        ("b".to_string(), Json::Object(vec![("c".to_string(), Json::String("d\"é😀\n".to_string()))])),
+        ("".to_string(), Json::Array(Vec::new())),
+    ]);
+    assert!(document == expected, "unexpected document {:?}", document);
+    assert!(document.get("b").and_then(|b| b.get("c")).and_then(Json::as_str) == Some("d\"é😀\n"), "expected to find nested member");
+
+    let errors = [
+        ("", "unexpected end of document at byte 0"),
+        ("[1, 2", "expected ',' or ']' at byte 5"),
+        ("{\"a\" 1}", "expected ':' at byte 5"),
+        ("{a: 1}", "expected '\"' at byte 1"),
+        ("\"tab\there\"", "control character in string at byte 5"),
+        ("\"\\q\"", "invalid escape at byte 3"),
+        ("\"\\ud83d\"", "expected '\\' at byte 7"),
+        ("tru", "expected 'e' at byte 3"),
+        ("1 2", "unexpected data after the document at byte 2"),
+        ("-", "invalid number at byte 0"),
+    ];
+    for (input, message) in errors {
+        let result = parse(input);
+        assert!(result == Err(message.to_string()), "expected {:?} to fail with {:?}, actually {:?}", input, message, result);
+    }
+    assert!(parse(&"[".repeat(1000)).is_err_and(|e| e.starts_with("document nested too deeply")), "expected deep nesting to fail");
+
+    let escaped = string("quote \" slash \\ newline \n bell \u{7}".as_bytes());
+    assert!(escaped == "\"quote \\\" slash \\\\ newline \\n bell \\u0007\"", "unexpected escaping {}", escaped);
+    assert!(parse(&escaped) == Ok(Json::String("quote \" slash \\ newline \n bell \u{7}".to_string())), "expected escaped string to parse back");
+}
+
+#[test]
+pub fn test_json_bytes() {
+    let cases: [(&[u8], &str); 6] = [(b"", ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foob", "Zm9vYg=="), (&[0xff, 0x00, 0xfe], "/wD+")];
+    for (bytes, encoded) in cases {
+        assert!(base64_encode(bytes) == encoded, "expected {:?} to encode as {}, actually {}", bytes, encoded, base64_encode(bytes));
+        assert!(base64_decode(encoded).as_deref() == Some(bytes), "expected {} to decode to {:?}", encoded, bytes);
+    }
+    for invalid in ["Zg=", "Zg", "Z===", "Zg==Zg==", "Zm9*"] {
+        assert!(base64_decode(invalid).is_none(), "expected {:?} not to decode", invalid);
+    }
+
+    let member = format!("{{{},{}}}", bytes_member("key", b"plain"), bytes_member("value", &[0xff, b'a']));
+    assert!(member == "{\"key\":\"plain\",\"value_base64\":\"/2E=\"}", "unexpected members {}", member);
+    let object = parse(&member).unwrap();
+    assert!(get_bytes(&object, "key") == Ok(Some(b"plain".to_vec())) && get_bytes(&object, "value") == Ok(Some(vec![0xff, b'a'])), "expected members to read back");
+    assert!(get_bytes(&object, "missing") == Ok(None), "expected a missing member to be None");
+    let invalid = parse("{\"a\":\"x\",\"a_base64\":\"eA==\",\"b\":1,\"c_base64\":\"!\"}").unwrap();
+    assert!(get_bytes(&invalid, "a") == Err("only one of a and a_base64 may be given".to_string()), "expected both forms to fail");
+    assert!(get_bytes(&invalid, "b") == Err("b must be a string".to_string()), "expected a number to fail");
+    assert!(get_bytes(&invalid, "c") == Err("c_base64 is not valid base64".to_string()), "expected invalid base64 to fail");
+}
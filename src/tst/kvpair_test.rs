@@ -0,0 +1,48 @@
+#[cfg(test)]
+use std::borrow::Cow;
+
+#[cfg(test)]
+use crate::kvpair::KVPair;
+
+#[test]
+pub fn test_kvpair_ord_consistent_with_eq() {
+    let pairs = [
+        KVPair::new(&b"a"[..], &b"1"[..]),
+        KVPair::new(&b"a"[..], &b"2"[..]),
+        KVPair::new(&b"a"[..], &b"1"[..]).with_seq(3),
+        KVPair::new(&b"a"[..], &b"1"[..]).with_seq(7),
+        KVPair::tombstone(&b"a"[..]),
+        KVPair::tombstone(&b"a"[..]).with_seq(5),
+        KVPair::new(&b"b"[..], &b""[..]),
+    ];
+
+    for (i, a) in pairs.iter().enumerate() {
+        for (j, b) in pairs.iter().enumerate() {
+            assert!((a == b) == (i == j), "{} and {} compare equal: {}", a, b, a == b);
+            assert!((a != b) == (i != j), "{} and {} compare not equal: {}", a, b, a != b);
+            assert!((a.cmp(b) == std::cmp::Ordering::Equal) == (a == b), "ordering of {} and {} disagrees with eq", a, b);
+            assert!(a.cmp(b) == b.cmp(a).reverse(), "ordering of {} and {} is not antisymmetric", a, b);
+        }
+    }
+
+    // Same key sorts newest first, then unsequenced, tombstones ahead of values
+    let mut sorted = pairs.to_vec();
+    sorted.sort();
+    let expected = [3, 5, 2, 4, 0, 1, 6];
+    for (pair, i) in sorted.iter().zip(expected) {
+        assert!(*pair == pairs[i], "expected {} in sorted position, actually {}", pairs[i], pair);
+    }
+}
+
+#[test]
+pub fn test_kvpair_borrowed_and_owned() {
+    let key = b"foo".to_vec();
+    let borrowed = KVPair::new(key.as_slice(), &b"bar"[..]);
+    assert!(matches!(borrowed.key, Cow::Borrowed(_)), "expected the key to be borrowed");
+
+    let owned: KVPair<'static> = borrowed.clone().into_owned();
+    drop(key);
+    assert!(owned == KVPair::new(b"foo".to_vec(), b"bar".to_vec()), "owned copy differs: {}", owned);
+    assert!(owned.key_str() == "foo" && owned.value_str() == "bar", "unexpected text views of {}", owned);
+    assert!(format!("{}", KVPair::tombstone(&b"foo"[..]).with_seq(9)) == "key: foo, deleted, seq: 9", "unexpected display");
+}
@@ -1,6 +1,12 @@
+#[allow(unused_imports)]
 use crate::log;
 #[cfg(test)]
-use crate::storage::lsm::LsmTree;
+use std::{fs::{metadata, read_dir, OpenOptions}, io::{self, Seek, SeekFrom}, sync::{Arc, Mutex}, thread, time::Duration};
+#[cfg(test)]
+use crate::kvpair::KVPair;
+
+#[cfg(test)]
+use crate::storage::{cache::BlockCache, checkpoint::{Checkpoint, StagedCheckpoint}, clock::ManualClock, comparator::ReverseBytewiseComparator, error::LsmError, compression::{Compressor, LzCompressor, NoCompression}, files::{get_checkpoint_dir, get_lsmdir, get_wal_path, purge_lsm_dir}, lsm::LsmTree, merge_operator::{AppendOperator, U64AddOperator}, options::{LsmOptions, WriteBufferManager}, stats::Ticker, wal::WalRecord, write_batch::WriteBatch};
 
 #[allow(unused_imports)]
 use crate::tst::tst_util::verify_deleted;
@@ -17,7 +23,7 @@ pub fn test_lsm_basic() {
     // write <foo, bar> to tree
     let result = lsm.write(k, v);
 
-    assert!(result, "Failed to write <foo, bar> to lsm");
+    assert!(result.is_ok(), "Failed to write <foo, bar> to lsm");
 
     verify_key_value(&mut lsm, k, v);
 }
@@ -31,14 +37,14 @@ pub fn test_lsm_delete() {
     // write <foo, bar> to tree
     let result = lsm.write(k, v);
 
-    assert!(result, "Failed to write <foo, bar> to lsm");
+    assert!(result.is_ok(), "Failed to write <foo, bar> to lsm");
 
     verify_key_value(&mut lsm, k, v);
     
     // write <foo, bar> to tree
     let result = lsm.delete(k);
 
-    assert!(result, "Failed to delete foo");
+    assert!(result.is_ok(), "Failed to delete foo");
 
     verify_deleted(&mut lsm, k);
 }
@@ -53,9 +59,9 @@ pub fn test_lsm_create_delete_existing() {
     // write <foo, bar> to tree
     let result= lsm.write(k, v);
 
-    assert!(result, "Failed to write <foo, bar> to lsm");
+    assert!(result.is_ok(), "Failed to write <foo, bar> to lsm");
 
-    if let Some(value) = lsm.get(k) {
+    if let Some(value) = lsm.get_str(k) {
         assert!(value == v, "Expected {} for value of {}, actually {}", v, k, value);
     }
     else {
@@ -64,6 +70,7 @@ pub fn test_lsm_create_delete_existing() {
 
     let mut lsm_new_delete_existing = LsmTree::new_delete_existing(dbname);
 
+    #[allow(clippy::redundant_pattern_matching)]
     if let Some(_) = lsm_new_delete_existing.get("foo") {
         panic!("Failed to delete existing DB, found value for foo on new DB");
     }
@@ -77,7 +84,7 @@ pub fn test_lsm_overwrite_value() {
 
     // write <foo, bar> to tree
     let result= lsm.write(k, v);
-    if result {
+    if result.is_ok() {
         verify_key_value(&mut lsm, k, v);
     }
 
@@ -86,7 +93,7 @@ pub fn test_lsm_overwrite_value() {
 
     // write <foo, bar2> to tree, would expect to overwrite existing pair
     let result = lsm.write(k, v);
-    if result {
+    if result.is_ok() {
         verify_key_value(&mut lsm, k, v);
     }
 }
@@ -97,17 +104,19 @@ pub fn test_lsm_restore_from_log() {
 
     for i in 0..lsm.num_entries() {
         let (k, v) = (format!("foo{}", i), format!("bar{}", i));
-        lsm.write(&k, &v);
+        lsm.write(&k, &v).unwrap();
     }
 
-    // shadowing lsm with same DB name is equivalent to re-starting process and
+    // dropping lsm and opening the same DB name is equivalent to re-starting process and
     // spinning up an existing DB, internally, it should result in restoring from
-    // the existing lo (test_lsm_restore_from_log.log), rather than creating a fresh LSM
+    // the existing lo (test_lsm_restore_from_log.log), rather than creating a fresh LSM.
+    // The DB stays locked for writing until the first is dropped
+    drop(lsm);
     let mut lsm = LsmTree::new("test_lsm_restore_from_log");
 
     for i in 0..lsm.num_entries() {
         let (k, v) = (format!("foo{}", i), format!("bar{}", i));
-        lsm.write(&k, &v);
+        lsm.write(&k, &v).unwrap();
         verify_key_value(&mut lsm, &k, &v);
     }
 }
@@ -127,7 +136,7 @@ pub fn test_lsm_get_tuples_from_old_segments() {
     // Keep appending to the lsm until we persist the current log 
     while lsm.total_segments() == 0 {
         let (k, v) = (format!("foo{}", i), format!("bar{}", i));
-        lsm.write(&k, &v);
+        lsm.write(&k, &v).unwrap();
         verify_key_value(&mut lsm, &k, &v);
         i += 1;
     }
@@ -157,14 +166,14 @@ pub fn test_lsm_verify_reclaim_old_segments() {
     // Keep appending to the lsm until we persist the current log 
     while lsm.total_segments() == 0 {
         let (k, v) = (format!("foo{}", i), format!("bar{}", i));
-        lsm.write(&k, &v);
+        lsm.write(&k, &v).unwrap();
         verify_key_value(&mut lsm, &k, &v);
         i += 1;
     }
 
     // We flush the in-memory segment lazily, so append one more key to force this
     let (k, v) = (format!("foo{}", i), format!("bar{}", i));
-    lsm.write(&k, &v);
+    lsm.write(&k, &v).unwrap();
     verify_key_value(&mut lsm, &k, &v);
 
     let mut i = 0;
@@ -172,13 +181,13 @@ pub fn test_lsm_verify_reclaim_old_segments() {
     // Replace all keys from above with new values
     while lsm.total_segments() == 1 {
         let (k, v) = (format!("foo{}", i), format!("zar{}", i));
-        lsm.write(&k, &v);
+        lsm.write(&k, &v).unwrap();
         i += 1;
     }
 
     // We flush the in-memory segment lazily, so append one more key to force this
     let (k, v) = (format!("foo{}", i), format!("zar{}", i));
-    lsm.write(&k, &v);
+    lsm.write(&k, &v).unwrap();
 
     let ex_segments = 2;
     let ex_tree_size = 2;
@@ -195,6 +204,7 @@ pub fn test_lsm_verify_reclaim_old_segments() {
 }
 
 #[test]
+#[allow(unused_assignments, clippy::mut_range_bound)]
 pub fn test_lsm_tombstone_existing_value() {
     /*
     The goal of this test is to validate the enhancements made to persist full log segments to disk.
@@ -209,7 +219,7 @@ pub fn test_lsm_tombstone_existing_value() {
     // Keep appending to the lsm until we persist the current log 
     while lsm.total_segments() == 0 {
         let (k, v) = (format!("foo{}", i), format!("bar{}", i));
-        lsm.write(&k, &v);
+        lsm.write(&k, &v).unwrap();
         verify_key_value(&mut lsm, &k, &v);
         i += 1;
     }
@@ -217,14 +227,18 @@ pub fn test_lsm_tombstone_existing_value() {
     // Delete all keys from above
    for j in 0..i {
         let k = format!("foo{}", j);
-        lsm.delete(&k);
+        lsm.delete(&k).unwrap();
         log(&format!("Verifying {} is deleted", k));
         verify_deleted(&mut lsm, &k);
         log(&format!("tree size is {}", lsm.num_entries()));
     }
 
+    // Tombstones take less memory than the values they replace, so deleting every key
+    // does not fill the memtable again, flush so the tombstones are read from disk too
+    lsm.flush();
+
     let ex_segments = 2;
-    let ex_tree_size = 2;
+    let ex_tree_size = 0;
     assert!(lsm.total_segments() == ex_segments, "expected {} disk segments, actually {}", ex_segments, lsm.total_segments());
     assert!(lsm.num_entries() == ex_tree_size, "expected {} entries in tree, actually {}", ex_tree_size, lsm.num_entries());
 
@@ -234,4 +248,1108 @@ pub fn test_lsm_tombstone_existing_value() {
         verify_deleted(&mut lsm, &k);
         i += 1;
     }
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn test_lsm_flush_by_memtable_bytes() {
+    /*
+    The memtable is flushed once it holds write_buffer_size bytes, regardless of how many
+    entries that is, so a handful of large values flush as soon as many small ones
+    */
+    let options = LsmOptions::default().write_buffer_size(16 * 1024);
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_lsm_flush_by_memtable_bytes", options);
+
+    let large_value = "x".repeat(4 * 1024);
+    let mut large_writes = 0;
+    while lsm.total_segments() == 0 {
+        lsm.write(format!("large{}", large_writes), &large_value).unwrap();
+        large_writes += 1;
+    }
+
+    let mut small_writes = 0;
+    while lsm.total_segments() == 1 {
+        lsm.write(format!("small{}", small_writes), "v").unwrap();
+        small_writes += 1;
+    }
+
+    assert!(large_writes <= 6, "expected a flush within 6 large writes, actually {}", large_writes);
+    assert!(small_writes > 100, "expected more than 100 small writes before a flush, actually {}", small_writes);
+    assert!(lsm.memtable_bytes() < lsm.write_buffer_size(), "memtable holds {} bytes after flush", lsm.memtable_bytes());
+
+    for i in 0..large_writes {
+        verify_key_value(&mut lsm, &format!("large{}", i), &large_value);
+    }
+    for i in 0..small_writes {
+        verify_key_value(&mut lsm, &format!("small{}", i), "v");
+    }
+}
+
+#[test]
+pub fn test_lsm_shared_write_buffer_manager() {
+    /*
+    Two LSMs sharing a write buffer manager flush once their memtables together reach the
+    shared limit, even though neither reaches its own write buffer size
+    */
+    let manager = Arc::new(WriteBufferManager::new(32 * 1024));
+    let options = LsmOptions::default().write_buffer_size(1024 * 1024).write_buffer_manager(manager.clone());
+    let mut first = LsmTree::new_delete_existing_with_options("test_lsm_shared_write_buffer_manager_a", options.clone());
+    let mut second = LsmTree::new_delete_existing_with_options("test_lsm_shared_write_buffer_manager_b", options);
+
+    let value = "y".repeat(1024);
+    let mut i = 0;
+    while first.total_segments() == 0 && second.total_segments() == 0 {
+        first.write(format!("foo{}", i), &value).unwrap();
+        second.write(format!("foo{}", i), &value).unwrap();
+        i += 1;
+        assert!(i < 32, "expected the shared limit to force a flush, wrote {} keys to each", i);
+    }
+
+    let used = first.memtable_bytes() + second.memtable_bytes();
+    assert!(manager.memory_usage() == used, "manager tracks {} bytes, memtables hold {}", manager.memory_usage(), used);
+
+    drop(first);
+    assert!(manager.memory_usage() == second.memtable_bytes(), "dropped LSM did not release its memtable bytes");
+
+    for j in 0..i {
+        verify_key_value(&mut second, &format!("foo{}", j), &value);
+    }
+}
+
+#[test]
+pub fn test_lsm_reopen_reads_flushed_segments() {
+    /*
+    Flushed segments are found again when an existing DB is restored, alongside whatever
+    was only in the WAL, and the latest value for each key still wins
+    */
+    let dbname = "test_lsm_reopen_reads_flushed_segments";
+    let options = LsmOptions::default().write_buffer_size(8 * 1024).block_size(512);
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options.clone());
+
+    let mut i = 0;
+    while lsm.total_segments() < 3 {
+        lsm.write(format!("foo{}", i), format!("bar{}", i)).unwrap();
+        i += 1;
+    }
+    lsm.write("foo0", "updated").unwrap();
+    lsm.delete("foo1").unwrap();
+    let segments = lsm.total_segments();
+    drop(lsm);
+
+    let mut lsm = LsmTree::new_with_options(dbname, options);
+    assert!(lsm.total_segments() == segments, "expected {} segments after reopen, actually {}", segments, lsm.total_segments());
+    assert!(lsm.num_entries() == 3, "expected only the unflushed entries in the WAL, actually {}", lsm.num_entries());
+
+    verify_key_value(&mut lsm, "foo0", "updated");
+    verify_deleted(&mut lsm, "foo1");
+    for j in 2..i {
+        verify_key_value(&mut lsm, &format!("foo{}", j), &format!("bar{}", j));
+    }
+}
+
+#[test]
+pub fn test_lsm_bloom_filters_skip_segments() {
+    /*
+    Looking up keys that were never written checks every segment's bloom filter, and the
+    filters should rule out nearly all of those segments, while keys that were written
+    are still found
+    */
+    let options = LsmOptions::default().write_buffer_size(8 * 1024);
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_lsm_bloom_filters_skip_segments", options);
+
+    let mut i = 0;
+    while lsm.total_segments() < 4 {
+        lsm.write(format!("foo{}", i), format!("bar{}", i)).unwrap();
+        i += 1;
+    }
+
+    for j in 0..1000 {
+        verify_deleted(&mut lsm, &format!("missing{}", j));
+    }
+    let checked = lsm.statistics().get(Ticker::BloomFilterChecked);
+    let useful = lsm.statistics().get(Ticker::BloomFilterUseful);
+    assert!(checked == 4000, "expected a filter check per segment per lookup, actually {}", checked);
+    assert!(useful > 3900, "expected filters to rule out most segments, only {} of {}", useful, checked);
+
+    for j in 0..i {
+        verify_key_value(&mut lsm, &format!("foo{}", j), &format!("bar{}", j));
+    }
+}
+
+#[test]
+pub fn test_lsm_block_cache_bounded() {
+    /*
+    With index and filter blocks unpinned, every block a lookup reads goes through the
+    block cache, which stays within its capacity however many segments are read, and
+    serves repeated lookups of the same keys without going back to the files
+    */
+    let cache = Arc::new(BlockCache::new(16 * 1024));
+    let options = LsmOptions::default()
+        .write_buffer_size(8 * 1024)
+        .block_size(1024)
+        .block_cache(Some(cache.clone()))
+        .pin_index_and_filter_blocks(false);
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_lsm_block_cache_bounded", options);
+
+    let mut i = 0;
+    while lsm.total_segments() < 8 {
+        lsm.write(format!("foo{}", i), format!("bar{}", i)).unwrap();
+        i += 1;
+    }
+
+    for j in 0..i {
+        verify_key_value(&mut lsm, &format!("foo{}", j), &format!("bar{}", j));
+        assert!(cache.usage() <= cache.capacity(), "cache usage {} over capacity {}", cache.usage(), cache.capacity());
+    }
+    let misses = lsm.statistics().get(Ticker::BlockCacheMiss);
+    assert!(misses > 0, "expected block reads to miss a cold cache");
+
+    // A handful of keys from one segment stays cached, so repeating them only hits
+    let hits = lsm.statistics().get(Ticker::BlockCacheHit);
+    for _ in 0..10 {
+        for j in 0..5 {
+            verify_key_value(&mut lsm, &format!("foo{}", j), &format!("bar{}", j));
+        }
+    }
+    let new_misses = lsm.statistics().get(Ticker::BlockCacheMiss) - misses;
+    let new_hits = lsm.statistics().get(Ticker::BlockCacheHit) - hits;
+    assert!(new_misses <= 3, "expected repeated lookups to hit the cache, {} misses", new_misses);
+    assert!(new_hits >= 100, "expected repeated lookups to hit the cache, only {} hits", new_hits);
+}
+
+#[cfg(test)]
+fn segment_bytes(dbname: &str) -> u64 {
+    read_dir(get_lsmdir(dbname)).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "data"))
+        .map(|path| path.metadata().unwrap().len())
+        .sum()
+}
+
+#[test]
+pub fn test_lsm_compact_merges_segments() {
+    /*
+    Compaction merges every segment into one, keeping the newest value for each key and
+    dropping deleted keys entirely. Flushed (L0) segments are uncompressed by default
+    while the compacted (L1) segment is compressed, so it takes far less space
+    */
+    let dbname = "test_lsm_compact_merges_segments";
+    let options = LsmOptions::default().write_buffer_size(16 * 1024);
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options.clone());
+
+    let mut i = 0;
+    while lsm.total_segments() < 4 {
+        lsm.write(format!("foo{}", i), format!("{{\"id\":{},\"status\":\"active\",\"version\":1}}", i)).unwrap();
+        i += 1;
+    }
+    for j in (0..i).step_by(3) {
+        lsm.write(format!("foo{}", j), "overwritten").unwrap();
+    }
+    for j in (1..i).step_by(3) {
+        lsm.delete(format!("foo{}", j)).unwrap();
+    }
+    lsm.flush();
+    let uncompressed_bytes = segment_bytes(dbname);
+
+    lsm.compact().unwrap();
+    assert!(lsm.total_segments() == 1, "expected 1 segment after compaction, actually {}", lsm.total_segments());
+    assert!(lsm.num_entries() == 0, "expected compaction to flush the memtable, {} entries left", lsm.num_entries());
+    let compressed_bytes = segment_bytes(dbname);
+    assert!(compressed_bytes * 2 < uncompressed_bytes, "expected compaction to at least halve {} bytes, actually {}", uncompressed_bytes, compressed_bytes);
+
+    // Reopen, so we also read the compacted segment back from disk
+    drop(lsm);
+    let mut lsm = LsmTree::new_with_options(dbname, options);
+    assert!(lsm.total_segments() == 1, "expected 1 segment after reopen, actually {}", lsm.total_segments());
+    for j in 0..i {
+        let k = format!("foo{}", j);
+        match j % 3 {
+            0 => verify_key_value(&mut lsm, &k, "overwritten"),
+            1 => verify_deleted(&mut lsm, &k),
+            _ => verify_key_value(&mut lsm, &k, &format!("{{\"id\":{},\"status\":\"active\",\"version\":1}}", j)),
+        }
+    }
+
+    // New segments flushed after compaction are read ahead of the compacted one
+    lsm.write("foo2", "newer").unwrap();
+    lsm.flush();
+    verify_key_value(&mut lsm, "foo2", "newer");
+}
+
+#[test]
+pub fn test_lsm_compact_crash() {
+    /*
+    A crash part way through deleting the segments a compaction replaced leaves only the
+    newest of them, so a key deleted in one is still deleted, the compacted segment having
+    dropped its tombstone
+    */
+    let dbname = "test_lsm_compact_crash";
+    let mut lsm = LsmTree::new_delete_existing(dbname);
+    lsm.write("gone", "old").unwrap();
+    lsm.flush();
+    lsm.delete("gone").unwrap();
+    lsm.write("kept", "1").unwrap();
+    lsm.flush();
+    lsm.write("kept", "2").unwrap();
+    lsm.flush();
+
+    // Oldest first, as compaction deletes them
+    let segment_id = |path: &std::path::Path| -> usize { path.file_stem().unwrap().to_str().unwrap()["segment_".len()..].parse().unwrap() };
+    let mut segments: Vec<_> = read_dir(get_lsmdir(dbname)).unwrap()
+        .map(|item| item.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "data"))
+        .map(|path| (segment_id(&path), std::fs::read(&path).unwrap(), path))
+        .collect();
+    segments.sort();
+    assert!(segments.len() == 3, "expected 3 segments, actually {}", segments.len());
+    lsm.compact().unwrap();
+    drop(lsm);
+
+    for deleted in 0..segments.len() {
+        for (_, contents, path) in &segments[deleted..] {
+            std::fs::write(path, contents).unwrap();
+        }
+        let mut lsm = LsmTree::new(dbname);
+        assert!(lsm.total_segments() == 1 + segments.len() - deleted, "expected the segments left to be read in, actually {}", lsm.total_segments());
+        verify_deleted(&mut lsm, "gone");
+        verify_key_value(&mut lsm, "kept", "2");
+        drop(lsm);
+        for (_, _, path) in &segments[deleted..] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
+
+#[test]
+pub fn test_lsm_compression_per_level() {
+    /*
+    Compression is chosen per level, here L0 is compressed and L1 is not, the reverse of
+    the default, and data reads back the same at both levels
+    */
+    let dbname = "test_lsm_compression_per_level";
+    let compressors: Vec<Arc<dyn Compressor>> = vec![Arc::new(LzCompressor), Arc::new(NoCompression)];
+    let options = LsmOptions::default().write_buffer_size(16 * 1024).compression_per_level(compressors);
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options);
+
+    let value = "abcdefgh".repeat(16);
+    let mut i = 0;
+    while lsm.total_segments() < 2 {
+        lsm.write(format!("foo{}", i), &value).unwrap();
+        i += 1;
+    }
+    lsm.flush();
+    let l0_bytes = segment_bytes(dbname);
+    for j in 0..i {
+        verify_key_value(&mut lsm, &format!("foo{}", j), &value);
+    }
+
+    lsm.compact().unwrap();
+    let l1_bytes = segment_bytes(dbname);
+    assert!(l1_bytes > l0_bytes * 2, "expected uncompressed L1 to be far larger than compressed L0, {} vs {}", l1_bytes, l0_bytes);
+    for j in 0..i {
+        verify_key_value(&mut lsm, &format!("foo{}", j), &value);
+    }
+}
+
+#[test]
+pub fn test_lsm_binary_keys_and_values() {
+    /*
+    Keys and values are arbitrary bytes, including bytes that are not UTF-8 and the spaces
+    and newlines the WAL used to be split on, and read back the same from the WAL after a
+    reopen and from disk after a flush
+    */
+    let dbname = "test_lsm_binary_keys_and_values";
+    let mut lsm = LsmTree::new_delete_existing(dbname);
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..=255u8)
+        .map(|b| (vec![b, 0xff, b' ', b'\n', b], vec![0, b, b'\n', 0xfe, b' ']))
+        .collect();
+    for (k, v) in &pairs {
+        assert!(lsm.write(k, v).is_ok(), "Failed to write {:?}", k);
+    }
+    lsm.write(b"", b"empty key").unwrap();
+    lsm.write("empty value", b"").unwrap();
+    lsm.delete(&pairs[0].0).unwrap();
+    drop(lsm);
+
+    let mut lsm = LsmTree::new(dbname);
+    for _ in 0..2 {
+        assert!(lsm.get(&pairs[0].0).is_none(), "deleted key {:?} was found", pairs[0].0);
+        for (k, v) in &pairs[1..] {
+            assert!(lsm.get(k).as_ref() == Some(v), "expected {:?} for {:?}, actually {:?}", v, k, lsm.get(k));
+        }
+        assert!(lsm.get(b"") == Some(b"empty key".to_vec()), "empty key not found");
+        assert!(lsm.get("empty value") == Some(Vec::new()), "empty value not found");
+        lsm.flush();
+    }
+}
+
+#[test]
+pub fn test_lsm_restore_torn_wal_record() {
+    /*
+    A crash part way through appending to the WAL leaves a torn record at its end, which
+    restoring drops, keeping every record before it and any written after the restore
+    */
+    let dbname = "test_lsm_restore_torn_wal_record";
+    let mut lsm = LsmTree::new_delete_existing(dbname);
+    lsm.write("foo", "bar").unwrap();
+    lsm.write("baz", "qux").unwrap();
+    drop(lsm);
+
+    let wal_path = get_lsmdir(dbname).join(format!("{}.log", dbname));
+    let wal_len = metadata(&wal_path).unwrap().len();
+    OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(wal_len - 2).unwrap();
+
+    let mut lsm = LsmTree::new(dbname);
+    verify_key_value(&mut lsm, "foo", "bar");
+    verify_deleted(&mut lsm, "baz");
+    lsm.write("baz", "new").unwrap();
+    drop(lsm);
+
+    let mut lsm = LsmTree::new(dbname);
+    verify_key_value(&mut lsm, "foo", "bar");
+    verify_key_value(&mut lsm, "baz", "new");
+}
+
+#[test]
+pub fn test_lsm_restore_unreadable_wal() {
+    /*
+    A WAL that holds no readable records is only truncated when its first record is cut
+    short, one in the old text format or with a corrupt first record fails to open and is
+    left as it is
+    */
+    let dbname = "test_lsm_restore_unreadable_wal";
+    let lsm = LsmTree::new_delete_existing(dbname);
+    drop(lsm);
+    let wal_path = get_wal_path(dbname);
+    let expect_refused = |contents: &[u8]| {
+        std::fs::write(&wal_path, contents).unwrap();
+        match LsmTree::open(dbname, LsmOptions::default()) {
+            Err(LsmError::Io(e)) => assert!(e.kind() == io::ErrorKind::InvalidData, "expected invalid data, actually {}", e),
+            Err(e) => panic!("expected an io error, actually {}", e),
+            Ok(_) => panic!("expected opening an unreadable WAL to fail"),
+        }
+        assert!(std::fs::read(&wal_path).unwrap() == contents, "expected the WAL to be left as it was");
+    };
+    expect_refused(b"foo bar\nbaz qux\nfoo\n");
+
+    std::fs::write(&wal_path, b"").unwrap();
+    let mut lsm = LsmTree::new(dbname);
+    lsm.write("foo", "bar").unwrap();
+    drop(lsm);
+    let mut record = std::fs::read(&wal_path).unwrap();
+    let last = record.len() - 1;
+    record[last] ^= 1;
+    expect_refused(&record);
+
+    record[last] ^= 1;
+    std::fs::write(&wal_path, &record[..record.len() - 2]).unwrap();
+    let mut lsm = LsmTree::new(dbname);
+    verify_deleted(&mut lsm, "foo");
+    assert!(metadata(&wal_path).unwrap().len() == 0, "expected the torn record to be dropped");
+}
+
+#[test]
+pub fn test_lsm_comparator_persisted() {
+    /*
+    The comparator a DB is created with is kept in its manifest, so it reopens with the
+    same comparator, through flushes and compaction, and fails to open with any other
+    */
+    let dbname = "test_lsm_comparator_persisted";
+    let options = LsmOptions::default().write_buffer_size(8 * 1024).comparator(Arc::new(ReverseBytewiseComparator));
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options.clone());
+
+    let mut i = 0;
+    while lsm.total_segments() < 3 {
+        lsm.write(format!("foo{}", i), format!("bar{}", i)).unwrap();
+        i += 1;
+    }
+    lsm.write("foo0", "updated").unwrap();
+    lsm.delete("foo1").unwrap();
+    lsm.compact().unwrap();
+    drop(lsm);
+
+    match LsmTree::open(dbname, LsmOptions::default()) {
+        Err(LsmError::ComparatorMismatch{persisted, requested}) => {
+            assert!(persisted == "reverse_bytewise" && requested == "bytewise", "unexpected mismatch {} vs {}", persisted, requested);
+        },
+        Err(e) => panic!("expected a comparator mismatch, actually {}", e),
+        Ok(_) => panic!("expected opening with the wrong comparator to fail"),
+    }
+
+    let mut lsm = LsmTree::open(dbname, options).unwrap();
+    verify_key_value(&mut lsm, "foo0", "updated");
+    verify_deleted(&mut lsm, "foo1");
+    for j in 2..i {
+        verify_key_value(&mut lsm, &format!("foo{}", j), &format!("bar{}", j));
+    }
+}
+
+#[test]
+pub fn test_lsm_iter_merges_memtable_and_segments() {
+    /*
+    Iterating yields every live key once, in comparator order, with its newest value from
+    whichever of the memtable and segments holds it. Deleted keys are left out, except by
+    entries, which yields their tombstones
+    */
+    let options = LsmOptions::default().write_buffer_size(8 * 1024).comparator(Arc::new(ReverseBytewiseComparator));
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_lsm_iter_merges_memtable_and_segments", options);
+
+    let mut i = 0;
+    while lsm.total_segments() < 2 {
+        lsm.write(format!("foo{:04}", i), format!("bar{}", i)).unwrap();
+        i += 1;
+    }
+    lsm.write("foo0000", "updated").unwrap();
+    lsm.delete("foo0001").unwrap();
+    lsm.flush();
+    lsm.write("foo0002", "newest").unwrap();
+    lsm.delete("foo0003").unwrap();
+
+    let pairs: Vec<KVPair> = lsm.iter().collect();
+    assert!(pairs.len() == i - 2, "expected {} live keys, actually {}", i - 2, pairs.len());
+    assert!(pairs.windows(2).all(|w| w[0].key > w[1].key), "expected keys in reverse order");
+    for pair in &pairs {
+        let j: usize = pair.key_str()[3..].parse().unwrap();
+        let expected = match j {
+            0 => "updated".to_string(),
+            2 => "newest".to_string(),
+            _ => format!("bar{}", j),
+        };
+        assert!(!pair.tombstone && pair.value_str() == expected, "unexpected pair {}", pair);
+    }
+
+    // Seeking starts from the first key at or after the one given in comparator order
+    let middle = format!("foo{:04}", i / 2);
+    for start in ["foo9999", middle.as_str(), "foo0003", "foo0002", "a"] {
+        let from: Vec<KVPair> = lsm.iter_from(start.as_bytes()).collect();
+        let expected: Vec<&KVPair> = pairs.iter().skip_while(|pair| pair.key.as_ref() > start.as_bytes()).collect();
+        assert!(from.iter().eq(expected.iter().copied()), "expected {} keys from {}, actually {}", expected.len(), start, from.len());
+    }
+
+    let tombstones: Vec<KVPair> = lsm.entries().filter(|pair| pair.tombstone).collect();
+    let expected = [KVPair::tombstone(&b"foo0003"[..]), KVPair::tombstone(&b"foo0001"[..])];
+    assert!(tombstones == expected, "expected tombstones for foo0003 and foo0001, actually {:?}", tombstones);
+}
+
+#[test]
+pub fn test_lsm_multi_get() {
+    /*
+    Multi get returns the same results as a get per key, in the order the keys were given,
+    duplicates and missing keys included, while reading each segment's blocks at most once
+    rather than once per key
+    */
+    let options = LsmOptions::default()
+        .write_buffer_size(16 * 1024)
+        .block_size(1024)
+        .block_cache(Some(Arc::new(BlockCache::new(1024 * 1024))))
+        .pin_index_and_filter_blocks(false);
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_lsm_multi_get", options);
+
+    let mut i = 0;
+    while lsm.total_segments() < 4 {
+        lsm.write(format!("foo{:05}", i), format!("bar{}", i)).unwrap();
+        i += 1;
+    }
+    for j in (0..i).step_by(7) {
+        lsm.write(format!("foo{:05}", j), "updated").unwrap();
+    }
+    for j in (3..i).step_by(11) {
+        lsm.delete(format!("foo{:05}", j)).unwrap();
+    }
+
+    // Keys in a scrambled order, with some missing and some repeated
+    let mut keys: Vec<String> = (0..i).rev().step_by(2).map(|j| format!("foo{:05}", (j * 37) % i)).collect();
+    keys.extend(["missing".to_string(), format!("foo{:05}", i + 1), "foo00000".to_string(), "foo00003".to_string()]);
+
+    let reads_before = lsm.statistics().get(Ticker::BlockCacheHit) + lsm.statistics().get(Ticker::BlockCacheMiss);
+    let results = lsm.multi_get(&keys);
+    let batched_reads = lsm.statistics().get(Ticker::BlockCacheHit) + lsm.statistics().get(Ticker::BlockCacheMiss) - reads_before;
+
+    assert!(results.len() == keys.len(), "expected {} results, actually {}", keys.len(), results.len());
+    let reads_before = lsm.statistics().get(Ticker::BlockCacheHit) + lsm.statistics().get(Ticker::BlockCacheMiss);
+    for (key, result) in keys.iter().zip(&results) {
+        let expected = lsm.get(key);
+        assert!(*result == expected, "multi get of {} returned {:?}, get returned {:?}", key, result, expected);
+    }
+    let single_reads = lsm.statistics().get(Ticker::BlockCacheHit) + lsm.statistics().get(Ticker::BlockCacheMiss) - reads_before;
+    assert!(results[keys.len() - 2] == Some(b"updated".to_vec()), "expected the updated value for foo00000");
+    assert!(results[keys.len() - 1].is_none(), "expected foo00003 to be deleted");
+    assert!(batched_reads * 4 < single_reads, "expected far fewer block reads batched, {} vs {}", batched_reads, single_reads);
+}
+
+#[test]
+pub fn test_lsm_write_with_ttl() {
+    /*
+    Entries written with a TTL read as absent once it passes, whether they are in the
+    memtable, restored from the WAL or flushed to a segment, without the older values they
+    replaced coming back, and compaction drops them entirely
+    */
+    let dbname = "test_lsm_write_with_ttl";
+    let clock = Arc::new(ManualClock::new(1_000_000));
+    let options = LsmOptions::default().clock(clock.clone());
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options.clone());
+
+    lsm.write("short", "old value").unwrap();
+    lsm.flush();
+    lsm.write_with_ttl("short", "expires soon", Duration::from_secs(10)).unwrap();
+    lsm.write_with_ttl("long", "expires later", Duration::from_secs(60)).unwrap();
+    lsm.write("forever", "never expires").unwrap();
+
+    clock.advance(Duration::from_secs(9));
+    verify_key_value(&mut lsm, "short", "expires soon");
+
+    clock.advance(Duration::from_secs(1));
+    verify_deleted(&mut lsm, "short");
+    verify_key_value(&mut lsm, "long", "expires later");
+    let results = lsm.multi_get(&["short", "long", "forever"]);
+    assert!(results == vec![None, Some(b"expires later".to_vec()), Some(b"never expires".to_vec())], "unexpected multi get results {:?}", results);
+    let keys: Vec<String> = lsm.iter().map(|pair| pair.key_str().into_owned()).collect();
+    assert!(keys == ["forever", "long"], "expected only unexpired keys, actually {:?}", keys);
+
+    // Expiry survives being restored from the WAL and flushed to a segment
+    drop(lsm);
+    let mut lsm = LsmTree::new_with_options(dbname, options.clone());
+    verify_deleted(&mut lsm, "short");
+    verify_key_value(&mut lsm, "long", "expires later");
+    lsm.flush();
+    verify_key_value(&mut lsm, "long", "expires later");
+    clock.advance(Duration::from_secs(50));
+    verify_deleted(&mut lsm, "long");
+    assert!(lsm.multi_get(&["long"]) == vec![None], "expected expired key from a segment to be absent");
+
+    lsm.compact().unwrap();
+    let entries: Vec<KVPair> = lsm.entries().collect();
+    assert!(entries == [KVPair::new(&b"forever"[..], &b"never expires"[..])], "expected compaction to drop expired keys, actually {:?}", entries);
+
+    // A ttl too long to count in millis never expires, rather than wrapping round to a short one (384ms here)
+    lsm.write_with_ttl("huge", "never expires either", Duration::from_secs(18_446_744_073_709_552)).unwrap();
+    clock.advance(Duration::from_secs(1));
+    verify_key_value(&mut lsm, "huge", "never expires either");
+}
+
+#[test]
+pub fn test_lsm_max_total_size_evicts_oldest() {
+    /*
+    With a maximum size, the oldest segments are evicted as new ones are flushed, so the
+    segments stay within it, the newest writes stay readable and the oldest are gone
+    */
+    let dbname = "test_lsm_max_total_size_evicts_oldest";
+    let options = LsmOptions::default().write_buffer_size(8 * 1024).max_total_size(Some(32 * 1024));
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options);
+
+    for i in 0..5000 {
+        lsm.write(format!("foo{:05}", i), format!("bar{}", i)).unwrap();
+        assert!(lsm.total_size() <= 32 * 1024, "segments hold {} bytes, over the limit", lsm.total_size());
+    }
+    assert!(lsm.total_size() == segment_bytes(dbname), "expected evicted segment files to be deleted");
+
+    let evicted = lsm.statistics().get(Ticker::SegmentsEvicted);
+    let evicted_bytes = lsm.statistics().get(Ticker::BytesEvicted);
+    assert!(evicted > 0 && evicted_bytes > 0, "expected evictions, {} segments of {} bytes", evicted, evicted_bytes);
+    assert!(format!("{}", lsm.statistics()).contains(&format!("eviction.segments: {}", evicted)), "eviction counter missing from statistics");
+
+    verify_deleted(&mut lsm, "foo00000");
+    for i in 4900..5000 {
+        verify_key_value(&mut lsm, &format!("foo{:05}", i), &format!("bar{}", i));
+    }
+}
+
+#[test]
+pub fn test_lsm_conditional_writes() {
+    /*
+    Conditional writes only apply when the current value is the expected one, treating
+    deleted and expired keys as absent, and what they write is restored from the WAL
+    */
+    let dbname = "test_lsm_conditional_writes";
+    let clock = Arc::new(ManualClock::new(0));
+    let options = LsmOptions::default().clock(clock.clone());
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options.clone());
+
+    assert!(lsm.put_if_absent("foo", "first").unwrap(), "expected put if absent of a new key to write");
+    assert!(!lsm.put_if_absent("foo", "second").unwrap(), "expected put if absent of an existing key to fail");
+    assert!(!lsm.compare_and_swap("foo", Some(b"wrong"), "second").unwrap(), "expected swap from the wrong value to fail");
+    assert!(!lsm.compare_and_swap("foo", None, "second").unwrap(), "expected swap from absent to fail for an existing key");
+    assert!(lsm.compare_and_swap("foo", Some(b"first"), "second").unwrap(), "expected swap from the current value to succeed");
+    verify_key_value(&mut lsm, "foo", "second");
+
+    assert!(!lsm.delete_if_equals("foo", b"first").unwrap(), "expected delete of the wrong value to fail");
+    assert!(lsm.delete_if_equals("foo", b"second").unwrap(), "expected delete of the current value to succeed");
+    verify_deleted(&mut lsm, "foo");
+    assert!(lsm.compare_and_swap("foo", None, "third").unwrap(), "expected swap of a deleted key from absent to succeed");
+
+    // Values on disk and expired values are checked the same as ones in the memtable
+    lsm.write_with_ttl("bar", "expiring", Duration::from_secs(1)).unwrap();
+    lsm.flush();
+    assert!(!lsm.put_if_absent("bar", "new").unwrap(), "expected put if absent of an unexpired key to fail");
+    clock.advance(Duration::from_secs(1));
+    assert!(lsm.put_if_absent("bar", "new").unwrap(), "expected put if absent of an expired key to write");
+
+    drop(lsm);
+    let mut lsm = LsmTree::new_with_options(dbname, options);
+    verify_key_value(&mut lsm, "foo", "third");
+    verify_key_value(&mut lsm, "bar", "new");
+}
+
+#[test]
+pub fn test_lsm_compare_and_swap_concurrent_increments() {
+    /*
+    Threads incrementing a shared counter with compare and swap never lose an update,
+    where a get followed by a write would
+    */
+    let lsm = Arc::new(Mutex::new(LsmTree::new_delete_existing("test_lsm_compare_and_swap_concurrent_increments")));
+    lsm.lock().unwrap().write("counter", "0").unwrap();
+
+    let threads: Vec<_> = (0..4).map(|_| {
+        let lsm = lsm.clone();
+        thread::spawn(move || {
+            let mut swaps = 0;
+            while swaps < 100 {
+                let current = lsm.lock().unwrap().get_str("counter").unwrap();
+                let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                if lsm.lock().unwrap().compare_and_swap("counter", Some(current.as_bytes()), next).unwrap() {
+                    swaps += 1;
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    verify_key_value(&mut lsm.lock().unwrap(), "counter", "400");
+}
+
+#[test]
+pub fn test_lsm_merge_operands() {
+    /*
+    Merges fold over the value they apply to wherever it is, the memtable, a segment or
+    nowhere, whether the operands are in memory, flushed or restored from the WAL, and
+    compaction collapses them into a plain value
+    */
+    let dbname = "test_lsm_merge_operands";
+    let options = LsmOptions::default().merge_operator(Arc::new(AppendOperator::new(b",")));
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options.clone());
+
+    // Onto a value in the memtable, a value in a segment, a deleted key and no value at all
+    lsm.write("memtable", "a").unwrap();
+    lsm.write("segment", "a").unwrap();
+    lsm.write("deleted", "a").unwrap();
+    lsm.flush();
+    lsm.delete("deleted").unwrap();
+    lsm.write("memtable", "b").unwrap();
+    for key in ["memtable", "segment", "deleted", "absent"] {
+        assert!(lsm.merge(key, "c").is_ok(), "expected merge into {} to succeed", key);
+    }
+    lsm.flush();
+    for key in ["memtable", "segment", "deleted", "absent"] {
+        assert!(lsm.merge(key, "d").is_ok(), "expected merge into {} to succeed", key);
+    }
+
+    let expected = [("memtable", "b,c,d"), ("segment", "a,c,d"), ("deleted", "c,d"), ("absent", "c,d")];
+    for (key, value) in expected {
+        verify_key_value(&mut lsm, key, value);
+    }
+    let results = lsm.multi_get(&["memtable", "segment", "deleted", "absent"]);
+    assert!(results.iter().zip(expected).all(|(result, (_, value))| result.as_deref() == Some(value.as_bytes())), "unexpected multi get results {:?}", results);
+
+    // Operands restored from the WAL fold the same
+    drop(lsm);
+    let mut lsm = LsmTree::new_with_options(dbname, options);
+    lsm.merge("segment", "e").unwrap();
+    verify_key_value(&mut lsm, "segment", "a,c,d,e");
+
+    lsm.compact().unwrap();
+    assert!(lsm.total_segments() == 1, "expected compaction to leave 1 segment, actually {}", lsm.total_segments());
+    let entries: Vec<String> = lsm.entries().map(|pair| pair.to_string()).collect();
+    let expected = ["key: absent, value: c,d", "key: deleted, value: c,d", "key: memtable, value: b,c,d", "key: segment, value: a,c,d,e"];
+    assert!(entries == expected, "expected compaction to collapse operands, actually {:?}", entries);
+    verify_key_value(&mut lsm, "segment", "a,c,d,e");
+}
+
+#[test]
+pub fn test_lsm_merge_counters() {
+    /*
+    Counters incremented with merges add up across flushes, keep the TTL of the value
+    they add to, and restart from zero once it expires. Merging without a merge operator
+    fails
+    */
+    let dbname = "test_lsm_merge_counters";
+    let clock = Arc::new(ManualClock::new(0));
+    let options = LsmOptions::default().write_buffer_size(4 * 1024).clock(clock.clone()).merge_operator(Arc::new(U64AddOperator));
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options);
+
+    for i in 0..1000 {
+        lsm.merge(format!("counter{}", i % 10), 1u64.to_le_bytes()).unwrap();
+    }
+    assert!(lsm.total_segments() > 1, "expected merges to be flushed across segments");
+    for i in 0..10 {
+        let count = lsm.get(format!("counter{}", i)).unwrap();
+        assert!(count == 100u64.to_le_bytes(), "expected counter{} to be 100, actually {:?}", i, count);
+    }
+
+    lsm.write_with_ttl("expiring", 5u64.to_le_bytes(), Duration::from_secs(10)).unwrap();
+    lsm.merge("expiring", 2u64.to_le_bytes()).unwrap();
+    assert!(lsm.get("expiring").unwrap() == 7u64.to_le_bytes(), "expected merge onto a value with a TTL to add to it");
+    clock.advance(Duration::from_secs(10));
+    assert!(lsm.get("expiring").is_none(), "expected merged value to keep the TTL");
+    lsm.merge("expiring", 2u64.to_le_bytes()).unwrap();
+    assert!(lsm.get("expiring").unwrap() == 2u64.to_le_bytes(), "expected merge onto an expired value to start from zero");
+
+    let mut lsm = LsmTree::new_delete_existing("test_lsm_merge_counters_no_operator");
+    assert!(matches!(lsm.merge("counter", 1u64.to_le_bytes()), Err(LsmError::NoMergeOperator(_))), "expected merge without a merge operator to fail");
+    assert!(lsm.get("counter").is_none(), "expected failed merge not to be written");
+
+    // A crash after a flush persisted the sequence number but before it truncated the WAL
+    // leaves flushed merges in the WAL, which restoring must not apply a second time
+    drop(lsm);
+    let dbname = "test_lsm_merge_counters_flush_crash";
+    let options = || LsmOptions::default().merge_operator(Arc::new(U64AddOperator));
+    let mut lsm = LsmTree::new_delete_existing_with_options(dbname, options());
+    for _ in 0..5 {
+        lsm.merge("counter", 1u64.to_le_bytes()).unwrap();
+    }
+    let wal = std::fs::read(get_wal_path(dbname)).unwrap();
+    lsm.flush();
+    drop(lsm);
+    std::fs::write(get_wal_path(dbname), wal).unwrap();
+    let mut lsm = LsmTree::new_with_options(dbname, options());
+    assert!(lsm.get("counter").unwrap() == 5u64.to_le_bytes(), "expected flushed merges to be applied once, actually {:?}", lsm.get("counter"));
+    assert!(lsm.latest_sequence() == 5, "expected the sequence number of the last commit, actually {}", lsm.latest_sequence());
+    lsm.merge("counter", 1u64.to_le_bytes()).unwrap();
+    assert!(lsm.get("counter").unwrap() == 6u64.to_le_bytes(), "expected merges to carry on from the restored counter");
+}
+
+#[test]
+pub fn test_lsm_merge_operator_required() {
+    /*
+    A column family opened with a merge operator may hold operands, so the DB fails to open
+    without one for it, rather than failing to read them, and a replicated merge into a
+    family without one is refused
+    */
+    let dbname = "test_lsm_merge_operator_required";
+    purge_lsm_dir(dbname).unwrap();
+    let counters = || LsmOptions::default().merge_operator(Arc::new(U64AddOperator));
+    let mut lsm = LsmTree::open_with_column_families(dbname, counters(), vec![("counters", counters())]).unwrap();
+    lsm.merge("counter", 1u64.to_le_bytes()).unwrap();
+    lsm.flush();
+    lsm.merge("counter", 1u64.to_le_bytes()).unwrap();
+    lsm.column_family("counters").unwrap().merge("counter", 1u64.to_le_bytes()).unwrap();
+    drop(lsm);
+
+    let expect_refused = |options: LsmOptions, family_options: LsmOptions, family: &str| {
+        match LsmTree::open_with_column_families(dbname, options, vec![("counters", family_options)]) {
+            Err(LsmError::NoMergeOperator(name)) => assert!(name == family, "expected {} to need a merge operator, actually {}", family, name),
+            Err(e) => panic!("expected a missing merge operator, actually {}", e),
+            Ok(_) => panic!("expected opening without a merge operator to fail"),
+        }
+    };
+    expect_refused(LsmOptions::default(), counters(), "default");
+    expect_refused(counters(), LsmOptions::default(), "counters");
+    expect_refused(LsmOptions::default().read_only(true), counters(), "default");
+
+    let mut lsm = LsmTree::open_with_column_families(dbname, counters(), vec![("counters", counters())]).unwrap();
+    assert!(lsm.get("counter").unwrap() == 2u64.to_le_bytes(), "expected merges to be read back, actually {:?}", lsm.get("counter"));
+    lsm.compact().unwrap();
+    assert!(lsm.get("counter").unwrap() == 2u64.to_le_bytes(), "expected merges to be compacted, actually {:?}", lsm.get("counter"));
+    drop(lsm);
+
+    let dbname = "test_lsm_merge_operator_required_replicated";
+    let mut lsm = LsmTree::new_delete_existing(dbname);
+    let merge = vec![("default".to_string(), WalRecord::Merge{key: b"counter".to_vec(), operand: 1u64.to_le_bytes().to_vec()})];
+    assert!(matches!(lsm.apply_replicated(1, merge), Err(LsmError::NoMergeOperator(_))), "expected a replicated merge without a merge operator to be refused");
+    assert!(lsm.latest_sequence() == 0 && lsm.get("counter").is_none(), "expected the refused merge not to be applied");
+}
+
+#[test]
+pub fn test_lsm_column_families() {
+    /*
+    Column families are separate keyspaces, each ordered by its own comparator, flushed to
+    its own segments and compacted on its own, while sharing the DB's WAL, so writes to
+    every family are restored on reopening. A DB with column families only opens with all
+    of them, each with the comparator it was created with
+    */
+    let dbname = "test_lsm_column_families";
+    purge_lsm_dir(dbname).unwrap();
+    let families = || vec![("sessions", LsmOptions::default()), ("profiles", LsmOptions::default().comparator(Arc::new(ReverseBytewiseComparator)))];
+    let mut lsm = LsmTree::open_with_column_families(dbname, LsmOptions::default(), families()).unwrap();
+    assert!(lsm.column_family_names() == ["default", "sessions", "profiles"], "unexpected column families {:?}", lsm.column_family_names());
+
+    for i in 0..3 {
+        lsm.write(format!("foo{}", i), "default").unwrap();
+        lsm.column_family("sessions").unwrap().write(format!("foo{}", i), "sessions").unwrap();
+        lsm.column_family("profiles").unwrap().write(format!("foo{}", i), "profiles").unwrap();
+    }
+    lsm.column_family("sessions").unwrap().delete("foo1").unwrap();
+    verify_key_value(&mut lsm, "foo1", "default");
+    let mut sessions = lsm.column_family("sessions").unwrap();
+    assert!(sessions.get("foo1").is_none(), "expected foo1 to be deleted from sessions");
+    assert!(sessions.get_str("foo2").as_deref() == Some("sessions"), "expected sessions value for foo2");
+    let keys: Vec<String> = lsm.column_family("profiles").unwrap().iter().map(|pair| pair.key_str().into_owned()).collect();
+    assert!(keys == ["foo2", "foo1", "foo0"], "expected profiles in reverse order, actually {:?}", keys);
+
+    // Flushing writes each family's segments to its own directory
+    lsm.flush();
+    assert!(lsm.total_segments() == 1, "expected 1 default segment, actually {}", lsm.total_segments());
+    assert!(segment_bytes(&format!("{}/sessions", dbname)) > 0, "expected sessions segments in their own directory");
+    lsm.column_family("profiles").unwrap().write("foo0", "updated").unwrap();
+    lsm.flush();
+    lsm.column_family("profiles").unwrap().compact().unwrap();
+    assert!(lsm.column_family("profiles").unwrap().total_segments() == 1, "expected profiles to be compacted");
+    assert!(lsm.column_family("sessions").unwrap().total_segments() == 1, "expected sessions not to be compacted along with profiles");
+    lsm.column_family("sessions").unwrap().write("bar", "unflushed").unwrap();
+    drop(lsm);
+
+    match LsmTree::open(dbname, LsmOptions::default()) {
+        Err(LsmError::ColumnFamilyNotOpened(family)) => assert!(family == "sessions", "unexpected family {} not opened", family),
+        Err(e) => panic!("expected an error for the families not opened, actually {}", e),
+        Ok(_) => panic!("expected opening without the column families to fail"),
+    }
+    match LsmTree::open_with_column_families(dbname, LsmOptions::default(), vec![("sessions", LsmOptions::default()), ("profiles", LsmOptions::default())]) {
+        Err(LsmError::ComparatorMismatch{persisted, ..}) => assert!(persisted == "reverse_bytewise", "unexpected comparator {}", persisted),
+        Err(e) => panic!("expected a comparator mismatch, actually {}", e),
+        Ok(_) => panic!("expected opening profiles with the wrong comparator to fail"),
+    }
+    for name in ["default", "", "has space", "../escape"] {
+        match LsmTree::open_with_column_families(dbname, LsmOptions::default(), vec![(name, LsmOptions::default())]) {
+            Err(LsmError::InvalidColumnFamilyName(_)) => {},
+            _ => panic!("expected column family name {:?} to be rejected", name),
+        }
+    }
+
+    // Families may be given in any order, and new ones are created alongside
+    let mut reversed = families();
+    reversed.reverse();
+    reversed.push(("limits", LsmOptions::default()));
+    let mut lsm = LsmTree::open_with_column_families(dbname, LsmOptions::default(), reversed).unwrap();
+    verify_key_value(&mut lsm, "foo0", "default");
+    assert!(lsm.column_family("sessions").unwrap().get_str("bar").as_deref() == Some("unflushed"), "expected unflushed sessions write to be restored");
+    assert!(lsm.column_family("profiles").unwrap().get_str("foo0").as_deref() == Some("updated"), "expected profiles value to be restored");
+    assert!(lsm.column_family("limits").unwrap().get("foo0").is_none(), "expected new family to be empty");
+    assert!(matches!(lsm.column_family("missing"), Err(LsmError::UnknownColumnFamily(_))), "expected unknown family to be an error");
+}
+
+#[test]
+pub fn test_lsm_write_batch_across_column_families() {
+    /*
+    A write batch applies all of its writes across column families, in order, or none of
+    them, whether it is rejected up front or torn in the WAL by a crash
+    */
+    let dbname = "test_lsm_write_batch_across_column_families";
+    purge_lsm_dir(dbname).unwrap();
+    let families = || vec![("counters", LsmOptions::default().merge_operator(Arc::new(U64AddOperator))), ("sessions", LsmOptions::default())];
+    let mut lsm = LsmTree::open_with_column_families(dbname, LsmOptions::default(), families()).unwrap();
+    lsm.column_family("counters").unwrap().write("logins", 1u64.to_le_bytes()).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put("user", "alice");
+    batch.put_cf("sessions", "session1", "alice");
+    batch.delete_cf("sessions", "session0");
+    batch.merge_cf("counters", "logins", 1u64.to_le_bytes());
+    batch.put_cf("counters", "visits", 5u64.to_le_bytes());
+    batch.merge_cf("counters", "visits", 2u64.to_le_bytes());
+    assert!(batch.len() == 6, "expected 6 writes in batch, actually {}", batch.len());
+    lsm.write_batch(batch).unwrap();
+
+    let check = |lsm: &mut LsmTree| {
+        verify_key_value(lsm, "user", "alice");
+        assert!(lsm.column_family("sessions").unwrap().get_str("session1").as_deref() == Some("alice"), "expected session1 from batch");
+        let mut counters = lsm.column_family("counters").unwrap();
+        assert!(counters.get("logins").unwrap() == 2u64.to_le_bytes(), "expected merge in batch to add to logins");
+        assert!(counters.get("visits").unwrap() == 7u64.to_le_bytes(), "expected merge in batch to add to the put before it");
+    };
+    check(&mut lsm);
+
+    // Nothing in a rejected batch is applied
+    let mut batch = WriteBatch::new();
+    batch.put("user", "bob");
+    batch.put_cf("missing", "key", "value");
+    assert!(matches!(lsm.write_batch(batch), Err(LsmError::UnknownColumnFamily(_))), "expected batch to an unknown family to fail");
+    let mut batch = WriteBatch::new();
+    batch.put("user", "bob");
+    batch.merge_cf("sessions", "session1", "more");
+    assert!(matches!(lsm.write_batch(batch), Err(LsmError::NoMergeOperator(_))), "expected merge without a merge operator to fail");
+    check(&mut lsm);
+
+    // A batch torn in the WAL is dropped as a whole on restore
+    let mut batch = WriteBatch::new();
+    batch.put("user", "carol");
+    batch.put_cf("sessions", "session2", "carol");
+    lsm.write_batch(batch).unwrap();
+    drop(lsm);
+    let wal_path = get_lsmdir(dbname).join(format!("{}.log", dbname));
+    let wal_len = metadata(&wal_path).unwrap().len();
+    OpenOptions::new().write(true).open(&wal_path).unwrap().set_len(wal_len - 2).unwrap();
+
+    let mut lsm = LsmTree::open_with_column_families(dbname, LsmOptions::default(), families()).unwrap();
+    check(&mut lsm);
+    assert!(lsm.column_family("sessions").unwrap().get("session2").is_none(), "expected torn batch not to be applied");
+}
+
+#[test]
+pub fn test_lsm_read_only() {
+    let dbname = "test_lsm_read_only";
+    purge_lsm_dir(dbname).unwrap();
+    let read_only = || LsmOptions::default().read_only(true);
+    let families = || vec![("counters", LsmOptions::default().merge_operator(Arc::new(U64AddOperator)))];
+    let open_reader = || LsmTree::open_with_column_families(dbname, read_only(), families()).unwrap();
+    assert!(matches!(LsmTree::open(dbname, read_only()), Err(LsmError::Io(_))), "expected a missing DB not to be created read-only");
+    assert!(matches!(LsmTree::open("test_lsm_read_only_missing/db", LsmOptions::default()), Err(LsmError::Io(_))), "expected a DB that can't be created to be an error");
+
+    let mut writer = LsmTree::open_with_column_families(dbname, LsmOptions::default().write_buffer_size(4 * 1024), families()).unwrap();
+    for i in 0..10 {
+        writer.write(format!("foo{}", i), format!("bar{}", i)).unwrap();
+    }
+    writer.column_family("counters").unwrap().merge("visits", 1u64.to_le_bytes()).unwrap();
+    assert!(matches!(LsmTree::open_with_column_families(dbname, LsmOptions::default(), families()), Err(LsmError::Locked(_))), "expected a second writer to be locked out");
+
+    // Readers share the DB with the writer and each other, reading what it has logged
+    let mut reader = open_reader();
+    let mut other_reader = open_reader();
+    assert!(reader.is_read_only() && reader.latest_sequence() == 11, "expected the reader to restore every commit, actually {}", reader.latest_sequence());
+    verify_key_value(&mut reader, "foo3", "bar3");
+    assert!(other_reader.iter().count() == 10, "expected the other reader to see every key");
+    assert!(matches!(LsmTree::open_with_column_families(dbname, read_only(), Vec::new()), Err(LsmError::ColumnFamilyNotOpened(_))), "expected every family to be required");
+    let mut extra = families();
+    extra.push(("sessions", LsmOptions::default()));
+    assert!(matches!(LsmTree::open_with_column_families(dbname, read_only(), extra), Err(LsmError::UnknownColumnFamily(_))), "expected no family to be created read-only");
+
+    // Writes are refused, and nothing is written by trying
+    assert!(matches!(reader.write("foo0", "changed"), Err(LsmError::ReadOnly)), "expected write to be refused");
+    assert!(matches!(reader.delete("foo1"), Err(LsmError::ReadOnly)), "expected delete to be refused");
+    assert!(matches!(reader.compact(), Err(LsmError::ReadOnly)), "expected compact to be refused");
+    assert!(matches!(reader.column_family("counters").unwrap().merge("visits", 1u64.to_le_bytes()), Err(LsmError::ReadOnly)), "expected merge to be refused");
+    let mut batch = WriteBatch::new();
+    batch.put("foo0", "changed");
+    assert!(matches!(reader.write_batch(batch), Err(LsmError::ReadOnly)), "expected batch to be refused");
+    assert!(matches!(reader.put_if_absent("new", "value"), Err(LsmError::ReadOnly)), "expected conditional write to be refused");
+    reader.flush();
+    assert!(reader.total_segments() == 0 && reader.get("foo0").unwrap() == b"bar0", "expected flush to leave a reader as it was");
+    assert!(!reader.catch_up().unwrap(), "expected nothing new to catch up on");
+
+    // The WAL tail, then segments once the writer has flushed and compacted
+    writer.write("foo0", "updated").unwrap();
+    writer.delete("foo1").unwrap();
+    assert!(reader.catch_up().unwrap(), "expected new commits to catch up on");
+    verify_key_value(&mut reader, "foo0", "updated");
+    verify_deleted(&mut reader, "foo1");
+    for i in 0..200 {
+        writer.write(format!("key{:03}", i), [b'v'; 100]).unwrap();
+        writer.column_family("counters").unwrap().merge("visits", 1u64.to_le_bytes()).unwrap();
+    }
+    assert!(writer.total_segments() > 1, "expected the writer to flush");
+    assert!(reader.catch_up().unwrap(), "expected flushed commits to catch up on");
+    assert!(reader.latest_sequence() == writer.latest_sequence(), "expected the reader to reach the writer's sequence number");
+    assert!(reader.iter().collect::<Vec<_>>() == writer.iter().collect::<Vec<_>>(), "expected the reader to see what the writer has");
+    assert!(reader.column_family("counters").unwrap().get("visits").unwrap() == 201u64.to_le_bytes(), "expected merges to be counted once each");
+    writer.compact().unwrap();
+    writer.write("after", "compaction").unwrap();
+    assert!(reader.catch_up().unwrap(), "expected compaction to be caught up on");
+    assert!(reader.total_segments() == 1, "expected the compacted segment, actually {}", reader.total_segments());
+    assert!(reader.iter().collect::<Vec<_>>() == writer.iter().collect::<Vec<_>>(), "expected the reader to see the compacted DB");
+    assert!(other_reader.iter().count() == 10, "expected a reader not caught up to keep reading what it had");
+
+    // Once the writer is gone another may open the DB, readers and all
+    drop(writer);
+    let mut writer = LsmTree::open_with_column_families(dbname, LsmOptions::default(), families()).unwrap();
+    writer.write("reopened", "yes").unwrap();
+    assert!(other_reader.catch_up().unwrap() && other_reader.get("reopened").is_some(), "expected the reader to follow the new writer");
+    assert!(!writer.catch_up().unwrap(), "expected a writer to always be caught up");
+}
+
+// Stages a copy of each of the checkpoint's files for the LSM
+#[cfg(test)]
+fn stage_copy(lsm: &LsmTree, checkpoint: &Checkpoint) -> StagedCheckpoint {
+    let staged = lsm.stage_checkpoint(checkpoint.sequence, &checkpoint.manifest).unwrap();
+    for file in &checkpoint.files {
+        let mut copy = staged.create_file(&file.family, &file.name).unwrap();
+        let mut source = &file.file;
+        source.seek(SeekFrom::Start(0)).unwrap();
+        assert!(io::copy(&mut source, &mut copy).unwrap() == file.len, "expected the whole of {} to be copied", file.name);
+    }
+    staged
+}
+
+#[test]
+pub fn test_lsm_checkpoint() {
+    let families = || vec![("counters", LsmOptions::default().merge_operator(Arc::new(U64AddOperator)))];
+    purge_lsm_dir("test_lsm_checkpoint_source").unwrap();
+    let mut source = LsmTree::open_with_column_families("test_lsm_checkpoint_source", LsmOptions::default(), families()).unwrap();
+    for i in 0..10 {
+        source.write(format!("key{}", i), format!("value{}", i)).unwrap();
+        source.column_family("counters").unwrap().merge("visits", 1u64.to_le_bytes()).unwrap();
+    }
+    source.flush();
+    let flushed = source.latest_sequence();
+    source.write("unflushed", "in the WAL").unwrap();
+    let checkpoint = source.checkpoint().unwrap();
+    assert!(checkpoint.sequence == flushed, "expected the checkpoint to be pinned at the last flush {}, actually {}", flushed, checkpoint.sequence);
+    assert!(checkpoint.files.len() == 2 && checkpoint.files.iter().all(|file| file.len > 0), "expected a segment for each family");
+    assert!(checkpoint.manifest.column_families.len() == 1 && checkpoint.manifest.column_families[0].0 == "counters", "unexpected manifest {:?}", checkpoint.manifest);
+
+    // Compacting the source away doesn't take the checkpoint's files with it
+    source.compact().unwrap();
+    source.write("after", "checkpoint").unwrap();
+
+    let target_name = "test_lsm_checkpoint_target";
+    purge_lsm_dir(target_name).unwrap();
+    let open_target = || LsmTree::open_with_column_families(target_name, LsmOptions::default(), families()).unwrap();
+    let mut target = open_target();
+    for i in 0..100 {
+        target.write(format!("stale{}", i), [b's'; 100]).unwrap();
+    }
+    target.flush();
+    target.write("stale", "in the WAL").unwrap();
+
+    // A checkpoint that was never committed is thrown away, leaving the DB as it was
+    drop(stage_copy(&target, &checkpoint));
+    drop(target);
+    let mut target = open_target();
+    assert!(!get_checkpoint_dir(target_name).exists(), "expected the partly received checkpoint to be deleted");
+    verify_key_value(&mut target, "stale", "in the WAL");
+    assert!(target.get("key0").is_none(), "expected nothing of the checkpoint to be installed");
+
+    // One that was committed replaces everything, even when a crash got in the way of installing it
+    let staged = stage_copy(&target, &checkpoint);
+    staged.commit().unwrap();
+    drop(target);
+    let mut target = open_target();
+    assert!(!get_checkpoint_dir(target_name).exists(), "expected the installed checkpoint's directory to be deleted");
+    assert!(target.latest_sequence() == flushed, "expected the checkpoint's sequence number, actually {}", target.latest_sequence());
+    assert!(target.iter().collect::<Vec<_>>().len() == 10, "expected just the checkpoint's keys");
+    verify_key_value(&mut target, "key9", "value9");
+    verify_deleted(&mut target, "stale");
+    verify_deleted(&mut target, "unflushed");
+    assert!(target.column_family("counters").unwrap().get("visits").unwrap() == 10u64.to_le_bytes(), "expected the checkpoint's counter");
+    let applied = target.apply_replicated(flushed + 1, vec![("default".to_string(), crate::storage::wal::WalRecord::Delete{key: b"key0".to_vec()})]);
+    assert!(matches!(applied, Ok(true)), "expected the commit after the checkpoint to apply, actually {:?}", applied);
+    verify_deleted(&mut target, "key0");
+
+    // Installed into an open DB, and again over one installed before
+    target.write("stale", "again").unwrap();
+    let staged = stage_copy(&target, &checkpoint);
+    assert!(target.install_checkpoint(staged).unwrap() == flushed, "expected the checkpoint's sequence number");
+    verify_key_value(&mut target, "key0", "value0");
+    verify_deleted(&mut target, "stale");
+    target.write("written", "after").unwrap();
+    assert!(target.latest_sequence() == flushed + 1, "expected commits to follow on from the checkpoint");
+    drop(target);
+    let mut target = open_target();
+    verify_key_value(&mut target, "written", "after");
+    assert!(target.iter().count() == 11, "expected the checkpoint and the write after it to be persisted");
+
+    // Checkpoints only go to DBs able to hold them, with segments named as segments
+    let staged = target.stage_checkpoint(checkpoint.sequence, &checkpoint.manifest).unwrap();
+    assert!(staged.create_file("counters", "../escape.data").is_err(), "expected a path to be refused");
+    assert!(staged.create_file("counters", "MANIFEST").is_err(), "expected a file other than a segment to be refused");
+    assert!(staged.create_file("missing", "segment_0.data").is_err(), "expected a family not in the checkpoint to be refused");
+    purge_lsm_dir("test_lsm_checkpoint_plain").unwrap();
+    let plain = LsmTree::open("test_lsm_checkpoint_plain", LsmOptions::default()).unwrap();
+    let result = plain.stage_checkpoint(checkpoint.sequence, &checkpoint.manifest);
+    assert!(matches!(result, Err(LsmError::UnknownColumnFamily(_))), "expected a family the DB doesn't have to be refused");
+    drop(plain);
+    purge_lsm_dir("test_lsm_checkpoint_reversed").unwrap();
+    let reversed = LsmTree::open_with_column_families("test_lsm_checkpoint_reversed", LsmOptions::default().comparator(Arc::new(ReverseBytewiseComparator)), families()).unwrap();
+    let result = reversed.stage_checkpoint(checkpoint.sequence, &checkpoint.manifest);
+    assert!(matches!(result, Err(LsmError::ComparatorMismatch{..})), "expected a checkpoint ordered by another comparator to be refused");
+    let reader = LsmTree::open_with_column_families(target_name, LsmOptions::default().read_only(true), families()).unwrap();
+    assert!(matches!(reader.stage_checkpoint(checkpoint.sequence, &checkpoint.manifest), Err(LsmError::ReadOnly)), "expected a read-only DB to refuse a checkpoint");
+}
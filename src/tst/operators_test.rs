@@ -0,0 +1,214 @@
+#[cfg(test)]
+use std::{sync::Arc, time::Duration};
+#[cfg(test)]
+use crate::{kvpair::KVPair, operators::{execute, execute_streaming, parse, quote, run, CancellationToken, Command, Comparison, Field, KeyRange, Operator, Output, ParseError, Position, Predicate, QueryError, Write}, storage::{clock::ManualClock, comparator::ReverseBytewiseComparator, lsm::LsmTree, options::LsmOptions}};
+
+#[test]
+pub fn test_parse_commands() {
+    let commands = parse("get foo\nPUT foo bar; DEL foo\nSCAN\nscan prefix user: limit 10\nSCAN RANGE a c | filter value contains x|LIMIT 2 | count\nCOMPACT # flushes too\nstats").unwrap();
+    let expected = vec![
+        Command::Pipeline(vec![Operator::Get{key: b"foo".to_vec()}]),
+        Command::Pipeline(vec![Operator::Put{key: b"foo".to_vec(), value: b"bar".to_vec()}]),
+        Command::Pipeline(vec![Operator::Delete{key: b"foo".to_vec()}]),
+        Command::Pipeline(vec![Operator::Scan{range: KeyRange::All}]),
+        Command::Pipeline(vec![Operator::Scan{range: KeyRange::Prefix(b"user:".to_vec())}, Operator::Limit(10)]),
+        Command::Pipeline(vec![
+            Operator::Scan{range: KeyRange::Range{start: b"a".to_vec(), end: b"c".to_vec()}},
+            Operator::Filter(Predicate{field: Field::Value, comparison: Comparison::Contains, operand: b"x".to_vec()}),
+            Operator::Limit(2),
+            Operator::Count,
+        ]),
+        Command::Compact,
+        Command::Stats,
+    ];
+    assert!(commands == expected, "unexpected commands {:?}", commands);
+
+    let commands = parse("BATCH; PUT a 1; DEL b; END\nBATCH\n  put c 2\nEND").unwrap();
+    let expected = vec![
+        Command::Batch(vec![Write::Put{key: b"a".to_vec(), value: b"1".to_vec()}, Write::Delete{key: b"b".to_vec()}]),
+        Command::Batch(vec![Write::Put{key: b"c".to_vec(), value: b"2".to_vec()}]),
+    ];
+    assert!(commands == expected, "unexpected batches {:?}", commands);
+    assert!(parse("  \n# nothing to run\n;;").unwrap().is_empty(), "expected blank input to parse to no commands");
+}
+
+#[test]
+pub fn test_parse_quoted_arguments() {
+    /*
+    Quoted arguments can hold any bytes, including separators, quotes, keywords and
+    invalid UTF-8, and are never taken as keywords
+    */
+    let commands = parse(r#"PUT "key with spaces; and \"quotes\"" "\x00\xff\n\t\\" ; PUT 'raw \n' "" ; GET "END""#).unwrap();
+    let expected = vec![
+        Command::Pipeline(vec![Operator::Put{key: b"key with spaces; and \"quotes\"".to_vec(), value: vec![0, 0xff, b'\n', b'\t', b'\\']}]),
+        Command::Pipeline(vec![Operator::Put{key: b"raw \\n".to_vec(), value: Vec::new()}]),
+        Command::Pipeline(vec![Operator::Get{key: b"END".to_vec()}]),
+    ];
+    assert!(commands == expected, "unexpected commands {:?}", commands);
+
+    let commands = parse("SCAN PREFIX \"LIMIT\"; PUT ключ значение; GET \"a|b\"").unwrap();
+    assert!(commands[0] == Command::Pipeline(vec![Operator::Scan{range: KeyRange::Prefix(b"LIMIT".to_vec())}]), "unexpected scan {:?}", commands[0]);
+    assert!(commands[1] == Command::Pipeline(vec![Operator::Put{key: "ключ".as_bytes().to_vec(), value: "значение".as_bytes().to_vec()}]), "unexpected put {:?}", commands[1]);
+    assert!(commands[2] == Command::Pipeline(vec![Operator::Get{key: b"a|b".to_vec()}]), "unexpected get {:?}", commands[2]);
+}
+
+#[test]
+pub fn test_parse_errors() {
+    /*
+    Errors point at the token at fault, or where a missing one was expected
+    */
+    let error = |input: &str| -> ParseError {
+        match parse(input) {
+            Err(e) => e,
+            Ok(commands) => panic!("expected {:?} to fail to parse, got {:?}", input, commands),
+        }
+    };
+    let at = |offset, line, column| Position{offset, line, column};
+
+    let cases = [
+        ("FETCH foo", "unknown command FETCH", at(0, 1, 1)),
+        ("GET", "expected a key", at(3, 1, 4)),
+        ("GET foo\nPUT foo", "expected a value", at(15, 2, 8)),
+        ("GET foo bar", "unexpected argument bar", at(8, 1, 9)),
+        ("SCAN PREFIX", "expected a prefix", at(11, 1, 12)),
+        ("SCAN RANGE a", "expected an end key", at(12, 1, 13)),
+        ("SCAN LIMIT ten", "expected a number for LIMIT, found ten", at(11, 1, 12)),
+        ("GET \"foo", "unterminated string", at(4, 1, 5)),
+        ("GET 'foo", "unterminated string", at(4, 1, 5)),
+        ("GET \"a\\qb\"", "invalid escape \\q", at(6, 1, 7)),
+        ("GET \"\\x4\"", "\\x must be followed by two hex digits", at(5, 1, 6)),
+        ("GET fo\"o\"", "quotes must start an argument", at(6, 1, 7)),
+        ("\"GET\" foo", "expected a command, found a quoted argument", at(0, 1, 1)),
+        ("BATCH\nPUT a 1\nGET a\nEND", "GET cannot be used in a BATCH, only PUT and DEL", at(14, 3, 1)),
+        ("BATCH; BATCH; END", "BATCH cannot be nested", at(7, 1, 8)),
+        ("PUT a 1\nBATCH\nPUT b 2", "BATCH without END", at(8, 2, 1)),
+        ("END", "END without BATCH", at(0, 1, 1)),
+        ("SCAN | SORT", "unknown operator SORT", at(7, 1, 8)),
+        ("SCAN |", "expected an operator", at(6, 1, 7)),
+        ("SCAN | FILTER NAME PREFIX a", "expected KEY or VALUE, found NAME", at(14, 1, 15)),
+        ("SCAN | FILTER KEY LIKE a", "expected PREFIX, CONTAINS or EQUALS, found LIKE", at(18, 1, 19)),
+        ("STATS | COUNT", "STATS cannot be piped", at(6, 1, 7)),
+        ("BATCH | COUNT", "BATCH cannot be piped", at(6, 1, 7)),
+    ];
+    for (input, message, position) in cases {
+        let e = error(input);
+        assert!(e.message == message && e.position == position, "for {:?} expected {:?} at {:?}, actually {:?} at {:?}", input, message, position, e.message, e.position);
+    }
+    assert!(error("GET").to_string() == "syntax error at line 1, column 4: expected a key", "unexpected message {}", error("GET"));
+    for input in ["GET \"foo", "GET 'foo", "PUT a 1\nBATCH\nPUT b 2"] {
+        assert!(error(input).incomplete, "expected {:?} to be incomplete", input);
+    }
+    assert!(!error("GET").incomplete && !error("GET \"a\\qb\"").incomplete, "expected errors no more input can fix not to be incomplete");
+}
+
+#[cfg(test)]
+fn rows(output: &Output) -> Vec<String> {
+    match output {
+        Output::Rows(rows) => rows.iter().map(|pair| pair.to_string()).collect(),
+        _ => panic!("expected rows, actually {:?}", output),
+    }
+}
+
+#[test]
+pub fn test_execute_pipelines() {
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_execute_pipelines", LsmOptions::default().write_buffer_size(4 * 1024));
+    let token = CancellationToken::new();
+    for i in 0..200 {
+        lsm.write(format!("user:{:03}", i), if i % 2 == 0 { "even" } else { "odd" }).unwrap();
+        lsm.write(format!("item:{:03}", i), format!("{}", i)).unwrap();
+    }
+
+    let outputs = run(&mut lsm, "GET user:007; GET missing\nSCAN PREFIX user: | FILTER VALUE EQUALS odd | LIMIT 3\nSCAN RANGE item:010 item:013\nSCAN PREFIX item: | COUNT\nSCAN | FILTER KEY CONTAINS :19 | COUNT", &token).unwrap();
+    assert!(rows(&outputs[0]) == ["key: user:007, value: odd"], "unexpected get {:?}", outputs[0]);
+    assert!(rows(&outputs[1]).is_empty(), "expected no rows for a missing key");
+    assert!(rows(&outputs[2]) == ["key: user:001, value: odd", "key: user:003, value: odd", "key: user:005, value: odd"], "unexpected filtered scan {:?}", outputs[2]);
+    assert!(rows(&outputs[3]) == ["key: item:010, value: 10", "key: item:011, value: 11", "key: item:012, value: 12"], "unexpected range scan {:?}", outputs[3]);
+    assert!(outputs[4] == Output::Count(200), "unexpected count {:?}", outputs[4]);
+    assert!(outputs[5] == Output::Count(20), "unexpected filtered count {:?}", outputs[5]);
+
+    let outputs = run(&mut lsm, "PUT user:007 updated; DEL user:008\nBATCH; PUT a 1; PUT b 2; END\nCOMPACT\nSTATS\nSCAN PREFIX user:00 | LIMIT 10 | COUNT", &token).unwrap();
+    assert!(outputs[..3] == [Output::Written(1), Output::Written(1), Output::Written(2)], "unexpected writes {:?}", &outputs[..3]);
+    assert!(outputs[3] == Output::Compacted && matches!(outputs[4], Output::Stats(_)), "unexpected outputs {:?}", &outputs[3..5]);
+    assert!(outputs[5] == Output::Count(9), "expected deleted key not to be counted, actually {:?}", outputs[5]);
+    assert!(lsm.get_str("user:007").as_deref() == Some("updated") && lsm.get_str("b").as_deref() == Some("2"), "expected writes to be applied");
+}
+
+#[test]
+pub fn test_execute_validates_before_running() {
+    /*
+    A query with any invalid command is cancelled before any of it runs, including the
+    writes before the invalid command
+    */
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_execute_validates_before_running", LsmOptions::default().comparator(Arc::new(ReverseBytewiseComparator)));
+    let token = CancellationToken::new();
+    let invalid = |lsm: &mut LsmTree, input: &str| match run(lsm, input, &token) {
+        Err(QueryError::Invalid{command, message}) => (command, message),
+        result => panic!("expected {:?} to be invalid, actually {:?}", input, result),
+    };
+
+    assert!(invalid(&mut lsm, "PUT a 1\nSCAN | COUNT | LIMIT 1") == (1, "COUNT must be the last operator".to_string()), "unexpected validation error");
+    assert!(invalid(&mut lsm, "PUT a 1 | LIMIT 1") == (0, "PUT cannot be followed by LIMIT".to_string()), "unexpected validation error");
+    // Ranges run in comparator order, which is reversed here
+    assert!(invalid(&mut lsm, "PUT a 1\nSCAN RANGE a c") == (1, "RANGE starts after it ends".to_string()), "unexpected validation error");
+    assert!(lsm.get("a").is_none(), "expected nothing to run from an invalid query");
+
+    let commands = [Command::Pipeline(vec![Operator::Count]), Command::Pipeline(vec![Operator::Scan{range: KeyRange::All}, Operator::Get{key: b"a".to_vec()}])];
+    match execute(&mut lsm, &commands, &token) {
+        Err(QueryError::Invalid{command: 0, message}) => assert!(message == "COUNT needs rows to read, from GET or SCAN", "unexpected message {}", message),
+        result => panic!("expected a pipeline without a source to be invalid, actually {:?}", result),
+    }
+    match execute(&mut lsm, &commands[1..], &token) {
+        Err(QueryError::Invalid{command: 0, message}) => assert!(message == "GET can only start a pipeline", "unexpected message {}", message),
+        result => panic!("expected a source within a pipeline to be invalid, actually {:?}", result),
+    }
+    assert!(matches!(run(&mut lsm, "GET", &token), Err(QueryError::Parse(_))), "expected a syntax error");
+
+    let outputs = run(&mut lsm, "PUT a 1; PUT c 3; SCAN RANGE c a", &token).unwrap();
+    assert!(rows(&outputs[2]) == ["key: c, value: 3"], "unexpected reversed range scan {:?}", outputs[2]);
+}
+
+#[test]
+pub fn test_execute_cancel_scan() {
+    /*
+    Cancelling the token stops a scan part way, so it fails rather than returning part of
+    its rows, and a cancelled token cancels whatever is executed with it next
+    */
+    let clock = Arc::new(ManualClock::new(0));
+    let mut lsm = LsmTree::new_delete_existing_with_options("test_execute_cancel_scan", LsmOptions::default().clock(clock.clone()));
+    for i in 0..1000 {
+        lsm.write(format!("foo{:04}", i), "bar").unwrap();
+    }
+    lsm.write_with_ttl("foo0500", "expiring", Duration::from_secs(1)).unwrap();
+
+    let token = CancellationToken::new();
+    let mut streamed: Vec<KVPair> = Vec::new();
+    let command = &parse("SCAN PREFIX foo").unwrap()[0];
+    let result = execute_streaming(&mut lsm, command, &token, &mut |pair| {
+        streamed.push(pair);
+        if streamed.len() == 10 {
+            token.cancel();
+        }
+    });
+    assert!(matches!(result, Err(QueryError::Cancelled)), "expected scan to be cancelled, actually {:?}", result);
+    assert!(streamed.len() == 10, "expected scan to stop after 10 rows, actually {}", streamed.len());
+
+    // A scan filtering out every row still checks the token as it reads them
+    assert!(matches!(run(&mut lsm, "SCAN | FILTER KEY PREFIX bar | COUNT", &token), Err(QueryError::Cancelled)), "expected filtered scan to be cancelled");
+
+    let token = CancellationToken::new();
+    clock.advance(Duration::from_secs(1));
+    let result = execute_streaming(&mut lsm, command, &token, &mut |_| {});
+    assert!(matches!(result, Ok(Output::Streamed(999))), "expected all unexpired rows to stream, actually {:?}", result);
+}
+
+#[test]
+pub fn test_quote_round_trips() {
+    let arguments: [&[u8]; 9] = [b"plain", b"user:1/a-b", b"two words", b"", b"#hash", b"a|b;c", "ключ \"значение\"".as_bytes(), b"\\n\t\r\0\x1b", &[0xff, b'a', 0xc3]];
+    for argument in arguments {
+        let quoted = quote(argument);
+        let commands = parse(&format!("GET {}", quoted)).unwrap();
+        assert!(commands == [Command::Pipeline(vec![Operator::Get{key: argument.to_vec()}])], "expected {:?} to parse back to {:?}", quoted, argument);
+    }
+    assert!(quote(b"plain") == "plain" && quote(b"LIMIT") == "LIMIT", "expected plain words to be left bare");
+    assert!(quote(&[0xff, b'"']) == "\"\\xff\\\"\"", "unexpected quoting {}", quote(&[0xff, b'"']));
+}
@@ -0,0 +1,341 @@
+#[cfg(test)]
+use std::{sync::{Arc, Mutex}, thread, time::Duration};
+#[cfg(test)]
+use crate::{replication::{Primary, ReplicationOptions, Secondary, SecondaryHandle}, server::ServerHandle, storage::{error::LsmError, files::purge_lsm_dir, lsm::LsmTree, merge_operator::U64AddOperator, options::LsmOptions, write_batch::WriteBatch}};
+
+#[cfg(test)]
+const WAIT: Duration = Duration::from_secs(10);
+
+#[cfg(test)]
+fn families() -> Vec<(&'static str, LsmOptions)> {
+    vec![("counters", LsmOptions::default().merge_operator(Arc::new(U64AddOperator))), ("sessions", LsmOptions::default())]
+}
+
+#[cfg(test)]
+fn open(dbname: &str, options: LsmOptions, fresh: bool) -> Arc<Mutex<LsmTree>> {
+    if fresh {
+        purge_lsm_dir(dbname).unwrap();
+    }
+    Arc::new(Mutex::new(LsmTree::open_with_column_families(dbname, options, families()).unwrap()))
+}
+
+#[cfg(test)]
+fn archiving() -> LsmOptions {
+    LsmOptions::default().wal_archive_size(Some(64 * 1024 * 1024))
+}
+
+#[cfg(test)]
+fn start_primary(lsm: &Arc<Mutex<LsmTree>>, addr: &str) -> ServerHandle {
+    Primary::bind(addr, lsm.clone()).unwrap().spawn().unwrap()
+}
+
+#[cfg(test)]
+fn follow(primary: &ServerHandle, lsm: &Arc<Mutex<LsmTree>>) -> SecondaryHandle {
+    let options = ReplicationOptions::default().max_reconnect_delay(Duration::from_millis(100));
+    Secondary::with_options(primary.local_addr(), lsm.clone(), options).unwrap().spawn()
+}
+
+// Every row of every column family, as strings for readable failures
+#[cfg(test)]
+fn contents(lsm: &Arc<Mutex<LsmTree>>) -> Vec<(String, String, String)> {
+    let mut lsm = lsm.lock().unwrap();
+    let mut rows = Vec::new();
+    for family in ["default", "counters", "sessions"] {
+        for pair in lsm.column_family(family).unwrap().iter() {
+            rows.push((family.to_string(), String::from_utf8_lossy(&pair.key).into_owned(), String::from_utf8_lossy(&pair.value).into_owned()));
+        }
+    }
+    rows
+}
+
+// Writes to each column family, with deletes, merges and batches among them
+#[cfg(test)]
+fn write_some(lsm: &Arc<Mutex<LsmTree>>, round: usize) {
+    let mut lsm = lsm.lock().unwrap();
+    for i in 0..50 {
+        lsm.write(format!("key:{:03}", i), format!("value {} {}", round, i)).unwrap();
+    }
+    for i in (0..50).step_by(7) {
+        lsm.delete(format!("key:{:03}", i)).unwrap();
+    }
+    let mut batch = WriteBatch::new();
+    batch.put_cf("sessions", format!("session:{}", round), b"open");
+    batch.merge_cf("counters", b"visits", 2u64.to_le_bytes());
+    batch.put(format!("round:{}", round), b"done");
+    lsm.write_batch(batch).unwrap();
+    lsm.column_family("counters").unwrap().merge(b"visits", 1u64.to_le_bytes()).unwrap();
+}
+
+#[test]
+pub fn test_replication_secondaries_converge() {
+    let primary_lsm = open("test_replication_converge_primary", archiving(), true);
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let early_lsm = open("test_replication_converge_early", LsmOptions::default(), true);
+    let early = follow(&primary, &early_lsm);
+    write_some(&primary_lsm, 0);
+
+    // One secondary follows along as commits are made, the other catches up on all of them at once
+    let late_lsm = open("test_replication_converge_late", LsmOptions::default(), true);
+    let late = follow(&primary, &late_lsm);
+    write_some(&primary_lsm, 1);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(latest == 2 * 60, "expected a sequence number per commit, actually {}", latest);
+    for (name, secondary, lsm) in [("early", &early, &early_lsm), ("late", &late, &late_lsm)] {
+        assert!(secondary.wait_for(latest, WAIT), "expected the {} secondary to catch up, status {:?}", name, secondary.status());
+        assert!(contents(lsm) == contents(&primary_lsm), "expected the {} secondary to match the primary", name);
+        assert!(lsm.lock().unwrap().latest_sequence() == latest, "expected the {} secondary's sequence number to match", name);
+        let status = secondary.status();
+        assert!(status.connected && status.applied == latest && status.primary_sequence == latest && status.error.is_none(), "unexpected status {:?}", status);
+    }
+    assert!(early_lsm.lock().unwrap().column_family("counters").unwrap().get(b"visits") == Some(6u64.to_le_bytes().to_vec()), "expected merges to be applied once each");
+
+    let status = early.shutdown();
+    assert!(!status.connected && status.error.is_none(), "unexpected status after shutdown {:?}", status);
+    late.shutdown();
+    primary.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_replication_resume() {
+    let primary_lsm = open("test_replication_resume_primary", archiving(), true);
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    // Small enough for the secondary to flush, so its position comes from both the WAL and the flushed sequence number
+    let options = || LsmOptions::default().write_buffer_size(2 * 1024);
+    let secondary_lsm = open("test_replication_resume_secondary", options(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    for round in 0..3 {
+        write_some(&primary_lsm, round);
+    }
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up, status {:?}", secondary.status());
+    secondary.shutdown();
+    drop(secondary_lsm);
+
+    // The secondary restarts from disk, picking up after the last commit it applied
+    write_some(&primary_lsm, 3);
+    let secondary_lsm = open("test_replication_resume_secondary", options(), false);
+    let applied = secondary_lsm.lock().unwrap().latest_sequence();
+    assert!(applied == latest, "expected the secondary to persist its position {}, actually {}", latest, applied);
+    let secondary = follow(&primary, &secondary_lsm);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the restarted secondary to catch up, status {:?}", secondary.status());
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the restarted secondary to match the primary");
+    assert!(secondary_lsm.lock().unwrap().column_family("counters").unwrap().get(b"visits") == Some(12u64.to_le_bytes().to_vec()), "expected no merge to be applied twice");
+
+    // A commit already applied is skipped
+    let replayed = secondary_lsm.lock().unwrap().apply_replicated(latest, vec![("default".to_string(), crate::storage::wal::WalRecord::Delete{key: b"round:0".to_vec()})]);
+    assert!(matches!(replayed, Ok(false)), "expected an old commit to be skipped, actually {:?}", replayed);
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_replication_merge_after_flush() {
+    /*
+    A merge the primary logged once the value it applies to was flushed still applies to
+    that value on a secondary holding it unflushed
+    */
+    let primary_lsm = open("test_replication_merge_after_flush_primary", archiving(), true);
+    {
+        let mut lsm = primary_lsm.lock().unwrap();
+        let mut counters = lsm.column_family("counters").unwrap();
+        counters.write(b"put", 1u64.to_le_bytes()).unwrap();
+        counters.write(b"deleted", 1u64.to_le_bytes()).unwrap();
+        counters.delete(b"deleted").unwrap();
+        lsm.flush();
+        let mut counters = lsm.column_family("counters").unwrap();
+        counters.merge(b"put", 1u64.to_le_bytes()).unwrap();
+        counters.merge(b"deleted", 1u64.to_le_bytes()).unwrap();
+    }
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let secondary_lsm = open("test_replication_merge_after_flush_secondary", LsmOptions::default(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up, status {:?}", secondary.status());
+    let mut lsm = secondary_lsm.lock().unwrap();
+    let mut counters = lsm.column_family("counters").unwrap();
+    assert!(counters.total_segments() == 0, "expected the secondary not to have flushed");
+    assert!(counters.get(b"put") == Some(2u64.to_le_bytes().to_vec()), "expected the merge to add to the put, actually {:?}", counters.get(b"put"));
+    assert!(counters.get(b"deleted") == Some(1u64.to_le_bytes().to_vec()), "expected the merge to start over from the delete, actually {:?}", counters.get(b"deleted"));
+    drop(lsm);
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the secondary to match the primary");
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_replication_primary_restart() {
+    let primary_lsm = open("test_replication_restart_primary", archiving(), true);
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let addr = primary.local_addr();
+    let secondary_lsm = open("test_replication_restart_secondary", LsmOptions::default(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    write_some(&primary_lsm, 0);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up");
+
+    // Writes while the primary is away are shipped once it is back on the same address
+    primary.shutdown().unwrap();
+    write_some(&primary_lsm, 1);
+    let primary = start_primary(&primary_lsm, &addr.to_string());
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to reconnect and catch up, status {:?}", secondary.status());
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the secondary to match the primary");
+    assert!(secondary.status().connected, "expected the secondary to be connected");
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_replication_archive() {
+    // Commits flushed out of the WAL are read back from the archive
+    let options = || archiving().write_buffer_size(2 * 1024);
+    let primary_lsm = open("test_replication_archive_primary", options(), true);
+    for round in 0..5 {
+        write_some(&primary_lsm, round);
+    }
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    let first = primary_lsm.lock().unwrap().updates_since(0, 1).unwrap();
+    assert!(first.len() == 1 && first[0].sequence == 1, "expected a single commit past the byte limit, actually {:?}", first);
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let secondary_lsm = open("test_replication_archive_secondary", LsmOptions::default(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up from the archive, status {:?}", secondary.status());
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the secondary to match the primary");
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+
+    // An archived commit past the byte limit by itself is returned alone, however many commits the WAL holds
+    let primary_lsm = open("test_replication_archive_large", archiving(), true);
+    {
+        let mut lsm = primary_lsm.lock().unwrap();
+        lsm.write("large", vec![b'v'; 8 * 1024]).unwrap();
+        lsm.flush();
+        for i in 0..3 {
+            lsm.write(format!("small{}", i), "v").unwrap();
+        }
+        let batches = lsm.updates_since(0, 1024).unwrap();
+        assert!(batches.len() == 1 && batches[0].sequence == 1, "expected only the large commit, actually {:?}", batches);
+        let batches = lsm.updates_since(1, 1024).unwrap();
+        assert!(batches.len() == 3, "expected the commits in the WAL after it, actually {:?}", batches);
+    }
+
+    // Without the archive reaching back far enough, a new secondary starts from a checkpoint
+    let primary_lsm = open("test_replication_archive_pruned", archiving().wal_archive_size(Some(1)).write_buffer_size(2 * 1024), true);
+    for round in 0..5 {
+        write_some(&primary_lsm, round);
+    }
+    let result = primary_lsm.lock().unwrap().updates_since(0, usize::MAX);
+    assert!(matches!(result, Err(LsmError::SequenceUnavailable{sequence: 0, earliest}) if earliest > 1), "expected early commits to be pruned, actually {:?}", result);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let secondary_lsm = open("test_replication_archive_bootstrapped", LsmOptions::default(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up from a checkpoint, status {:?}", secondary.status());
+    assert!(secondary.status().checkpoint.is_some(), "expected a checkpoint to be installed, status {:?}", secondary.status());
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the secondary to match the primary");
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+
+    // A secondary ahead of its primary is refused
+    let primary_lsm = open("test_replication_archive_behind", archiving(), true);
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let status = follow(&primary, &secondary_lsm).join();
+    assert!(status.error.as_ref().is_some_and(|error| error.contains("past the primary's")), "expected the secondary to stop with an error, status {:?}", status);
+    assert!(secondary_lsm.lock().unwrap().latest_sequence() == latest, "expected nothing to be applied");
+    primary.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_replication_checkpoint_handoff() {
+    // Without a WAL archive every flushed commit is gone from the primary's log, leaving the unflushed ones in the WAL
+    let options = || LsmOptions::default().write_buffer_size(2 * 1024);
+    let primary_lsm = open("test_replication_handoff_primary", options(), true);
+    for round in 0..5 {
+        write_some(&primary_lsm, round);
+    }
+    primary_lsm.lock().unwrap().column_family("sessions").unwrap().compact().unwrap();
+    write_some(&primary_lsm, 5);
+    let (pinned, latest) = {
+        let lsm = primary_lsm.lock().unwrap();
+        (lsm.checkpoint().unwrap().sequence, lsm.latest_sequence())
+    };
+    assert!(pinned > 0 && pinned < latest, "expected commits both before and after the checkpoint, pinned at {} of {}", pinned, latest);
+
+    // The primary carries on writing, flushing as it goes, while the secondary is bootstrapped
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let writing = primary_lsm.clone();
+    let writer = thread::spawn(move || {
+        for round in 6..20 {
+            write_some(&writing, round);
+            thread::sleep(Duration::from_millis(2));
+        }
+    });
+    let secondary_lsm = open("test_replication_handoff_secondary", options(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    writer.join().unwrap();
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(latest == 20 * 60, "expected a sequence number per commit, actually {}", latest);
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up, status {:?}", secondary.status());
+    let status = secondary.status();
+    assert!(status.checkpoint.is_some_and(|checkpoint| checkpoint >= pinned && checkpoint < latest), "expected commits to follow the checkpoint, status {:?}", status);
+    assert!(status.applied == latest && status.error.is_none(), "unexpected status {:?}", status);
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the secondary to match the primary");
+    // Each round merges 3 into the counter, so a commit missed or applied twice on either side of the checkpoint would show
+    let visits = secondary_lsm.lock().unwrap().column_family("counters").unwrap().get(b"visits");
+    assert!(visits == Some(60u64.to_le_bytes().to_vec()), "expected every merge to be applied once, actually {:?}", visits);
+
+    // The checkpoint and the commits after it are persisted, so a restart resumes from where it was
+    secondary.shutdown();
+    drop(secondary_lsm);
+    let secondary_lsm = open("test_replication_handoff_secondary", options(), false);
+    assert!(secondary_lsm.lock().unwrap().latest_sequence() == latest, "expected the secondary to persist its position");
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the restarted secondary to match the primary");
+    write_some(&primary_lsm, 20);
+    let secondary = follow(&primary, &secondary_lsm);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the restarted secondary to catch up, status {:?}", secondary.status());
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the restarted secondary to match the primary");
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_replication_checkpoint_replaces() {
+    let options = || LsmOptions::default().write_buffer_size(2 * 1024);
+    let primary_lsm = open("test_replication_replaces_primary", options(), true);
+    let primary = start_primary(&primary_lsm, "127.0.0.1:0");
+    let secondary_lsm = open("test_replication_replaces_secondary", options(), true);
+    let secondary = follow(&primary, &secondary_lsm);
+    write_some(&primary_lsm, 0);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up, status {:?}", secondary.status());
+    secondary.shutdown();
+
+    // While the secondary is away, keys it has are deleted and compacted away, tombstones and all
+    {
+        let mut lsm = primary_lsm.lock().unwrap();
+        lsm.delete("round:0").unwrap();
+        lsm.column_family("sessions").unwrap().delete("session:0").unwrap();
+    }
+    for round in 1..5 {
+        write_some(&primary_lsm, round);
+    }
+    for family in ["default", "sessions"] {
+        primary_lsm.lock().unwrap().column_family(family).unwrap().compact().unwrap();
+    }
+    write_some(&primary_lsm, 5);
+
+    // Only a checkpoint can bring the secondary up to date, replacing what it had
+    let secondary = follow(&primary, &secondary_lsm);
+    let latest = primary_lsm.lock().unwrap().latest_sequence();
+    assert!(secondary.wait_for(latest, WAIT), "expected the secondary to catch up, status {:?}", secondary.status());
+    assert!(secondary.status().checkpoint.is_some(), "expected a checkpoint to be installed, status {:?}", secondary.status());
+    assert!(contents(&secondary_lsm) == contents(&primary_lsm), "expected the secondary to match the primary");
+    let mut lsm = secondary_lsm.lock().unwrap();
+    assert!(lsm.get("round:0").is_none() && lsm.column_family("sessions").unwrap().get("session:0").is_none(), "expected keys deleted on the primary to be gone");
+    assert!(lsm.column_family("counters").unwrap().get(b"visits") == Some(18u64.to_le_bytes().to_vec()), "expected every merge to be applied once");
+    drop(lsm);
+    secondary.shutdown();
+    primary.shutdown().unwrap();
+}
@@ -0,0 +1,258 @@
+#[cfg(test)]
+use std::{collections::BTreeSet, io::{BufReader, Write}, net::TcpStream, sync::{Arc, Mutex}, thread, time::Duration};
+#[cfg(test)]
+use crate::{resp::{encode_command, glob_match, read_command, read_value, RespServer, RespValue}, server::ServerHandle, storage::{clock::ManualClock, lsm::LsmTree, options::LsmOptions}};
+
+#[cfg(test)]
+struct TestClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+#[cfg(test)]
+impl TestClient {
+    fn connect(server: &ServerHandle) -> TestClient {
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        TestClient{reader: BufReader::new(stream.try_clone().unwrap()), stream}
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        self.stream.write_all(&encode_command(args)).unwrap();
+    }
+
+    fn reply(&mut self) -> Option<RespValue> {
+        read_value(&mut self.reader).unwrap()
+    }
+
+    fn call(&mut self, args: &[&str]) -> RespValue {
+        self.send(args);
+        self.reply().unwrap()
+    }
+}
+
+#[cfg(test)]
+fn bulks(values: &[Option<&str>]) -> RespValue {
+    RespValue::Array(Some(values.iter().map(|value| RespValue::Bulk(value.map(|value| value.as_bytes().to_vec()))).collect()))
+}
+
+#[test]
+pub fn test_resp_encode_decode() {
+    let value = RespValue::Array(Some(vec![
+        RespValue::Simple("OK".to_string()),
+        RespValue::Error("ERR bad".to_string()),
+        RespValue::Integer(-42),
+        RespValue::bulk(b"with\r\nCRLF\0"),
+        RespValue::Bulk(None),
+        RespValue::Array(None),
+        RespValue::Array(Some(Vec::new())),
+    ]));
+    let bytes = value.to_bytes();
+    assert!(bytes.starts_with(b"*7\r\n+OK\r\n-ERR bad\r\n:-42\r\n$11\r\nwith\r\nCRLF\0\r\n$-1\r\n*-1\r\n*0\r\n"), "unexpected encoding {:?}", String::from_utf8_lossy(&bytes));
+    let mut reader = &bytes[..];
+    assert!(read_value(&mut reader).unwrap() == Some(value), "expected value to decode as encoded");
+    assert!(read_value(&mut reader).unwrap().is_none(), "expected nothing after the value");
+
+    let mut reader: &[u8] = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\nset  k  v\r\n\r\nPING\n";
+    assert!(read_command(&mut reader).unwrap() == Some(vec![b"GET".to_vec(), b"key".to_vec()]), "unexpected command");
+    assert!(read_command(&mut reader).unwrap() == Some(vec![b"set".to_vec(), b"k".to_vec(), b"v".to_vec()]), "unexpected inline command");
+    assert!(read_command(&mut reader).unwrap() == Some(Vec::new()), "expected a blank line to be an empty command");
+    assert!(read_command(&mut reader).unwrap() == Some(vec![b"PING".to_vec()]), "expected a line ending in LF to be read");
+    assert!(read_command(&mut reader).unwrap().is_none(), "expected the stream to have ended");
+
+    let malformed: [&[u8]; 5] = [b"$abc\r\n", b"$3\r\nabcde\r\n", b"*-5\r\n", b"$-2\r\n", b"?\r\n"];
+    for input in malformed {
+        let mut reader = input;
+        let result = read_value(&mut reader);
+        assert!(result.as_ref().is_err_and(|e| e.to_string().starts_with("Protocol error")), "expected {:?} to be a protocol error, actually {:?}", String::from_utf8_lossy(input), result);
+    }
+    let mut reader: &[u8] = b"*1\r\n:1\r\n";
+    assert!(read_command(&mut reader).is_err(), "expected a command of anything but bulk strings to fail");
+    let mut reader: &[u8] = b"*2\r\n$3\r\nGET\r\n";
+    assert!(read_command(&mut reader).is_err(), "expected a truncated command to fail");
+
+    // Nested arrays fail at the first one in a command, and past MAX_NESTING in a value, rather than running out of stack
+    let nested = b"*1\r\n".repeat(1_000_000);
+    let result = read_command(&mut &nested[..]);
+    assert!(result.as_ref().is_err_and(|e| e.to_string() == "Protocol error: expected a bulk string"), "expected a nested command to fail, actually {:?}", result);
+    let result = read_value(&mut &nested[..]);
+    assert!(result.as_ref().is_err_and(|e| e.to_string() == "Protocol error: arrays nested too deeply"), "expected a deeply nested value to fail, actually {:?}", result);
+    let shallow = [b"*1\r\n".repeat(64), b":1\r\n".to_vec()].concat();
+    assert!(read_value(&mut &shallow[..]).is_ok_and(|value| value.is_some()), "expected values nested 64 deep to be read");
+}
+
+#[test]
+pub fn test_resp_glob_match() {
+    let cases: [(&str, &str, bool); 16] = [
+        ("*", "", true),
+        ("*", "anything", true),
+        ("user:*", "user:1", true),
+        ("user:*", "users", false),
+        ("h?llo", "hello", true),
+        ("h?llo", "hllo", false),
+        ("h*llo", "heeeello", true),
+        ("h*l*o", "hello world o", true),
+        ("*o", "hello world", false),
+        ("h[ae]llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[^e]llo", "hallo", true),
+        ("h[^e]llo", "hello", false),
+        ("h[a-c]llo", "hbllo", true),
+        ("h[c-a]llo", "hbllo", true),
+        ("h\\*llo", "h*llo", true),
+    ];
+    for (pattern, text, matches) in cases {
+        assert!(glob_match(pattern.as_bytes(), text.as_bytes()) == matches, "expected {:?} matching {:?} to be {}", pattern, text, matches);
+    }
+    assert!(!glob_match(b"h\\*llo", b"hello") && glob_match(b"[abc", b"b"), "unexpected match of escapes or unclosed classes");
+}
+
+#[test]
+pub fn test_resp_server_commands() {
+    let clock = Arc::new(ManualClock::new(0));
+    let lsm = LsmTree::new_delete_existing_with_options("test_resp_server_commands", LsmOptions::default().clock(clock.clone()));
+    let server = RespServer::bind("127.0.0.1:0", Arc::new(Mutex::new(lsm))).unwrap().spawn().unwrap();
+    let mut client = TestClient::connect(&server);
+    let ok = RespValue::ok();
+
+    assert!(client.call(&["PING"]) == RespValue::Simple("PONG".to_string()), "unexpected ping");
+    assert!(client.call(&["ping", "hi"]) == RespValue::bulk("hi"), "unexpected ping with a message");
+    assert!(client.call(&["SET", "foo", "bar"]) == ok, "unexpected set");
+    assert!(client.call(&["GET", "foo"]) == RespValue::bulk("bar"), "unexpected get");
+    assert!(client.call(&["GET", "missing"]) == RespValue::Bulk(None), "expected missing key to be null");
+    assert!(client.call(&["MSET", "a", "1", "b", "2", "c", "3"]) == ok, "unexpected mset");
+    assert!(client.call(&["MGET", "a", "missing", "c"]) == bulks(&[Some("1"), None, Some("3")]), "unexpected mget");
+    assert!(client.call(&["EXISTS", "a", "a", "missing"]) == RespValue::Integer(2), "expected exists to count repeated keys");
+    assert!(client.call(&["DEL", "a", "b", "missing"]) == RespValue::Integer(2), "expected del to count deleted keys");
+    assert!(client.call(&["EXISTS", "a", "b"]) == RespValue::Integer(0), "expected keys to be deleted");
+
+    assert!(client.call(&["SET", "session", "x", "EX", "10"]) == ok, "unexpected set with EX");
+    assert!(client.call(&["SET", "token", "y", "px", "1500"]) == ok, "unexpected set with PX");
+    clock.advance(Duration::from_millis(1500));
+    assert!(client.call(&["MGET", "session", "token"]) == bulks(&[Some("x"), None]), "expected PX key to have expired");
+    clock.advance(Duration::from_secs(10));
+    assert!(client.call(&["GET", "session"]) == RespValue::Bulk(None), "expected EX key to have expired");
+
+    let errors: [(&[&str], &str); 9] = [
+        (&["GET"], "ERR wrong number of arguments for 'get' command"),
+        (&["GET", "a", "b"], "ERR wrong number of arguments for 'get' command"),
+        (&["MSET", "a", "1", "b"], "ERR wrong number of arguments for 'mset' command"),
+        (&["SET", "a", "1", "EX"], "ERR syntax error"),
+        (&["SET", "a", "1", "EX", "1", "PX", "1"], "ERR syntax error"),
+        (&["SET", "a", "1", "PX", "soon"], "ERR value is not an integer or out of range"),
+        (&["SET", "a", "1", "EX", "-1"], "ERR invalid expire time in 'set' command"),
+        (&["SCAN", "12345"], "ERR invalid cursor"),
+        (&["HGET", "a", "b"], "ERR unknown command 'hget', with args beginning with: 'a'"),
+    ];
+    for (args, message) in errors {
+        assert!(client.call(args) == RespValue::Error(message.to_string()), "expected {:?} to fail with {:?}", args, message);
+    }
+    assert!(client.call(&["GET", "a"]) == RespValue::Bulk(None), "expected failed commands not to write");
+
+    // Replies to pipelined commands come back in order
+    let mut pipeline = Vec::new();
+    for i in 0..100 {
+        pipeline.extend(encode_command(&["SET", &format!("key:{:03}", i), &i.to_string()]));
+        pipeline.extend(encode_command(&["GET", &format!("key:{:03}", i)]));
+    }
+    client.stream.write_all(&pipeline).unwrap();
+    for i in 0..100 {
+        assert!(client.reply() == Some(ok.clone()), "unexpected reply to pipelined set");
+        assert!(client.reply() == Some(RespValue::bulk(i.to_string())), "unexpected reply to pipelined get {}", i);
+    }
+
+    assert!(client.call(&["QUIT"]) == ok, "unexpected quit");
+    assert!(client.reply().is_none(), "expected connection to be closed after quit");
+
+    let mut client = TestClient::connect(&server);
+    client.stream.write_all(b"*1\r\n$x\r\n").unwrap();
+    assert!(matches!(client.reply(), Some(RespValue::Error(e)) if e.starts_with("ERR Protocol error")), "expected a protocol error");
+    assert!(client.reply().is_none(), "expected connection to be closed after a protocol error");
+
+    // A read-only DB alongside the writer serves reads and refuses writes
+    let reader = LsmTree::open("test_resp_server_commands", LsmOptions::default().clock(clock.clone()).read_only(true)).unwrap();
+    let replica = RespServer::bind("127.0.0.1:0", Arc::new(Mutex::new(reader))).unwrap().spawn().unwrap();
+    let mut client = TestClient::connect(&replica);
+    assert!(client.call(&["GET", "foo"]) == RespValue::bulk("bar"), "unexpected get from a read-only DB");
+    for args in [&["SET", "foo", "baz"][..], &["MSET", "foo", "baz"], &["DEL", "foo"]] {
+        assert!(client.call(args) == RespValue::Error("READONLY You can't write against a read only replica.".to_string()), "expected {:?} to be refused", args);
+    }
+    assert!(client.call(&["GET", "foo"]) == RespValue::bulk("bar"), "expected refused writes not to write");
+    replica.shutdown().unwrap();
+    server.shutdown().unwrap();
+}
+
+#[test]
+pub fn test_resp_server_scan() {
+    let lsm = LsmTree::new_delete_existing("test_resp_server_scan");
+    let server = RespServer::bind("127.0.0.1:0", Arc::new(Mutex::new(lsm))).unwrap().spawn().unwrap();
+
+    // Clients on their own threads write at the same time
+    let writers: Vec<_> = (0..4).map(|writer| {
+        let mut client = TestClient::connect(&server);
+        thread::spawn(move || {
+            for i in writer * 25..(writer + 1) * 25 {
+                assert!(client.call(&["SET", &format!("{}:{:02}", if i % 2 == 0 { "even" } else { "odd" }, i), "v"]) == RespValue::ok(), "unexpected set");
+            }
+        })
+    }).collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    let mut client = TestClient::connect(&server);
+    let mut scan = |pattern: &str, count: &str, deleting: bool| {
+        let (mut cursor, mut keys, mut calls) = ("0".to_string(), BTreeSet::new(), 0);
+        loop {
+            let reply = client.call(&["SCAN", &cursor, "MATCH", pattern, "COUNT", count]);
+            let (next, batch) = match reply {
+                RespValue::Array(Some(mut values)) if values.len() == 2 => (values.remove(0), values.remove(0)),
+                reply => panic!("unexpected scan reply {:?}", reply),
+            };
+            if let RespValue::Array(Some(batch)) = batch {
+                for key in batch {
+                    if let RespValue::Bulk(Some(key)) = key {
+                        assert!(keys.insert(String::from_utf8(key).unwrap()), "expected every key to be returned once");
+                    }
+                }
+            }
+            calls += 1;
+            cursor = match next {
+                RespValue::Bulk(Some(next)) => String::from_utf8(next).unwrap(),
+                next => panic!("unexpected cursor {:?}", next),
+            };
+            if deleting && calls == 2 {
+                // Keys deleted part way through don't stop the scan returning the rest
+                assert!(client.call(&["DEL", "even:00", "odd:99"]) == RespValue::Integer(2), "unexpected del");
+            }
+            if cursor == "0" {
+                return (keys, calls);
+            }
+        }
+    };
+
+    let (keys, calls) = scan("*", "7", false);
+    assert!(keys.len() == 100 && calls == 15, "expected all 100 keys in 15 calls, actually {} in {}", keys.len(), calls);
+    let (keys, _) = scan("odd:*", "10", false);
+    assert!(keys.len() == 50 && keys.iter().all(|key| key.starts_with("odd:")), "unexpected matching keys {:?}", keys);
+    let (keys, _) = scan("*", "30", true);
+    assert!(keys.len() == 99 && !keys.contains("odd:99"), "expected keys present for the whole scan to be returned, actually {}", keys.len());
+
+    // A cursor resumes after its last key even once that key is deleted, even:00 and odd:99 being gone
+    let cursor = match client.call(&["SCAN", "0", "COUNT", "49"]) {
+        RespValue::Array(Some(values)) => values[0].clone(),
+        reply => panic!("unexpected scan reply {:?}", reply),
+    };
+    assert!(client.call(&["DEL", "even:98"]) == RespValue::Integer(1), "unexpected del");
+    let cursor = match cursor {
+        RespValue::Bulk(Some(cursor)) => String::from_utf8(cursor).unwrap(),
+        cursor => panic!("unexpected cursor {:?}", cursor),
+    };
+    match client.call(&["SCAN", &cursor, "COUNT", "100"]) {
+        RespValue::Array(Some(values)) => match &values[1] {
+            RespValue::Array(Some(keys)) => assert!(keys.len() == 49 && keys[0] == RespValue::bulk("odd:01"), "expected the odd keys after the deleted one, actually {:?}", keys),
+            keys => panic!("unexpected keys {:?}", keys),
+        },
+        reply => panic!("unexpected scan reply {:?}", reply),
+    }
+    server.shutdown().unwrap();
+}
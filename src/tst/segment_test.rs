@@ -0,0 +1,189 @@
+#[cfg(test)]
+use std::{fs::{create_dir_all, read, write, OpenOptions}, sync::Arc};
+
+#[cfg(test)]
+use crate::storage::{block::{Block, BlockBuilder, RESTART_INTERVAL}, coding::{get_length_prefixed, put_length_prefixed, put_varint64}, comparator::BytewiseComparator, files::get_lsmdir, filter::{bloom_hash, key_may_match, BloomFilterPolicy}, mmap::Mmap, options::LsmOptions, segment::{SegmentBuilder, SegmentReader}, stats::{Statistics, Ticker}, tree::TriOption::*};
+
+#[test]
+pub fn test_block_seek_across_restarts() {
+    let mut builder = BlockBuilder::new();
+    let entries = RESTART_INTERVAL * 4 + 3;
+    for i in 0..entries {
+        // Keys share long prefixes, so most are stored as a short suffix
+        builder.add(format!("shared_prefix_key{:04}", i * 2).as_bytes(), format!("value{}", i).as_bytes());
+    }
+    let block = Block::new(builder.finish()).unwrap();
+
+    let all: Vec<_> = block.iter().map(|entry| entry.unwrap().0).collect();
+    assert!(all.len() == entries, "expected {} entries in block, actually {}", entries, all.len());
+
+    for i in 0..entries {
+        // Seeking between two keys lands on the next key up
+        let mut iter = block.iter();
+        iter.seek(format!("shared_prefix_key{:04}", i * 2 - (i > 0) as usize).as_bytes(), &BytewiseComparator).unwrap();
+        let (key, value) = iter.next_entry().unwrap().unwrap();
+        assert!(key == format!("shared_prefix_key{:04}", i * 2).as_bytes(), "seek for entry {} found {:?}", i, String::from_utf8_lossy(&key));
+        assert!(value == format!("value{}", i).as_bytes(), "unexpected value for entry {}", i);
+    }
+
+    let mut iter = block.iter();
+    iter.seek(b"zzz", &BytewiseComparator).unwrap();
+    assert!(iter.next_entry().unwrap().is_none(), "expected seek past the last key to exhaust the block");
+}
+
+#[test]
+pub fn test_segment_point_lookups() {
+    let dir = get_lsmdir("test_segment_point_lookups");
+    create_dir_all(&dir).unwrap();
+    let path = dir.join("segment_0.data");
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path).unwrap();
+
+    // Small blocks so the segment spans many of them
+    let mut builder = SegmentBuilder::new(file, &LsmOptions::default().block_size(256), 0);
+    for i in 0..1000 {
+        let key = format!("foo{:04}", i);
+        if i % 10 == 0 {
+            builder.add(key.as_bytes(), Tombstoned).unwrap();
+        }
+        else {
+            builder.add(key.as_bytes(), TriSome(format!("bar{}", i).as_bytes())).unwrap();
+        }
+    }
+    let properties = builder.finish().unwrap();
+    assert!(properties.num_entries == 1000, "expected 1000 entries, actually {}", properties.num_entries);
+    assert!(properties.num_deletions == 100, "expected 100 deletions, actually {}", properties.num_deletions);
+    assert!(properties.num_data_blocks > 10, "expected many data blocks, actually {}", properties.num_data_blocks);
+
+    let reader = SegmentReader::open(OpenOptions::new().read(true).open(&path).unwrap(), &LsmOptions::default(), Arc::new(Statistics::new())).unwrap();
+    assert!(*reader.properties() == properties, "properties read back differ from those written");
+
+    for i in 0..1000 {
+        let key = format!("foo{:04}", i);
+        match reader.get(key.as_bytes()).unwrap() {
+            TriSome(v) => assert!(i % 10 != 0 && v == format!("bar{}", i).as_bytes(), "unexpected value for {}", key),
+            Tombstoned => assert!(i % 10 == 0, "unexpected tombstone for {}", key),
+            TriNone => panic!("{} missing from segment", key),
+            TriMerge(_) => panic!("unexpected merge operands for {}", key),
+        }
+    }
+
+    for missing in ["foo", "foo0000a", "foo9999", "a", "zzz"] {
+        assert!(matches!(reader.get(missing.as_bytes()).unwrap(), TriNone), "found key {} never written", missing);
+    }
+
+    // Iterating from a key seeks to it, tombstones included, whichever block it's in
+    for (start, first) in [("a", Some(0)), ("foo0500", Some(500)), ("foo0499a", Some(500)), ("foo0999", Some(999)), ("zzz", None)] {
+        let keys: Vec<Vec<u8>> = reader.iter_from(start.as_bytes()).unwrap().map(|entry| entry.unwrap().key).collect();
+        let expected: Vec<Vec<u8>> = first.map_or(0..0, |first| first..1000).map(|i| format!("foo{:04}", i).into_bytes()).collect();
+        assert!(keys == expected, "expected {} keys from {}, actually {}", expected.len(), start, keys.len());
+    }
+}
+
+#[test]
+pub fn test_segment_block_checksums() {
+    /*
+    Every block is checksummed, so a damaged data block fails to read rather than being
+    decoded, and a segment from before blocks had checksums fails to open
+    */
+    let dir = get_lsmdir("test_segment_block_checksums");
+    create_dir_all(&dir).unwrap();
+    let path = dir.join("segment_0.data");
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path).unwrap();
+    let mut builder = SegmentBuilder::new(file, &LsmOptions::default().block_size(256), 0);
+    for i in 0..100 {
+        builder.add(format!("foo{:04}", i).as_bytes(), TriSome(format!("bar{}", i).as_bytes())).unwrap();
+    }
+    builder.finish().unwrap();
+    let contents = read(&path).unwrap();
+    let open = || SegmentReader::open(OpenOptions::new().read(true).open(&path).unwrap(), &LsmOptions::default(), Arc::new(Statistics::new()));
+
+    // Past the frame header and the codec id, in the first data block
+    let mut damaged = contents.clone();
+    damaged[12] ^= 1;
+    write(&path, &damaged).unwrap();
+    let reader = open().unwrap();
+    assert!(reader.get(b"foo0000").is_err(), "expected a damaged block to fail its checksum");
+    assert!(reader.iter().unwrap().any(|entry| entry.is_err()), "expected iterating over a damaged block to fail");
+    assert!(matches!(reader.get(b"foo0099").unwrap(), TriSome(_)), "expected undamaged blocks to still be read");
+
+    let mut unframed = contents.clone();
+    let magic = unframed.len() - 8;
+    unframed[magic] ^= 1;
+    write(&path, &unframed).unwrap();
+    match open() {
+        Err(e) => assert!(e.to_string().contains("without block checksums"), "unexpected error {}", e),
+        Ok(_) => panic!("expected a segment without block checksums to fail to open"),
+    }
+}
+
+#[test]
+pub fn test_bloom_filter_false_positive_rate() {
+    let policy = BloomFilterPolicy::new(10);
+    let hashes: Vec<u32> = (0..10000).map(|i| bloom_hash(format!("foo{}", i).as_bytes())).collect();
+    let filter = policy.create_filter(&hashes);
+
+    for i in 0..10000 {
+        let key = format!("foo{}", i);
+        assert!(key_may_match(key.as_bytes(), &filter), "bloom filter ruled out {} which was added", key);
+    }
+
+    let false_positives = (0..10000).filter(|i| key_may_match(format!("bar{}", i).as_bytes(), &filter)).count();
+    assert!(false_positives < 200, "expected a false positive rate under 2%, actually {} in 10000", false_positives);
+}
+
+#[test]
+pub fn test_segment_mmap_reads() {
+    let dir = get_lsmdir("test_segment_mmap_reads");
+    create_dir_all(&dir).unwrap();
+    let path = dir.join("segment_0.data");
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path).unwrap();
+
+    let mut builder = SegmentBuilder::new(file, &LsmOptions::default().block_size(256), 1);
+    for i in 0..500 {
+        builder.add(format!("foo{:04}", i).as_bytes(), TriSome(format!("bar{}", i).repeat(4).as_bytes())).unwrap();
+    }
+    builder.finish().unwrap();
+
+    // Read through the mapping both with and without a block cache in front of it
+    for options in [LsmOptions::default().use_mmap_reads(true), LsmOptions::default().use_mmap_reads(true).block_cache(None)] {
+        let stats = Arc::new(Statistics::new());
+        let reader = SegmentReader::open(OpenOptions::new().read(true).open(&path).unwrap(), &options, stats.clone()).unwrap();
+        assert!(reader.is_mmapped(), "expected segment to be mapped");
+        assert!(stats.get(Ticker::MmapFallback) == 0, "expected mapping not to fall back to file reads");
+
+        for i in 0..500 {
+            let key = format!("foo{:04}", i);
+            match reader.get(key.as_bytes()).unwrap() {
+                TriSome(v) => assert!(v == format!("bar{}", i).repeat(4).as_bytes(), "unexpected value for {}", key),
+                _ => panic!("{} missing from mapped segment", key),
+            }
+        }
+        let scanned = reader.iter().unwrap().count();
+        assert!(scanned == 500, "expected to scan 500 entries from mapped segment, actually {}", scanned);
+    }
+
+    let reader = SegmentReader::open(OpenOptions::new().read(true).open(&path).unwrap(), &LsmOptions::default(), Arc::new(Statistics::new())).unwrap();
+    assert!(!reader.is_mmapped(), "segment mapped without mmap reads enabled");
+}
+
+#[test]
+pub fn test_mmap_empty_file_fails() {
+    let dir = get_lsmdir("test_mmap_empty_file_fails");
+    create_dir_all(&dir).unwrap();
+    let file = OpenOptions::new().create(true).write(true).read(true).truncate(true).open(dir.join("empty")).unwrap();
+    assert!(Mmap::map(&file).is_err(), "mapped an empty file");
+}
+
+#[test]
+pub fn test_length_prefixed_bounds() {
+    let mut src = Vec::new();
+    put_length_prefixed(&mut src, b"abc");
+    assert!(get_length_prefixed(&src).unwrap() == (&b"abc"[..], 4), "expected the slice and the bytes it took");
+    assert!(get_length_prefixed(&src[..3]).is_err(), "expected a slice cut short to fail");
+
+    // A length that would overflow the end offset is corruption, not a panic
+    let mut src = Vec::new();
+    put_varint64(&mut src, u64::MAX);
+    src.extend_from_slice(b"abc");
+    assert!(get_length_prefixed(&src).is_err(), "expected a huge length to fail");
+}
@@ -0,0 +1,76 @@
+#[cfg(test)]
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, thread};
+
+#[cfg(test)]
+use crate::storage::skiplist::SkipList;
+
+#[test]
+pub fn test_skiplist_iterates_in_order() {
+    let list: SkipList<String, usize> = SkipList::new();
+
+    // Insert out of order, including the sequential keys that degenerate an unbalanced tree
+    for i in (0..500).rev() {
+        list.insert(format!("foo{:04}", i), i);
+    }
+
+    assert!(list.len() == 500, "Expected 500 entries, actually {}", list.len());
+    let mut expected = 0;
+    for (k, v) in list.iter() {
+        assert!(*k == format!("foo{:04}", expected), "Expected key foo{:04}, actually {}", expected, k);
+        assert!(*v == expected, "Expected value {} for {}, actually {}", expected, k, v);
+        expected += 1;
+    }
+    assert!(expected == 500, "Expected to iterate 500 entries, actually {}", expected);
+}
+
+#[test]
+pub fn test_skiplist_overwrite_keeps_size() {
+    let list: SkipList<String, String> = SkipList::new();
+
+    list.insert("foo".to_string(), "bar".to_string());
+    let replaced = list.insert("foo".to_string(), "bar2".to_string());
+
+    assert!(replaced.map(|v| v.as_str()) == Some("bar"), "Expected to replace bar, actually {:?}", replaced);
+    assert!(list.len() == 1, "Expected 1 entry after overwrite, actually {}", list.len());
+    assert!(list.get(&"foo".to_string()).map(|v| v.as_str()) == Some("bar2"), "Expected bar2 for foo");
+    assert!(!list.contains_key(&"bar".to_string()), "Found key bar that was never inserted");
+}
+
+#[test]
+pub fn test_skiplist_concurrent_readers() {
+    /*
+    Readers run without locking while a single writer inserts, every key a reader
+    finds must carry the value written for it, and every key inserted before the
+    readers started must always be found
+    */
+    let list: Arc<SkipList<usize, usize>> = Arc::new(SkipList::new());
+    for i in 0..1000 {
+        list.insert(i * 2, i * 2);
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4).map(|_| {
+        let list = list.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Acquire) {
+                for i in 0..2000 {
+                    match list.get(&i) {
+                        Some(v) => assert!(*v == i, "Expected {} for key {}, actually {}", i, i, v),
+                        None => assert!(i % 2 == 1, "Pre-existing key {} missing during concurrent insert", i),
+                    }
+                }
+            }
+        })
+    }).collect();
+
+    for i in 0..1000 {
+        list.insert(i * 2 + 1, i * 2 + 1);
+    }
+    done.store(true, Ordering::Release);
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert!(list.len() == 2000, "Expected 2000 entries, actually {}", list.len());
+}
@@ -1,6 +1,6 @@
 use crate::{storage::lsm::LsmTree, log};
 pub fn verify_key_value(tree: &mut LsmTree, k: &str, v: &str) {
-    if let Some(value) = tree.get(k) {
+    if let Some(value) = tree.get_str(k) {
         assert!(v == value, "invalid key, expected {}, actually {}", v, value);
         log(&format!("verified {} {}", k, v));
     }
@@ -10,7 +10,7 @@ pub fn verify_key_value(tree: &mut LsmTree, k: &str, v: &str) {
 }
 
 pub fn verify_deleted(tree: &mut LsmTree, k: &str) {
-    if let Some(value) = tree.get(k) {
+    if let Some(value) = tree.get_str(k) {
         panic!("expected deleted key {}, actually {}", k, value);
     }
 }
\ No newline at end of file